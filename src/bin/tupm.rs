@@ -13,13 +13,16 @@ extern crate rpassword;
 extern crate upm;
 
 use chrono::prelude::*;
-use clap::{App, Arg, ArgMatches};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process;
 use tupm::controller::Controller;
-use upm::database::Database;
+use upm::backup;
+use upm::database::{Database, DatabaseFormat, KdfParams};
 use upm::error::UpmError;
+use upm::harden;
+use upm::secret::SecretString;
 use upm::sync;
 
 mod tupm {
@@ -85,10 +88,21 @@ fn get_default_database_path() -> Result<PathBuf, UpmError> {
 }
 
 /// Open the database file at the specified path using the provided password.  Print an error and
-/// exit if it cannot be opened, read, and decrypted.
+/// exit if it cannot be opened, read, and decrypted, or a warning if it uses the legacy password
+/// encoding.
 fn open_database_or_exit(filename: &PathBuf, password: &str) -> Database {
     match Database::load_from_file(filename, password) {
-        Ok(database) => database,
+        Ok(database) => {
+            if database.has_legacy_password_encoding() {
+                println!(
+                    "Warning: {} was encrypted with the password encoding of an earlier version \
+                     of tupm, which the UPM Java client cannot read.  Re-encrypt it with \
+                     \"tupm migrate --to 3\".",
+                    filename.display()
+                );
+            }
+            database
+        }
         Err(e) => {
             println!("error opening database: {}", e);
             process::exit(EXIT_FAILURE);
@@ -121,7 +135,12 @@ fn export(database: &Database) {
         "-------------------", "----------------------------------", "------------"
     );
     for account in accounts.iter() {
-        println!(exportfmt!(), account.name, account.user, account.password);
+        println!(
+            exportfmt!(),
+            account.name,
+            account.user,
+            account.password.as_str()
+        );
     }
 
     // Long-form output
@@ -137,16 +156,254 @@ fn export(database: &Database) {
 
         println!("Account:  {}", account.name);
         println!("Username: {}", account.user);
-        println!("Password: {}", account.password);
+        println!("Password: {}", account.password.as_str());
         println!("URL:      {}", account.url);
         println!("Notes:    {}", notes);
         println!();
     }
 }
 
+/// Report what a sync of the provided database would do, without syncing.  Exit if the remote
+/// database cannot be checked.
+fn status(database: &Database) {
+    match sync::status(database, None) {
+        Ok(status) => println!("{}", status),
+        Err(e) => {
+            println!("Error checking remote database: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+}
+
+/// Show how the second database file differs from the first, such as a database and one of its
+/// backups.  The password is used for both files, unless the second needs another one.  Exit if
+/// either file cannot be opened.
+fn diff(
+    old_path: &PathBuf,
+    new_path: &PathBuf,
+    password: Option<SecretString>,
+    show_passwords: bool,
+) {
+    let prompt = |text: &str| {
+        SecretString::new(rpassword::prompt_password_stdout(text).unwrap_or_else(|e| {
+            println!("Error reading password: {}", e);
+            process::exit(EXIT_FAILURE);
+        }))
+    };
+    let password = password.unwrap_or_else(|| prompt("Password: "));
+    let old = open_database_or_exit(old_path, &password);
+    let prompt_new = || {
+        let text = format!("Password for {}: ", new_path.display());
+        open_database_or_exit(new_path, &prompt(&text))
+    };
+    let new = match Database::load_from_file(new_path, &password) {
+        Err(UpmError::BadPassword) => prompt_new(),
+        #[cfg(feature = "openssl_crypto")]
+        Err(UpmError::Crypto(_)) => prompt_new(),
+        Ok(database) => database,
+        Err(e) => {
+            println!("error opening database: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    };
+    if show_passwords {
+        println!("{}", old.diff_with_passwords(&new));
+    } else {
+        println!("{}", old.diff(&new));
+    }
+}
+
+/// Convert the database file to the provided format, after backing it up.  The password is
+/// prompted for if none was provided.  Exit if the database cannot be converted, or if the
+/// hardened format is requested and the database syncs with a repository which can't hold it.  A
+/// database already in the provided format is only saved again if it uses the legacy password
+/// encoding, which re-encrypts it.
+fn migrate(path: &PathBuf, password: Option<SecretString>, format: DatabaseFormat) {
+    let password = password.unwrap_or_else(|| {
+        SecretString::new(
+            rpassword::prompt_password_stdout("Password: ").unwrap_or_else(|e| {
+                println!("Error reading password: {}", e);
+                process::exit(EXIT_FAILURE);
+            }),
+        )
+    });
+    let mut database = open_database_or_exit(path, &password);
+    // Saving in the same format still re-encrypts a database which uses the legacy password
+    // encoding.
+    let reencrypt = database.format() == format;
+    if reencrypt && !database.has_legacy_password_encoding() {
+        println!("The database is already in the {} format.", format);
+        return;
+    }
+    if let DatabaseFormat::Hardened(_) = format {
+        if database.has_remote() && !sync::url_accepts_hardened_format(&database.sync_url) {
+            println!(
+                "Error: This database syncs with the UPM sync server at \"{}\", which must hold \
+                 version 3 databases so the UPM Java client can read them.  Keep the version 3 \
+                 format, or remove the sync URL with Edit Database Properties first.",
+                database.sync_url
+            );
+            process::exit(EXIT_FAILURE);
+        }
+    }
+    if let Err(e) = database.set_format(format) {
+        println!("Error: {}", e);
+        process::exit(EXIT_FAILURE);
+    }
+    if let Err(e) = backup::backup(path) {
+        println!("Error backing up database; not converted: {}", e);
+        process::exit(EXIT_FAILURE);
+    }
+    if let Err(e) = database.save() {
+        println!("Error saving database: {}", e);
+        process::exit(EXIT_FAILURE);
+    }
+    if reencrypt {
+        println!("Database re-encrypted with the standard password encoding.");
+        return;
+    }
+    println!("Database converted to the {} format.", format);
+    if format == DatabaseFormat::V3 {
+        println!("(The UPM Java client and the UPM sync server can now read it.)");
+    } else {
+        println!("(Only tupm can read it.  Convert it back with \"tupm migrate --to 3\".)");
+    }
+}
+
+/// Return the database format selected by the arguments of the migrate subcommand.  Exit if a
+/// KDF parameter is not a number.
+fn migrate_format(matches: &ArgMatches) -> DatabaseFormat {
+    if matches.value_of("to") == Some("3") {
+        return DatabaseFormat::V3;
+    }
+    let value = |name: &str, default: u32| match matches.value_of(name) {
+        Some(v) => v.parse().unwrap_or_else(|_| {
+            println!("Error: --{} must be a number.", name);
+            process::exit(EXIT_FAILURE);
+        }),
+        None => default,
+    };
+    let defaults = KdfParams::default();
+    DatabaseFormat::Hardened(KdfParams {
+        memory_kib: value("memory", defaults.memory_kib),
+        iterations: value("iterations", defaults.iterations),
+        parallelism: value("parallelism", defaults.parallelism),
+    })
+}
+
+/// Delete old backup copies of the provided database from its remote repository, according to the
+/// policy in its sync settings (or the default policy, if none is set).  Exit if they cannot be
+/// pruned.
+fn prune_backups(database: &Database) {
+    let result = sync::SyncSettings::load_for(database)
+        .map(|s| s.remote_backup_policy().unwrap_or_default())
+        .and_then(|policy| {
+            let mut repo = sync::open_backend(database)?;
+            sync::prune_remote_backups(&mut *repo, database, &policy)
+        });
+    match result {
+        Ok(deleted) => {
+            for name in deleted.iter() {
+                println!("Deleted {}", name);
+            }
+            println!("{} old remote backup(s) deleted.", deleted.len());
+        }
+        Err(e) => {
+            println!("Error pruning remote backups: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+}
+
+/// Prompt for the credentials of the repository at the provided URL, if it needs any.  Exit if
+/// they cannot be read.
+fn prompt_repository_credentials(url: &str) -> (String, SecretString) {
+    if !sync::url_requires_credentials(url) {
+        return (String::new(), SecretString::default());
+    }
+    (
+        rpassword::prompt_response_stdout("Repository username: ").unwrap_or_else(|e| {
+            println!("Error reading username: {}", e);
+            process::exit(EXIT_FAILURE);
+        }),
+        SecretString::new(
+            rpassword::prompt_password_stdout("Repository password: ").unwrap_or_else(|e| {
+                println!("Error reading password: {}", e);
+                process::exit(EXIT_FAILURE);
+            }),
+        ),
+    )
+}
+
+/// List the databases in a remote repository.
+fn list(url: &str) {
+    let (username, password) = prompt_repository_credentials(url);
+    match sync::list(url, &username, &password) {
+        Ok(ref names) if names.is_empty() => println!("No databases found in the repository."),
+        Ok(names) => {
+            for name in names.iter() {
+                println!("{}", name);
+            }
+        }
+        Err(e) => {
+            println!("Error listing repository: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+}
+
+/// Upload the provided database to a remote repository for the first time, under the provided
+/// remote name (or its own name).  Once the upload succeeds, the repository becomes the
+/// database's sync URL.
+fn upload(mut database: Database, url: &str, remote_name: Option<&str>) {
+    let database_name = match remote_name {
+        Some(n) => String::from(n),
+        None => sync::remote_name(&database).unwrap_or_else(|e| {
+            println!("Error: {}", e);
+            process::exit(EXIT_FAILURE);
+        }),
+    };
+    println!(
+        "Uploading database as \"{}\" to repository \"{}\".",
+        database_name, url
+    );
+    if database.format() != DatabaseFormat::V3 && !sync::url_accepts_hardened_format(url) {
+        println!(
+            "Error: This database is in the hardened format, which the UPM sync server cannot \
+             hold.  Convert it first with \"tupm migrate --to 3\"."
+        );
+        process::exit(EXIT_FAILURE);
+    }
+    let (username, password) = prompt_repository_credentials(url);
+
+    // The uploaded copy holds the new sync URL too, but the local database is only saved with it
+    // once the upload has succeeded.
+    let url_changed = database.sync_url != url;
+    database.sync_url = String::from(url);
+    if let Err(e) = sync::upload(url, &username, &password, &database, Some(&database_name)) {
+        println!("Error uploading database: {}", e);
+        process::exit(EXIT_FAILURE);
+    }
+    println!("Database uploaded to repository.");
+    if url_changed {
+        if let Err(e) = database.save() {
+            println!("Error saving database: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+    if sync::url_requires_credentials(url) && database.account(&database.sync_credentials).is_none()
+    {
+        println!(
+            "(To sync, choose the account holding the repository credentials with \
+             Edit Database Properties.)"
+        );
+    }
+}
+
 /// Download a remote database and exit.  This is useful for fetching a remote database for the
-/// first time.
-fn download(path: &Path, url: &str) {
+/// first time.  If a remote name is provided, that database is downloaded instead of the one with
+/// the same name as the local database, and the remote name is recorded so syncs will use it.
+fn download(path: &Path, url: &str, remote_name: Option<&str>) {
     // Avoid overwriting any existing local database -- the user must manually remove the database
     // or specify an alternate path.
     if path.exists() {
@@ -158,30 +415,24 @@ fn download(path: &Path, url: &str) {
         process::exit(EXIT_FAILURE);
     }
 
-    let database_name = match Database::path_to_name(&path) {
+    let local_name = match Database::path_to_name(&path) {
         Ok(n) => n,
         Err(e) => {
             println!("Error: {}", e);
             process::exit(EXIT_FAILURE);
         }
     };
+    let database_name = remote_name.unwrap_or(local_name);
     println!(
         "Downloading remote database \"{}\" from repository \"{}\".",
         database_name, url
     );
 
-    // Collect the repository credentials
-    let username = rpassword::prompt_response_stdout("Repository username: ").unwrap_or_else(|e| {
-        println!("Error reading username: {}", e);
-        process::exit(EXIT_FAILURE);
-    });
-    let password = rpassword::prompt_password_stdout("Repository password: ").unwrap_or_else(|e| {
-        println!("Error reading password: {}", e);
-        process::exit(EXIT_FAILURE);
-    });
+    // Collect the repository credentials, if needed
+    let (username, password) = prompt_repository_credentials(url);
 
     // Download
-    let database_bytes = match sync::download(url, &username, &password, path) {
+    let database_bytes = match sync::download_named(url, &username, &password, database_name) {
         Ok(d) => d,
         Err(e) => {
             println!("Error downloading database: {}", e);
@@ -196,6 +447,19 @@ fn download(path: &Path, url: &str) {
         process::exit(EXIT_FAILURE);
     }
     println!("Database written to: {}.", path.to_string_lossy());
+
+    // Remember which remote database this is, if the names differ.
+    if database_name != local_name {
+        let result = sync::SyncSettings::path_for_filename(path).and_then(|settings_path| {
+            let mut settings = sync::SyncSettings::load(&settings_path)?;
+            settings.remote_name = Some(String::from(database_name));
+            settings.save(&settings_path)
+        });
+        if let Err(e) = result {
+            println!("Error saving sync settings: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    }
 }
 
 /// Parse the command-line arguments and present a user interface with the selected UPM database.
@@ -224,6 +488,27 @@ fn main() {
                 .long("export")
                 .help("Export database to a flat text file."),
         )
+        .arg(
+            Arg::with_name("status")
+                .short("s")
+                .long("status")
+                .help("Show what a sync would do, without syncing."),
+        )
+        .arg(
+            Arg::with_name("prune-backups")
+                .long("prune-backups")
+                .help("Delete old backups from the remote repository."),
+        )
+        .arg(
+            Arg::with_name("no-harden")
+                .long("no-harden")
+                .help("Do not lock memory or disable core dumps and debugging."),
+        )
+        .arg(
+            Arg::with_name("hardening-report")
+                .long("hardening-report")
+                .help("Show which memory protections can be applied, then exit."),
+        )
         .arg(
             Arg::with_name("download")
                 .short("l")
@@ -231,6 +516,87 @@ fn main() {
                 .value_name("URL")
                 .help("Download a remote database.")
                 .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("upload")
+                .short("u")
+                .long("upload")
+                .value_name("URL")
+                .help("Upload the database to a repository for the first time.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .value_name("URL")
+                .help("List the databases in a repository.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("name")
+                .short("n")
+                .long("name")
+                .value_name("NAME")
+                .help("Specify the remote database name for --download or --upload.")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Shows how one database file differs from another, such as a backup.")
+                .arg(
+                    Arg::with_name("old")
+                        .value_name("A")
+                        .help("The database (or backup) to compare against.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .value_name("B")
+                        .help("The database to compare.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("show-passwords")
+                        .long("show-passwords")
+                        .help("Show changed passwords instead of masking them."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about(
+                    "Converts the database to the hardened format, which only tupm can read, or \
+                     back to version 3.",
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("FORMAT")
+                        .help("The format to convert to.")
+                        .possible_values(&["3", "hardened"])
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("memory")
+                        .long("memory")
+                        .value_name("KIB")
+                        .help("Set the Argon2id memory size of the hardened format, in KiB.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .value_name("N")
+                        .help("Set the Argon2id iteration count of the hardened format.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("parallelism")
+                        .long("parallelism")
+                        .value_name("N")
+                        .help("Set the Argon2id parallelism of the hardened format.")
+                        .takes_value(true),
+                ),
         );
     #[cfg(feature = "test_database")]
     let app = app.arg(
@@ -241,6 +607,20 @@ fn main() {
     );
     let matches = app.get_matches();
 
+    // Protect the secrets in memory before any are read, unless asked not to.
+    let hardening = if matches.is_present("no-harden") {
+        None
+    } else {
+        Some(harden::harden())
+    };
+    if matches.is_present("hardening-report") {
+        match hardening {
+            Some(report) => println!("{}", report),
+            None => println!("Memory protections are disabled by --no-harden."),
+        }
+        process::exit(EXIT_SUCCESS);
+    }
+
     // Determine the database path.
     let database_filename = matches
         .value_of("database")
@@ -253,17 +633,30 @@ fn main() {
 
     // Determine the database password, if possible
     let password = if matches.is_present("password") {
-        Some(
+        Some(SecretString::new(
             rpassword::prompt_password_stdout("Password: ").unwrap_or_else(|e| {
                 println!("Error reading password: {}", e);
                 process::exit(EXIT_FAILURE);
             }),
-        )
+        ))
     } else {
-        test_password(&matches).map(|p| String::from(p))
+        test_password(&matches).map(SecretString::from)
     };
 
     // Dispatch to non-UI tasks, if requested.
+    if let Some(matches) = matches.subcommand_matches("diff") {
+        diff(
+            &PathBuf::from(matches.value_of("old").unwrap()),
+            &PathBuf::from(matches.value_of("new").unwrap()),
+            password,
+            matches.is_present("show-passwords"),
+        );
+        process::exit(EXIT_SUCCESS);
+    }
+    if let Some(matches) = matches.subcommand_matches("migrate") {
+        migrate(&database_filename, password, migrate_format(matches));
+        process::exit(EXIT_SUCCESS);
+    }
     if matches.is_present("export") {
         match password {
             Some(p) => export(&open_database_or_exit(&database_filename, p.as_str())),
@@ -274,13 +667,56 @@ fn main() {
         }
         process::exit(EXIT_SUCCESS);
     }
+    if matches.is_present("status") {
+        match password {
+            Some(p) => status(&open_database_or_exit(&database_filename, p.as_str())),
+            None => {
+                println!(
+                    "Cannot check the remote database without a password.  \
+                     Use --password to prompt."
+                );
+                process::exit(EXIT_FAILURE);
+            }
+        }
+        process::exit(EXIT_SUCCESS);
+    }
+    if matches.is_present("prune-backups") {
+        match password {
+            Some(p) => prune_backups(&open_database_or_exit(&database_filename, p.as_str())),
+            None => {
+                println!(
+                    "Cannot prune remote backups without a password.  Use --password to prompt."
+                );
+                process::exit(EXIT_FAILURE);
+            }
+        }
+        process::exit(EXIT_SUCCESS);
+    }
     if let Some(url) = matches.value_of("download") {
-        download(&database_filename, url);
+        download(&database_filename, url, matches.value_of("name"));
+        process::exit(EXIT_SUCCESS);
+    }
+    if let Some(url) = matches.value_of("upload") {
+        match password {
+            Some(p) => upload(
+                open_database_or_exit(&database_filename, p.as_str()),
+                url,
+                matches.value_of("name"),
+            ),
+            None => {
+                println!("Cannot upload without a password.  Use --password to prompt.");
+                process::exit(EXIT_FAILURE);
+            }
+        }
+        process::exit(EXIT_SUCCESS);
+    }
+    if let Some(url) = matches.value_of("list") {
+        list(url);
         process::exit(EXIT_SUCCESS);
     }
 
     // Launch the controller and UI.
-    let controller = Controller::new(&database_filename, password);
+    let controller = Controller::new(&database_filename, password, hardening.as_ref());
     match controller {
         Ok(mut controller) => controller.run(),
         Err(e) => {
@@ -5,14 +5,19 @@
 extern crate upm;
 
 use std::io;
+use std::mem;
 use std::path::PathBuf;
 use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
 use tupm;
-use upm::backup::backup;
+use tupm::ui::ConflictChoice;
 use upm::database::{Account, Database};
 use upm::error::UpmError;
+use upm::harden::HardeningReport;
+use upm::secret::SecretString;
 use upm::sync;
-use upm::sync::SyncResult;
+use upm::sync::{PendingMerge, SyncProgress, SyncResult, SyncSettings, SyncStatus};
 
 /// The controller maintains a message queue consisting of zero or more of these messages.  Other
 /// components (mostly likely the UI) can add messages to the queue, and the controller will
@@ -22,23 +27,71 @@ pub enum Message {
     AccountEdit(Option<Account>, Option<Account>),
     DatabaseEdit(String, String),
     Sync,
-    ChangePassword(String),
+    SyncProgress(SyncProgress),
+    SyncFinished(Result<SyncResult, UpmError>),
+    SyncSettingsEdit(SyncSettings),
+    CheckRemote,
+    CheckFinished(Result<SyncStatus, UpmError>),
+    ForceSync(ForcedSync),
+    ForceSyncFinished(ForcedSync, Result<(), UpmError>),
+    PruneBackups,
+    PruneFinished(Result<Vec<String>, UpmError>),
+    ChangePassword(SecretString),
     Quit,
 }
 
+/// A sync which replaces one copy of the database with the other, regardless of their revisions.
+#[derive(Clone, Copy, Debug)]
+pub enum ForcedSync {
+    /// Replace the remote database with the local database.
+    Push,
+    /// Replace the local database with the remote database.
+    Pull,
+}
+
+/// The characters of the spinner shown in the status line while a sync is in progress.
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+/// Advance the spinner at this interval.
+const SPINNER_INTERVAL_MS: u128 = 100;
+
+/// Track a sync (or a check of the remote database) which is running on a worker thread.
+struct SyncWorker {
+    /// What the worker is doing, as shown in the status line.
+    activity: &'static str,
+    /// The password for the remote database, if it differs from the local password.
+    remote_password: Option<SecretString>,
+    /// The step the sync is currently performing, once it has started.
+    progress: Option<SyncProgress>,
+    /// The time the sync was started, which drives the spinner.
+    started: Instant,
+}
+
 /// This struct provides the core logic of the `tupm` application.  It fulfills the role of the
 /// controller in the Model-View-Controller (MVC) design pattern.  (The Ui class provides the view,
 /// and the Database class provides the model.)
 pub struct Controller {
     rx: mpsc::Receiver<Message>,
+    tx: mpsc::Sender<Message>,
     ui: tupm::ui::Ui,
     database: Database,
+    sync_worker: Option<SyncWorker>,
+    /// Messages which would modify the database are held here while a sync is in progress.
+    held_messages: Vec<Message>,
+    sync_settings: SyncSettings,
+    /// The time the most recent sync finished (or the database was loaded), which determines
+    /// when the next periodic sync is due.
+    last_sync: Instant,
 }
 
 impl Controller {
     /// Create a new controller with the provided database path and password.  This will load the
-    /// database (if possible) and initialize the user interface.
-    pub fn new(database_path: &PathBuf, password: Option<String>) -> Result<Controller, UpmError> {
+    /// database (if possible) and initialize the user interface.  If memory protections were
+    /// requested, the status line warns about any which could not be applied.
+    pub fn new(
+        database_path: &PathBuf,
+        password: Option<SecretString>,
+        hardening: Option<&HardeningReport>,
+    ) -> Result<Controller, UpmError> {
         let (tx, rx) = mpsc::channel::<Message>();
         let mut ui = tupm::ui::Ui::new(tx.clone());
         let mut fresh_database = false;
@@ -65,6 +118,7 @@ impl Controller {
             database_try = match Database::load_from_file(database_path, &password) {
                 Ok(mut database) => {
                     database.accounts.sort();
+                    sync::restore_sync_status(&mut database);
                     ui.set_statusline(&format!("Database loaded from {}", database_path.display()));
                     Some(database)
                 }
@@ -130,12 +184,58 @@ impl Controller {
             }
         }
 
-        Ok(Controller { rx, ui, database })
+        if database.has_legacy_password_encoding() {
+            ui.notice_dialog(
+                "Old password encoding",
+                "This database was encrypted by an earlier version of tupm, which encoded \
+                 passwords containing emoji and other characters outside the Basic \
+                 Multilingual Plane differently from the UPM Java client.\n\nIt will be \
+                 re-encrypted in the standard way the next time it is saved.",
+            );
+        }
+
+        if let Some(report) = hardening {
+            let missing = report.missing();
+            if !missing.is_empty() {
+                let names: Vec<String> = missing
+                    .iter()
+                    .map(|p| p.description().to_lowercase())
+                    .collect();
+                ui.set_statusline(&format!(
+                    "Warning: memory protections unavailable ({}); see tupm --hardening-report",
+                    names.join(", ")
+                ));
+            }
+        }
+
+        let sync_settings = match SyncSettings::load_for(&database) {
+            Ok(s) => s,
+            Err(e) => {
+                ui.set_statusline(&format!("{}", e));
+                SyncSettings::default()
+            }
+        };
+        ui.set_sync_settings(&sync_settings);
+
+        let mut controller = Controller {
+            rx,
+            tx,
+            ui,
+            database,
+            sync_worker: None,
+            held_messages: Vec::new(),
+            sync_settings,
+            last_sync: Instant::now(),
+        };
+        if controller.sync_settings.sync_on_startup {
+            controller.auto_sync();
+        }
+        Ok(controller)
     }
 
     /// Continuously prompt for a password until either one is provided or the user decides to
     /// quit.
-    fn password_prompt(ui: &mut tupm::ui::Ui) -> Option<String> {
+    fn password_prompt(ui: &mut tupm::ui::Ui) -> Option<SecretString> {
         let mut password = None;
         while password.is_none() {
             password = match ui
@@ -165,27 +265,85 @@ impl Controller {
     pub fn run(&mut self) {
         while self.ui.step() {
             while let Some(message) = self.next_message() {
-                // Dispatch to handler functions as needed.
-                match message {
-                    Message::AccountEdit(before, after) => self.handle_account_edit(before, after),
-                    Message::DatabaseEdit(url, credentials) => {
-                        self.handle_database_edit(url, credentials)
-                    }
-                    Message::Sync => {
-                        self.handle_sync(None).ok();
-                        ()
-                    }
-                    Message::ChangePassword(password) => {
-                        self.handle_change_password(password);
-                    }
-                    Message::Quit => {
-                        self.ui.quit();
-                    }
-                };
+                self.handle_message(message);
+            }
+            if self.sync_worker.is_some() {
+                self.update_sync_status();
+            } else {
+                self.check_sync_interval();
+            }
+        }
+    }
+
+    /// Start a periodic sync if one is due and the user is idle.
+    fn check_sync_interval(&mut self) {
+        if let Some(interval) = self.sync_settings.sync_interval {
+            if self.last_sync.elapsed() >= interval && self.ui.is_idle() {
+                self.auto_sync();
             }
         }
     }
 
+    /// Start a sync which the user did not explicitly request, as configured by the sync
+    /// settings.  This is skipped if the database has no remote repository.
+    fn auto_sync(&mut self) {
+        if self.database.has_remote() {
+            self.handle_sync(None);
+        }
+    }
+
+    /// Dispatch a message to the appropriate handler function.  While a sync is in progress,
+    /// messages which would modify the database are held until the sync completes.
+    fn handle_message(&mut self, message: Message) {
+        if self.sync_worker.is_some() {
+            match message {
+                Message::SyncProgress(_) | Message::SyncFinished(_) => {}
+                Message::CheckFinished(_) | Message::ForceSyncFinished(_, _) => {}
+                Message::PruneFinished(_) => {}
+                Message::Sync
+                | Message::CheckRemote
+                | Message::ForceSync(_)
+                | Message::PruneBackups => {
+                    self.ui.set_statusline("A sync is already in progress.");
+                    return;
+                }
+                _ => {
+                    self.held_messages.push(message);
+                    return;
+                }
+            }
+        }
+        match message {
+            Message::AccountEdit(before, after) => self.handle_account_edit(before, after),
+            Message::DatabaseEdit(url, credentials) => self.handle_database_edit(url, credentials),
+            Message::Sync => self.handle_sync(None),
+            Message::SyncProgress(progress) => {
+                if let Some(ref mut worker) = self.sync_worker {
+                    worker.progress = Some(progress);
+                }
+                self.update_sync_status();
+            }
+            Message::SyncFinished(result) => self.handle_sync_finished(result),
+            Message::SyncSettingsEdit(sync_settings) => {
+                self.handle_sync_settings_edit(sync_settings)
+            }
+            Message::CheckRemote => self.handle_check_remote(None),
+            Message::CheckFinished(result) => self.handle_check_finished(result),
+            Message::ForceSync(force) => self.handle_force_sync(force, None),
+            Message::ForceSyncFinished(force, result) => {
+                self.handle_force_sync_finished(force, result)
+            }
+            Message::PruneBackups => self.handle_prune_backups(),
+            Message::PruneFinished(result) => self.handle_prune_finished(result),
+            Message::ChangePassword(password) => {
+                self.handle_change_password(password);
+            }
+            Message::Quit => {
+                self.ui.quit();
+            }
+        };
+    }
+
     /// Return the next message in the message queue, if one is present.
     fn next_message(&self) -> Option<Message> {
         self.rx.try_iter().next()
@@ -249,9 +407,280 @@ impl Controller {
         self.ui.update_status();
     }
 
-    /// Process a sync.
-    fn handle_sync(&mut self, remote_password: Option<&str>) -> Result<(), UpmError> {
-        match sync::sync(&self.database, remote_password) {
+    /// Process a change to the sync settings.
+    fn handle_sync_settings_edit(&mut self, sync_settings: SyncSettings) {
+        if let Err(e) = sync_settings.save_for(&self.database) {
+            self.ui
+                .set_statusline(&format!("Error saving sync settings: {}", e));
+            return;
+        }
+        self.sync_settings = sync_settings;
+        self.ui.set_sync_settings(&self.sync_settings);
+        self.ui.set_statusline("Sync settings saved.");
+    }
+
+    /// Start a sync on a worker thread.  The worker reports its progress and result through the
+    /// message queue, so the UI remains responsive in the meantime.
+    fn handle_sync(&mut self, remote_password: Option<SecretString>) {
+        let password = remote_password.clone();
+        self.start_worker("Syncing", remote_password, move |database, tx| {
+            let result = sync::open_backend(database).and_then(|mut backend| {
+                sync::sync_with_progress(&mut *backend, database, password.as_deref(), |p| {
+                    tx.send(Message::SyncProgress(p)).ok();
+                })
+            });
+            Message::SyncFinished(result)
+        });
+    }
+
+    /// Start checking the remote database on a worker thread, to show the user what a sync would
+    /// do without changing anything.
+    fn handle_check_remote(&mut self, remote_password: Option<SecretString>) {
+        let password = remote_password.clone();
+        self.start_worker("Checking", remote_password, move |database, tx| {
+            tx.send(Message::SyncProgress(SyncProgress::Downloading))
+                .ok();
+            Message::CheckFinished(sync::status(database, password.as_deref()))
+        });
+    }
+
+    /// Start a forced sync on a worker thread.
+    fn handle_force_sync(&mut self, force: ForcedSync, remote_password: Option<SecretString>) {
+        let activity = match force {
+            ForcedSync::Push => "Pushing",
+            ForcedSync::Pull => "Pulling",
+        };
+        let password = remote_password.clone();
+        self.start_worker(activity, remote_password, move |database, _| {
+            let result = sync::open_backend(database).and_then(|mut backend| match force {
+                ForcedSync::Push => sync::push_force(&mut *backend, database, password.as_deref()),
+                ForcedSync::Pull => sync::pull_force(&mut *backend, database, password.as_deref()),
+            });
+            Message::ForceSyncFinished(force, result)
+        });
+    }
+
+    /// Start deleting old backups from the remote repository on a worker thread, according to the
+    /// policy in the sync settings (or the default policy, if none is set).
+    fn handle_prune_backups(&mut self) {
+        let policy = self
+            .sync_settings
+            .remote_backup_policy()
+            .unwrap_or_default();
+        self.start_worker("Pruning", None, move |database, _| {
+            let result = sync::open_backend(database).and_then(|mut backend| {
+                sync::prune_remote_backups(&mut *backend, database, &policy)
+            });
+            Message::PruneFinished(result)
+        });
+    }
+
+    /// Run a job against a copy of the database on a worker thread, unless a worker is already
+    /// running.  The job may send progress messages, and returns the message carrying its result.
+    fn start_worker<F>(
+        &mut self,
+        activity: &'static str,
+        remote_password: Option<SecretString>,
+        job: F,
+    ) where
+        F: FnOnce(&Database, &mpsc::Sender<Message>) -> Message + Send + 'static,
+    {
+        if self.sync_worker.is_some() {
+            return;
+        }
+        let database = self.database.clone();
+        let tx = self.tx.clone();
+        thread::spawn(move || {
+            let message = job(&database, &tx);
+            // If the controller is gone, the application is exiting and the result is moot.
+            tx.send(message).ok();
+        });
+        self.sync_worker = Some(SyncWorker {
+            activity,
+            remote_password,
+            progress: None,
+            started: Instant::now(),
+        });
+        self.ui.set_busy(true);
+        self.update_sync_status();
+    }
+
+    /// Show the progress of the sync in the status line, along with a spinner.
+    fn update_sync_status(&mut self) {
+        let text = match self.sync_worker {
+            Some(ref worker) => {
+                let elapsed = worker.started.elapsed().as_millis() / SPINNER_INTERVAL_MS;
+                let spinner = SPINNER[elapsed as usize % SPINNER.len()];
+                let progress = match worker.progress {
+                    Some(progress) => progress.to_string(),
+                    None => String::from("Contacting the remote repository"),
+                };
+                format!("{}: {}... {}", worker.activity, progress, spinner)
+            }
+            None => return,
+        };
+        self.ui.set_statusline(&text);
+    }
+
+    /// Process the result of a sync performed by the worker thread, then apply any messages which
+    /// were held while it was in progress.
+    fn handle_sync_finished(&mut self, result: Result<SyncResult, UpmError>) {
+        let remote_password = match self.sync_worker.take() {
+            Some(worker) => worker.remote_password,
+            None => return,
+        };
+        self.ui.set_busy(false);
+        self.last_sync = Instant::now();
+        self.process_sync_result(result, remote_password);
+        self.release_held_messages();
+    }
+
+    /// Show the result of checking the remote database performed by the worker thread, then
+    /// apply any messages which were held while it was in progress.
+    fn handle_check_finished(&mut self, result: Result<SyncStatus, UpmError>) {
+        let remote_password = match self.sync_worker.take() {
+            Some(worker) => worker.remote_password,
+            None => return,
+        };
+        self.ui.set_busy(false);
+        match result {
+            Ok(status) => {
+                self.ui.set_statusline(&format!(
+                    "Checked the remote database: a sync would {}.",
+                    status.direction
+                ));
+                let sync_now = self.ui.yesno_dialog(
+                    "Remote database",
+                    &status.to_string(),
+                    "Close",
+                    "Sync now",
+                );
+                if sync_now {
+                    self.handle_sync(remote_password);
+                }
+            }
+            Err(UpmError::BadPassword) => {
+                if let Some(password) = self.remote_password_prompt(remote_password) {
+                    self.handle_check_remote(Some(password));
+                }
+            }
+            Err(e) => {
+                self.ui
+                    .set_statusline(&format!("Cannot check the remote database: {}", e));
+            }
+        }
+        self.release_held_messages();
+    }
+
+    /// Process the result of a forced sync performed by the worker thread, then apply any messages
+    /// which were held while it was in progress.  Either way, the local database file was
+    /// rewritten, so it is reloaded.
+    fn handle_force_sync_finished(&mut self, force: ForcedSync, result: Result<(), UpmError>) {
+        let remote_password = match self.sync_worker.take() {
+            Some(worker) => worker.remote_password,
+            None => return,
+        };
+        self.ui.set_busy(false);
+        self.last_sync = Instant::now();
+        match result {
+            Ok(()) => {
+                if self.reload_database() {
+                    let text = match force {
+                        ForcedSync::Push => "Remote database replaced with the local database",
+                        ForcedSync::Pull => "Local database replaced with the remote database",
+                    };
+                    self.ui.set_statusline(&format!(
+                        "{} at revision {}",
+                        text, self.database.sync_revision
+                    ));
+                }
+                self.database.set_synced();
+                self.ui.set_database(&self.database); // So the UI gets new sync status
+            }
+            Err(UpmError::BadPassword) => {
+                if let Some(password) = self.remote_password_prompt(remote_password) {
+                    self.handle_force_sync(force, Some(password));
+                }
+            }
+            Err(UpmError::Sync(ref report)) => {
+                self.ui.set_statusline("Cannot force sync.");
+                self.sync_failure_dialog(report);
+            }
+            Err(e) => {
+                self.ui.set_statusline(&format!("Cannot force sync: {}", e));
+            }
+        }
+        self.release_held_messages();
+    }
+
+    /// Report the result of pruning remote backups on the worker thread, then apply any messages
+    /// which were held while it was in progress.
+    fn handle_prune_finished(&mut self, result: Result<Vec<String>, UpmError>) {
+        if self.sync_worker.take().is_none() {
+            return;
+        }
+        self.ui.set_busy(false);
+        match result {
+            Ok(deleted) => {
+                self.ui
+                    .set_statusline(&format!("Deleted {} old remote backup(s).", deleted.len()));
+            }
+            Err(e) => {
+                self.ui
+                    .set_statusline(&format!("Cannot prune remote backups: {}", e));
+            }
+        }
+        self.release_held_messages();
+    }
+
+    /// Show the report of a failed sync.  The report may describe several steps and the state
+    /// of the remote database afterwards, which would not fit on the status line, so each
+    /// sentence is shown on its own line.
+    fn sync_failure_dialog(&mut self, report: &str) {
+        self.ui
+            .notice_dialog("Sync failed", &report.replace(".  ", ".\n"));
+    }
+
+    /// Apply the messages which were held while a worker was running.  If another worker has
+    /// been started in the meantime, the held messages must keep waiting.
+    fn release_held_messages(&mut self) {
+        if self.sync_worker.is_none() {
+            let held_messages = mem::take(&mut self.held_messages);
+            for message in held_messages {
+                self.handle_message(message);
+            }
+        }
+    }
+
+    /// Prompt for the password of the remote database after it could not be decrypted.  Return
+    /// `None` if the user declines, or if the remote password already provided was wrong too.
+    fn remote_password_prompt(
+        &mut self,
+        remote_password: Option<SecretString>,
+    ) -> Option<SecretString> {
+        if remote_password.is_some() {
+            // Prevent arbitrary-depth recursion by only asking for the remote database password
+            // once.
+            self.ui
+                .notice_dialog("Bad password", "Bad password for the remote database.");
+            self.ui
+                .set_statusline("Bad password for the remote database.");
+            return None;
+        }
+        self.ui.password_dialog(
+            "The remote database uses a different password.  \
+             Please supply the password to the remote database:",
+            true,
+        )
+    }
+
+    /// Update the database and UI according to the result of a sync.
+    fn process_sync_result(
+        &mut self,
+        result: Result<SyncResult, UpmError>,
+        remote_password: Option<SecretString>,
+    ) {
+        match result {
             Ok(SyncResult::RemoteSynced) => {
                 self.ui.set_statusline(&format!(
                     "Remote database synced to revision {}",
@@ -259,31 +688,29 @@ impl Controller {
                 ));
                 self.database.set_synced();
                 self.ui.set_database(&self.database); // So the UI gets new sync status
-                Ok(())
             }
             Ok(SyncResult::LocalSynced) => {
-                // Reload local database
-                match Database::load_from_file(
-                    self.database.path().unwrap(),
-                    self.database.password().unwrap(),
-                ) {
-                    Ok(mut reloaded_database) => {
-                        reloaded_database.accounts.sort();
-                        self.database = reloaded_database;
-                        self.ui.set_database(&self.database);
-                        self.ui.set_statusline(&format!(
-                            "Local database synced to revision {}",
-                            self.database.sync_revision
-                        ));
-                    }
-                    Err(e) => {
-                        self.ui
-                            .set_statusline(&format!("error reloading local database: {}", e));
-                    }
-                };
+                if self.reload_database() {
+                    self.ui.set_statusline(&format!(
+                        "Local database synced to revision {}",
+                        self.database.sync_revision
+                    ));
+                }
                 self.database.set_synced();
                 self.ui.set_database(&self.database); // So the UI gets new sync status
-                Ok(())
+            }
+            Ok(SyncResult::Merged) => {
+                if self.reload_database() {
+                    self.ui.set_statusline(&format!(
+                        "Local and remote changes merged into revision {}",
+                        self.database.sync_revision
+                    ));
+                }
+                self.database.set_synced();
+                self.ui.set_database(&self.database); // So the UI gets new sync status
+            }
+            Ok(SyncResult::Conflicted(pending)) => {
+                self.resolve_conflicts(*pending, remote_password);
             }
             Ok(SyncResult::NeitherSynced) => {
                 self.ui.set_statusline(&format!(
@@ -292,43 +719,78 @@ impl Controller {
                 ));
                 self.database.set_synced();
                 self.ui.set_database(&self.database); // So the UI gets new sync status
-                Ok(())
             }
             Err(UpmError::BadPassword) => {
-                if remote_password.is_none() {
-                    // Prompt for remote database password and try again
-                    let password = self.ui.password_dialog(
-                        "The remote database uses a different password.  \
-                         Please supply the password to the remote database:",
-                        true,
-                    );
-                    if let Some(password) = password {
-                        self.handle_sync(Some(&password))
-                    } else {
-                        Ok(())
-                    }
-                } else {
-                    // Prevent arbitrary-depth recursion by only asking for the remote database
-                    // password once.
-                    self.ui
-                        .notice_dialog("Bad password", "Bad password for the remote database.");
-                    self.ui.set_statusline(&format!(
-                        "Cannot sync: Bad password for the remote database."
-                    ));
-                    Err(UpmError::Sync(String::from(
-                        "Bad password for the remote database.",
-                    )))
+                // Prompt for remote database password and try again
+                if let Some(password) = self.remote_password_prompt(remote_password) {
+                    self.handle_sync(Some(password));
                 }
             }
+            Err(UpmError::Sync(ref report)) => {
+                self.ui.set_statusline("Cannot sync.");
+                self.sync_failure_dialog(report);
+            }
             Err(e) => {
                 self.ui.set_statusline(&format!("Cannot sync: {}", e));
-                Err(UpmError::Sync(format!("Cannot sync: {}", e)))
+            }
+        }
+    }
+
+    /// Let the user review the conflicts of a merge, which have been provisionally resolved in the
+    /// merged database.  The merged database, with any other resolutions the user chooses, then
+    /// replaces both the local and remote databases on a worker thread.
+    fn resolve_conflicts(
+        &mut self,
+        mut pending: PendingMerge,
+        remote_password: Option<SecretString>,
+    ) {
+        let count = pending.conflicts.len();
+        for (index, conflict) in pending.conflicts.iter().enumerate() {
+            match self.ui.conflict_dialog(conflict, index, count) {
+                Some(ConflictChoice::Account(resolution)) => {
+                    conflict.resolve(&mut pending.merged, resolution)
+                }
+                Some(ConflictChoice::Fields(resolutions)) => {
+                    conflict.resolve_fields(&mut pending.merged, &resolutions)
+                }
+                // The provisional resolution is kept.
+                None => {}
+            }
+        }
+        pending.merged.accounts.sort();
+
+        let password = remote_password.clone();
+        self.start_worker("Merging", remote_password, move |_, _| {
+            let result = sync::open_backend(&pending.merged).and_then(|mut backend| {
+                sync::finish_merge(&mut *backend, &pending, password.as_deref())
+            });
+            Message::SyncFinished(result)
+        });
+    }
+
+    /// Reload the local database from the filesystem after a sync has replaced it.  Return true if
+    /// successful; otherwise report the error and return false.
+    fn reload_database(&mut self) -> bool {
+        match Database::load_from_file(
+            self.database.path().unwrap(),
+            self.database.password().unwrap(),
+        ) {
+            Ok(mut reloaded_database) => {
+                reloaded_database.accounts.sort();
+                self.database = reloaded_database;
+                self.ui.set_database(&self.database);
+                true
+            }
+            Err(e) => {
+                self.ui
+                    .set_statusline(&format!("error reloading local database: {}", e));
+                false
             }
         }
     }
 
     /// Process a request to change the database password.
-    fn handle_change_password(&mut self, new_password: String) {
+    fn handle_change_password(&mut self, new_password: SecretString) {
         self.database.set_password(&new_password);
         if let Err(e) = self.save_database() {
             self.ui.set_statusline(&format!("{}", e));
@@ -339,26 +801,14 @@ impl Controller {
         self.ui.set_database(&self.database);
     }
 
-    /// Save the database to the local filesystem.  This is the basic function which increments the
-    /// revision and makes any needed backups before saving.
+    /// Save the database to the local filesystem.  This is the basic function which commits the
+    /// changes made since the last save (or in the open transaction) as one new revision, making
+    /// any needed backups before saving.
     fn save_database(&mut self) -> Result<(), UpmError> {
-        // Bump the revision
-        self.database.sync_revision += 1;
-
-        // Make a backup of the old database, if present.
-        if upm::PARANOID_BACKUPS {
-            if let Some(f) = self.database.path() {
-                if let Err(e) = backup(&f) {
-                    return Err(UpmError::Backup(format!(
-                        "Error making backup; not saved: {}",
-                        e
-                    )));
-                }
-            }
+        self.database.commit()?;
+        if self.sync_settings.sync_after_save {
+            self.auto_sync();
         }
-
-        // Save the database
-        self.database.save()?;
         Ok(())
     }
 
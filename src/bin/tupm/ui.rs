@@ -17,9 +17,14 @@ use std::cell::RefCell;
 use std::ops::Deref;
 use std::rc::Rc;
 use std::sync::mpsc;
+use std::time::Duration;
 use tupm::clipboard::clipboard_copy;
 use tupm::controller;
+use tupm::controller::ForcedSync;
 use upm::database::{Account, Database};
+use upm::merge::{AccountField, Conflict, Resolution, ACCOUNT_FIELDS};
+use upm::secret::SecretString;
+use upm::sync::SyncSettings;
 
 // View ids.  These are used to reference specific views within the Cursive view tree.
 const VIEW_ID_SELECT: &'static str = "select";
@@ -32,6 +37,13 @@ const VIEW_ID_STATUSLINE: &'static str = "statusline";
 const VIEW_ID_EDIT: &'static str = "edit";
 const VIEW_ID_MODAL: &'static str = "modal";
 const VIEW_ID_INPUT: &'static str = "input";
+const VIEW_ID_CONFLICT: &str = "conflict";
+
+/// The UI refreshes at this rate while a background operation is in progress.
+const BUSY_FPS: u32 = 10;
+/// The UI refreshes at this rate when periodic syncs are enabled, so the controller can notice
+/// when one is due even without user input.
+const PERIODIC_SYNC_FPS: u32 = 1;
 
 // Human-readable field labels
 const FIELD_NAME: &'static str = "Account";
@@ -456,7 +468,7 @@ impl AccountEditView {
         Account {
             name: self.get(FIELD_NAME),
             user: self.get(FIELD_USER),
-            password: self.get(FIELD_PASSWORD),
+            password: SecretString::new(self.get(FIELD_PASSWORD)),
             url: self.get(FIELD_URL),
             notes: self.get(FIELD_NOTES),
         }
@@ -524,11 +536,12 @@ pub struct DatabaseEditView {
     content: LinearLayout,
     url: String,
     credentials: String,
+    sync_settings: SyncSettings,
 }
 
 impl DatabaseEditView {
     /// Create a new DatabaseEditView.
-    pub fn new(url: &str, credentials: &str) -> Self {
+    pub fn new(url: &str, credentials: &str, sync_settings: &SyncSettings) -> Self {
         let mut v_layout = LinearLayout::vertical();
 
         let id = format!("{}_{}", VIEW_ID_EDIT, "url");
@@ -557,6 +570,41 @@ impl DatabaseEditView {
                 )),
         );
 
+        let checkbox = |checked: bool| {
+            let mut checkbox = Checkbox::new();
+            checkbox.set_checked(checked);
+            checkbox
+        };
+        let id = format!("{}_{}", VIEW_ID_EDIT, "sync_on_startup");
+        v_layout.add_child(
+            LinearLayout::horizontal()
+                .child(checkbox(sync_settings.sync_on_startup).with_id(id))
+                .child(TextView::new(" Sync when the database is opened")),
+        );
+        let id = format!("{}_{}", VIEW_ID_EDIT, "sync_after_save");
+        v_layout.add_child(
+            LinearLayout::horizontal()
+                .child(checkbox(sync_settings.sync_after_save).with_id(id))
+                .child(TextView::new(" Sync after each change")),
+        );
+        let id = format!("{}_{}", VIEW_ID_EDIT, "sync_interval");
+        let mut edit_view = EditView::new();
+        edit_view.set_content(
+            sync_settings
+                .sync_interval
+                .map_or(0, |i| i.as_secs() / 60)
+                .to_string(),
+        );
+        v_layout.add_child(
+            LinearLayout::horizontal()
+                .child(TextView::new("Sync every (minutes, 0 for never): "))
+                .child(BoxView::new(
+                    SizeConstraint::AtLeast(6),
+                    SizeConstraint::AtMost(1),
+                    edit_view.with_id(id),
+                )),
+        );
+
         v_layout.add_child(TextView::new("Ctrl-X: Apply changes"));
         v_layout.add_child(TextView::new(
             "The sync credentials must exactly match the name of an \
@@ -568,6 +616,7 @@ impl DatabaseEditView {
             content: v_layout,
             url: String::from(url),
             credentials: String::from(credentials),
+            sync_settings: sync_settings.clone(),
         }
     }
 
@@ -575,11 +624,13 @@ impl DatabaseEditView {
     pub fn show(
         cursive: &mut Cursive,
         database: Rc<RefCell<Database>>,
+        sync_settings: &SyncSettings,
         controller_tx: mpsc::Sender<controller::Message>,
     ) {
         let database_edit = DatabaseEditView::new(
             &database.borrow().sync_url,
             &database.borrow().sync_credentials,
+            sync_settings,
         )
         .with_id(VIEW_ID_EDIT);
         let controller_tx_clone = controller_tx.clone();
@@ -602,11 +653,50 @@ impl DatabaseEditView {
 
     /// Record the (potentially edited) UI fields into the database.
     fn apply(cursive: &mut Cursive, controller_tx: &mpsc::Sender<controller::Message>) {
-        let (old_url, old_credentials) = {
+        let (old_url, old_credentials, old_sync_settings) = {
             let database_edit = cursive.find_id::<DatabaseEditView>(VIEW_ID_EDIT).unwrap();
-            (database_edit.url.clone(), database_edit.credentials.clone())
+            (
+                database_edit.url.clone(),
+                database_edit.credentials.clone(),
+                database_edit.sync_settings.clone(),
+            )
         };
 
+        let id = format!("{}_{}", VIEW_ID_EDIT, "sync_interval");
+        let sync_interval = cursive.find_id::<EditView>(&id).unwrap().get_content();
+        let sync_interval = match sync_interval.trim().parse::<u64>() {
+            Ok(0) => None,
+            Ok(minutes) => Some(Duration::from_secs(minutes * 60)),
+            Err(_) => {
+                cursive.add_layer(
+                    Dialog::around(TextView::new(
+                        "The sync interval must be a whole number of minutes.",
+                    ))
+                    .title("Alert")
+                    .button("OK", |s| {
+                        s.pop_layer();
+                    }),
+                );
+                return;
+            }
+        };
+        let is_checked = |cursive: &mut Cursive, name: &str| {
+            let id = format!("{}_{}", VIEW_ID_EDIT, name);
+            cursive.find_id::<Checkbox>(&id).unwrap().is_checked()
+        };
+        let new_sync_settings = SyncSettings {
+            sync_on_startup: is_checked(cursive, "sync_on_startup"),
+            sync_after_save: is_checked(cursive, "sync_after_save"),
+            sync_interval,
+            // The HTTP connection options are only edited by hand, and are kept as they were.
+            ..old_sync_settings.clone()
+        };
+        if new_sync_settings != old_sync_settings {
+            controller_tx
+                .send(controller::Message::SyncSettingsEdit(new_sync_settings))
+                .unwrap();
+        }
+
         let id = format!("{}_{}", VIEW_ID_EDIT, "url");
         let new_url = cursive.find_id::<EditView>(&id).unwrap().get_content();
 
@@ -629,6 +719,154 @@ impl ViewWrapper for DatabaseEditView {
     wrap_impl!(self.content: LinearLayout);
 }
 
+////////////////////////////////////////////////////////////////////////
+// ConflictView
+////////////////////////////////////////////////////////////////////////
+
+/// The user's choice for resolving a sync conflict, as made in a ConflictView.
+#[derive(Debug)]
+pub enum ConflictChoice {
+    /// Resolve the account as a whole.
+    Account(Resolution),
+    /// Resolve each conflicting field individually, in the order of the conflict's fields.
+    Fields(Vec<Resolution>),
+}
+
+/// This view shows the local and remote versions of an account which could not be merged
+/// automatically during a sync, side by side.  Each conflicting field has its own choice of
+/// resolution.
+pub struct ConflictView {
+    content: LinearLayout,
+    conflict: Conflict,
+}
+
+impl ConflictView {
+    /// Create a new ConflictView.
+    pub fn new(conflict: Conflict) -> Self {
+        let mut v_layout = LinearLayout::vertical();
+
+        let explanation = match (&conflict.ours, &conflict.theirs) {
+            (Some(_), Some(_)) => "This account was changed both locally and remotely.",
+            (None, _) => "This account was deleted locally, but changed remotely.",
+            (_, None) => "This account was changed locally, but deleted remotely.",
+        };
+        v_layout.add_child(TextView::new(explanation));
+        v_layout.add_child(TextView::new(" "));
+
+        let column = |text: &str| {
+            BoxView::new(
+                SizeConstraint::Fixed(28),
+                SizeConstraint::Free,
+                TextView::new(text),
+            )
+        };
+        let header = |account: &Option<Account>, name: &str| match *account {
+            Some(_) => String::from(name),
+            None => format!("{} (deleted)", name),
+        };
+        v_layout.add_child(
+            LinearLayout::horizontal()
+                .child(TextView::new(format!("{:10}", "")))
+                .child(column(&header(&conflict.ours, "Local")))
+                .child(column(&header(&conflict.theirs, "Remote"))),
+        );
+
+        for &field in ACCOUNT_FIELDS.iter() {
+            let value = |account: &Option<Account>| match *account {
+                Some(ref a) => ConflictView::render_value(field, a, false),
+                None => String::new(),
+            };
+            let id = format!("{}_{}", VIEW_ID_CONFLICT, field.name());
+            let mut row = LinearLayout::horizontal()
+                .child(TextView::new(format!(
+                    "{:10}",
+                    format!("{}:", field.name())
+                )))
+                .child(BoxView::new(
+                    SizeConstraint::Fixed(28),
+                    SizeConstraint::Free,
+                    TextView::new(value(&conflict.ours)).with_id(format!("{}_local", id)),
+                ))
+                .child(BoxView::new(
+                    SizeConstraint::Fixed(28),
+                    SizeConstraint::Free,
+                    TextView::new(value(&conflict.theirs)).with_id(format!("{}_remote", id)),
+                ));
+            if conflict.fields.contains(&field) {
+                row.add_child(
+                    SelectView::new()
+                        .popup()
+                        .item("Keep local", Resolution::Ours)
+                        .item("Keep remote", Resolution::Theirs)
+                        .item("Keep both", Resolution::Both)
+                        .with_id(id),
+                );
+            }
+            v_layout.add_child(row);
+        }
+
+        v_layout.add_child(TextView::new(" "));
+        v_layout.add_child(TextView::new("Ctrl-R: Reveal passwords"));
+        if !conflict.fields.is_empty() {
+            v_layout.add_child(TextView::new(
+                "Keeping both values of a field adds the remote value to the notes, except for \
+                 the password.  Keeping both passwords, or both accounts, adds the remote \
+                 account under a different name.",
+            ));
+        }
+
+        ConflictView {
+            content: v_layout,
+            conflict,
+        }
+    }
+
+    /// Render a field value for display, masking the password unless it should be revealed.
+    fn render_value(field: AccountField, account: &Account, reveal_password: bool) -> String {
+        if field == AccountField::Password && !reveal_password {
+            String::from("************")
+        } else {
+            String::from(field.get(account))
+        }
+    }
+
+    /// Handle the CTRL-R "reveal password" feature.
+    fn reveal_password(&mut self) {
+        let field = AccountField::Password;
+        let sides = [
+            ("local", self.conflict.ours.clone()),
+            ("remote", self.conflict.theirs.clone()),
+        ];
+        for &(side, ref account) in sides.iter() {
+            if let Some(ref account) = *account {
+                let id = format!("{}_{}_{}", VIEW_ID_CONFLICT, field.name(), side);
+                let value = ConflictView::render_value(field, account, true);
+                self.find_id(&id, |text_view: &mut TextView| text_view.set_content(value));
+            }
+        }
+    }
+
+    /// Return the chosen resolution of each conflicting field.
+    fn field_resolutions(&mut self) -> Vec<Resolution> {
+        let fields = self.conflict.fields.clone();
+        fields
+            .iter()
+            .map(|field| {
+                let id = format!("{}_{}", VIEW_ID_CONFLICT, field.name());
+                self.find_id(&id, |select: &mut SelectView<Resolution>| {
+                    select.selection()
+                })
+                .and_then(|selection| selection)
+                .map_or(Resolution::Ours, |selection| *selection)
+            })
+            .collect()
+    }
+}
+
+impl ViewWrapper for ConflictView {
+    wrap_impl!(self.content: LinearLayout);
+}
+
 ////////////////////////////////////////////////////////////////////////
 // Ui
 ////////////////////////////////////////////////////////////////////////
@@ -653,6 +891,8 @@ pub struct Ui {
     ui_tx: mpsc::Sender<UiMessage>,
     controller_tx: mpsc::Sender<controller::Message>,
     database: Rc<RefCell<Database>>,
+    sync_settings: SyncSettings,
+    busy: bool,
 }
 
 impl Ui {
@@ -666,6 +906,8 @@ impl Ui {
             ui_rx,
             controller_tx,
             database: Rc::new(RefCell::new(Database::new())),
+            sync_settings: SyncSettings::default(),
+            busy: false,
         };
 
         ////////////////////////////////////////////////////////////
@@ -793,6 +1035,7 @@ impl Ui {
         let controller_tx_clone1 = ui.controller_tx.clone();
         let controller_tx_clone2 = ui.controller_tx.clone();
         let controller_tx_clone3 = ui.controller_tx.clone();
+        let controller_tx_clone4 = ui.controller_tx.clone();
         let ui_tx_clone1 = ui.ui_tx.clone();
         let ui_tx_clone2 = ui.ui_tx.clone();
         let ui_tx_clone3 = ui.ui_tx.clone();
@@ -888,6 +1131,12 @@ impl Ui {
                 .unwrap();
         });
 
+        let do_check_remote = Callback::from_fn(move |_| {
+            controller_tx_clone4
+                .send(controller::Message::CheckRemote)
+                .unwrap();
+        });
+
         let do_edit_database = Callback::from_fn(move |_| {
             ui_tx_clone3.send(UiMessage::ShowDatabaseEdit).unwrap();
         });
@@ -923,6 +1172,19 @@ impl Ui {
         let mut database_menu = MenuTree::new();
         database_menu.children = vec![
             MenuItem::Leaf(String::from("Sync Database            ^Y"), do_sync.clone()),
+            MenuItem::Leaf(String::from("Check Remote Database"), do_check_remote),
+            MenuItem::Leaf(
+                String::from("Force Push to Remote"),
+                force_sync_callback(ui.controller_tx.clone(), ForcedSync::Push),
+            ),
+            MenuItem::Leaf(
+                String::from("Force Pull from Remote"),
+                force_sync_callback(ui.controller_tx.clone(), ForcedSync::Pull),
+            ),
+            MenuItem::Leaf(
+                String::from("Prune Remote Backups"),
+                prune_backups_callback(ui.controller_tx.clone()),
+            ),
             MenuItem::Leaf(
                 String::from("Edit Database Properties ^K"),
                 do_edit_database.clone(),
@@ -1103,6 +1365,7 @@ impl Ui {
         DatabaseEditView::show(
             &mut self.cursive,
             self.database.clone(),
+            &self.sync_settings,
             self.controller_tx.clone(),
         );
     }
@@ -1150,6 +1413,36 @@ impl Ui {
         self.cursive.clear();
     }
 
+    /// Load the sync settings of the current database into the UI.
+    pub fn set_sync_settings(&mut self, sync_settings: &SyncSettings) {
+        self.sync_settings = sync_settings.clone();
+        self.update_refresh_rate();
+    }
+
+    /// Indicate whether a background operation is in progress.  While busy, the UI steps
+    /// periodically even without input, so progress can be shown as it is reported.
+    pub fn set_busy(&mut self, busy: bool) {
+        self.busy = busy;
+        self.update_refresh_rate();
+    }
+
+    /// Choose how often the UI steps in the absence of input.
+    fn update_refresh_rate(&mut self) {
+        let fps = if self.busy {
+            BUSY_FPS
+        } else if self.sync_settings.sync_interval.is_some() {
+            PERIODIC_SYNC_FPS
+        } else {
+            0
+        };
+        self.cursive.set_fps(fps);
+    }
+
+    /// Return true if the user is not in the middle of anything, i.e. no dialog is open.
+    pub fn is_idle(&self) -> bool {
+        self.cursive.screen().layer_sizes().len() == 1
+    }
+
     /// Quit.
     pub fn quit(&mut self) {
         self.cursive.quit();
@@ -1207,18 +1500,75 @@ impl Ui {
         );
     }
 
+    /// Present a modal dialog showing a sync conflict, and step the UI until the user chooses how
+    /// to resolve it.  Return `None` if the dialog is dismissed, in which case the conflict's
+    /// provisional resolution should be kept.
+    ///
+    /// This is a synchronous operation, and will not return until the dialog is finished.
+    pub fn conflict_dialog(
+        &mut self,
+        conflict: &Conflict,
+        index: usize,
+        count: usize,
+    ) -> Option<ConflictChoice> {
+        let result = Rc::new(RefCell::new(None));
+        {
+            let conflict_view = ConflictView::new(conflict.clone()).with_id(VIEW_ID_CONFLICT);
+            let key_override = KeyOverrideView::new(conflict_view).register(
+                cursive::event::Event::CtrlChar('r'),
+                |s| {
+                    if let Some(mut conflict_view) = s.find_id::<ConflictView>(VIEW_ID_CONFLICT) {
+                        conflict_view.reveal_password();
+                    }
+                },
+            );
+            let mut dialog = Dialog::around(key_override).title(format!(
+                "Sync conflict {} of {}: {}",
+                index + 1,
+                count,
+                conflict.name
+            ));
+            let choices = [
+                ("Keep local", Resolution::Ours),
+                ("Keep remote", Resolution::Theirs),
+                ("Keep both", Resolution::Both),
+            ];
+            for &(label, resolution) in choices.iter() {
+                let result = result.clone();
+                dialog.add_button(label, move |s| {
+                    *result.borrow_mut() = Some(ConflictChoice::Account(resolution));
+                    s.pop_layer();
+                });
+            }
+            if !conflict.fields.is_empty() {
+                let result = result.clone();
+                dialog.add_button("Apply fields", move |s| {
+                    let resolutions = match s.find_id::<ConflictView>(VIEW_ID_CONFLICT) {
+                        Some(mut conflict_view) => conflict_view.field_resolutions(),
+                        None => return,
+                    };
+                    *result.borrow_mut() = Some(ConflictChoice::Fields(resolutions));
+                    s.pop_layer();
+                });
+            }
+            self.modal_dialog(dialog);
+        }
+        let choice = result.borrow_mut().take();
+        choice
+    }
+
     /// Present a modal password dialog to the user and step the UI until the dialog is dismissed.
     /// Returns a password if one was provided, otherwise returns None if the password field was
     /// left empty or cancel was selected.  This is a synchronous operation, and will not return
     /// until the dialog is finished.
-    pub fn password_dialog(&mut self, text: &str, secret: bool) -> Option<String> {
+    pub fn password_dialog(&mut self, text: &str, secret: bool) -> Option<SecretString> {
         let result = Rc::new(RefCell::new(None));
         {
             let result_clone1 = result.clone();
             let result_clone2 = result.clone();
             let mut editview = EditView::new().on_submit(move |s, text| {
                 if !text.is_empty() {
-                    *result_clone1.borrow_mut() = Some(String::from(text));
+                    *result_clone1.borrow_mut() = Some(SecretString::from(text));
                 }
                 s.pop_layer();
                 s.focus_id(VIEW_ID_SELECT).ok();
@@ -1232,7 +1582,7 @@ impl Ui {
                     .button("Ok", move |s| {
                         let text = s.find_id::<EditView>(VIEW_ID_INPUT).unwrap().get_content();
                         if !text.is_empty() {
-                            *result_clone2.borrow_mut() = Some((*text).clone());
+                            *result_clone2.borrow_mut() = Some(SecretString::from(text.as_str()));
                         }
                         s.pop_layer();
                         s.focus_id(VIEW_ID_SELECT).ok();
@@ -1241,10 +1591,7 @@ impl Ui {
                     .title("Enter password"),
             );
         }
-        let result = match *result.borrow() {
-            Some(ref s) => Some(s.clone()),
-            None => None,
-        };
+        let result = result.borrow_mut().take();
         result
     }
 
@@ -1325,21 +1672,20 @@ fn render_account_text(account: &Account, reveal_password: bool) -> String {
         // wrap around.
         String::from(value.trim().replace("\n", "\n          ").as_str())
     }
-    fn render_line(text: &mut String, field: &str, value: &String) {
+    fn render_line(text: &mut String, field: &str, value: &str) {
         let mut label = String::from(field);
         label.push(':');
         text.push_str(&(format!("{:10}{}\n", label, indent_multiline(value)))[..]);
     };
-    let password;
-    if reveal_password {
-        password = account.password.clone();
+    let password = if reveal_password {
+        account.password.as_str()
     } else {
-        password = String::from("************");
+        "************"
     };
     let mut text = String::new();
     render_line(&mut text, FIELD_NAME, &account.name);
     render_line(&mut text, FIELD_USER, &account.user);
-    render_line(&mut text, FIELD_PASSWORD, &password);
+    render_line(&mut text, FIELD_PASSWORD, password);
     render_line(&mut text, FIELD_URL, &account.url);
     render_line(&mut text, FIELD_NOTES, &account.notes);
     text
@@ -1358,3 +1704,63 @@ where
         false
     }
 }
+
+/// Return a callback which asks the user to confirm a forced sync, and then requests it.
+fn force_sync_callback(
+    controller_tx: mpsc::Sender<controller::Message>,
+    force: ForcedSync,
+) -> Callback {
+    Callback::from_fn(move |s| {
+        let text = match force {
+            ForcedSync::Push => {
+                "Really replace the remote database with the local database?  \
+                 This ignores the revision numbers, so any remote changes will be lost.  \
+                 (A backup of the remote database is made first.)"
+            }
+            ForcedSync::Pull => {
+                "Really replace the local database with the remote database?  \
+                 This ignores the revision numbers, so any local changes will be lost.  \
+                 (A backup of the local database is made first.)"
+            }
+        };
+        let controller_tx = controller_tx.clone();
+        s.add_layer(
+            Dialog::around(TextView::new(text))
+                .title("Confirm")
+                .button("No", |s| {
+                    s.pop_layer();
+                })
+                .button("Yes", move |s| {
+                    controller_tx
+                        .send(controller::Message::ForceSync(force))
+                        .unwrap();
+                    s.pop_layer();
+                }),
+        );
+    })
+}
+
+/// Return a callback which asks the user to confirm deleting old remote backups, and then
+/// requests it.
+fn prune_backups_callback(controller_tx: mpsc::Sender<controller::Message>) -> Callback {
+    Callback::from_fn(move |s| {
+        let controller_tx = controller_tx.clone();
+        s.add_layer(
+            Dialog::around(TextView::new(
+                "Really delete old backups of this database from the remote repository?  \
+                 Only backups uploaded by this client are deleted, and the newest are kept \
+                 according to the sync settings.",
+            ))
+            .title("Confirm")
+            .button("No", |s| {
+                s.pop_layer();
+            })
+            .button("Yes", move |s| {
+                controller_tx
+                    .send(controller::Message::PruneBackups)
+                    .unwrap();
+                s.pop_layer();
+            }),
+        );
+    })
+}
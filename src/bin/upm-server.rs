@@ -0,0 +1,83 @@
+//! UPM sync server
+//!
+//! This serves a directory of UPM databases using the UPM sync protocol, so that UPM clients
+//! (including tupm and the original UPM Java application) can synchronize with it.  It is a
+//! self-hosted replacement for the PHP scripts distributed with UPM.
+
+extern crate clap;
+extern crate rpassword;
+extern crate upm;
+
+use clap::{App, Arg};
+use std::env;
+use std::process;
+use upm::server::Server;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8080";
+
+/// The repository password is read from this environment variable if it is set, to allow running
+/// the server non-interactively.
+const PASSWORD_ENV_VAR: &str = "UPM_SERVER_PASSWORD";
+
+// Possible exit codes
+const EXIT_FAILURE: i32 = 1;
+
+/// Parse the command-line arguments and serve the repository until interrupted.
+fn main() {
+    let matches = App::new("UPM sync server")
+        .version("0.1.0")
+        .about("Serves a directory of UPM databases using the UPM sync protocol.")
+        .arg(
+            Arg::with_name("directory")
+                .short("d")
+                .long("directory")
+                .value_name("DIR")
+                .help("Specify the directory containing the databases.")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .value_name("ADDRESS")
+                .help("Specify the address and port to listen on (default: 127.0.0.1:8080).")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("username")
+                .short("u")
+                .long("username")
+                .value_name("USERNAME")
+                .help("Specify the username clients must authenticate with.")
+                .takes_value(true)
+                .required(true),
+        )
+        .get_matches();
+
+    let directory = matches.value_of("directory").unwrap();
+    let address = matches.value_of("listen").unwrap_or(DEFAULT_LISTEN_ADDRESS);
+    let username = matches.value_of("username").unwrap();
+    let password = env::var(PASSWORD_ENV_VAR).unwrap_or_else(|_| {
+        rpassword::prompt_password_stdout("Repository password: ").unwrap_or_else(|e| {
+            eprintln!("Error reading password: {}", e);
+            process::exit(EXIT_FAILURE);
+        })
+    });
+
+    let server = match Server::bind(address, directory, username, &password) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    };
+    match server.local_addr() {
+        Ok(a) => println!("Serving \"{}\" at http://{}/", directory, a),
+        Err(e) => println!("Serving \"{}\" ({})", directory, e),
+    }
+    if let Err(e) = server.run() {
+        eprintln!("Error: {}", e);
+        process::exit(EXIT_FAILURE);
+    }
+}
@@ -3,17 +3,29 @@
 //! Because `tupm` is experimental code, we are fairly paranoid about making backups of the
 //! database early and often -- perhaps even to the point of annoyance to anyone wondering why
 //! their UPM directory is littered with all these files.  Backup databases are suffixed with a
-//! timestamp and a `.bak` extension.  Backups are made in the following scenarios:
+//! timestamp and a `.bak` extension.  Remote backups made within the same second as an existing
+//! one also carry a sequence number after the timestamp.  Backups are made in the following
+//! scenarios:
 //!
 //! 1. Up to 30 backups of the pre-existing local database are made whenever the database is saved.
 //!    If 30 backups are already present, the oldest is deleted to make room for a new one.
 //! 2. When a sync operation is about to overwrite a remote database with a new revision, it first
 //!    uploads a backup file of the new revision.  If the upload of this backup file fails, the
 //!    pre-existing remote database is not deleted and an error is presented to the user.  This is
-//!    particularly useful since syncing a new revision consists of non-atomic steps: a "delete"
-//!    operation followed by an "upload" operation.  If the "delete" succeeds but the "upload"
-//!    fails, the remote database would be lost forever in the absence of backups.  There is
-//!    currently no limit on the number of backups stored on the remote server.
+//!    particularly useful since syncing a new revision with the HTTP sync protocol consists of
+//!    non-atomic steps: a "delete" operation followed by an "upload" operation.  If the "delete"
+//!    succeeds but the "upload" fails, the remote database would be lost forever in the absence of
+//!    backups.
+//! 3. When a forced push replaces a remote database regardless of its revision, it first uploads
+//!    a backup file of the remote database being replaced.
+//!
+//! In both of the latter cases, the new remote database is downloaded again to verify it.  If it
+//! cannot be uploaded intact, the remote backup file is copied back in its place.
+//!
+//! The names of the remote backup files are recorded in the database's sync state, so they can be
+//! pruned later according to a count or age policy (see
+//! [`prune_remote_backups()`](../sync/fn.prune_remote_backups.html)).  Remote backups made by
+//! other clients, or before names were recorded, are left alone.
 //!
 
 use error::UpmError;
@@ -29,6 +41,13 @@ const MAX_BACKUP_FILES: usize = 30;
 /// Use this filename extension for backup files.
 const BACKUP_FILE_EXTENSION: &'static str = ".bak";
 
+/// Backup filenames contain a local timestamp in this `strftime` format.  Timestamps in this
+/// format sort in chronological order.
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// Separates the timestamp from the sequence number in numbered backup filenames.
+const BACKUP_SEQUENCE_SEPARATOR: char = '-';
+
 /// Remove the oldest backup files as needed to bring the total number of backup files for this
 /// path within the limit.
 fn prune_old_backups(path: &Path) -> Result<usize, UpmError> {
@@ -74,6 +93,17 @@ fn prune_old_backups(path: &Path) -> Result<usize, UpmError> {
 /// Generate a backup filename for the specified path by appending a timestamp and `.bak`
 /// extension.
 pub fn generate_backup_filename<P: AsRef<Path>>(path: P) -> Result<PathBuf, UpmError> {
+    generate_numbered_backup_filename(path, 0)
+}
+
+/// Generate a backup filename as [`generate_backup_filename()`](fn.generate_backup_filename.html)
+/// does, with a sequence number following the timestamp.  This tells apart backups made within
+/// the same second.  Sequence number 0 is omitted, so it yields the same filename as
+/// `generate_backup_filename()`.
+pub fn generate_numbered_backup_filename<P: AsRef<Path>>(
+    path: P,
+    sequence: u32,
+) -> Result<PathBuf, UpmError> {
     let basename = if let Some(s) = path.as_ref().file_name() {
         match s.to_str() {
             Some(x) => x,
@@ -82,17 +112,45 @@ pub fn generate_backup_filename<P: AsRef<Path>>(path: P) -> Result<PathBuf, UpmE
     } else {
         return Err(UpmError::InvalidFilename);
     };
-    let current_time = time::now();
-    let timestamp = match current_time.strftime("%Y%m%d%H%M%S") {
-        Ok(t) => t,
-        Err(e) => {
-            return Err(UpmError::TimeParseError(e));
-        }
-    };
+    let mut timestamp = backup_timestamp_at(&time::now())?;
+    if sequence > 0 {
+        timestamp.push_str(&format!("{}{}", BACKUP_SEQUENCE_SEPARATOR, sequence));
+    }
     let backup_basename = format!("{}.{}{}", basename, timestamp, BACKUP_FILE_EXTENSION);
     Ok(path.as_ref().to_path_buf().with_file_name(backup_basename))
 }
 
+/// Return the timestamp which a backup filename generated at the provided (local) time would
+/// contain.
+pub fn backup_timestamp_at(tm: &time::Tm) -> Result<String, UpmError> {
+    match tm.strftime(BACKUP_TIMESTAMP_FORMAT) {
+        Ok(t) => Ok(t.to_string()),
+        Err(e) => Err(UpmError::TimeParseError(e)),
+    }
+}
+
+/// Return the timestamp contained in a backup filename generated by
+/// [`generate_backup_filename()`](fn.generate_backup_filename.html), or `None` if the filename
+/// is not that of a backup.  Any sequence number following the timestamp is not included.
+pub fn backup_timestamp(filename: &str) -> Option<&str> {
+    let stem = filename.strip_suffix(BACKUP_FILE_EXTENSION)?;
+    let suffix = &stem[stem.rfind('.')? + 1..];
+    let (timestamp, sequence) = match suffix.find(BACKUP_SEQUENCE_SEPARATOR) {
+        Some(i) => (&suffix[..i], Some(&suffix[i + 1..])),
+        None => (suffix, None),
+    };
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let sequence_valid = match sequence {
+        Some(sequence) => digits(sequence),
+        None => true,
+    };
+    if timestamp.len() == 14 && digits(timestamp) && sequence_valid {
+        Some(timestamp)
+    } else {
+        None
+    }
+}
+
 /// If the file at the specified path exists, make a backup, and remove any old backup files as
 /// needed to bring the total number of backup files for this path within the limit.  `Ok(true)` is
 /// returned on success, otherwise an error is returned.
@@ -177,5 +235,40 @@ mod tests {
         // Confirm that the timestamp roughly represents the expected time.
         let difference = timestamp_time.to_utc() - backup_time.to_utc();
         assert!(difference < time::Duration::seconds(ALLOWED_TIMESTAMP_VARIANCE_SECS));
+
+        // The timestamp can be recovered from the filename.
+        let backup_basename = backup_filename.rsplit('/').next().unwrap();
+        assert_eq!(backup_timestamp(backup_basename), Some(timestamp));
+
+        // A sequence number follows the timestamp, unless it is 0.
+        let numbered = generate_numbered_backup_filename(TEST_PATH, 3).unwrap();
+        let numbered = numbered.to_string_lossy();
+        assert!(numbered.ends_with(&format!("-3{}", BACKUP_FILE_EXTENSION)));
+        assert_matches!(backup_timestamp(&numbered), Some(_));
+        let unnumbered = generate_numbered_backup_filename(TEST_PATH, 0).unwrap();
+        assert_eq!(unnumbered.to_string_lossy().len(), expected_length);
+    }
+
+    #[test]
+    fn test_backup_timestamp() {
+        assert_eq!(
+            backup_timestamp("primary.20190102030405.bak"),
+            Some("20190102030405")
+        );
+        assert_eq!(
+            backup_timestamp("my.db.20190102030405.bak"),
+            Some("20190102030405")
+        );
+        assert_eq!(
+            backup_timestamp("primary.20190102030405-2.bak"),
+            Some("20190102030405")
+        );
+        assert_eq!(backup_timestamp("primary.2019010203040.bak"), None);
+        assert_eq!(backup_timestamp("primary.20190102030405-.bak"), None);
+        assert_eq!(backup_timestamp("primary.20190102030405-x.bak"), None);
+        assert_eq!(backup_timestamp("primary.20190102030405"), None);
+        assert_eq!(backup_timestamp("20190102030405.bak"), None);
+        let tm = time::strptime("20190102030405", BACKUP_TIMESTAMP_FORMAT).unwrap();
+        assert_eq!(backup_timestamp_at(&tm).unwrap(), "20190102030405");
     }
 }
@@ -1,12 +1,19 @@
 //! Perform encryption and decryption operations to support the ciphertext component of UPMv3
 //! database files.
 //!
-//! This module implements the following by way of OpenSSL:
+//! This module implements the following:
 //!
 //! 1. The UPMv3 key derivation function (KDF) to convert a password into a private key.
 //! 2. Encryption.
 //! 3. Decryption.
 //!
+//! It also provides the general-purpose hash functions needed by other modules, such as the
+//! request signing performed by some sync backends.
+//!
+//! The primitives come from one of two backends.  By default, OpenSSL is used.  If the
+//! `rust_crypto` feature is enabled, a pure-Rust backend is used instead, so no system OpenSSL
+//! is needed.  Both produce identical results.
+//!
 //! UPM encrypts databases using an AES 256-bit cipher in CBC mode.  The private key is derived
 //! from a password using a PKCS#12 key derivation function (KDF) as specified in RFC 7292 Appendix
 //! B, using 20 iterations.  This KDF is likely the weakest point of UPM's crypto for the following
@@ -17,42 +24,57 @@
 //!
 //! Nonetheless, use of this KDF is required to interoperate with UPMv3 databases.
 //!
+//! Databases which don't need to be read by other UPM clients may instead use the hardened
+//! format (see the [`database`](../database/index.html) module), which derives its key with the
+//! Argon2id KDF specified in RFC 9106 and encrypts with AES-256-GCM.  Its KDF parameters are
+//! tunable, and the authentication tag reliably detects a bad password or a damaged file.  Argon2id
+//! always comes from the pure-Rust `argon2` crate, since OpenSSL only gained it in version 3.2.
+//!
 
-extern crate openssl;
+extern crate argon2;
+
+use std::str;
 
 use error::UpmError;
-use openssl_extra;
+use secret::SecretBytes;
+use zeroize::Zeroize;
+
+#[cfg(feature = "openssl_crypto")]
+#[cfg_attr(feature = "rust_crypto", allow(dead_code))]
+mod openssl_backend;
+#[cfg(feature = "rust_crypto")]
+mod rust_backend;
+
+#[cfg(all(feature = "openssl_crypto", not(feature = "rust_crypto")))]
+use self::openssl_backend as backend;
+#[cfg(feature = "rust_crypto")]
+use self::rust_backend as backend;
+
+#[cfg(not(any(feature = "openssl_crypto", feature = "rust_crypto")))]
+compile_error!("either the openssl_crypto or the rust_crypto feature must be enabled");
 
 const KEY_MATERIAL_ID: u8 = 1;
 const IV_MATERIAL_ID: u8 = 2;
+const MAC_MATERIAL_ID: u8 = 3;
 const KEY_MATERIAL_BITS: usize = 256;
 const IV_MATERIAL_BITS: usize = 128;
 const KEY_MATERIAL_SIZE: usize = KEY_MATERIAL_BITS / 8;
 const IV_MATERIAL_SIZE: usize = IV_MATERIAL_BITS / 8;
 const KEY_DERIVATION_ITERATIONS: usize = 20;
 
+/// The size of the nonce used by AES-256-GCM in the hardened format.
+pub const NONCE_SIZE: usize = 12;
+/// The size of the authentication tag which follows the ciphertext in the hardened format.
+pub const AUTHENTICATION_TAG_SIZE: usize = 16;
+
 /// This KeyIVPair struct is to arrange zeroing of the key and IV buffers when they go out of
-/// scope.  Note that the current zeroing method is probably naive, and may not survive compiler
-/// optimization.  The best practices in Rust for storing sensitive material are still being worked
-/// out.
-///
-/// The following GitHub issue is informative:
-///
-/// * https://github.com/isislovecruft/curve25519-dalek/issues/11
-///
-/// Note that there is more sensitive data than just the key/IV.  In particular, the following
-/// items are sensitive and we need to develop a post-zeroing solution for them:
-///
-/// 1. The master password.
-/// 2. The account records, including their respective managed passwords.
-/// 3. Any intermediate data buffers used to pass these items around.
+/// scope.  The zeroing is performed with the `zeroize` crate, so it cannot be optimized away.
 ///
-/// We should probably consider using one of these tools:
-///
-/// * https://github.com/cesarb/clear_on_drop
-/// * https://github.com/ticki/secbox
-/// * https://github.com/stouset/secrets
-/// * https://github.com/myfreeweb/secstr
+/// The other sensitive material handled here is protected the same way: the master password is
+/// held in a [`SecretString`](../secret/struct.SecretString.html) by its owner, the password's
+/// BMPString encoding is wiped after key derivation, and decrypted plaintext is returned in a
+/// [`SecretBytes`](../secret/struct.SecretBytes.html) buffer.  Copies made inside the crypto
+/// backend (such as OpenSSL) are beyond our reach.
 struct KeyIVPair {
     pub key: [u8; KEY_MATERIAL_SIZE],
     pub iv: [u8; IV_MATERIAL_SIZE],
@@ -60,12 +82,8 @@ struct KeyIVPair {
 
 impl Drop for KeyIVPair {
     fn drop(&mut self) {
-        for i in 0..self.key.len() {
-            self.key[i] = 0;
-        }
-        for i in 0..self.iv.len() {
-            self.iv[i] = 0;
-        }
+        self.key.zeroize();
+        self.iv.zeroize();
     }
 }
 
@@ -78,84 +96,342 @@ impl KeyIVPair {
     }
 }
 
+/// The ways in which a password may be encoded for the PKCS#12 KDF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PasswordEncoding {
+    /// The encoding used by the UPM Java client, which passes the UTF-16 code units of the
+    /// password to BouncyCastle's `PKCS12PasswordToBytes()`.  Each code unit is written
+    /// big-endian (so characters outside the Basic Multilingual Plane become surrogate pairs),
+    /// followed by a two-byte null terminator.  An empty password encodes to no bytes at all.
+    Standard,
+    /// The encoding used by earlier versions of tupm, which kept only the low 16 bits of each
+    /// code point and always appended the terminator.  It differs from the standard encoding
+    /// only for passwords which are empty or contain characters outside the Basic Multilingual
+    /// Plane.
+    Legacy,
+}
+
+impl PasswordEncoding {
+    /// Return true if the two encodings produce different results for the provided password, in
+    /// which case a database encrypted by an earlier version of tupm must be decrypted with the
+    /// legacy encoding.
+    pub fn encodings_differ(password: &str) -> bool {
+        password.is_empty() || password.chars().any(|c| c as u32 > 0xFFFF)
+    }
+}
+
+/// Encode a password as a BMPString for the PKCS#12 KDF, in the way the UPM Java client does.
+///
+/// PKCS#12 calls this encoding "BMPString", which is strictly UCS-2BE and cannot represent code
+/// points outside the Basic Multilingual Plane.  Java strings are UTF-16, so the Java client
+/// encodes such code points as surrogate pairs, and we must do the same to derive the same key.
+fn str_to_bmpstring(text: &str) -> Box<[u8]> {
+    if text.is_empty() {
+        return Box::new([]);
+    }
+
+    // Use a boxed slice so the sensitive data can be reliably zeroed later.
+    // (A Vec may reallocate and leave behind sensitive material.)
+    let final_length = text.encode_utf16().count() * 2 + 2;
+    let mut bmpstring: Box<[u8]> = vec![0; final_length].into_boxed_slice();
+    for (i, unit) in text.encode_utf16().enumerate() {
+        bmpstring[i * 2] = (unit >> 8) as u8;
+        bmpstring[i * 2 + 1] = unit as u8;
+    }
+    bmpstring
+}
+
+/// Encode a password as earlier versions of tupm did, discarding the upper bits of code points
+/// outside the Basic Multilingual Plane.  This is only used to decrypt databases written by those
+/// versions.
+fn str_to_legacy_bmpstring(text: &str) -> Box<[u8]> {
+    let final_length = text.chars().count() * 2 + 2;
+    let mut bmpstring: Box<[u8]> = vec![0; final_length].into_boxed_slice();
+    for (i, c) in text.chars().enumerate() {
+        let codepoint = c as u32;
+        // The upper 16 bits of the codepoint will be discarded.
+        bmpstring[i * 2] = ((codepoint >> 8) & 0xFF) as u8;
+        bmpstring[i * 2 + 1] = (codepoint & 0xFF) as u8;
+    }
+    bmpstring
+}
+
 /// Perform key and IV generation based on the algorithm specified here:
 ///
 /// * RFC 7292: PKCS #12: Personal Information Exchange Syntax v1.1 Appendix B.  Deriving Keys and
 /// IVs from Passwords and Salt
 ///
 /// Note that this is probably the weak point of UPM crypto for the reasons mentioned above.
-fn pkcs12_derive_key(password: &str, salt: &[u8], pair: &mut KeyIVPair) -> Result<(), UpmError> {
-    match openssl_extra::pkcs12_key_gen(
-        password,
-        &salt,
+fn pkcs12_derive_key(
+    password: &str,
+    encoding: PasswordEncoding,
+    salt: &[u8],
+    pair: &mut KeyIVPair,
+) -> Result<(), UpmError> {
+    let mut bmpstring = match encoding {
+        PasswordEncoding::Standard => str_to_bmpstring(password),
+        PasswordEncoding::Legacy => str_to_legacy_bmpstring(password),
+    };
+    let result = backend::pkcs12_key_gen(
+        &bmpstring,
+        salt,
         KEY_MATERIAL_ID,
         KEY_DERIVATION_ITERATIONS,
         &mut pair.key,
-        openssl::hash::MessageDigest::sha256(),
-    ) {
-        Ok(()) => {}
-        Err(_) => {
-            return Err(UpmError::KeyIVGeneration);
+    )
+    .and_then(|_| {
+        backend::pkcs12_key_gen(
+            &bmpstring,
+            salt,
+            IV_MATERIAL_ID,
+            KEY_DERIVATION_ITERATIONS,
+            &mut pair.iv,
+        )
+    });
+    bmpstring.zeroize();
+    result
+}
+
+/// Decrypt the UPMv3 database ciphertext using the provided password, encoded as specified, and
+/// salt.  The plaintext is wiped from memory when the returned buffer is dropped.
+pub fn decrypt(
+    ciphertext: &[u8],
+    password: &str,
+    encoding: PasswordEncoding,
+    salt: &[u8],
+) -> Result<SecretBytes, UpmError> {
+    let mut pair = KeyIVPair::new();
+    try!(pkcs12_derive_key(password, encoding, salt, &mut pair));
+    backend::aes_256_cbc_decrypt(&pair.key, &pair.iv, ciphertext)
+}
+
+/// Encrypt the UPMv3 database plaintext using the provided password, encoded as specified, and
+/// salt.  Databases should always be encrypted using the standard encoding, so the UPM Java client
+/// can decrypt them.
+pub fn encrypt(
+    plaintext: &[u8],
+    password: &str,
+    encoding: PasswordEncoding,
+    salt: &[u8],
+) -> Result<Vec<u8>, UpmError> {
+    let mut pair = KeyIVPair::new();
+    try!(pkcs12_derive_key(password, encoding, salt, &mut pair));
+    backend::aes_256_cbc_encrypt(&pair.key, &pair.iv, plaintext)
+}
+
+/// The parameters of the Argon2id KDF used by the hardened database format.  These are stored in
+/// each database, so they may be changed without breaking existing databases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    /// The amount of memory to use, in kibibytes.
+    pub memory_kib: u32,
+    /// The number of passes over the memory.
+    pub iterations: u32,
+    /// The number of lanes which may be processed in parallel.
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// Return the second recommended option of RFC 9106 section 4: 64 MiB of memory, 3
+    /// iterations, and 4 lanes.
+    fn default() -> KdfParams {
+        KdfParams {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 4,
         }
-    };
-    match openssl_extra::pkcs12_key_gen(
-        password,
-        &salt,
-        IV_MATERIAL_ID,
-        KEY_DERIVATION_ITERATIONS,
-        &mut pair.iv,
-        openssl::hash::MessageDigest::sha256(),
-    ) {
-        Ok(()) => {}
-        Err(_) => {
-            return Err(UpmError::KeyIVGeneration);
+    }
+}
+
+impl KdfParams {
+    /// The largest amount of memory accepted, in kibibytes (4 GiB).  Databases are read using the
+    /// parameters stored in them, so this keeps a damaged or malicious file from demanding
+    /// unreasonable resources.
+    pub const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+    /// The largest number of iterations accepted.
+    pub const MAX_ITERATIONS: u32 = 100;
+    /// The largest number of lanes accepted.
+    pub const MAX_PARALLELISM: u32 = 64;
+
+    /// Return an error describing the problem if these parameters are outside the accepted
+    /// bounds.  Argon2 requires at least 8 KiB of memory per lane.
+    pub fn validate(&self) -> Result<(), UpmError> {
+        let invalid = |message: String| Err(UpmError::BadKdfParams(message));
+        if self.parallelism < 1 || self.parallelism > Self::MAX_PARALLELISM {
+            return invalid(format!(
+                "parallelism must be from 1 to {}",
+                Self::MAX_PARALLELISM
+            ));
+        }
+        if self.iterations < 1 || self.iterations > Self::MAX_ITERATIONS {
+            return invalid(format!(
+                "iterations must be from 1 to {}",
+                Self::MAX_ITERATIONS
+            ));
         }
+        if self.memory_kib < 8 * self.parallelism || self.memory_kib > Self::MAX_MEMORY_KIB {
+            return invalid(format!(
+                "memory must be from {} to {} KiB",
+                8 * self.parallelism,
+                Self::MAX_MEMORY_KIB
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// This key is arranged to be zeroed when it goes out of scope, like `KeyIVPair`.
+struct AeadKey {
+    pub key: [u8; KEY_MATERIAL_SIZE],
+}
+
+impl Drop for AeadKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// Derive a key for the hardened format from the UTF-8 encoded password and the salt using
+/// Argon2id with the provided parameters.
+fn argon2id_derive_key(
+    password: &str,
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<AeadKey, UpmError> {
+    params.validate()?;
+    let argon2_params = argon2::Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(KEY_MATERIAL_SIZE),
+    )
+    .map_err(|_| UpmError::KeyIVGeneration)?;
+    let argon2 = argon2::Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        argon2_params,
+    );
+    let mut key = AeadKey {
+        key: [0u8; KEY_MATERIAL_SIZE],
     };
-    Ok(())
+    argon2
+        .hash_password_into(password.as_bytes(), salt, &mut key.key)
+        .map_err(|_| UpmError::KeyIVGeneration)?;
+    Ok(key)
 }
 
-/// Decrypt the UPMv3 database ciphertext using the provided password and salt.
-pub fn decrypt(ciphertext: &[u8], password: &str, salt: &[u8]) -> Result<Vec<u8>, UpmError> {
-    let mut pair = KeyIVPair::new();
-    try!(pkcs12_derive_key(password, salt, &mut pair));
-
-    match openssl::symm::decrypt(
-        openssl::symm::Cipher::aes_256_cbc(),
-        &pair.key[..],
-        Option::Some(&pair.iv[..]),
-        &ciphertext[..],
-    ) {
-        Ok(x) => Ok(x),
-        Err(error_stack) => {
-            if openssl_extra::is_bad_decrypt(&error_stack) {
-                Err(UpmError::BadPassword)
-            } else {
-                Err(From::from(error_stack))
-            }
+/// Encrypt the plaintext of a hardened database using the provided password, KDF parameters,
+/// salt, and nonce.  The additional data (the database header) is authenticated, but not
+/// encrypted.  The authentication tag is appended to the returned ciphertext.
+pub fn encrypt_hardened(
+    plaintext: &[u8],
+    password: &str,
+    params: &KdfParams,
+    salt: &[u8],
+    nonce: &[u8],
+    additional_data: &[u8],
+) -> Result<Vec<u8>, UpmError> {
+    let key = argon2id_derive_key(password, salt, params)?;
+    backend::aes_256_gcm_encrypt(&key.key, nonce, additional_data, plaintext)
+}
+
+/// Decrypt the ciphertext of a hardened database, as produced by
+/// [`encrypt_hardened()`](fn.encrypt_hardened.html).  If the password is incorrect, or the
+/// ciphertext or additional data were altered, `UpmError::BadPassword` is returned.
+pub fn decrypt_hardened(
+    ciphertext: &[u8],
+    password: &str,
+    params: &KdfParams,
+    salt: &[u8],
+    nonce: &[u8],
+    additional_data: &[u8],
+) -> Result<SecretBytes, UpmError> {
+    let key = argon2id_derive_key(password, salt, params)?;
+    backend::aes_256_gcm_decrypt(&key.key, nonce, additional_data, ciphertext)
+}
+
+/// Derive a key for authenticating data kept outside the database (such as the sync state) from
+/// the password and salt.  The KDF is the one used by the database, so checking a password guess
+/// against the result is no cheaper than checking it against the database itself: the PKCS#12 KDF
+/// (with the ID for MAC material) for version 3 databases, or Argon2id with the provided
+/// parameters for hardened databases.
+pub fn derive_mac_key(
+    password: &str,
+    salt: &[u8],
+    params: Option<&KdfParams>,
+) -> Result<SecretBytes, UpmError> {
+    match params {
+        Some(params) => {
+            let key = argon2id_derive_key(password, salt, params)?;
+            Ok(SecretBytes::from(key.key.to_vec()))
+        }
+        None => {
+            let mut key = AeadKey {
+                key: [0u8; KEY_MATERIAL_SIZE],
+            };
+            let mut bmpstring = str_to_bmpstring(password);
+            let result = backend::pkcs12_key_gen(
+                &bmpstring,
+                salt,
+                MAC_MATERIAL_ID,
+                KEY_DERIVATION_ITERATIONS,
+                &mut key.key,
+            );
+            bmpstring.zeroize();
+            result.map(|_| SecretBytes::from(key.key.to_vec()))
         }
     }
 }
 
-/// Encrypt the UPMv3 database plaintext using the provided password and salt.
-pub fn encrypt(plaintext: &[u8], password: &str, salt: &[u8]) -> Result<Vec<u8>, UpmError> {
-    let mut pair = KeyIVPair::new();
-    try!(pkcs12_derive_key(password, salt, &mut pair));
-
-    match openssl::symm::encrypt(
-        openssl::symm::Cipher::aes_256_cbc(),
-        &pair.key[..],
-        Option::Some(&pair.iv[..]),
-        &plaintext[..],
-    ) {
-        Ok(x) => Ok(x),
-        Err(error_stack) => {
-            if openssl_extra::is_bad_decrypt(&error_stack) {
-                Err(UpmError::BadPassword)
-            } else {
-                Err(From::from(error_stack))
+/// Return the SHA-256 digest of the provided data.
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    backend::sha256(data)
+}
+
+/// Return the MD5 digest of the provided data.  This is only for protocols which still require
+/// it, such as HTTP Digest authentication.
+pub fn md5(data: &[u8]) -> Result<Vec<u8>, UpmError> {
+    backend::md5(data)
+}
+
+/// Return the DER encoding of each certificate in the provided PEM data, which may contain any
+/// number of certificates (such as a CA bundle).
+pub fn pem_certificates_to_der(pem: &[u8]) -> Result<Vec<Vec<u8>>, UpmError> {
+    backend::pem_certificates_to_der(pem)
+}
+
+/// Return the HMAC-SHA256 message authentication code of the provided data using the provided
+/// key.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, UpmError> {
+    backend::hmac_sha256(key, data)
+}
+
+/// Return true if the two byte slices are equal.  The comparison takes the same amount of time
+/// regardless of where the slices differ, although it returns early if their lengths differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    backend::constant_time_eq(a, b)
+}
+
+/// Return the lowercase hexadecimal representation of the provided bytes.
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode a hexadecimal string of either case, or return `None` if it is invalid.
+pub fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    // An odd number of digits leaves a final chunk with only one.  from_str_radix() alone would
+    // also accept a leading sign, so every character is checked first.
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            if pair.len() != 2 || !pair.iter().all(|b| b.is_ascii_hexdigit()) {
+                return None;
             }
-        }
-    }
+            str::from_utf8(pair)
+                .ok()
+                .and_then(|p| u8::from_str_radix(p, 16).ok())
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -195,26 +471,537 @@ mod tests {
         0x30, 0x30, 0x30, 0x30, 0x30,
     ];
 
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256(b"abc"),
+            &[
+                0xBA, 0x78, 0x16, 0xBF, 0x8F, 0x01, 0xCF, 0xEA, 0x41, 0x41, 0x40, 0xDE, 0x5D, 0xAE,
+                0x22, 0x23, 0xB0, 0x03, 0x61, 0xA3, 0x96, 0x17, 0x7A, 0x9C, 0xB4, 0x10, 0xFF, 0x61,
+                0xF2, 0x00, 0x15, 0xAD,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test case 2
+        let result = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_matches!(result, Ok(_));
+        assert_eq!(
+            result.unwrap(),
+            &[
+                0x5B, 0xDC, 0xC1, 0x46, 0xBF, 0x60, 0x75, 0x4E, 0x6A, 0x04, 0x24, 0x26, 0x08, 0x95,
+                0x75, 0xC7, 0x5A, 0x00, 0x3F, 0x08, 0x9D, 0x27, 0x39, 0x83, 0x9D, 0xEC, 0x58, 0xB9,
+                0x64, 0xEC, 0x38, 0x43,
+            ]
+        );
+    }
+
+    const HELLOWORLD_STR: &str = "hello world";
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const FANCY_UTF8: &[u8] = &[
+        0xCE, 0xB3, 0xCE, 0xBB, 0xCF, 0x8E, 0xCF, 0x83,
+        0xCF, 0x83, 0xCE, 0xB1
+    ];
+    const EMPTY_BMPSTRING: &[u8] = &[0x00, 0x00];
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const HELLOWORLD_BMPSTRING: &[u8] = &[
+        0x00, 0x68, 0x00, 0x65, 0x00, 0x6C, 0x00, 0x6C,
+        0x00, 0x6F, 0x00, 0x20, 0x00, 0x77, 0x00, 0x6F,
+        0x00, 0x72, 0x00, 0x6C, 0x00, 0x64, 0x00, 0x00
+    ];
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const FANCY_BMPSTRING: &[u8] = &[
+        0x03, 0xB3, 0x03, 0xBB, 0x03, 0xCE, 0x03, 0xC3,
+        0x03, 0xC3, 0x03, 0xB1, 0x00, 0x00
+    ];
+
+    /// A password containing a character outside the Basic Multilingual Plane (U+1F511) and
+    /// characters inside it.  The expected encoding, key, IV, and ciphertext were cross-checked
+    /// against Java's `String.toCharArray()` fed to BouncyCastle's `PKCS12PasswordToBytes()`
+    /// algorithm, OpenSSL's `openssl kdf ... PKCS12KDF`, and an independent RFC 7292
+    /// implementation.
+    const EMOJI_PASSWORD: &str = "\u{1F511} p\u{E4}ssw\u{F6}rd";
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const EMOJI_BMPSTRING: &[u8] = &[
+        0xD8, 0x3D, 0xDD, 0x11, 0x00, 0x20, 0x00, 0x70,
+        0x00, 0xE4, 0x00, 0x73, 0x00, 0x73, 0x00, 0x77,
+        0x00, 0xF6, 0x00, 0x72, 0x00, 0x64, 0x00, 0x00,
+    ];
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const EMOJI_LEGACY_BMPSTRING: &[u8] = &[
+        0xF5, 0x11, 0x00, 0x20, 0x00, 0x70, 0x00, 0xE4,
+        0x00, 0x73, 0x00, 0x73, 0x00, 0x77, 0x00, 0xF6,
+        0x00, 0x72, 0x00, 0x64, 0x00, 0x00,
+    ];
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const EMOJI_EXPECTED_KEY: &[u8] = &[
+        0x14, 0xC0, 0x2D, 0x23, 0x3F, 0x7C, 0xD1, 0xA8,
+        0x8F, 0x7B, 0xC3, 0x54, 0x55, 0xC3, 0xAF, 0xAA,
+        0xC5, 0x5F, 0x49, 0xEB, 0x1B, 0xFC, 0x63, 0xB7,
+        0xB5, 0x34, 0xF6, 0xEF, 0xDF, 0xD2, 0x14, 0xA9,
+    ];
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const EMOJI_EXPECTED_IV: &[u8] = &[
+        0x2C, 0xC9, 0xA3, 0xEF, 0x88, 0x9D, 0x22, 0x67,
+        0x87, 0xA5, 0x1A, 0xC7, 0xA5, 0x18, 0x5E, 0x4C,
+    ];
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const EMOJI_CIPHERTEXT: &[u8] = &[
+        0xEB, 0x06, 0x29, 0xB5, 0xBC, 0x08, 0xD0, 0xDF,
+        0x35, 0xFC, 0x68, 0x48, 0x3E, 0xE1, 0x28, 0x88,
+        0x21, 0xC8, 0xA1, 0x33, 0x75, 0x88, 0x1D, 0xE6,
+        0x1D, 0xC9, 0x26, 0xB7, 0x04, 0x77, 0xBF, 0x4F,
+        0x3F, 0x30, 0x9D, 0xF7, 0xE8, 0x1C, 0xBD, 0xC0,
+        0x76, 0x1D, 0x27, 0x88, 0x74, 0x75, 0x69, 0xF9,
+    ];
+    /// The key and IV which the Java client derives from an empty password.
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const EMPTY_EXPECTED_KEY: &[u8] = &[
+        0xAF, 0x09, 0x0C, 0x1A, 0x4A, 0xA9, 0x22, 0x05,
+        0x8F, 0x95, 0x21, 0xB8, 0xE5, 0xE0, 0x90, 0x62,
+        0xB5, 0x78, 0x55, 0x8B, 0xDA, 0xCB, 0xE2, 0xAB,
+        0xF8, 0x16, 0xB1, 0x74, 0x98, 0x85, 0x00, 0x2A,
+    ];
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const EMPTY_EXPECTED_IV: &[u8] = &[
+        0x48, 0x6C, 0x2B, 0x09, 0x86, 0x5A, 0x25, 0xD8,
+        0x54, 0x2E, 0x41, 0x32, 0x77, 0x2B, 0xA2, 0xD5,
+    ];
+
+    #[test]
+    fn test_str_to_bmpstring() {
+        use std::str;
+        assert!(str_to_bmpstring("").is_empty());
+        assert_eq!(&*str_to_bmpstring(HELLOWORLD_STR), HELLOWORLD_BMPSTRING);
+        assert_eq!(
+            &*str_to_bmpstring(str::from_utf8(FANCY_UTF8).unwrap()),
+            FANCY_BMPSTRING
+        );
+        assert_eq!(&*str_to_bmpstring(EMOJI_PASSWORD), EMOJI_BMPSTRING);
+    }
+
+    #[test]
+    fn test_str_to_legacy_bmpstring() {
+        use std::str;
+        assert_eq!(&*str_to_legacy_bmpstring(""), EMPTY_BMPSTRING);
+        assert_eq!(
+            &*str_to_legacy_bmpstring(HELLOWORLD_STR),
+            HELLOWORLD_BMPSTRING
+        );
+        assert_eq!(
+            &*str_to_legacy_bmpstring(str::from_utf8(FANCY_UTF8).unwrap()),
+            FANCY_BMPSTRING
+        );
+        assert_eq!(
+            &*str_to_legacy_bmpstring(EMOJI_PASSWORD),
+            EMOJI_LEGACY_BMPSTRING
+        );
+
+        // The legacy encoding collides with a password using U+F511 in place of U+1F511.
+        assert_eq!(
+            str_to_legacy_bmpstring("\u{1F511}"),
+            str_to_legacy_bmpstring("\u{F511}")
+        );
+        assert_ne!(str_to_bmpstring("\u{1F511}"), str_to_bmpstring("\u{F511}"));
+    }
+
+    #[test]
+    fn test_encodings_differ() {
+        assert!(!PasswordEncoding::encodings_differ(PASSWORD));
+        assert!(!PasswordEncoding::encodings_differ("p\u{E4}ssw\u{F6}rd"));
+        assert!(PasswordEncoding::encodings_differ(EMOJI_PASSWORD));
+        assert!(PasswordEncoding::encodings_differ(""));
+    }
+
+    #[test]
+    fn test_md5() {
+        let result = md5(b"abc");
+        assert_matches!(result, Ok(_));
+        assert_eq!(
+            result.unwrap(),
+            &[
+                0x90, 0x01, 0x50, 0x98, 0x3C, 0xD2, 0x4F, 0xB0, 0xD6, 0x96, 0x3F, 0x7D, 0x28, 0xE1,
+                0x7F, 0x72,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+
+    /// A self-signed certificate, and the SHA-256 digest of its DER encoding as reported by
+    /// `openssl x509 -noout -fingerprint -sha256`.
+    const CERTIFICATE_PEM: &str = "\
+        -----BEGIN CERTIFICATE-----\n\
+        MIIBfzCCASWgAwIBAgIURmWL8iy/SCnloTT85XWtn7K6ii4wCgYIKoZIzj0EAwIw\n\
+        FDESMBAGA1UEAwwJdHVwbSB0ZXN0MCAXDTI2MTAxNjIyMjk1NFoYDzIxMjYwOTIy\n\
+        MjIyOTU0WjAUMRIwEAYDVQQDDAl0dXBtIHRlc3QwWTATBgcqhkjOPQIBBggqhkjO\n\
+        PQMBBwNCAAT+HvWNJxoMAns2U9dnSWdWvKU3t2xG8PNKkRJvHELU8Q2GCswGsk8P\n\
+        9vb5VEoVMG+np6FCF9thL4+vSdN0StNDo1MwUTAdBgNVHQ4EFgQUaFJNCkI64RFA\n\
+        kg2lz4b+gXgW4eQwHwYDVR0jBBgwFoAUaFJNCkI64RFAkg2lz4b+gXgW4eQwDwYD\n\
+        VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiBqaa/fnVsbnDpMyJSYMSt4\n\
+        gmVzd73E6bISbpkW3KeOjQIhAOsEbimggs9tU7rph5I/1J1v5VjIv9y69KgIjB7e\n\
+        Brtm\n\
+        -----END CERTIFICATE-----\n\
+";
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    const CERTIFICATE_FINGERPRINT: &[u8] = &[
+        0xB0, 0x60, 0x2E, 0x40, 0x1A, 0x84, 0x7B, 0x16,
+        0x55, 0x54, 0x2F, 0x37, 0xFF, 0x8A, 0x4B, 0x00,
+        0x0E, 0x29, 0xF2, 0x67, 0x3A, 0xCD, 0xE7, 0xB9,
+        0x0E, 0x40, 0xC5, 0xC6, 0x68, 0x44, 0x09, 0x90,
+    ];
+
+    #[test]
+    fn test_pem_certificates_to_der() {
+        let bundle = format!("Test bundle\n{}{}", CERTIFICATE_PEM, CERTIFICATE_PEM);
+        let result = pem_certificates_to_der(bundle.as_bytes());
+        assert_matches!(result, Ok(_));
+        let certificates = result.unwrap();
+        assert_eq!(certificates.len(), 2);
+        for certificate in certificates {
+            assert_eq!(sha256(&certificate), CERTIFICATE_FINGERPRINT);
+        }
+        assert_eq!(
+            pem_certificates_to_der(b"no certificates").unwrap().len(),
+            0
+        );
+    }
+
+    // How a bad decrypt is reported by OpenSSL varies between its versions, so this is only
+    // checked against the pure-Rust backend.
+    #[cfg(feature = "rust_crypto")]
+    #[test]
+    fn test_decrypt_bad_password() {
+        let result = decrypt(
+            CIPHERTEXT,
+            "wrong password",
+            PasswordEncoding::Standard,
+            SALT,
+        );
+        assert_matches!(result, Err(UpmError::BadPassword));
+    }
+
     #[test]
     fn test_pkcs12_derive_key() {
         let mut pair = KeyIVPair::new();
-        let result = pkcs12_derive_key(PASSWORD, SALT, &mut pair);
+        let result = pkcs12_derive_key(PASSWORD, PasswordEncoding::Standard, SALT, &mut pair);
+        assert_matches!(result, Ok(_));
+        assert_eq!(pair.key, EXPECTED_KEY);
+        assert_eq!(pair.iv, EXPECTED_IV);
+
+        // Both encodings agree for passwords in the Basic Multilingual Plane.
+        let mut pair = KeyIVPair::new();
+        let result = pkcs12_derive_key(PASSWORD, PasswordEncoding::Legacy, SALT, &mut pair);
         assert_matches!(result, Ok(_));
         assert_eq!(pair.key, EXPECTED_KEY);
         assert_eq!(pair.iv, EXPECTED_IV);
+
+        let mut pair = KeyIVPair::new();
+        let result = pkcs12_derive_key(EMOJI_PASSWORD, PasswordEncoding::Standard, SALT, &mut pair);
+        assert_matches!(result, Ok(_));
+        assert_eq!(pair.key, EMOJI_EXPECTED_KEY);
+        assert_eq!(pair.iv, EMOJI_EXPECTED_IV);
+
+        let mut pair = KeyIVPair::new();
+        let result = pkcs12_derive_key("", PasswordEncoding::Standard, SALT, &mut pair);
+        assert_matches!(result, Ok(_));
+        assert_eq!(pair.key, EMPTY_EXPECTED_KEY);
+        assert_eq!(pair.iv, EMPTY_EXPECTED_IV);
+    }
+
+    #[test]
+    fn test_derive_mac_key() {
+        // The MAC key differs from the encryption key derived from the same password and salt.
+        let key = derive_mac_key(PASSWORD, SALT, None).unwrap();
+        assert_eq!(key.len(), KEY_MATERIAL_SIZE);
+        assert_ne!(&*key, EXPECTED_KEY);
+        assert_eq!(key, derive_mac_key(PASSWORD, SALT, None).unwrap());
+        assert_ne!(key, derive_mac_key(EMOJI_PASSWORD, SALT, None).unwrap());
+        assert_ne!(key, derive_mac_key(PASSWORD, &SALT[1..], None).unwrap());
+
+        let argon2_key = derive_mac_key(PASSWORD, SALT, Some(&TEST_KDF_PARAMS)).unwrap();
+        assert_eq!(argon2_key.len(), KEY_MATERIAL_SIZE);
+        assert_ne!(argon2_key, key);
+        let other_params = KdfParams {
+            iterations: 3,
+            ..TEST_KDF_PARAMS
+        };
+        assert_ne!(
+            argon2_key,
+            derive_mac_key(PASSWORD, SALT, Some(&other_params)).unwrap()
+        );
     }
 
     #[test]
     fn test_decrypt() {
-        let result = decrypt(CIPHERTEXT, PASSWORD, SALT);
+        let result = decrypt(CIPHERTEXT, PASSWORD, PasswordEncoding::Standard, SALT);
+        assert_matches!(result, Ok(_));
+        assert_eq!(&*result.unwrap(), PLAINTEXT);
+
+        let result = decrypt(
+            EMOJI_CIPHERTEXT,
+            EMOJI_PASSWORD,
+            PasswordEncoding::Standard,
+            SALT,
+        );
         assert_matches!(result, Ok(_));
-        assert_eq!(result.unwrap().as_slice(), PLAINTEXT);
+        assert_eq!(&*result.unwrap(), PLAINTEXT);
     }
 
     #[test]
     fn test_encrypt() {
-        let result = encrypt(PLAINTEXT, PASSWORD, SALT);
+        let result = encrypt(PLAINTEXT, PASSWORD, PasswordEncoding::Standard, SALT);
         assert_matches!(result, Ok(_));
         assert_eq!(result.unwrap().as_slice(), CIPHERTEXT);
+
+        let result = encrypt(PLAINTEXT, EMOJI_PASSWORD, PasswordEncoding::Standard, SALT);
+        assert_matches!(result, Ok(_));
+        assert_eq!(result.unwrap().as_slice(), EMOJI_CIPHERTEXT);
+    }
+
+    /// Argon2id parameters small enough to keep the tests fast.
+    const TEST_KDF_PARAMS: KdfParams = KdfParams {
+        memory_kib: 256,
+        iterations: 2,
+        parallelism: 2,
+    };
+    const NONCE: &[u8] = &[0x4E; NONCE_SIZE];
+    const ADDITIONAL_DATA: &[u8] = b"UPM\x04";
+
+    #[test]
+    fn test_aes_256_gcm() {
+        // Test cases 13 and 14 from "The Galois/Counter Mode of Operation (GCM)" by McGrew and
+        // Viega.
+        let key = [0u8; KEY_MATERIAL_SIZE];
+        let nonce = [0u8; NONCE_SIZE];
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let expected_empty: &[u8] = &[
+            0x53, 0x0F, 0x8A, 0xFB, 0xC7, 0x45, 0x36, 0xB9,
+            0xA9, 0x63, 0xB4, 0xF1, 0xC4, 0xCB, 0x73, 0x8B,
+        ];
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let expected_block: &[u8] = &[
+            0xCE, 0xA7, 0x40, 0x3D, 0x4D, 0x60, 0x6B, 0x6E,
+            0x07, 0x4E, 0xC5, 0xD3, 0xBA, 0xF3, 0x9D, 0x18,
+            0xD0, 0xD1, 0xC8, 0xA7, 0x99, 0x99, 0x6B, 0xF0,
+            0x26, 0x5B, 0x98, 0xB5, 0xD4, 0x8A, 0xB9, 0x19,
+        ];
+        let result = backend::aes_256_gcm_encrypt(&key, &nonce, &[], &[]);
+        assert_eq!(result.unwrap().as_slice(), expected_empty);
+        let result = backend::aes_256_gcm_encrypt(&key, &nonce, &[], &[0u8; 16]);
+        assert_eq!(result.unwrap().as_slice(), expected_block);
+
+        let result = backend::aes_256_gcm_decrypt(&key, &nonce, &[], expected_block);
+        assert_eq!(&*result.unwrap(), &[0u8; 16]);
+        let result = backend::aes_256_gcm_decrypt(&key, &nonce, &[], &expected_block[1..]);
+        assert_matches!(result, Err(UpmError::BadPassword));
+        let result = backend::aes_256_gcm_decrypt(&key, &nonce, &[], &expected_empty[1..]);
+        assert_matches!(result, Err(UpmError::ReadUnderrun));
+    }
+
+    #[test]
+    fn test_encrypt_hardened() {
+        let ciphertext = encrypt_hardened(
+            PLAINTEXT,
+            EMOJI_PASSWORD,
+            &TEST_KDF_PARAMS,
+            SALT,
+            NONCE,
+            ADDITIONAL_DATA,
+        )
+        .unwrap();
+        assert_eq!(ciphertext.len(), PLAINTEXT.len() + AUTHENTICATION_TAG_SIZE);
+
+        let result = decrypt_hardened(
+            &ciphertext,
+            EMOJI_PASSWORD,
+            &TEST_KDF_PARAMS,
+            SALT,
+            NONCE,
+            ADDITIONAL_DATA,
+        );
+        assert_eq!(&*result.unwrap(), PLAINTEXT);
+
+        // A wrong password, different parameters, or altered data must all be detected.
+        let result = decrypt_hardened(
+            &ciphertext,
+            PASSWORD,
+            &TEST_KDF_PARAMS,
+            SALT,
+            NONCE,
+            ADDITIONAL_DATA,
+        );
+        assert_matches!(result, Err(UpmError::BadPassword));
+        let other_params = KdfParams {
+            iterations: 3,
+            ..TEST_KDF_PARAMS
+        };
+        let result = decrypt_hardened(
+            &ciphertext,
+            EMOJI_PASSWORD,
+            &other_params,
+            SALT,
+            NONCE,
+            ADDITIONAL_DATA,
+        );
+        assert_matches!(result, Err(UpmError::BadPassword));
+        let result = decrypt_hardened(
+            &ciphertext,
+            EMOJI_PASSWORD,
+            &TEST_KDF_PARAMS,
+            SALT,
+            NONCE,
+            b"UPM\x03",
+        );
+        assert_matches!(result, Err(UpmError::BadPassword));
+        let mut altered = ciphertext.clone();
+        altered[0] ^= 0x01;
+        let result = decrypt_hardened(
+            &altered,
+            EMOJI_PASSWORD,
+            &TEST_KDF_PARAMS,
+            SALT,
+            NONCE,
+            ADDITIONAL_DATA,
+        );
+        assert_matches!(result, Err(UpmError::BadPassword));
+    }
+
+    #[test]
+    fn test_encrypt_hardened_bad_params() {
+        let params = KdfParams {
+            parallelism: 0,
+            ..TEST_KDF_PARAMS
+        };
+        let result = encrypt_hardened(PLAINTEXT, PASSWORD, &params, SALT, NONCE, &[]);
+        assert_matches!(result, Err(UpmError::BadKdfParams(_)));
+
+        assert_matches!(KdfParams::default().validate(), Ok(()));
+        assert_matches!(TEST_KDF_PARAMS.validate(), Ok(()));
+        let params = KdfParams {
+            memory_kib: 8 * TEST_KDF_PARAMS.parallelism - 1,
+            ..TEST_KDF_PARAMS
+        };
+        assert_matches!(params.validate(), Err(UpmError::BadKdfParams(_)));
+        let params = KdfParams {
+            memory_kib: KdfParams::MAX_MEMORY_KIB + 1,
+            ..TEST_KDF_PARAMS
+        };
+        assert_matches!(params.validate(), Err(UpmError::BadKdfParams(_)));
+        let params = KdfParams {
+            iterations: 0,
+            ..TEST_KDF_PARAMS
+        };
+        assert_matches!(params.validate(), Err(UpmError::BadKdfParams(_)));
+        let params = KdfParams {
+            iterations: KdfParams::MAX_ITERATIONS + 1,
+            ..TEST_KDF_PARAMS
+        };
+        assert_matches!(params.validate(), Err(UpmError::BadKdfParams(_)));
+    }
+
+    /// Check the pure-Rust backend against OpenSSL, when both are built.
+    #[cfg(all(feature = "openssl_crypto", feature = "rust_crypto"))]
+    mod cross_check {
+        use super::super::{openssl_backend, rust_backend};
+        use rand::{self, Rng};
+
+        #[test]
+        fn test_pkcs12_key_gen() {
+            let mut rng = rand::thread_rng();
+            for length in 0..80 {
+                let bmpstring: Vec<u8> = (0..length * 2 + 2).map(|_| rng.gen()).collect();
+                let salt: Vec<u8> = (0..length % 70).map(|_| rng.gen()).collect();
+                let id = (length % 3 + 1) as u8;
+                let iterations = length % 25 + 1;
+                let mut expected = vec![0u8; length + 1];
+                let mut actual = vec![0u8; length + 1];
+                openssl_backend::pkcs12_key_gen(&bmpstring, &salt, id, iterations, &mut expected)
+                    .unwrap();
+                rust_backend::pkcs12_key_gen(&bmpstring, &salt, id, iterations, &mut actual)
+                    .unwrap();
+                assert_eq!(actual, expected);
+            }
+        }
+
+        #[test]
+        fn test_aes_256_cbc() {
+            let mut rng = rand::thread_rng();
+            for length in 0..100 {
+                let key: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
+                let iv: Vec<u8> = (0..16).map(|_| rng.gen()).collect();
+                let plaintext: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+                let ciphertext = openssl_backend::aes_256_cbc_encrypt(&key, &iv, &plaintext);
+                assert_eq!(
+                    rust_backend::aes_256_cbc_encrypt(&key, &iv, &plaintext).unwrap(),
+                    ciphertext.as_ref().unwrap()[..]
+                );
+                assert_eq!(
+                    &*rust_backend::aes_256_cbc_decrypt(&key, &iv, &ciphertext.unwrap()).unwrap(),
+                    &plaintext[..]
+                );
+            }
+        }
+
+        #[test]
+        fn test_aes_256_gcm() {
+            let mut rng = rand::thread_rng();
+            for length in 0..100 {
+                let key: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
+                let nonce: Vec<u8> = (0..12).map(|_| rng.gen()).collect();
+                let aad: Vec<u8> = (0..length % 40).map(|_| rng.gen()).collect();
+                let plaintext: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+                let data =
+                    openssl_backend::aes_256_gcm_encrypt(&key, &nonce, &aad, &plaintext).unwrap();
+                assert_eq!(
+                    rust_backend::aes_256_gcm_encrypt(&key, &nonce, &aad, &plaintext).unwrap(),
+                    data
+                );
+                assert_eq!(
+                    &*rust_backend::aes_256_gcm_decrypt(&key, &nonce, &aad, &data).unwrap(),
+                    &plaintext[..]
+                );
+            }
+        }
+
+        #[test]
+        fn test_hashes() {
+            let mut rng = rand::thread_rng();
+            for length in 0..200 {
+                let data: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+                let key: Vec<u8> = (0..length % 100 + 1).map(|_| rng.gen()).collect();
+                assert_eq!(rust_backend::sha256(&data), openssl_backend::sha256(&data));
+                assert_eq!(
+                    rust_backend::md5(&data).unwrap(),
+                    openssl_backend::md5(&data).unwrap()
+                );
+                assert_eq!(
+                    rust_backend::hmac_sha256(&key, &data).unwrap(),
+                    openssl_backend::hmac_sha256(&key, &data).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex_encode(&[0x00, 0xff, 0x7a]), "00ff7a");
+        assert_eq!(hex_encode(&[]), "");
+        assert_eq!(hex_decode("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(hex_decode("00FF7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(hex_decode(""), Some(vec![]));
+        assert_eq!(hex_decode("00f"), None);
+        assert_eq!(hex_decode("0g"), None);
+        assert_eq!(hex_decode("+f"), None);
+        assert_eq!(hex_decode("-f"), None);
+        assert_eq!(hex_decode("é0"), None);
     }
 }
@@ -0,0 +1,142 @@
+//! The OpenSSL crypto backend.  This is used unless the `rust_crypto` feature is enabled.
+
+extern crate openssl;
+
+use super::AUTHENTICATION_TAG_SIZE;
+use error::UpmError;
+use openssl_extra;
+use secret::SecretBytes;
+
+/// Derive key material from the provided BMPString-encoded password using the PKCS#12 KDF with
+/// SHA-256.
+pub fn pkcs12_key_gen(
+    bmpstring: &[u8],
+    salt: &[u8],
+    id: u8,
+    iterations: usize,
+    out: &mut [u8],
+) -> Result<(), UpmError> {
+    openssl_extra::pkcs12_key_gen(
+        bmpstring,
+        salt,
+        id,
+        iterations,
+        out,
+        openssl::hash::MessageDigest::sha256(),
+    )
+    .map_err(|_| UpmError::KeyIVGeneration)
+}
+
+/// Decrypt AES-256-CBC ciphertext with PKCS#7 padding.
+pub fn aes_256_cbc_decrypt(
+    key: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+) -> Result<SecretBytes, UpmError> {
+    match openssl::symm::decrypt(
+        openssl::symm::Cipher::aes_256_cbc(),
+        key,
+        Some(iv),
+        ciphertext,
+    ) {
+        Ok(x) => Ok(SecretBytes::from(x)),
+        Err(error_stack) => {
+            if openssl_extra::is_bad_decrypt(&error_stack) {
+                Err(UpmError::BadPassword)
+            } else {
+                Err(From::from(error_stack))
+            }
+        }
+    }
+}
+
+/// Encrypt plaintext with AES-256-CBC and PKCS#7 padding.
+pub fn aes_256_cbc_encrypt(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, UpmError> {
+    match openssl::symm::encrypt(
+        openssl::symm::Cipher::aes_256_cbc(),
+        key,
+        Some(iv),
+        plaintext,
+    ) {
+        Ok(x) => Ok(x),
+        Err(error_stack) => {
+            if openssl_extra::is_bad_decrypt(&error_stack) {
+                Err(UpmError::BadPassword)
+            } else {
+                Err(From::from(error_stack))
+            }
+        }
+    }
+}
+
+/// Encrypt plaintext with AES-256-GCM, and append the authentication tag.
+pub fn aes_256_gcm_encrypt(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, UpmError> {
+    let mut tag = [0u8; AUTHENTICATION_TAG_SIZE];
+    let mut ciphertext = openssl::symm::encrypt_aead(
+        openssl::symm::Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        plaintext,
+        &mut tag,
+    )?;
+    ciphertext.extend_from_slice(&tag);
+    Ok(ciphertext)
+}
+
+/// Decrypt AES-256-GCM ciphertext followed by its authentication tag.  A tag which does not match
+/// (because the password is wrong, or the data was altered) is reported as
+/// `UpmError::BadPassword`.
+pub fn aes_256_gcm_decrypt(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    data: &[u8],
+) -> Result<SecretBytes, UpmError> {
+    if data.len() < AUTHENTICATION_TAG_SIZE {
+        return Err(UpmError::ReadUnderrun);
+    }
+    let (ciphertext, tag) = data.split_at(data.len() - AUTHENTICATION_TAG_SIZE);
+    openssl::symm::decrypt_aead(
+        openssl::symm::Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag,
+    )
+    .map(SecretBytes::from)
+    .map_err(|_| UpmError::BadPassword)
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    openssl::sha::sha256(data).to_vec()
+}
+
+pub fn md5(data: &[u8]) -> Result<Vec<u8>, UpmError> {
+    Ok(openssl::hash::hash(openssl::hash::MessageDigest::md5(), data)?.to_vec())
+}
+
+pub fn pem_certificates_to_der(pem: &[u8]) -> Result<Vec<Vec<u8>>, UpmError> {
+    let mut certificates = Vec::new();
+    for certificate in openssl::x509::X509::stack_from_pem(pem)? {
+        certificates.push(certificate.to_der()?);
+    }
+    Ok(certificates)
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, UpmError> {
+    let key = openssl::pkey::PKey::hmac(key)?;
+    let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
@@ -0,0 +1,228 @@
+//! The pure-Rust crypto backend, selected by the `rust_crypto` feature.  This needs no C toolchain
+//! or system OpenSSL.  AES, SHA-256, HMAC, and MD5 come from the RustCrypto crates; the PKCS#12
+//! KDF is implemented here, since no maintained crate provides it.
+
+extern crate aes;
+extern crate aes_gcm;
+extern crate cbc;
+extern crate hmac;
+extern crate md5;
+extern crate sha2;
+extern crate subtle;
+
+use self::aes::Aes256;
+use self::aes_gcm::aead::{Aead, Payload};
+use self::aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use self::cbc::cipher::block_padding::Pkcs7;
+use self::cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use self::hmac::{Hmac, Mac};
+use self::md5::Md5;
+use self::sha2::{Digest, Sha256};
+use self::subtle::ConstantTimeEq;
+use super::AUTHENTICATION_TAG_SIZE;
+use base64;
+use error::UpmError;
+use secret::SecretBytes;
+use zeroize::Zeroize;
+
+/// The output size of SHA-256, which is "u" in RFC 7292.
+const SHA256_OUTPUT_SIZE: usize = 32;
+/// The block size of SHA-256, which is "v" in RFC 7292.
+const SHA256_BLOCK_SIZE: usize = 64;
+
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// Fill `out` by repeating `data`.  `out` is left as zeros if `data` is empty.
+fn fill_repeating(out: &mut [u8], data: &[u8]) {
+    if !data.is_empty() {
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = data[i % data.len()];
+        }
+    }
+}
+
+/// Return the length of `data` rounded up to a whole number of SHA-256 blocks.
+fn padded_length(data: &[u8]) -> usize {
+    data.chunks(SHA256_BLOCK_SIZE).len() * SHA256_BLOCK_SIZE
+}
+
+/// Derive key material from the provided BMPString-encoded password using the PKCS#12 KDF with
+/// SHA-256, as specified in RFC 7292 Appendix B.2.  The steps are numbered as in the RFC.
+pub fn pkcs12_key_gen(
+    bmpstring: &[u8],
+    salt: &[u8],
+    id: u8,
+    iterations: usize,
+    out: &mut [u8],
+) -> Result<(), UpmError> {
+    if iterations == 0 {
+        return Err(UpmError::KeyIVGeneration);
+    }
+
+    // 1. Construct the diversifier D from the ID.
+    let diversifier = [id; SHA256_BLOCK_SIZE];
+
+    // 2-4. Concatenate the salt and password, each repeated to a whole number of blocks, to form
+    // I.  This holds the password, so it is wiped before returning.
+    let salt_length = padded_length(salt);
+    let mut input = vec![0u8; salt_length + padded_length(bmpstring)];
+    {
+        let (salt_part, password_part) = input.split_at_mut(salt_length);
+        fill_repeating(salt_part, salt);
+        fill_repeating(password_part, bmpstring);
+    }
+
+    // 6. Produce the output one hash at a time.
+    let mut hash = [0u8; SHA256_OUTPUT_SIZE];
+    let mut block = [0u8; SHA256_BLOCK_SIZE];
+    let chunk_count = out.chunks(SHA256_OUTPUT_SIZE).len();
+    for (index, chunk) in out.chunks_mut(SHA256_OUTPUT_SIZE).enumerate() {
+        // 6a. A = H^r(D || I)
+        let mut hasher = Sha256::new();
+        hasher.update(&diversifier[..]);
+        hasher.update(&input);
+        hash.copy_from_slice(&hasher.finalize());
+        for _ in 1..iterations {
+            let digest = Sha256::digest(hash);
+            hash.copy_from_slice(&digest);
+        }
+        chunk.copy_from_slice(&hash[..chunk.len()]);
+
+        // Step 6c is not needed once the last chunk is produced.
+        if index + 1 == chunk_count {
+            break;
+        }
+
+        // 6b. Form B by repeating A.
+        fill_repeating(&mut block, &hash);
+
+        // 6c. Treat each block of I as a big-endian integer, and set it to (I_j + B + 1) mod
+        // 2^(8v).
+        for input_block in input.chunks_mut(SHA256_BLOCK_SIZE) {
+            let mut carry = 1u16;
+            for (x, b) in input_block.iter_mut().zip(block.iter()).rev() {
+                let sum = *x as u16 + *b as u16 + carry;
+                *x = sum as u8;
+                carry = sum >> 8;
+            }
+        }
+    }
+
+    input.zeroize();
+    hash.zeroize();
+    block.zeroize();
+    Ok(())
+}
+
+/// Decrypt AES-256-CBC ciphertext with PKCS#7 padding.  Incorrect padding (usually caused by a
+/// bad password) is reported as `UpmError::BadPassword`.
+pub fn aes_256_cbc_decrypt(
+    key: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+) -> Result<SecretBytes, UpmError> {
+    let decryptor = cbc::Decryptor::<Aes256>::new_from_slices(key, iv)
+        .map_err(|_| UpmError::KeyIVGeneration)?;
+    decryptor
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map(SecretBytes::from)
+        .map_err(|_| UpmError::BadPassword)
+}
+
+/// Encrypt plaintext with AES-256-CBC and PKCS#7 padding.
+pub fn aes_256_cbc_encrypt(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, UpmError> {
+    let encryptor = cbc::Encryptor::<Aes256>::new_from_slices(key, iv)
+        .map_err(|_| UpmError::KeyIVGeneration)?;
+    Ok(encryptor.encrypt_padded_vec_mut::<Pkcs7>(plaintext))
+}
+
+/// Encrypt plaintext with AES-256-GCM, and append the authentication tag.
+pub fn aes_256_gcm_encrypt(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, UpmError> {
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| UpmError::KeyIVGeneration)?;
+    cipher
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| UpmError::KeyIVGeneration)
+}
+
+/// Decrypt AES-256-GCM ciphertext followed by its authentication tag.  A tag which does not match
+/// (because the password is wrong, or the data was altered) is reported as
+/// `UpmError::BadPassword`.
+pub fn aes_256_gcm_decrypt(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    data: &[u8],
+) -> Result<SecretBytes, UpmError> {
+    if data.len() < AUTHENTICATION_TAG_SIZE {
+        return Err(UpmError::ReadUnderrun);
+    }
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| UpmError::KeyIVGeneration)?;
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+        .map(SecretBytes::from)
+        .map_err(|_| UpmError::BadPassword)
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+pub fn md5(data: &[u8]) -> Result<Vec<u8>, UpmError> {
+    Ok(Md5::digest(data).to_vec())
+}
+
+/// Decode each `CERTIFICATE` block of the provided PEM data.  Text outside the blocks is ignored,
+/// as OpenSSL does.
+pub fn pem_certificates_to_der(pem: &[u8]) -> Result<Vec<Vec<u8>>, UpmError> {
+    let pem = String::from_utf8_lossy(pem);
+    let mut certificates = Vec::new();
+    let mut base64_text: Option<String> = None;
+    for line in pem.lines().map(|l| l.trim()) {
+        match base64_text.take() {
+            None => {
+                if line == PEM_CERTIFICATE_BEGIN {
+                    base64_text = Some(String::new());
+                }
+            }
+            Some(text) => {
+                if line == PEM_CERTIFICATE_END {
+                    let der = base64::decode(&text)
+                        .map_err(|e| UpmError::Pem(format!("invalid certificate: {}", e)))?;
+                    certificates.push(der);
+                } else {
+                    base64_text = Some(text + line);
+                }
+            }
+        }
+    }
+    if base64_text.is_some() {
+        return Err(UpmError::Pem(format!(
+            "missing \"{}\" line",
+            PEM_CERTIFICATE_END
+        )));
+    }
+    Ok(certificates)
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, UpmError> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && bool::from(a.ct_eq(b))
+}
@@ -5,6 +5,9 @@
 //! Versions 1 and 2 of the UPM database format are not supported.  Version 3 was introduced in
 //! 2011, so there may not be many cases where the older versions are still in use.
 //!
+//! An optional hardened format (version 4) is also supported, which only tupm can read.  See
+//! [`DatabaseFormat`](enum.DatabaseFormat.html).
+//!
 //! # Database format
 //!
 //! UPMv3 databases are stored in the following format:
@@ -34,10 +37,26 @@
 //!        3. Password
 //!        4. URL
 //!        5. Notes
-
+//!
+//! Hardened databases replace the salt and the version 3 encryption with the following, and
+//! contain the same flatpack records:
+//!
+//! * A 1-byte KDF field.  (Only 1, Argon2id, is defined.)
+//! * The Argon2id memory size in KiB, iteration count, and parallelism, each a 4-byte big-endian
+//!   integer.
+//! * The 16-byte salt.
+//! * The 12-byte nonce.
+//! * The remainder of the file is encrypted using AES-256-GCM, with a 16-byte authentication tag
+//!   appended.  The entire header, starting with the magic, is authenticated as additional data.
+
+use backup;
 use crypto;
+pub use crypto::KdfParams;
+use crypto::PasswordEncoding;
+use diff::DatabaseDiff;
 use error::UpmError;
 use rand::{OsRng, Rng};
+use secret::{SecretBytes, SecretString};
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::fmt;
@@ -48,8 +67,9 @@ use std::io::Read;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::time::Instant;
+use std::time::SystemTime;
 
 /// The size in bytes of the UPM header magic field.
 const MAGIC_SIZE: usize = 3;
@@ -61,6 +81,19 @@ const UPM_DB_VERSION_SIZE: usize = 1;
 const UPM_DB_VERSION: u8 = 3;
 /// The size in bytes of the header salt field.
 const SALT_SIZE: usize = 8;
+/// The database version of the hardened format.
+const UPM_DB_VERSION_HARDENED: u8 = 4;
+/// The KDF field value which identifies Argon2id.
+const KDF_ARGON2ID: u8 = 1;
+/// The size in bytes of the hardened header KDF parameter fields.
+const KDF_PARAMS_SIZE: usize = 1 + 3 * 4;
+/// The size in bytes of the hardened header salt field.
+const HARDENED_SALT_SIZE: usize = 16;
+/// The size in bytes of the complete hardened header.
+const HARDENED_HEADER_SIZE: usize =
+    MAGIC_SIZE + UPM_DB_VERSION_SIZE + KDF_PARAMS_SIZE + HARDENED_SALT_SIZE + crypto::NONCE_SIZE;
+/// The size in bytes of the salt from which a content MAC key is derived.
+const CONTENT_MAC_SALT_SIZE: usize = 16;
 
 /// After this much time elapses from the last synch, the database will once again be considered
 /// unsynced (i.e. dirty).  This mimics the behavior of the java-swing UPM client.
@@ -75,7 +108,7 @@ const FLATPACK_MAX_RECORD_SIZE: usize = 9999;
 /// UTF-8 encoded decimal which specifies the size in bytes of the record payload which follows.
 /// The payload is always a UTF-8 string; integers are encoded as decimal digits.
 struct FlatpackParser {
-    buffer: Vec<u8>,
+    buffer: SecretBytes,
     position: usize,
     error: bool,
 }
@@ -131,7 +164,7 @@ impl<'a> Iterator for FlatpackParser {
 
 impl FlatpackParser {
     /// Construct a new flatpack parser with the provided byte buffer.
-    fn new(buffer: Vec<u8>) -> FlatpackParser {
+    fn new(buffer: SecretBytes) -> FlatpackParser {
         FlatpackParser {
             buffer: buffer,
             position: 0,
@@ -182,14 +215,14 @@ impl FlatpackParser {
 
 /// This struct provides a means of encoding data as flatpack records.
 struct FlatpackWriter {
-    buffer: Vec<u8>,
+    buffer: SecretBytes,
 }
 
 impl FlatpackWriter {
     /// Construct a new flatpack writer.
     fn new() -> FlatpackWriter {
         FlatpackWriter {
-            buffer: Vec::<u8>::new(),
+            buffer: SecretBytes::default(),
         }
     }
 
@@ -200,9 +233,10 @@ impl FlatpackWriter {
             return Err(UpmError::FlatpackOverflow);
         }
         // Write the length prefix
-        self.buffer.extend(format!("{:04}", data.len()).as_bytes());
+        self.buffer
+            .extend_from_slice(format!("{:04}", data.len()).as_bytes());
         // Write the data
-        self.buffer.extend(data);
+        self.buffer.extend_from_slice(data);
         Ok(())
     }
 
@@ -220,12 +254,13 @@ impl FlatpackWriter {
 }
 
 /// This struct represents a single UPM account, and provides an ordering based on the
-/// alphanumeric case-insensitive comparison of account names.
+/// alphanumeric case-insensitive comparison of account names.  The password is wiped from memory
+/// when the account is dropped.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Account {
     pub name: String,
     pub user: String,
-    pub password: String,
+    pub password: SecretString,
     pub url: String,
     pub notes: String,
 }
@@ -236,7 +271,7 @@ impl Account {
         Account {
             name: String::new(),
             user: String::new(),
-            password: String::new(),
+            password: SecretString::default(),
             url: String::new(),
             notes: String::new(),
         }
@@ -256,6 +291,29 @@ impl PartialOrd for Account {
     }
 }
 
+/// The on-disk format of a database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatabaseFormat {
+    /// The UPMv3 format, which the UPM Java client and the UPM sync server can read.
+    V3,
+    /// The hardened format, using Argon2id with the provided parameters and AES-256-GCM.  Only
+    /// tupm can read this format.
+    Hardened(KdfParams),
+}
+
+impl fmt::Display for DatabaseFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DatabaseFormat::V3 => write!(f, "version 3"),
+            DatabaseFormat::Hardened(ref params) => write!(
+                f,
+                "hardened (Argon2id with {} KiB, {} iterations, parallelism {})",
+                params.memory_kib, params.iterations, params.parallelism
+            ),
+        }
+    }
+}
+
 /// This struct represents a UPM database, as read from a local file or a remote sync repository.
 #[derive(Clone)]
 pub struct Database {
@@ -268,10 +326,41 @@ pub struct Database {
     path: Option<PathBuf>,
     /// Track the password used to decrypt this database, so it can be used to re-encrypt when
     /// saving and syncing.
-    password: Option<String>,
+    password: Option<SecretString>,
     /// Record the time of last sync.  Some edit features only work when the database has been
     /// recently synced.
-    last_synced: Option<Instant>,
+    last_synced: Option<SystemTime>,
+    /// While a transaction is open, the content of the database when it began, so the
+    /// transaction can be rolled back.
+    transaction: Option<Snapshot>,
+    /// True if the database was decrypted using the legacy password encoding of earlier versions
+    /// of tupm.  It is always saved using the standard encoding.
+    legacy_password_encoding: bool,
+    /// The format used when saving the database, which is the format it was loaded from unless
+    /// changed.
+    format: DatabaseFormat,
+    /// The most recently derived content MAC key, which is slow to derive on purpose.  Clones of
+    /// the database share it.
+    content_mac_key: Arc<Mutex<Option<ContentMacKey>>>,
+}
+
+/// A content MAC key, along with the salt, password, and format it was derived from.  It is only
+/// reused while all three still apply.
+struct ContentMacKey {
+    salt: Vec<u8>,
+    password: SecretString,
+    format: DatabaseFormat,
+    key: SecretBytes,
+}
+
+/// The content of a database which a rolled-back transaction restores.
+#[derive(Clone)]
+struct Snapshot {
+    sync_url: String,
+    sync_credentials: String,
+    accounts: Vec<Account>,
+    password: Option<SecretString>,
+    format: DatabaseFormat,
 }
 
 impl fmt::Debug for Database {
@@ -296,6 +385,10 @@ impl Database {
             path: None,
             password: None,
             last_synced: None,
+            transaction: None,
+            legacy_password_encoding: false,
+            format: DatabaseFormat::V3,
+            content_mac_key: Arc::default(),
         }
     }
 
@@ -317,14 +410,71 @@ impl Database {
             return Err(UpmError::BadMagic);
         }
         let (db_version, remainder) = unshift(remainder, UPM_DB_VERSION_SIZE);
+        if db_version[0] == UPM_DB_VERSION_HARDENED {
+            return Database::load_hardened(bytes, password);
+        }
         if db_version[0] != UPM_DB_VERSION {
             return Err(UpmError::BadVersion(db_version[0]));
         }
         let (salt, ciphertext) = unshift(remainder, SALT_SIZE);
 
-        // Decrypt the ciphertext
-        let plaintext = crypto::decrypt(&ciphertext, password, &salt)?;
+        // Decrypt the ciphertext.  Earlier versions of tupm encoded passwords containing
+        // characters outside the Basic Multilingual Plane (or empty passwords) differently from
+        // the UPM Java client, so if such a password fails, try again with the old encoding.  A
+        // bad password does not always cause a decryption error, so parsing errors are retried as
+        // well.
+        let decrypt = |encoding| {
+            crypto::decrypt(ciphertext, password, encoding, salt)
+                .and_then(|plaintext| Database::decode(plaintext, password))
+        };
+        match decrypt(PasswordEncoding::Standard) {
+            Err(e) => {
+                if !PasswordEncoding::encodings_differ(password) {
+                    return Err(e);
+                }
+                match decrypt(PasswordEncoding::Legacy) {
+                    Ok(mut database) => {
+                        database.legacy_password_encoding = true;
+                        Ok(database)
+                    }
+                    Err(_) => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Load and decrypt a database in the hardened format.
+    fn load_hardened(bytes: &[u8], password: &str) -> Result<Database, UpmError> {
+        if bytes.len() < HARDENED_HEADER_SIZE {
+            return Err(UpmError::ReadUnderrun);
+        }
+        let (header, ciphertext) = bytes.split_at(HARDENED_HEADER_SIZE);
+        let fields = &header[MAGIC_SIZE + UPM_DB_VERSION_SIZE..];
+        if fields[0] != KDF_ARGON2ID {
+            return Err(UpmError::BadKdfParams(format!("unknown KDF {}", fields[0])));
+        }
+        let read_u32 = |offset: usize| {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(&fields[offset..offset + 4]);
+            u32::from_be_bytes(value)
+        };
+        let params = KdfParams {
+            memory_kib: read_u32(1),
+            iterations: read_u32(5),
+            parallelism: read_u32(9),
+        };
+        let (salt, nonce) = fields[KDF_PARAMS_SIZE..].split_at(HARDENED_SALT_SIZE);
 
+        let plaintext =
+            crypto::decrypt_hardened(ciphertext, password, &params, salt, nonce, header)?;
+        let mut database = Database::decode(plaintext, password)?;
+        database.format = DatabaseFormat::Hardened(params);
+        Ok(database)
+    }
+
+    /// Parse the decrypted contents of a database.
+    fn decode(plaintext: SecretBytes, password: &str) -> Result<Database, UpmError> {
         // The resulting plaintext is encoded as a series of "flatpack" records.
         let mut pack = FlatpackParser::new(plaintext);
 
@@ -346,7 +496,7 @@ impl Database {
             let record = Account {
                 name: elements.0,
                 user: elements.1,
-                password: elements.2,
+                password: SecretString::new(elements.2),
                 url: elements.3,
                 notes: elements.4,
             };
@@ -368,8 +518,12 @@ impl Database {
             sync_credentials: sync_credentials,
             accounts: accounts,
             path: None,
-            password: Some(String::from(password)),
+            password: Some(SecretString::from(password)),
             last_synced: None,
+            transaction: None,
+            legacy_password_encoding: false,
+            format: DatabaseFormat::V3,
+            content_mac_key: Arc::default(),
         })
     }
 
@@ -439,22 +593,55 @@ impl Database {
         Ok(())
     }
 
-    /// Save the database to an in-memory byte buffer.  This is useful, for example, when sending
-    /// the database to a remote sync repository.
+    /// Save the database to an in-memory byte buffer, in the database's format.  This is useful,
+    /// for example, when sending the database to a remote sync repository.
     pub fn save_to_bytes(&self, password: &str) -> Result<Vec<u8>, UpmError> {
+        let plaintext = self.encode()?;
         let mut buffer: Vec<u8> = vec![];
-
-        // Generate a salt
         let mut rng = OsRng::new().ok().unwrap();
-        let mut salt = [0u8; SALT_SIZE];
-        rng.fill_bytes(&mut salt);
-
-        // Write unencrypted metadata
         buffer.extend_from_slice(&UPM_MAGIC);
-        buffer.extend_from_slice(&[UPM_DB_VERSION]);
-        buffer.extend_from_slice(&salt);
+        match self.format {
+            DatabaseFormat::V3 => {
+                // Generate a salt
+                let mut salt = [0u8; SALT_SIZE];
+                rng.fill_bytes(&mut salt);
+
+                // Write unencrypted metadata
+                buffer.extend_from_slice(&[UPM_DB_VERSION]);
+                buffer.extend_from_slice(&salt);
+
+                // Encrypt and write to the file
+                let ciphertext =
+                    crypto::encrypt(&plaintext, password, PasswordEncoding::Standard, &salt)?;
+                buffer.extend_from_slice(ciphertext.as_slice());
+            }
+            DatabaseFormat::Hardened(ref params) => {
+                // Generate a salt and nonce
+                let mut salt = [0u8; HARDENED_SALT_SIZE];
+                rng.fill_bytes(&mut salt);
+                let mut nonce = [0u8; crypto::NONCE_SIZE];
+                rng.fill_bytes(&mut nonce);
+
+                // Write unencrypted metadata, which is authenticated along with the ciphertext.
+                buffer.extend_from_slice(&[UPM_DB_VERSION_HARDENED, KDF_ARGON2ID]);
+                buffer.extend_from_slice(&params.memory_kib.to_be_bytes());
+                buffer.extend_from_slice(&params.iterations.to_be_bytes());
+                buffer.extend_from_slice(&params.parallelism.to_be_bytes());
+                buffer.extend_from_slice(&salt);
+                buffer.extend_from_slice(&nonce);
+
+                // Encrypt and write to the file
+                let ciphertext =
+                    crypto::encrypt_hardened(&plaintext, password, params, &salt, &nonce, &buffer)?;
+                buffer.extend_from_slice(ciphertext.as_slice());
+            }
+        }
+        Ok(buffer)
+    }
 
-        // Write encrypted metadata
+    /// Encode the contents of the database as flatpack records, ready to be encrypted.
+    fn encode(&self) -> Result<SecretBytes, UpmError> {
+        // Write metadata
         let mut pack = FlatpackWriter::new();
         pack.put_u32(self.sync_revision)?;
         pack.put_string(&self.sync_url)?;
@@ -468,11 +655,7 @@ impl Database {
             pack.put_string(&account.url)?;
             pack.put_string(&account.notes)?;
         }
-
-        // Encrypt and write to the file
-        let ciphertext = crypto::encrypt(&pack.buffer, password, &salt)?;
-        buffer.extend_from_slice(ciphertext.as_slice());
-        Ok(buffer)
+        Ok(pack.buffer)
     }
 
     /// Return a reference to the named account.
@@ -527,6 +710,65 @@ impl Database {
         self.accounts.retain(|ref a| a.name != name);
     }
 
+    /// Begin a transaction.  Any number of changes to the accounts, the sync settings, the
+    /// password, and the format may then be made, and commit() saves them all as a single
+    /// revision, or rollback() discards them.  If a transaction is already open (for example,
+    /// because its commit failed), the changes join it.
+    pub fn begin(&mut self) {
+        if self.transaction.is_none() {
+            self.transaction = Some(Snapshot {
+                sync_url: self.sync_url.clone(),
+                sync_credentials: self.sync_credentials.clone(),
+                accounts: self.accounts.clone(),
+                password: self.password.clone(),
+                format: self.format,
+            });
+        }
+    }
+
+    /// Return true if a transaction is open; otherwise return false.
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Save the changes made since begin() as a new revision: increment the revision, back up the
+    /// previously saved database, and save.  Without an open transaction, the changes made since
+    /// the last save are saved the same way.  If saving fails, the revision is left unchanged and
+    /// the transaction remains open, so it can be committed again or rolled back.
+    pub fn commit(&mut self) -> Result<(), UpmError> {
+        self.sync_revision += 1;
+        if let Err(e) = self.save_revision() {
+            self.sync_revision -= 1;
+            return Err(e);
+        }
+        self.transaction = None;
+        Ok(())
+    }
+
+    /// Back up the previously saved database, if any, then save this one.
+    fn save_revision(&self) -> Result<(), UpmError> {
+        if ::PARANOID_BACKUPS {
+            if let Some(path) = self.path() {
+                if let Err(e) = backup::backup(path) {
+                    return Err(UpmError::Backup(e.to_string()));
+                }
+            }
+        }
+        self.save()
+    }
+
+    /// Discard the changes made since begin(), and close the transaction.  This does nothing if
+    /// no transaction is open.
+    pub fn rollback(&mut self) {
+        if let Some(snapshot) = self.transaction.take() {
+            self.sync_url = snapshot.sync_url;
+            self.sync_credentials = snapshot.sync_credentials;
+            self.accounts = snapshot.accounts;
+            self.password = snapshot.password;
+            self.format = snapshot.format;
+        }
+    }
+
     /// Return true if this database has a remote sync repository configured; otherwise return
     /// false.
     pub fn has_remote(&self) -> bool {
@@ -587,7 +829,7 @@ impl Database {
 
     /// Set the password used to encrypt this database.
     pub fn set_password<P: AsRef<str>>(&mut self, password: &P) {
-        self.password = Some(password.as_ref().to_owned());
+        self.password = Some(SecretString::from(password.as_ref()));
     }
 
     /// Retrieve the password used to encrypt and decrypt this database.
@@ -598,10 +840,38 @@ impl Database {
         }
     }
 
+    /// Return true if the database was encrypted by an earlier version of tupm, using a password
+    /// encoding which the UPM Java client cannot decrypt.  The database will be re-encrypted
+    /// using the standard encoding the next time it is saved.
+    pub fn has_legacy_password_encoding(&self) -> bool {
+        self.legacy_password_encoding
+    }
+
+    /// Return the format which will be used when the database is saved.
+    pub fn format(&self) -> DatabaseFormat {
+        self.format
+    }
+
+    /// Set the format to use when the database is saved.  Hardened parameters are validated here,
+    /// so a bad value is reported before anything is written.
+    pub fn set_format(&mut self, format: DatabaseFormat) -> Result<(), UpmError> {
+        if let DatabaseFormat::Hardened(ref params) = format {
+            params.validate()?;
+        }
+        self.format = format;
+        Ok(())
+    }
+
     /// Mark the database as being synchronized with the remote sync repository.  This is only
     /// valid for 5 minutes.
     pub fn set_synced(&mut self) {
-        self.last_synced = Some(Instant::now());
+        self.set_synced_at(SystemTime::now());
+    }
+
+    /// Mark the database as having been synchronized with the remote sync repository at the
+    /// provided time.  This is useful when restoring a sync status which was recorded earlier.
+    pub fn set_synced_at(&mut self, time: SystemTime) {
+        self.last_synced = Some(time);
     }
 
     /// Mark the database as not being synchronized with the remote sync repository.
@@ -612,10 +882,125 @@ impl Database {
     /// Return true if the database is synchronized with the remote sync repository; otherwise
     /// return false.
     pub fn is_synced(&self) -> bool {
-        match self.last_synced {
-            Some(t) => t.elapsed() < Duration::from_secs(SYNC_VALIDITY_SECS),
-            None => false,
+        match self.last_synced.map(|t| t.elapsed()) {
+            Some(Ok(elapsed)) => elapsed < Duration::from_secs(SYNC_VALIDITY_SECS),
+            // A sync time in the future means the clock has changed, so don't trust it.
+            Some(Err(_)) | None => false,
+        }
+    }
+
+    /// Describe how the provided database differs from this one, with the passwords masked.
+    pub fn diff(&self, other: &Database) -> DatabaseDiff {
+        DatabaseDiff::new(self, other, false)
+    }
+
+    /// Describe how the provided database differs from this one, including the passwords.
+    pub fn diff_with_passwords(&self, other: &Database) -> DatabaseDiff {
+        DatabaseDiff::new(self, other, true)
+    }
+
+    /// Return a SHA-256 hash of the database content: the sync settings and the accounts, but not
+    /// the revision.  Accounts are hashed in order of their names, so the hash does not depend on
+    /// the order of the accounts.  This allows determining whether two copies of a database have
+    /// the same content, even though their encrypted forms always differ.
+    ///
+    /// The hash covers the passwords, so it must not be stored in the clear; use
+    /// [`content_mac()`](#method.content_mac) for that.
+    pub fn content_hash(&self) -> Vec<u8> {
+        crypto::sha256(&self.content_bytes())
+    }
+
+    /// Return an HMAC-SHA256 of the database content, as covered by
+    /// [`content_hash()`](#method.content_hash), keyed with the provided key.  The key should come
+    /// from [`content_mac_key()`](#method.content_mac_key).
+    pub fn content_mac(&self, key: &[u8]) -> Result<Vec<u8>, UpmError> {
+        crypto::hmac_sha256(key, &self.content_bytes())
+    }
+
+    /// Derive a key for [`content_mac()`](#method.content_mac) from the database password and the
+    /// provided salt.  The KDF of the database's format is used, so a MAC stored in the clear
+    /// allows checking password guesses no faster than the database itself does.
+    ///
+    /// That makes the key as slow to derive as the database is to unlock, so the most recently
+    /// derived key is kept with the database, and returned again for the same salt while the
+    /// password and format are unchanged.
+    pub fn content_mac_key(&self, salt: &[u8]) -> Result<SecretBytes, UpmError> {
+        if let Some(key) = self.cached_content_mac_key(Some(salt)) {
+            return Ok(key.1);
         }
+        let password = match self.password() {
+            Some(p) => p,
+            None => return Err(UpmError::NoDatabasePassword),
+        };
+        let key = match self.format {
+            DatabaseFormat::V3 => crypto::derive_mac_key(password, salt, None)?,
+            DatabaseFormat::Hardened(ref params) => {
+                crypto::derive_mac_key(password, salt, Some(params))?
+            }
+        };
+        if let Ok(mut cache) = self.content_mac_key.lock() {
+            *cache = Some(ContentMacKey {
+                salt: salt.to_vec(),
+                password: SecretString::from(password),
+                format: self.format,
+                key: key.clone(),
+            });
+        }
+        Ok(key)
+    }
+
+    /// Return a salt and the key derived from it, as from
+    /// [`content_mac_key()`](#method.content_mac_key).  The most recently derived key is reused
+    /// if it still applies; otherwise, a key is derived from a fresh salt.
+    pub fn content_mac_salt_and_key(&self) -> Result<(Vec<u8>, SecretBytes), UpmError> {
+        if let Some(salt_and_key) = self.cached_content_mac_key(None) {
+            return Ok(salt_and_key);
+        }
+        let mut salt = vec![0u8; CONTENT_MAC_SALT_SIZE];
+        OsRng::new()?.fill_bytes(&mut salt);
+        let key = self.content_mac_key(&salt)?;
+        Ok((salt, key))
+    }
+
+    /// Return the most recently derived content MAC key with its salt, if it was derived from the
+    /// current password and format (and the provided salt, if any).
+    fn cached_content_mac_key(&self, salt: Option<&[u8]>) -> Option<(Vec<u8>, SecretBytes)> {
+        let cache = self.content_mac_key.lock().ok()?;
+        let cached = cache.as_ref()?;
+        let salt_matches = match salt {
+            Some(salt) => salt == &cached.salt[..],
+            None => true,
+        };
+        if salt_matches
+            && self.password() == Some(cached.password.as_str())
+            && self.format == cached.format
+        {
+            Some((cached.salt.clone(), cached.key.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Encode the content covered by [`content_hash()`](#method.content_hash) unambiguously.
+    fn content_bytes(&self) -> SecretBytes {
+        fn put(buffer: &mut SecretBytes, value: &str) {
+            buffer.extend_from_slice(format!("{}:", value.len()).as_bytes());
+            buffer.extend_from_slice(value.as_bytes());
+        }
+        let mut accounts: Vec<&Account> = self.accounts.iter().collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut buffer = SecretBytes::default();
+        put(&mut buffer, &self.sync_url);
+        put(&mut buffer, &self.sync_credentials);
+        for account in accounts {
+            put(&mut buffer, &account.name);
+            put(&mut buffer, &account.user);
+            put(&mut buffer, &account.password);
+            put(&mut buffer, &account.url);
+            put(&mut buffer, &account.notes);
+        }
+        buffer
     }
 }
 
@@ -636,6 +1021,7 @@ impl fmt::Display for Database {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile;
 
     #[test]
     fn test_flatpack() {
@@ -698,7 +1084,7 @@ mod tests {
             accounts.push(Account {
                 name: String::from(*name),
                 user: String::from("user"),
-                password: String::from("password"),
+                password: SecretString::from("password"),
                 url: String::from("url"),
                 notes: String::from("notes"),
             });
@@ -768,7 +1154,7 @@ mod tests {
         let result = database.add_account(&Account {
             name: String::from("acct2"),
             user: String::from("user2"),
-            password: String::from("pass2"),
+            password: SecretString::from("pass2"),
             url: String::from(""),
             notes: String::from(""),
         });
@@ -777,7 +1163,7 @@ mod tests {
         let result = database.add_account(&Account {
             name: String::from("acct3"),
             user: String::from("user3"),
-            password: String::from("pass3"),
+            password: SecretString::from("pass3"),
             url: String::from(""),
             notes: String::from(""),
         });
@@ -788,7 +1174,7 @@ mod tests {
             &Account {
                 name: String::from("acct1"),
                 user: String::from("user1"),
-                password: String::from("pass1"),
+                password: SecretString::from("pass1"),
                 url: String::from(""),
                 notes: String::from(""),
             },
@@ -804,7 +1190,7 @@ mod tests {
             &Account {
                 name: String::from("acct3"),
                 user: String::from("user1"),
-                password: String::from("pass1"),
+                password: SecretString::from("pass1"),
                 url: String::from(""),
                 notes: String::from(""),
             },
@@ -813,7 +1199,7 @@ mod tests {
         let result = database.add_account(&Account {
             name: String::from("acct1"),
             user: String::from("user1"),
-            password: String::from("pass1"),
+            password: SecretString::from("pass1"),
             url: String::from(""),
             notes: String::from(""),
         });
@@ -839,6 +1225,279 @@ mod tests {
         assert_eq!(database.account("acct3").unwrap().password, "pass3");
     }
 
+    #[test]
+    fn test_legacy_password_encoding() {
+        const EMOJI_PASSWORD: &str = "\u{1F511} xyzzy";
+        let database = Database::load_from_bytes(DATABASE_BYTES, PASSWORD).unwrap();
+        assert!(!database.has_legacy_password_encoding());
+
+        // A database saved by this version uses the standard encoding.
+        let bytes = database.save_to_bytes(EMOJI_PASSWORD).unwrap();
+        let loaded = Database::load_from_bytes(&bytes, EMOJI_PASSWORD).unwrap();
+        assert!(!loaded.has_legacy_password_encoding());
+
+        // A database saved with the legacy encoding still loads, and is reported.
+        let legacy_bytes = {
+            let (header, _) = bytes.split_at(MAGIC_SIZE + UPM_DB_VERSION_SIZE + SALT_SIZE);
+            let salt = &header[MAGIC_SIZE + UPM_DB_VERSION_SIZE..];
+            let plaintext = crypto::decrypt(
+                &bytes[header.len()..],
+                EMOJI_PASSWORD,
+                PasswordEncoding::Standard,
+                salt,
+            )
+            .unwrap();
+            let mut legacy_bytes = header.to_vec();
+            legacy_bytes.extend_from_slice(
+                &crypto::encrypt(&plaintext, EMOJI_PASSWORD, PasswordEncoding::Legacy, salt)
+                    .unwrap(),
+            );
+            legacy_bytes
+        };
+        assert_ne!(legacy_bytes, bytes);
+        let loaded = Database::load_from_bytes(&legacy_bytes, EMOJI_PASSWORD).unwrap();
+        assert!(loaded.has_legacy_password_encoding());
+        assert_accounts(&loaded, &["acct"]);
+    }
+
+    #[test]
+    fn test_hardened_format() {
+        // Small parameters keep the test fast.
+        let params = KdfParams {
+            memory_kib: 256,
+            iterations: 2,
+            parallelism: 2,
+        };
+        let mut database = Database::load_from_bytes(DATABASE_BYTES, PASSWORD).unwrap();
+        assert_eq!(database.format(), DatabaseFormat::V3);
+        assert_matches!(
+            database.set_format(DatabaseFormat::Hardened(params)),
+            Ok(())
+        );
+
+        // A hardened database round-trips, and remembers its format and parameters.
+        let bytes = database.save_to_bytes(PASSWORD).unwrap();
+        assert_eq!(&bytes[..MAGIC_SIZE + UPM_DB_VERSION_SIZE], b"UPM\x04");
+        let loaded = Database::load_from_bytes(&bytes, PASSWORD).unwrap();
+        assert_eq!(loaded.format(), DatabaseFormat::Hardened(params));
+        assert_eq!(loaded.sync_revision, database.sync_revision);
+        assert_accounts(&loaded, &["acct"]);
+        assert_eq!(loaded.content_hash(), database.content_hash());
+
+        // A wrong password, or a change to any part of the file, is detected.
+        assert_matches!(
+            Database::load_from_bytes(&bytes, "wrong password"),
+            Err(UpmError::BadPassword)
+        );
+        for &offset in &[
+            MAGIC_SIZE + UPM_DB_VERSION_SIZE + KDF_PARAMS_SIZE,
+            HARDENED_HEADER_SIZE - 1,
+            HARDENED_HEADER_SIZE,
+            bytes.len() - 1,
+        ] {
+            let mut altered = bytes.clone();
+            altered[offset] ^= 0x01;
+            assert_matches!(
+                Database::load_from_bytes(&altered, PASSWORD),
+                Err(UpmError::BadPassword)
+            );
+        }
+        assert_matches!(
+            Database::load_from_bytes(&bytes[..HARDENED_HEADER_SIZE + 4], PASSWORD),
+            Err(UpmError::ReadUnderrun)
+        );
+        assert_matches!(
+            Database::load_from_bytes(&bytes[..HARDENED_HEADER_SIZE - 1], PASSWORD),
+            Err(UpmError::ReadUnderrun)
+        );
+
+        // Unknown KDFs and unreasonable parameters are refused before deriving a key.
+        let mut altered = bytes.clone();
+        altered[MAGIC_SIZE + UPM_DB_VERSION_SIZE] = 2;
+        assert_matches!(
+            Database::load_from_bytes(&altered, PASSWORD),
+            Err(UpmError::BadKdfParams(_))
+        );
+        let mut altered = bytes.clone();
+        altered[MAGIC_SIZE + UPM_DB_VERSION_SIZE + 1] = 0xFF;
+        assert_matches!(
+            Database::load_from_bytes(&altered, PASSWORD),
+            Err(UpmError::BadKdfParams(_))
+        );
+        let bad_params = KdfParams {
+            iterations: 0,
+            ..params
+        };
+        assert_matches!(
+            database.set_format(DatabaseFormat::Hardened(bad_params)),
+            Err(UpmError::BadKdfParams(_))
+        );
+        assert_eq!(database.format(), DatabaseFormat::Hardened(params));
+
+        // Converting back produces a version 3 database.
+        let mut database = loaded;
+        assert_matches!(database.set_format(DatabaseFormat::V3), Ok(()));
+        let bytes = database.save_to_bytes(PASSWORD).unwrap();
+        assert_eq!(&bytes[..MAGIC_SIZE + UPM_DB_VERSION_SIZE], b"UPM\x03");
+        let loaded = Database::load_from_bytes(&bytes, PASSWORD).unwrap();
+        assert_eq!(loaded.format(), DatabaseFormat::V3);
+        assert_accounts(&loaded, &["acct"]);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let database = Database::load_from_bytes(DATABASE_BYTES, PASSWORD).unwrap();
+        let hash = database.content_hash();
+        assert_eq!(hash.len(), 32);
+
+        // The revision and the account order are not part of the content.
+        let mut other = database.clone();
+        other.sync_revision += 1;
+        other.accounts.insert(0, Account::new());
+        let mut reordered = other.clone();
+        reordered.accounts.reverse();
+        assert_eq!(other.content_hash(), reordered.content_hash());
+        assert_ne!(other.content_hash(), hash);
+        other.accounts.remove(0);
+        assert_eq!(other.content_hash(), hash);
+
+        // Any change to the content is detected.
+        other.accounts[0].notes.push('x');
+        assert_ne!(other.content_hash(), hash);
+        let mut other = database.clone();
+        other.sync_credentials.push('x');
+        assert_ne!(other.content_hash(), hash);
+    }
+
+    #[test]
+    fn test_content_mac() {
+        const SALT: &[u8] = b"0123456789abcdef";
+        let database = Database::load_from_bytes(DATABASE_BYTES, PASSWORD).unwrap();
+        let key = database.content_mac_key(SALT).unwrap();
+        let mac = database.content_mac(&key).unwrap();
+        assert_eq!(mac.len(), 32);
+        assert_ne!(mac, database.content_hash());
+
+        // The MAC follows the content like the hash, but depends on the password and salt.
+        let mut other = database.clone();
+        other.sync_revision += 1;
+        assert_eq!(other.content_mac(&key).unwrap(), mac);
+        other.accounts[0].notes.push('x');
+        assert_ne!(other.content_mac(&key).unwrap(), mac);
+        let mut other = database.clone();
+        other.set_password(&"other password");
+        assert_ne!(other.content_mac_key(SALT).unwrap(), key);
+        assert_ne!(database.content_mac_key(&SALT[1..]).unwrap(), key);
+        assert_matches!(
+            Database::new().content_mac_key(SALT),
+            Err(UpmError::NoDatabasePassword)
+        );
+    }
+
+    #[test]
+    fn test_content_mac_key_cache() {
+        let database = Database::load_from_bytes(DATABASE_BYTES, PASSWORD).unwrap();
+        let (salt, key) = database.content_mac_salt_and_key().unwrap();
+        assert_eq!(salt.len(), CONTENT_MAC_SALT_SIZE);
+
+        // The salt and key are reused by the database and its clones, and match a fresh derivation.
+        assert_eq!(
+            database.content_mac_salt_and_key().unwrap(),
+            (salt.clone(), key.clone())
+        );
+        let mut other = database.clone();
+        assert_eq!(
+            other.content_mac_salt_and_key().unwrap(),
+            (salt.clone(), key.clone())
+        );
+        assert_eq!(database.content_mac_key(&salt).unwrap(), key);
+
+        // Changing the password invalidates the cached key.
+        other.set_password(&"other password");
+        let (other_salt, other_key) = other.content_mac_salt_and_key().unwrap();
+        assert_ne!(other_salt, salt);
+        assert_ne!(other_key, key);
+        assert_eq!(other.content_mac_key(&other_salt).unwrap(), other_key);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("primary");
+        let backups = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().path().extension().unwrap_or_default() == "bak")
+                .count()
+        };
+        let account = |name: &str| Account {
+            name: String::from(name),
+            ..Account::new()
+        };
+        let mut database = Database::load_from_bytes(DATABASE_BYTES, PASSWORD).unwrap();
+        database.set_path(&path).unwrap();
+        database.save().unwrap();
+
+        // Many changes are saved as one revision, with one backup.
+        database.begin();
+        assert!(database.in_transaction());
+        for name in &["a", "b", "c"] {
+            database.add_account(&account(name)).unwrap();
+        }
+        database.delete_account("b");
+        database.sync_url = String::from("http://example.com/");
+        assert_matches!(database.commit(), Ok(()));
+        assert!(!database.in_transaction());
+        assert_eq!(database.sync_revision, 2);
+        assert_eq!(backups(), 1);
+        let saved = Database::load_from_file(&path, PASSWORD).unwrap();
+        assert_eq!(saved.sync_revision, 2);
+        assert_eq!(saved.sync_url, "http://example.com/");
+        assert_accounts(&saved, &["acct", "a", "c"]);
+
+        // Rolling back restores the content as of begin().
+        database.begin();
+        database.delete_account("acct");
+        database.update_account("a", &account("d")).unwrap();
+        database.sync_credentials = String::from("a");
+        database.set_password(&"other");
+        database
+            .set_format(DatabaseFormat::Hardened(KdfParams::default()))
+            .unwrap();
+        database.rollback();
+        assert!(!database.in_transaction());
+        assert_accounts(&database, &["acct", "a", "c"]);
+        assert_eq!(database.sync_credentials, "");
+        assert_eq!(database.password(), Some(PASSWORD));
+        assert_eq!(database.format(), DatabaseFormat::V3);
+        database.rollback();
+        assert_accounts(&database, &["acct", "a", "c"]);
+
+        // A failed commit leaves the revision alone and the transaction open.
+        database.begin();
+        database.delete_account("acct");
+        database
+            .set_path(&dir.path().join("missing").join("primary"))
+            .unwrap();
+        assert_matches!(database.commit(), Err(UpmError::Io(_)));
+        assert_eq!(database.sync_revision, 2);
+        assert!(database.in_transaction());
+        database.rollback();
+        assert_accounts(&database, &["acct", "a", "c"]);
+
+        // Without a transaction, the pending changes are committed as one revision.
+        database.set_path(&path).unwrap();
+        database.delete_account("c");
+        assert_matches!(database.commit(), Ok(()));
+        assert_eq!(database.sync_revision, 3);
+        assert_eq!(
+            Database::load_from_file(&path, PASSWORD)
+                .unwrap()
+                .sync_revision,
+            3
+        );
+    }
+
     #[cfg_attr(rustfmt, rustfmt_skip)]
     const VALID_UTF8: &[u8] = &[
         0xCE, 0xB3, 0xCE, 0xBB, 0xCF, 0x8E, 0xCF, 0x83,
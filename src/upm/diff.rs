@@ -0,0 +1,339 @@
+//! Compare two copies of a database, such as the local database and one of its backups, and
+//! describe how the second differs from the first.
+//!
+//! Accounts are matched by name.  An account which is only present in one copy is added or
+//! removed, unless it appears to have been renamed: a removed account and an added account are
+//! considered the same account if they have the same (non-empty) password and the same username or
+//! URL.  Passwords are masked in the result unless they are explicitly requested, so a diff can be
+//! shown without revealing them.
+
+use database::{Account, Database};
+use merge::{AccountField, ACCOUNT_FIELDS};
+use secret::SecretString;
+use std::fmt;
+
+/// Passwords which are not requested are replaced with this text.
+pub const PASSWORD_MASK: &str = "********";
+
+/// A field which has different values in the two copies of an account.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: AccountField,
+    pub old: String,
+    pub new: String,
+}
+
+/// An account which is present in both databases (perhaps under different names), but differs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccountChange {
+    /// The name of the account in the old database.
+    pub old_name: String,
+    /// The name of the account in the new database.
+    pub new_name: String,
+    /// The fields which differ, in the order of `ACCOUNT_FIELDS`.
+    pub fields: Vec<FieldChange>,
+}
+
+/// The differences between an old and a new database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseDiff {
+    pub old_revision: u32,
+    pub new_revision: u32,
+    /// The old and new sync URL, if it differs.
+    pub sync_url: Option<(String, String)>,
+    /// The old and new name of the account holding the sync credentials, if it differs.
+    pub sync_credentials: Option<(String, String)>,
+    /// The accounts only present in the new database, in its order.
+    pub added: Vec<Account>,
+    /// The accounts only present in the old database, in its order.
+    pub removed: Vec<Account>,
+    /// The accounts which were renamed (and perhaps changed too), in the order of the new
+    /// database.
+    pub renamed: Vec<AccountChange>,
+    /// The accounts which kept their names but were changed, in the order of the new database.
+    pub changed: Vec<AccountChange>,
+}
+
+impl DatabaseDiff {
+    /// Compare the provided databases.  Passwords are masked unless `show_passwords` is true.
+    pub fn new(old: &Database, new: &Database, show_passwords: bool) -> DatabaseDiff {
+        let mask = |account: &Account| {
+            let mut account = account.clone();
+            if !show_passwords {
+                account.password = SecretString::new(mask_password(&account.password));
+            }
+            account
+        };
+        let setting = |old: &str, new: &str| {
+            if old != new {
+                Some((String::from(old), String::from(new)))
+            } else {
+                None
+            }
+        };
+
+        let mut removed: Vec<&Account> = old
+            .accounts
+            .iter()
+            .filter(|a| !new.contains(&a.name))
+            .collect();
+        let mut added = Vec::new();
+        let mut renamed = Vec::new();
+        let mut changed = Vec::new();
+        for account in new.accounts.iter() {
+            if let Some(old_account) = old.account(&account.name) {
+                let fields = field_changes(old_account, account, show_passwords);
+                if !fields.is_empty() {
+                    changed.push(AccountChange {
+                        old_name: old_account.name.clone(),
+                        new_name: account.name.clone(),
+                        fields,
+                    });
+                }
+            } else if let Some(index) = removed.iter().position(|r| is_renamed(r, account)) {
+                let old_account = removed.remove(index);
+                renamed.push(AccountChange {
+                    old_name: old_account.name.clone(),
+                    new_name: account.name.clone(),
+                    fields: field_changes(old_account, account, show_passwords),
+                });
+            } else {
+                added.push(mask(account));
+            }
+        }
+
+        DatabaseDiff {
+            old_revision: old.sync_revision,
+            new_revision: new.sync_revision,
+            sync_url: setting(&old.sync_url, &new.sync_url),
+            sync_credentials: setting(&old.sync_credentials, &new.sync_credentials),
+            added,
+            removed: removed.into_iter().map(mask).collect(),
+            renamed,
+            changed,
+        }
+    }
+
+    /// Return true if the databases have the same content (apart from their revisions).
+    pub fn is_empty(&self) -> bool {
+        self.sync_url.is_none()
+            && self.sync_credentials.is_none()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.renamed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+/// Mask a password, unless it is empty.
+fn mask_password(password: &str) -> String {
+    if password.is_empty() {
+        String::new()
+    } else {
+        String::from(PASSWORD_MASK)
+    }
+}
+
+/// Return the changes to the fields of an account.
+fn field_changes(old: &Account, new: &Account, show_passwords: bool) -> Vec<FieldChange> {
+    ACCOUNT_FIELDS
+        .iter()
+        .cloned()
+        .filter(|f| f.get(old) != f.get(new))
+        .map(|field| {
+            let (old, new) = if field == AccountField::Password && !show_passwords {
+                (mask_password(&old.password), mask_password(&new.password))
+            } else {
+                (String::from(field.get(old)), String::from(field.get(new)))
+            };
+            FieldChange { field, old, new }
+        })
+        .collect()
+}
+
+/// Return true if the new account appears to be the old account under a new name.
+fn is_renamed(old: &Account, new: &Account) -> bool {
+    !old.password.is_empty()
+        && old.password == new.password
+        && ((!old.user.is_empty() && old.user == new.user)
+            || (!old.url.is_empty() && old.url == new.url))
+}
+
+impl fmt::Display for DatabaseDiff {
+    /// List the differences on several lines, suitable for showing to the user.  Values are
+    /// quoted, so that empty values and line breaks in the notes are visible.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn fields(f: &mut fmt::Formatter, changes: &[FieldChange]) -> fmt::Result {
+            for change in changes.iter() {
+                write!(
+                    f,
+                    "\n    {}: {:?} -> {:?}",
+                    change.field.name(),
+                    change.old,
+                    change.new
+                )?;
+            }
+            Ok(())
+        }
+
+        write!(f, "Revision {} -> {}", self.old_revision, self.new_revision)?;
+        if self.is_empty() {
+            return write!(f, "\nThe databases are identical.");
+        }
+        if let Some((ref old, ref new)) = self.sync_url {
+            write!(f, "\nSync URL: {:?} -> {:?}", old, new)?;
+        }
+        if let Some((ref old, ref new)) = self.sync_credentials {
+            write!(f, "\nSync credentials: {:?} -> {:?}", old, new)?;
+        }
+        for account in self.added.iter() {
+            write!(f, "\n+ {} (added)", account.name)?;
+            for &field in ACCOUNT_FIELDS.iter() {
+                if !field.get(account).is_empty() {
+                    write!(f, "\n    {}: {:?}", field.name(), field.get(account))?;
+                }
+            }
+        }
+        for account in self.removed.iter() {
+            write!(f, "\n- {} (removed)", account.name)?;
+        }
+        for change in self.renamed.iter() {
+            write!(
+                f,
+                "\n> {} -> {} (renamed)",
+                change.old_name, change.new_name
+            )?;
+            fields(f, &change.fields)?;
+        }
+        for change in self.changed.iter() {
+            write!(f, "\n* {} (changed)", change.new_name)?;
+            fields(f, &change.fields)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(name: &str, user: &str, password: &str) -> Account {
+        Account {
+            name: String::from(name),
+            user: String::from(user),
+            password: SecretString::from(password),
+            ..Account::new()
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut old = Database::new();
+        old.sync_revision = 3;
+        for account in &[
+            account("same", "user", "pass"),
+            account("changed", "user", "pass"),
+            account("removed", "user", "secret"),
+            account("renamed", "user", "hunter2"),
+            account("notes", "", ""),
+        ] {
+            old.add_account(account).unwrap();
+        }
+        let diff = old.diff(&old);
+        assert!(diff.is_empty());
+        assert_eq!(
+            diff.to_string(),
+            "Revision 3 -> 3\nThe databases are identical."
+        );
+
+        let mut new = old.clone();
+        new.sync_revision = 5;
+        new.sync_url = String::from("http://example.com/");
+        new.account_mut("changed").unwrap().password = SecretString::from("new");
+        new.account_mut("changed").unwrap().url = String::from("http://example.com/");
+        new.delete_account("removed");
+        new.account_mut("renamed").unwrap().name = String::from("moved");
+        new.account_mut("notes").unwrap().notes = String::from("a\nb");
+        new.add_account(&account("added", "user", "letmein"))
+            .unwrap();
+
+        let diff = old.diff(&new);
+        assert!(!diff.is_empty());
+        assert_eq!((diff.old_revision, diff.new_revision), (3, 5));
+        assert_eq!(
+            diff.sync_url,
+            Some((String::new(), String::from("http://example.com/")))
+        );
+        assert_eq!(diff.sync_credentials, None);
+        assert_eq!(diff.added, vec![account("added", "user", PASSWORD_MASK)]);
+        assert_eq!(
+            diff.removed,
+            vec![account("removed", "user", PASSWORD_MASK)]
+        );
+        assert_eq!(
+            diff.renamed,
+            vec![AccountChange {
+                old_name: String::from("renamed"),
+                new_name: String::from("moved"),
+                fields: vec![],
+            }]
+        );
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(
+            diff.changed[0].fields,
+            vec![
+                FieldChange {
+                    field: AccountField::Password,
+                    old: String::from(PASSWORD_MASK),
+                    new: String::from(PASSWORD_MASK),
+                },
+                FieldChange {
+                    field: AccountField::Url,
+                    old: String::new(),
+                    new: String::from("http://example.com/"),
+                },
+            ]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "Revision 3 -> 5\n\
+             Sync URL: \"\" -> \"http://example.com/\"\n\
+             + added (added)\n    Username: \"user\"\n    Password: \"********\"\n\
+             - removed (removed)\n\
+             > renamed -> moved (renamed)\n\
+             * changed (changed)\n\
+             \x20   Password: \"********\" -> \"********\"\n\
+             \x20   URL: \"\" -> \"http://example.com/\"\n\
+             * notes (changed)\n    Notes: \"\" -> \"a\\nb\""
+        );
+
+        // Passwords are only shown on request.
+        let diff = old.diff_with_passwords(&new);
+        assert_eq!(diff.added, vec![account("added", "user", "letmein")]);
+        assert_eq!(diff.removed, vec![account("removed", "user", "secret")]);
+        assert_eq!(diff.changed[0].fields[0].old, "pass");
+        assert_eq!(diff.changed[0].fields[0].new, "new");
+
+        // Accounts without a password in common are not renames.
+        let mut new = old.clone();
+        new.account_mut("removed").unwrap().name = String::from("other");
+        new.account_mut("other").unwrap().user = String::from("someone");
+        new.account_mut("notes").unwrap().name = String::from("empty");
+        let diff = old.diff(&new);
+        assert_eq!(diff.renamed, vec![]);
+        assert_eq!(
+            diff.added
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["other", "empty"]
+        );
+        assert_eq!(
+            diff.removed
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["removed", "notes"]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 //! Provide a UpmError enum which can represent all of the errors that may be returned by upm
 //! functions.
 
+#[cfg(feature = "openssl_crypto")]
 extern crate openssl;
 
 use std::error;
@@ -17,7 +18,10 @@ pub enum UpmError {
     Io(io::Error),
     BadMagic,
     BadVersion(u8),
+    BadKdfParams(String),
+    #[cfg(feature = "openssl_crypto")]
     Crypto(openssl::error::ErrorStack),
+    Pem(String),
     BadPassword,
     InvalidFilename,
     TimeParseError(time::ParseError),
@@ -46,7 +50,12 @@ impl fmt::Display for UpmError {
             UpmError::Io(ref e) => write!(f, "IO error: {}", e),
             UpmError::BadMagic => write!(f, "Bad magic in file header."),
             UpmError::BadVersion(v) => write!(f, "Unsupported database version: {}", v),
+            UpmError::BadKdfParams(ref s) => {
+                write!(f, "Unsupported key derivation parameters: {}", s)
+            }
+            #[cfg(feature = "openssl_crypto")]
             UpmError::Crypto(ref e) => write!(f, "Crypto error: {}", e),
+            UpmError::Pem(ref s) => write!(f, "Invalid PEM data: {}", s),
             UpmError::BadPassword => write!(f, "The provided password is incorrect."),
             UpmError::InvalidFilename => write!(f, "The database file path is invalid."),
             UpmError::TimeParseError(e) => write!(f, "Time parsing error: {}", e),
@@ -78,7 +87,10 @@ impl error::Error for UpmError {
             UpmError::Io(ref err) => error::Error::description(err),
             UpmError::BadMagic => "bad magic",
             UpmError::BadVersion(_) => "bad database version",
+            UpmError::BadKdfParams(_) => "bad key derivation parameters",
+            #[cfg(feature = "openssl_crypto")]
             UpmError::Crypto(_) => "OpenSSL error",
+            UpmError::Pem(_) => "invalid PEM data",
             UpmError::BadPassword => "bad password",
             UpmError::InvalidFilename => "invalid filename",
             UpmError::TimeParseError(_) => "time parsing error",
@@ -98,6 +110,7 @@ impl error::Error for UpmError {
     fn cause(&self) -> Option<&error::Error> {
         match *self {
             UpmError::Io(ref err) => Some(err),
+            #[cfg(feature = "openssl_crypto")]
             UpmError::Crypto(ref err) => Some(err),
             UpmError::TimeParseError(ref err) => Some(err),
             _ => None,
@@ -111,6 +124,7 @@ impl From<io::Error> for UpmError {
     }
 }
 
+#[cfg(feature = "openssl_crypto")]
 impl From<openssl::error::ErrorStack> for UpmError {
     fn from(err: openssl::error::ErrorStack) -> UpmError {
         UpmError::Crypto(err)
@@ -0,0 +1,349 @@
+//! Make it harder for secrets to leave the process while a database is open.  Three protections
+//! are applied, where the platform supports them:
+//!
+//! 1. Core dumps are disabled (`RLIMIT_CORE` is set to zero), so a crash does not write the
+//!    decrypted database to disk.
+//! 2. Other processes are refused permission to attach with a debugger.  On Linux, the process is
+//!    marked non-dumpable with `prctl(PR_SET_DUMPABLE)`, which stops processes of the same user
+//!    (but not root) from attaching with `ptrace()` or reading `/proc/<pid>/mem`.  On macOS,
+//!    `ptrace(PT_DENY_ATTACH)` is used.
+//! 3. The pages holding secrets are locked into memory with `mlock()`, so they are not written to
+//!    swap.  Once hardening is applied, `SecretString` and `SecretBytes` lock their memory when
+//!    they are created and unlock it when they are dropped.  Pages are counted, so a page shared
+//!    by two secrets stays locked until both are gone.  The amount of memory which may be locked
+//!    is limited by `RLIMIT_MEMLOCK`; a secret which cannot be locked is still usable, but may be
+//!    swapped.
+//!
+//! Each protection is attempted independently, and the `HardeningReport` returned by `harden()`
+//! records which ones were applied.
+
+extern crate libc;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// A protection which `harden()` attempts to apply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protection {
+    /// Core dumps are disabled.
+    NoCoreDumps,
+    /// Other processes may not attach with a debugger.
+    NoPtrace,
+    /// The memory holding secrets is locked, so it is never swapped.
+    LockedMemory,
+}
+
+impl Protection {
+    /// Return a short description of the protection, suitable for showing to the user.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Protection::NoCoreDumps => "Disable core dumps",
+            Protection::NoPtrace => "Refuse debugger attachment",
+            Protection::LockedMemory => "Lock secrets in memory",
+        }
+    }
+}
+
+/// The result of attempting to apply a protection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The protection is in effect.
+    Applied,
+    /// The protection could not be applied, for the provided reason.
+    Failed(String),
+    /// The protection is not available on this platform.
+    Unsupported,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outcome::Applied => write!(f, "applied"),
+            Outcome::Failed(ref reason) => write!(f, "failed ({})", reason),
+            Outcome::Unsupported => write!(f, "not supported on this platform"),
+        }
+    }
+}
+
+/// Which protections were applied by `harden()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HardeningReport {
+    pub outcomes: Vec<(Protection, Outcome)>,
+}
+
+impl HardeningReport {
+    /// Return the protections which are not in effect.
+    pub fn missing(&self) -> Vec<Protection> {
+        self.outcomes
+            .iter()
+            .filter(|o| o.1 != Outcome::Applied)
+            .map(|o| o.0)
+            .collect()
+    }
+
+    /// Return true if every protection is in effect.
+    pub fn is_complete(&self) -> bool {
+        self.missing().is_empty()
+    }
+}
+
+impl fmt::Display for HardeningReport {
+    /// List each protection and its outcome on a separate line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(protection, ref outcome)) in self.outcomes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {}", protection.description(), outcome)?;
+        }
+        Ok(())
+    }
+}
+
+/// Apply every protection this platform supports, and report which ones were applied.  This
+/// should be called at startup, before any password is read.  Calling it again is harmless.
+pub fn harden() -> HardeningReport {
+    let locked_memory = sys::check_mlock();
+    if locked_memory == Outcome::Applied {
+        LOCK_SECRETS.store(true, Ordering::SeqCst);
+    }
+    HardeningReport {
+        outcomes: vec![
+            (Protection::NoCoreDumps, sys::disable_core_dumps()),
+            (Protection::NoPtrace, sys::deny_ptrace()),
+            (Protection::LockedMemory, locked_memory),
+        ],
+    }
+}
+
+/// Secrets are only locked into memory once `harden()` has found that locking works.
+static LOCK_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// The pages locked on behalf of secrets.
+static LOCKED_PAGES: Mutex<LockedPages> = Mutex::new(LockedPages::new());
+
+/// Lock the memory at the provided address into memory, if hardening has been applied.  Return
+/// true if it was locked, in which case `unlock()` must be called with the same address and
+/// length before the memory is freed.
+pub(crate) fn lock(ptr: *const u8, len: usize) -> bool {
+    if len == 0 || !LOCK_SECRETS.load(Ordering::SeqCst) {
+        return false;
+    }
+    let mut pages = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    pages.lock(ptr as usize, len, sys::page_size())
+}
+
+/// Release memory which was locked by `lock()`.
+pub(crate) fn unlock(ptr: *const u8, len: usize) {
+    let mut pages = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    pages.unlock(ptr as usize, len, sys::page_size())
+}
+
+/// Count the secrets on each locked page, so a page is only unlocked when the last secret on it
+/// is dropped.
+struct LockedPages {
+    /// The number of secrets on each locked page, by page address.
+    pages: BTreeMap<usize, usize>,
+}
+
+impl LockedPages {
+    const fn new() -> LockedPages {
+        LockedPages {
+            pages: BTreeMap::new(),
+        }
+    }
+
+    /// Return the addresses of the pages spanned by the provided region.
+    fn pages_of(address: usize, len: usize, page_size: usize) -> impl Iterator<Item = usize> {
+        let first = address & !(page_size - 1);
+        let last = (address + len - 1) & !(page_size - 1);
+        (first..=last).step_by(page_size)
+    }
+
+    /// Lock the pages spanned by the provided region.  If any page cannot be locked, none are.
+    fn lock(&mut self, address: usize, len: usize, page_size: usize) -> bool {
+        for page in LockedPages::pages_of(address, len, page_size) {
+            if !self.pages.contains_key(&page) && !sys::mlock(page, page_size) {
+                if page > address {
+                    self.unlock(address, page - address, page_size);
+                }
+                return false;
+            }
+            *self.pages.entry(page).or_insert(0) += 1;
+        }
+        true
+    }
+
+    /// Unlock the pages spanned by the provided region, unless other secrets remain on them.
+    fn unlock(&mut self, address: usize, len: usize, page_size: usize) {
+        for page in LockedPages::pages_of(address, len, page_size) {
+            let remaining = match self.pages.get_mut(&page) {
+                Some(count) => {
+                    *count -= 1;
+                    *count
+                }
+                None => continue,
+            };
+            if remaining == 0 {
+                self.pages.remove(&page);
+                sys::munlock(page, page_size);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use super::libc;
+    use super::Outcome;
+    use std::io;
+
+    /// Return a description of the most recent OS error.
+    fn last_error() -> String {
+        io::Error::last_os_error().to_string()
+    }
+
+    pub fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    pub fn mlock(page: usize, len: usize) -> bool {
+        unsafe { libc::mlock(page as *const libc::c_void, len) == 0 }
+    }
+
+    pub fn munlock(page: usize, len: usize) {
+        unsafe {
+            libc::munlock(page as *const libc::c_void, len);
+        }
+    }
+
+    /// Check that a page can be locked, so a low `RLIMIT_MEMLOCK` is reported up front.
+    pub fn check_mlock() -> Outcome {
+        let page = vec![0u8; page_size()];
+        if mlock(page.as_ptr() as usize, page.len()) {
+            munlock(page.as_ptr() as usize, page.len());
+            Outcome::Applied
+        } else {
+            Outcome::Failed(last_error())
+        }
+    }
+
+    pub fn disable_core_dumps() -> Outcome {
+        let limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+            return Outcome::Failed(last_error());
+        }
+        Outcome::Applied
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn deny_ptrace() -> Outcome {
+        if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+            return Outcome::Failed(last_error());
+        }
+        Outcome::Applied
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn deny_ptrace() -> Outcome {
+        if unsafe { libc::ptrace(libc::PT_DENY_ATTACH, 0, ::std::ptr::null_mut(), 0) } != 0 {
+            return Outcome::Failed(last_error());
+        }
+        Outcome::Applied
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn deny_ptrace() -> Outcome {
+        Outcome::Unsupported
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use super::Outcome;
+
+    pub fn page_size() -> usize {
+        4096
+    }
+
+    pub fn mlock(_: usize, _: usize) -> bool {
+        false
+    }
+
+    pub fn munlock(_: usize, _: usize) {}
+
+    pub fn check_mlock() -> Outcome {
+        Outcome::Unsupported
+    }
+
+    pub fn disable_core_dumps() -> Outcome {
+        Outcome::Unsupported
+    }
+
+    pub fn deny_ptrace() -> Outcome {
+        Outcome::Unsupported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let report = HardeningReport {
+            outcomes: vec![
+                (Protection::NoCoreDumps, Outcome::Applied),
+                (Protection::NoPtrace, Outcome::Unsupported),
+                (
+                    Protection::LockedMemory,
+                    Outcome::Failed(String::from("Cannot allocate memory")),
+                ),
+            ],
+        };
+        assert!(!report.is_complete());
+        assert_eq!(
+            report.missing(),
+            vec![Protection::NoPtrace, Protection::LockedMemory]
+        );
+        assert_eq!(
+            report.to_string(),
+            "Disable core dumps: applied\n\
+             Refuse debugger attachment: not supported on this platform\n\
+             Lock secrets in memory: failed (Cannot allocate memory)"
+        );
+    }
+
+    #[test]
+    fn test_locked_pages() {
+        let page_size = sys::page_size();
+        let buffer = vec![0u8; page_size * 3];
+        let base = (buffer.as_ptr() as usize + page_size - 1) & !(page_size - 1);
+        let mut pages = LockedPages::new();
+        if !pages.lock(base + 10, page_size, page_size) {
+            // Memory cannot be locked here (for example, RLIMIT_MEMLOCK is zero).
+            assert!(pages.pages.is_empty());
+            return;
+        }
+        assert_eq!(
+            pages.pages.keys().cloned().collect::<Vec<_>>(),
+            vec![base, base + page_size]
+        );
+
+        // A page shared by two regions stays locked until both are unlocked.
+        assert!(pages.lock(base + page_size + 100, 10, page_size));
+        assert_eq!(pages.pages[&(base + page_size)], 2);
+        pages.unlock(base + 10, page_size, page_size);
+        assert_eq!(
+            pages.pages.keys().cloned().collect::<Vec<_>>(),
+            vec![base + page_size]
+        );
+        pages.unlock(base + page_size + 100, 10, page_size);
+        assert!(pages.pages.is_empty());
+    }
+}
@@ -4,19 +4,29 @@
 //!
 //! A terminal-based interface to UPM databases (tupm) is provided as an example application.
 
+extern crate base64;
 extern crate rand;
 extern crate reqwest;
 extern crate time;
+extern crate zeroize;
 
 #[cfg(test)]
 #[macro_use]
 extern crate matches;
+#[cfg(test)]
+extern crate tempfile;
 
 pub mod backup;
 mod crypto;
 pub mod database;
+pub mod diff;
 pub mod error;
+pub mod harden;
+pub mod merge;
+#[cfg(feature = "openssl_crypto")]
 mod openssl_extra;
+pub mod secret;
+pub mod server;
 pub mod sync;
 
 /// If this is true, we'll back backups to both the local filesystem and
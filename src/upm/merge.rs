@@ -0,0 +1,502 @@
+//! Merge two copies of a database which were modified independently since a common base revision.
+//! This allows two UPM clients to edit the same database between syncs without one silently
+//! losing the other's changes.
+//!
+//! Accounts are matched by name, so renaming an account is seen as deleting the old account and
+//! adding a new one.  Changes made on only one side are always applied.  Changes made on both
+//! sides are merged field by field, and a [`Conflict`](struct.Conflict.html) is reported for each
+//! account which cannot be merged automatically:
+//!
+//! 1. Both sides changed the same field of an account to different values.  (Accounts added on
+//!    both sides with the same name are treated as edits of an empty account.)
+//! 2. One side changed an account which the other side deleted.
+//!
+//! Conflicts are provisionally resolved so that no data is lost: conflicting fields take the value
+//! from our side, and an account which was changed on one side is kept even if the other side
+//! deleted it.  The user may then revisit each conflict and choose a different
+//! [`Resolution`](enum.Resolution.html), either for the account as a whole or field by field.
+//!
+//! When syncing, our side is the local database and their side is the remote database, so copies
+//! of their values which are kept alongside ours are labeled as remote.
+
+use database::{Account, Database};
+use secret::SecretString;
+use std::cmp;
+
+/// The fields of an account which are merged individually.  (The account name is what identifies
+/// an account, so it is never merged.)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountField {
+    User,
+    Password,
+    Url,
+    Notes,
+}
+
+/// All fields which are merged individually.
+pub const ACCOUNT_FIELDS: [AccountField; 4] = [
+    AccountField::User,
+    AccountField::Password,
+    AccountField::Url,
+    AccountField::Notes,
+];
+
+impl AccountField {
+    /// Return a human-readable name for this field.
+    pub fn name(self) -> &'static str {
+        match self {
+            AccountField::User => "Username",
+            AccountField::Password => "Password",
+            AccountField::Url => "URL",
+            AccountField::Notes => "Notes",
+        }
+    }
+
+    /// Return this field's value in the provided account.
+    pub fn get(self, account: &Account) -> &str {
+        match self {
+            AccountField::User => &account.user,
+            AccountField::Password => &account.password,
+            AccountField::Url => &account.url,
+            AccountField::Notes => &account.notes,
+        }
+    }
+
+    /// Set this field's value in the provided account.
+    pub fn set(self, account: &mut Account, value: &str) {
+        let field = match self {
+            AccountField::User => &mut account.user,
+            AccountField::Password => {
+                account.password = SecretString::from(value);
+                return;
+            }
+            AccountField::Url => &mut account.url,
+            AccountField::Notes => &mut account.notes,
+        };
+        *field = String::from(value);
+    }
+}
+
+/// An account which could not be merged automatically.  The account is provided as it appears in
+/// the base, our, and their databases, where `None` indicates that the account is not present
+/// (e.g. was deleted).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    /// The name of the account.
+    pub name: String,
+    pub base: Option<Account>,
+    pub ours: Option<Account>,
+    pub theirs: Option<Account>,
+    /// The fields which were changed to different values on both sides.  This is empty if one
+    /// side deleted the account.
+    pub fields: Vec<AccountField>,
+}
+
+/// A choice of how to resolve a conflict, or a single conflicting field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// Keep our version.
+    Ours,
+    /// Keep their version.
+    Theirs,
+    /// Keep both versions.  For a whole account, their account is kept as a separate account
+    /// whose name is marked as remote.  For a single field, their value is added to the notes
+    /// (or, for the notes themselves, appended to ours) -- except for the password, which is never
+    /// copied into the notes: their account is kept as a separate account instead.
+    Both,
+}
+
+/// The label used when keeping their version of an account or field next to ours.
+const THEIR_LABEL: &str = "remote";
+
+impl Conflict {
+    /// Resolve this conflict in the merged database (as returned by
+    /// [`Database::merge()`](../database/struct.Database.html#method.merge)) by choosing one side
+    /// for the account as a whole.  Changes which were merged automatically are retained.
+    pub fn resolve(&self, merged: &mut Database, resolution: Resolution) {
+        let side = match resolution {
+            Resolution::Ours => &self.ours,
+            Resolution::Theirs => &self.theirs,
+            Resolution::Both => {
+                if let (Some(_), Some(theirs)) = (&self.ours, &self.theirs) {
+                    self.resolve_fields(merged, &vec![Resolution::Ours; self.fields.len()]);
+                    self.add_their_copy(merged, theirs);
+                } else {
+                    // Only one side has the account, so keeping both means keeping that one.
+                    self.restore(merged, self.ours.as_ref().or(self.theirs.as_ref()));
+                }
+                return;
+            }
+        };
+        match *side {
+            Some(_) if !self.fields.is_empty() => {
+                self.resolve_fields(merged, &vec![resolution; self.fields.len()])
+            }
+            ref side => self.restore(merged, side.as_ref()),
+        }
+    }
+
+    /// Resolve the conflicting fields of this conflict individually in the merged database.  The
+    /// resolutions are provided in the same order as `fields`.  This has no effect if one side
+    /// deleted the account.
+    pub fn resolve_fields(&self, merged: &mut Database, resolutions: &[Resolution]) {
+        let (ours, theirs) = match (&self.ours, &self.theirs) {
+            (Some(o), Some(t)) => (o, t),
+            _ => return,
+        };
+        let mut account = match merged.account(&self.name) {
+            Some(a) => a.clone(),
+            None => ours.clone(),
+        };
+        let mut extra_notes = Vec::new();
+        let mut keep_their_copy = false;
+        for (&field, &resolution) in self.fields.iter().zip(resolutions.iter()) {
+            let (our_value, their_value) = (field.get(ours), field.get(theirs));
+            match resolution {
+                Resolution::Ours => field.set(&mut account, our_value),
+                Resolution::Theirs => field.set(&mut account, their_value),
+                Resolution::Both if field == AccountField::Notes => {
+                    extra_notes.push(String::from(their_value));
+                    field.set(&mut account, our_value);
+                }
+                Resolution::Both if field == AccountField::Password => {
+                    keep_their_copy = true;
+                    field.set(&mut account, our_value);
+                }
+                Resolution::Both => {
+                    extra_notes.push(format!(
+                        "{} ({}): {}",
+                        field.name(),
+                        THEIR_LABEL,
+                        their_value
+                    ));
+                    field.set(&mut account, our_value);
+                }
+            }
+        }
+        for note in extra_notes {
+            if !account.notes.is_empty() {
+                account.notes.push('\n');
+            }
+            account.notes.push_str(&note);
+        }
+        self.restore(merged, Some(&account));
+        if keep_their_copy {
+            self.add_their_copy(merged, theirs);
+        }
+    }
+
+    /// Add a copy of their version of the account to the merged database, under a name which is
+    /// marked as remote.
+    fn add_their_copy(&self, merged: &mut Database, theirs: &Account) {
+        let copy = Account {
+            name: unique_name(merged, &format!("{} ({})", self.name, THEIR_LABEL)),
+            ..theirs.clone()
+        };
+        merged.add_account(&copy).unwrap_or_default();
+    }
+
+    /// Put the provided version of the account into the merged database, or remove the account
+    /// if `None`.
+    fn restore(&self, merged: &mut Database, account: Option<&Account>) {
+        match (merged.account_mut(&self.name), account) {
+            (Some(existing), Some(account)) => *existing = account.clone(),
+            (None, Some(account)) => merged.add_account(account).unwrap_or_default(),
+            (Some(_), None) => merged.delete_account(&self.name),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Return the provided account name, or a variation of it which is not yet used in the database.
+fn unique_name(database: &Database, name: &str) -> String {
+    let mut candidate = String::from(name);
+    let mut n = 2;
+    while database.contains(&candidate) {
+        candidate = format!("{} {}", name, n);
+        n += 1;
+    }
+    candidate
+}
+
+/// Merge a value which may have been changed on either side.  Return the merged value, or `None`
+/// if both sides changed it to different values.
+fn merge_value<'a, T: PartialEq + ?Sized>(base: &T, ours: &'a T, theirs: &'a T) -> Option<&'a T> {
+    if ours == theirs || theirs == base {
+        Some(ours)
+    } else if ours == base {
+        Some(theirs)
+    } else {
+        None
+    }
+}
+
+/// Merge an account which was changed on both sides.  Return the merged account along with the
+/// conflicting fields, if any.
+fn merge_account(
+    base: Option<&Account>,
+    ours: &Account,
+    theirs: &Account,
+) -> (Account, Vec<AccountField>) {
+    let empty = Account {
+        name: ours.name.clone(),
+        ..Account::new()
+    };
+    let base = base.unwrap_or(&empty);
+    let mut merged = ours.clone();
+    let mut conflicts = Vec::new();
+    for &field in ACCOUNT_FIELDS.iter() {
+        match merge_value(field.get(base), field.get(ours), field.get(theirs)) {
+            Some(value) => field.set(&mut merged, value),
+            None => conflicts.push(field),
+        }
+    }
+    (merged, conflicts)
+}
+
+impl Database {
+    /// Perform a three-way merge of two databases (ours and theirs) which were both derived from
+    /// a common base database.  Return the merged database along with a list of conflicts which
+    /// could not be merged automatically.  See the [`merge`](../merge/index.html) module for
+    /// details.
+    ///
+    /// The merged database is a copy of our database (retaining its path and password) with the
+    /// merged accounts and sync settings.  Its revision is greater than both of the merged
+    /// revisions.
+    pub fn merge(base: &Database, ours: &Database, theirs: &Database) -> (Database, Vec<Conflict>) {
+        let mut merged = ours.clone();
+        merged.sync_revision = cmp::max(ours.sync_revision, theirs.sync_revision) + 1;
+        // Sync settings are merged like account fields, except that a conflict is quietly
+        // resolved in favor of our side.
+        merged.sync_url = merge_value(&base.sync_url, &ours.sync_url, &theirs.sync_url)
+            .unwrap_or(&ours.sync_url)
+            .clone();
+        merged.sync_credentials = merge_value(
+            &base.sync_credentials,
+            &ours.sync_credentials,
+            &theirs.sync_credentials,
+        )
+        .unwrap_or(&ours.sync_credentials)
+        .clone();
+
+        // Consider every account name present on any side, keeping our ordering.
+        let mut names: Vec<&str> = Vec::new();
+        for account in ours
+            .accounts
+            .iter()
+            .chain(theirs.accounts.iter())
+            .chain(base.accounts.iter())
+        {
+            if !names.contains(&account.name.as_str()) {
+                names.push(&account.name);
+            }
+        }
+
+        let mut accounts = Vec::new();
+        let mut conflicts = Vec::new();
+        for name in names {
+            let (b, o, t) = (base.account(name), ours.account(name), theirs.account(name));
+            if let Some(&account) = merge_value(&b, &o, &t) {
+                // At most one side changed this account.
+                accounts.extend(account.cloned());
+                continue;
+            }
+            let (account, fields) = match (o, t) {
+                (Some(o), Some(t)) => merge_account(b, o, t),
+                // One side deleted an account which the other side changed.  Keep the changes.
+                (Some(a), None) | (None, Some(a)) => (a.clone(), vec![]),
+                (None, None) => unreachable!(),
+            };
+            if o.is_none() || t.is_none() || !fields.is_empty() {
+                conflicts.push(Conflict {
+                    name: String::from(name),
+                    base: b.cloned(),
+                    ours: o.cloned(),
+                    theirs: t.cloned(),
+                    fields,
+                });
+            }
+            accounts.push(account);
+        }
+        merged.accounts = accounts;
+        (merged, conflicts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(name: &str, user: &str, password: &str) -> Account {
+        Account {
+            name: String::from(name),
+            user: String::from(user),
+            password: SecretString::from(password),
+            url: String::new(),
+            notes: String::new(),
+        }
+    }
+
+    fn database(revision: u32, accounts: &[Account]) -> Database {
+        let mut database = Database::new();
+        database.sync_revision = revision;
+        database.accounts = accounts.to_vec();
+        database
+    }
+
+    #[test]
+    fn test_merge_without_conflicts() {
+        let base = database(
+            5,
+            &[
+                account("edited", "user", "pass"),
+                account("deleted", "user", "pass"),
+                account("both", "user", "pass"),
+            ],
+        );
+        let mut ours = base.clone();
+        ours.sync_revision = 6;
+        ours.account_mut("edited").unwrap().password = SecretString::from("ours");
+        ours.account_mut("both").unwrap().user = String::from("ours");
+        ours.add_account(&account("added-ours", "a", "b")).unwrap();
+        ours.add_account(&account("added-both", "a", "b")).unwrap();
+        let mut theirs = base.clone();
+        theirs.sync_revision = 8;
+        theirs.delete_account("deleted");
+        theirs.account_mut("both").unwrap().password = SecretString::from("theirs");
+        theirs
+            .add_account(&account("added-theirs", "c", "d"))
+            .unwrap();
+        theirs
+            .add_account(&account("added-both", "a", "b"))
+            .unwrap();
+        theirs.sync_url = String::from("http://example.com/");
+
+        let (merged, conflicts) = Database::merge(&base, &ours, &theirs);
+        assert_eq!(conflicts, vec![]);
+        assert_eq!(merged.sync_revision, 9);
+        assert_eq!(merged.sync_url, "http://example.com/");
+        assert_eq!(
+            merged.accounts,
+            vec![
+                account("edited", "user", "ours"),
+                account("both", "ours", "theirs"),
+                account("added-ours", "a", "b"),
+                account("added-both", "a", "b"),
+                account("added-theirs", "c", "d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let base = database(
+            1,
+            &[
+                account("field", "user", "pass"),
+                account("deleted-ours", "user", "pass"),
+                account("deleted-theirs", "user", "pass"),
+            ],
+        );
+        let mut ours = base.clone();
+        ours.account_mut("field").unwrap().password = SecretString::from("ours");
+        ours.account_mut("field").unwrap().notes = String::from("notes");
+        ours.delete_account("deleted-ours");
+        ours.account_mut("deleted-theirs").unwrap().user = String::from("ours");
+        ours.add_account(&account("added", "user", "ours")).unwrap();
+        let mut theirs = base.clone();
+        theirs.account_mut("field").unwrap().password = SecretString::from("theirs");
+        theirs.account_mut("deleted-ours").unwrap().user = String::from("theirs");
+        theirs.delete_account("deleted-theirs");
+        theirs
+            .add_account(&account("added", "user", "theirs"))
+            .unwrap();
+
+        let (merged, conflicts) = Database::merge(&base, &ours, &theirs);
+        let names: Vec<&str> = conflicts.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["field", "deleted-theirs", "added", "deleted-ours"]
+        );
+        assert_eq!(conflicts[0].fields, vec![AccountField::Password]);
+        assert_eq!(conflicts[1].fields, vec![]);
+        assert_eq!(conflicts[1].theirs, None);
+        assert_eq!(conflicts[2].base, None);
+        assert_eq!(conflicts[2].fields, vec![AccountField::Password]);
+        assert_eq!(conflicts[3].ours, None);
+
+        // Conflicts are provisionally resolved without losing anything.
+        let field = merged.account("field").unwrap();
+        assert_eq!(
+            (field.password.as_str(), field.notes.as_str()),
+            ("ours", "notes")
+        );
+        assert_eq!(merged.account("deleted-ours").unwrap().user, "theirs");
+        assert_eq!(merged.account("deleted-theirs").unwrap().user, "ours");
+        assert_eq!(merged.account("added").unwrap().password, "ours");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let base = database(
+            1,
+            &[
+                account("field", "user", "pass"),
+                account("deleted", "u", "p"),
+            ],
+        );
+        let mut ours = base.clone();
+        ours.account_mut("field").unwrap().user = String::from("our-user");
+        ours.account_mut("field").unwrap().password = SecretString::from("our-pass");
+        ours.delete_account("deleted");
+        let mut theirs = base.clone();
+        theirs.account_mut("field").unwrap().user = String::from("their-user");
+        theirs.account_mut("field").unwrap().password = SecretString::from("their-pass");
+        theirs.account_mut("deleted").unwrap().user = String::from("theirs");
+        let (merged, conflicts) = Database::merge(&base, &ours, &theirs);
+        assert_eq!(conflicts.len(), 2);
+
+        let mut resolved = merged.clone();
+        conflicts[0].resolve(&mut resolved, Resolution::Theirs);
+        conflicts[1].resolve(&mut resolved, Resolution::Ours);
+        assert_eq!(
+            resolved.accounts,
+            vec![account("field", "their-user", "their-pass")]
+        );
+
+        let mut resolved = merged.clone();
+        conflicts[0].resolve(&mut resolved, Resolution::Both);
+        conflicts[1].resolve(&mut resolved, Resolution::Both);
+        assert_eq!(resolved.account("field").unwrap().password, "our-pass");
+        assert_eq!(
+            resolved.account("field (remote)").unwrap().password,
+            "their-pass"
+        );
+        assert_eq!(resolved.account("deleted").unwrap().user, "theirs");
+
+        let mut resolved = merged.clone();
+        assert_eq!(
+            conflicts[0].fields,
+            vec![AccountField::User, AccountField::Password]
+        );
+        conflicts[0].resolve_fields(&mut resolved, &[Resolution::Theirs, Resolution::Both]);
+        let field = resolved.account("field").unwrap();
+        assert_eq!(
+            (field.user.as_str(), field.password.as_str()),
+            ("their-user", "our-pass")
+        );
+        assert_eq!(field.notes, "");
+        assert_eq!(
+            resolved.account("field (remote)").unwrap().password,
+            "their-pass"
+        );
+
+        // Keeping both values of another field adds the remote value to the notes.
+        let mut resolved = merged.clone();
+        conflicts[0].resolve_fields(&mut resolved, &[Resolution::Both, Resolution::Theirs]);
+        let field = resolved.account("field").unwrap();
+        assert_eq!(field.user, "our-user");
+        assert_eq!(field.notes, "Username (remote): their-user");
+        assert!(!resolved.contains("field (remote)"));
+    }
+}
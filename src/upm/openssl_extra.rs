@@ -59,47 +59,18 @@ extern "C" {
     ) -> c_int;
 }
 
-/// Convert a UTF-8 encoded string into a UCS-2BE encoding suitable for PKCS12_key_gen_uni().
-///
-/// PKCS#12 wants strings in "BMPString" encoding, which is actually UCS-2BE.  (Not "UTF-16" as the
-/// OpenSSL comments would lead you to believe.)  This only allows for codepoints in the Basic
-/// Multilingual Plane.  Hopefully nobody is using fancy emojis in their passwords.
-fn str_to_bmpstring(text: &str) -> Box<[u8]> {
-    // Use a boxed slice so the sensitive data can be reliably zeroed later.
-    // (A Vec may reallocate and leave behind sensitive material.)
-    let final_length = text.chars().count() * 2 + 2;
-    let mut bmpstring: Box<[u8]> = vec![0; final_length].into_boxed_slice();
-
-    let mut index = 0;
-    for c in text.chars() {
-        let codepoint = c as u32;
-        // The upper 16 bits of the codepoint will be discarded.
-        bmpstring[index] = ((codepoint >> 8) & 0xFF) as u8;
-        index += 1;
-        bmpstring[index] = ((codepoint >> 0) & 0xFF) as u8;
-        index += 1;
-    }
-    bmpstring[index] = 0;
-    index += 1;
-    bmpstring[index] = 0;
-
-    bmpstring
-}
-
 /// Generate a key or IV using the key derivation function specified in RFC 7292, "PKCS #12:
 /// Personal Information Exchange Syntax v1.1", Appendix B, "Deriving Keys and IVs from Passwords
-/// and Salt".
+/// and Salt".  The password must already be encoded as a BMPString, including the terminating
+/// null.
 pub fn pkcs12_key_gen(
-    pass: &str,
+    pass: &[u8],
     salt: &[u8],
     id: u8,
     iter: usize,
     key: &mut [u8],
     hash: openssl::hash::MessageDigest,
 ) -> Result<(), openssl::error::ErrorStack> {
-    // Convert password to a BMPString
-    let mut pass = str_to_bmpstring(pass);
-
     // Proxy to OpenSSL's PKCS12_key_gen_uni().
     let result: c_int;
     unsafe {
@@ -120,13 +91,6 @@ pub fn pkcs12_key_gen(
         );
     }
 
-    // Zero the encoded bmpstring.
-    // This may need to be revisited -- will the compiler optimize this out?
-    // Best practices for sensitive material in Rust are still evolving.
-    for i in 0..pass.len() {
-        pass[i] = 0;
-    }
-
     if result <= 0 {
         Err(openssl::error::ErrorStack::get())
     } else {
@@ -148,34 +112,4 @@ mod tests {
         assert_eq!(decompose_error_code(0x00000000), (0x00, 0x000, 0x000));
         assert_eq!(decompose_error_code(0xFFFFFFFF), (0xFF, 0xFFF, 0xFFF));
     }
-
-    const HELLOWORLD_STR: &str = "hello world";
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    const FANCY_UTF8: &[u8] = &[
-        0xCE, 0xB3, 0xCE, 0xBB, 0xCF, 0x8E, 0xCF, 0x83,
-        0xCF, 0x83, 0xCE, 0xB1
-    ];
-    const EMPTY_BMPSTRING: &[u8] = &[0x00, 0x00];
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    const HELLOWORLD_BMPSTRING: &[u8] = &[
-        0x00, 0x68, 0x00, 0x65, 0x00, 0x6C, 0x00, 0x6C,
-        0x00, 0x6F, 0x00, 0x20, 0x00, 0x77, 0x00, 0x6F,
-        0x00, 0x72, 0x00, 0x6C, 0x00, 0x64, 0x00, 0x00
-    ];
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    const FANCY_BMPSTRING: &[u8] = &[
-        0x03, 0xB3, 0x03, 0xBB, 0x03, 0xCE, 0x03, 0xC3,
-        0x03, 0xC3, 0x03, 0xB1, 0x00, 0x00
-    ];
-
-    #[test]
-    fn test_str_to_bmpstring() {
-        use std::str;
-        assert_eq!(&*str_to_bmpstring(""), EMPTY_BMPSTRING);
-        assert_eq!(&*str_to_bmpstring(HELLOWORLD_STR), HELLOWORLD_BMPSTRING);
-        assert_eq!(
-            &*str_to_bmpstring(str::from_utf8(FANCY_UTF8).unwrap()),
-            FANCY_BMPSTRING
-        );
-    }
 }
@@ -0,0 +1,227 @@
+//! Hold sensitive material, such as the master password, account passwords, and decrypted
+//! database contents, in types which wipe their memory when they are dropped.
+//!
+//! Wiping is performed by the `zeroize` crate, which uses volatile writes and a compiler fence so
+//! the compiler cannot optimize it away as a dead store.  This only protects the memory these types
+//! own: copies made elsewhere (for example, by the terminal UI library, by OpenSSL, or by a
+//! `String` which reallocated while a password was typed) are beyond its reach.  The `Debug`
+//! representations never show the contents, so secrets do not leak into logs or test failures.
+//!
+//! Once `harden::harden()` has been called, these types also lock their memory so it cannot be
+//! swapped to disk.
+
+use harden;
+use std::cmp;
+use std::fmt;
+use std::ops::Deref;
+use zeroize::Zeroize;
+
+/// A string, such as a password, which is wiped when dropped.
+pub struct SecretString {
+    value: String,
+    /// True if the memory holding the value was locked by `harden::lock()`.
+    locked: bool,
+}
+
+impl SecretString {
+    /// Take ownership of the provided string.  The string is not copied, so no unwiped copy is
+    /// left behind.
+    pub fn new(s: String) -> SecretString {
+        let locked = harden::lock(s.as_ptr(), s.capacity());
+        SecretString { value: s, locked }
+    }
+
+    /// Return the secret as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        let (ptr, capacity) = (self.value.as_ptr(), self.value.capacity());
+        self.value.zeroize();
+        if self.locked {
+            harden::unlock(ptr, capacity);
+        }
+    }
+}
+
+impl Clone for SecretString {
+    fn clone(&self) -> SecretString {
+        SecretString::new(self.value.clone())
+    }
+}
+
+impl Default for SecretString {
+    fn default() -> SecretString {
+        SecretString::new(String::new())
+    }
+}
+
+impl Deref for SecretString {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.value
+    }
+}
+
+impl AsRef<str> for SecretString {
+    fn as_ref(&self) -> &str {
+        &self.value
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> SecretString {
+        SecretString::new(s)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> SecretString {
+        SecretString::new(String::from(s))
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &SecretString) -> bool {
+        self.value == other.value
+    }
+}
+
+impl Eq for SecretString {}
+
+impl PartialEq<str> for SecretString {
+    fn eq(&self, other: &str) -> bool {
+        self.value == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for SecretString {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.value == *other
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretString(***)")
+    }
+}
+
+/// A byte buffer, such as decrypted database contents, which is wiped when dropped.
+pub struct SecretBytes {
+    bytes: Vec<u8>,
+    /// True if the memory holding the bytes was locked by `harden::lock()`.
+    locked: bool,
+}
+
+impl SecretBytes {
+    /// Create an empty buffer with room for the provided number of bytes.
+    pub fn with_capacity(capacity: usize) -> SecretBytes {
+        SecretBytes::from(Vec::with_capacity(capacity))
+    }
+
+    /// Append the provided bytes.  If the buffer must grow, the contents are moved to a larger
+    /// allocation by hand, so the old allocation is wiped rather than freed with the contents
+    /// still in it.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        let needed = self.bytes.len() + data.len();
+        if needed > self.bytes.capacity() {
+            let mut grown = Vec::with_capacity(cmp::max(needed, self.bytes.capacity() * 2));
+            let locked = harden::lock(grown.as_ptr(), grown.capacity());
+            grown.extend_from_slice(&self.bytes);
+            self.wipe();
+            self.bytes = grown;
+            self.locked = locked;
+        }
+        self.bytes.extend_from_slice(data);
+    }
+
+    /// Wipe the bytes, and unlock their memory so it may be freed.
+    fn wipe(&mut self) {
+        let (ptr, capacity) = (self.bytes.as_ptr(), self.bytes.capacity());
+        self.bytes.zeroize();
+        if self.locked {
+            harden::unlock(ptr, capacity);
+            self.locked = false;
+        }
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> SecretBytes {
+        SecretBytes::from(self.bytes.clone())
+    }
+}
+
+impl Default for SecretBytes {
+    fn default() -> SecretBytes {
+        SecretBytes::from(Vec::new())
+    }
+}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &SecretBytes) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> SecretBytes {
+        let locked = harden::lock(bytes.as_ptr(), bytes.capacity());
+        SecretBytes { bytes, locked }
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes({} bytes)", self.bytes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_string() {
+        let secret = SecretString::from("hunter2");
+        assert_eq!(secret, "hunter2");
+        assert_eq!(secret.len(), 7);
+        assert_eq!(secret.clone(), secret);
+        assert_eq!(format!("{:?}", secret), "SecretString(***)");
+
+        // Wiping leaves an empty string.
+        let mut secret = SecretString::new(String::from("hunter2"));
+        secret.value.zeroize();
+        assert_eq!(secret, "");
+    }
+
+    #[test]
+    fn test_secret_bytes() {
+        let mut secret = SecretBytes::with_capacity(2);
+        secret.extend_from_slice(b"ab");
+        secret.extend_from_slice(b"cdefg");
+        secret.extend_from_slice(b"h");
+        assert_eq!(&*secret, b"abcdefgh");
+        assert!(secret.bytes.capacity() >= 8);
+        assert_eq!(format!("{:?}", secret), "SecretBytes(8 bytes)");
+    }
+}
@@ -0,0 +1,573 @@
+//! Provide a minimal server for the UPM sync protocol, as spoken by
+//! [`sync::Repository`](../sync/struct.Repository.html).  This replaces the PHP scripts
+//! distributed with the original UPM Java application, for those who want to host their own
+//! repository.
+//!
+//! Databases are stored as files in a single directory.  The server understands the following
+//! requests, all of which require HTTP Basic authentication:
+//!
+//! * `GET` or `HEAD` of a database name: Download the database.
+//! * `POST` to `upload.php`: Upload a database as the `userfile` field of a multipart form.  An
+//!   existing database is never overwritten; it must be deleted first.
+//! * `POST` to `deletefile.php`: Delete the database named by the `fileToDelete` form field.
+//!
+//! Only the final component of the request path is significant, so the server may be placed
+//! behind a reverse proxy at any prefix.  The server speaks plain HTTP; a reverse proxy should be
+//! used to provide HTTPS when the repository is reachable by anyone else.
+//!
+//! This is a deliberately simple server: each connection is handled by its own thread, serves a
+//! single request, and is then closed.  Requests are processed one at a time.  The number of
+//! connections served at once is limited, as is the time a client may take to send a request,
+//! and the body of a request is only read once its credentials have been checked.
+
+use base64;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crypto;
+use error::UpmError;
+use sync::{DirectoryRepository, SyncBackend};
+
+/// The UPM sync protocol's delete command.
+const DELETE_CMD: &str = "deletefile.php";
+/// The UPM sync protocol's upload command.
+const UPLOAD_CMD: &str = "upload.php";
+/// The form field containing the uploaded database.
+const UPLOAD_FIELD_NAME: &str = "userfile";
+/// The form field naming the database to delete.
+const DELETE_FIELD_NAME: &str = "fileToDelete";
+
+/// Protocol responses, as returned by the original PHP scripts.
+const UPM_SUCCESS: &str = "OK";
+const UPM_FILE_DOESNT_EXIST: &str = "FILE_DOESNT_EXIST";
+const UPM_FILE_WASNT_DELETED: &str = "FILE_WASNT_DELETED";
+const UPM_FILE_ALREADY_EXISTS: &str = "FILE_ALREADY_EXISTS";
+const UPM_FILE_WASNT_UPLOADED: &str = "FILE_WASNT_UPLOADED";
+
+/// The MIME type used when serving a database.
+const DATABASE_MIME_TYPE: &str = "application/octet-stream";
+
+/// The realm presented to clients which fail to authenticate.
+const AUTH_REALM: &str = "UPM";
+
+/// Reject requests with bodies larger than this size.  UPM databases are much smaller.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+/// Reject requests with more header lines than this.
+const MAX_HEADERS: usize = 100;
+/// Reject requests with any line longer than this.
+const MAX_LINE_LENGTH: usize = 8192;
+/// Reject connections while this many are already being served.
+const MAX_CONNECTIONS: usize = 32;
+/// Close connections which are idle for this long while a request is read or a response written.
+const IO_TIMEOUT_SECS: u64 = 30;
+/// After rejecting a request without reading its body, discard up to this much of the body before
+/// closing the connection.  Closing a connection with unread data makes the client see a reset,
+/// often before it has read the response.
+const MAX_DISCARD_SIZE: u64 = 64 * 1024;
+
+/// The state shared by all connection threads.
+struct ServerState {
+    repository: Mutex<DirectoryRepository>,
+    username: String,
+    password: String,
+    /// The number of connections currently being served.
+    connections: AtomicUsize,
+}
+
+/// Count a connection as being served until this is dropped.
+struct ConnectionGuard<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for ConnectionGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A UPM sync protocol server bound to a listening socket.
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<ServerState>,
+}
+
+/// A parsed HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Return the value of the named header, if present.  Header names are case-insensitive.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.0.eq_ignore_ascii_case(name))
+            .map(|h| h.1.as_str())
+    }
+}
+
+/// An HTTP response to be sent.
+struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, reason: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            reason,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// Return a "200 OK" response with the provided UPM protocol response code as its body.
+    fn protocol(code: &str) -> Response {
+        Response::new(200, "OK", code.as_bytes().to_vec())
+    }
+
+    /// Return an error response with the reason phrase as its body.
+    fn error(status: u16, reason: &'static str) -> Response {
+        Response::new(status, reason, reason.as_bytes().to_vec())
+    }
+
+    /// Return a response asking the client to authenticate.
+    fn unauthorized() -> Response {
+        let mut response = Response::error(401, "Unauthorized");
+        response.headers.push((
+            "WWW-Authenticate",
+            format!("Basic realm=\"{}\"", AUTH_REALM),
+        ));
+        response
+    }
+}
+
+impl Server {
+    /// Create a new server listening on the provided address, which serves the databases in the
+    /// provided directory to clients presenting the provided credentials.
+    pub fn bind<A: ToSocketAddrs, P: AsRef<Path>>(
+        address: A,
+        directory: P,
+        username: &str,
+        password: &str,
+    ) -> Result<Server, UpmError> {
+        let repository = DirectoryRepository::new(directory)?;
+        let listener = TcpListener::bind(address)?;
+        Ok(Server {
+            listener,
+            state: Arc::new(ServerState {
+                repository: Mutex::new(repository),
+                username: String::from(username),
+                password: String::from(password),
+                connections: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    /// Return the address the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr, UpmError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept and serve connections until an error occurs.
+    pub fn run(&self) -> Result<(), UpmError> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            // Errors here only affect this connection (e.g. the client went away), so there is
+            // nothing more to do about them.
+            if self.state.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                self.state.connections.fetch_sub(1, Ordering::SeqCst);
+                let response = Response::error(503, "Service Unavailable");
+                write_response(&stream, &response, false).unwrap_or_default();
+                continue;
+            }
+            let state = self.state.clone();
+            thread::spawn(move || {
+                let _guard = ConnectionGuard(&state.connections);
+                handle_connection(&state, stream).unwrap_or_default();
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Serve a single request on the provided connection.  The credentials are checked before the
+/// body of the request is read.
+fn handle_connection(state: &ServerState, stream: TcpStream) -> Result<(), UpmError> {
+    let timeout = Some(Duration::from_secs(IO_TIMEOUT_SECS));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let (response, head, body_read) = match read_request_head(&mut reader) {
+        Ok(mut request) => {
+            let head = request.method == "HEAD";
+            if !is_authorized(state, &request) {
+                (Response::unauthorized(), head, false)
+            } else {
+                match read_body(&mut reader, &mut request) {
+                    Ok(()) => (handle_request(state, &request), head, true),
+                    Err(response) => (response, false, false),
+                }
+            }
+        }
+        Err(response) => (response, false, false),
+    };
+    write_response(&stream, &response, head)?;
+    if !body_read {
+        stream.shutdown(Shutdown::Write)?;
+        io::copy(&mut reader.take(MAX_DISCARD_SIZE), &mut io::sink())?;
+    }
+    Ok(())
+}
+
+/// Read a line terminated by CRLF (or a bare LF), without the terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, Response> {
+    let mut line = Vec::new();
+    match reader
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', &mut line)
+    {
+        Ok(_) if line.ends_with(b"\n") => {}
+        _ => return Err(Response::error(400, "Bad Request")),
+    }
+    while line.ends_with(b"\n") || line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| Response::error(400, "Bad Request"))
+}
+
+/// Read and parse the request line and headers of an HTTP request.  The body is left to
+/// [`read_body()`](fn.read_body.html).  If the request is malformed, the appropriate error
+/// response is returned instead.
+fn read_request_head<R: BufRead>(reader: &mut R) -> Result<Request, Response> {
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m, t),
+        _ => return Err(Response::error(400, "Bad Request")),
+    };
+    let path = match target.find('?') {
+        Some(i) => &target[..i],
+        None => target,
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err(Response::error(431, "Request Header Fields Too Large"));
+        }
+        match line.find(':') {
+            Some(i) => headers.push((
+                line[..i].trim().to_string(),
+                line[i + 1..].trim().to_string(),
+            )),
+            None => return Err(Response::error(400, "Bad Request")),
+        }
+    }
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Read the body of an HTTP request, if there is one, as specified by its headers.  If the body
+/// cannot be read, the appropriate error response is returned instead.
+fn read_body<R: BufRead>(reader: &mut R, request: &mut Request) -> Result<(), Response> {
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(Response::error(411, "Length Required"));
+    }
+    let length = match request.header("Content-Length").map(|l| l.parse::<usize>()) {
+        Some(Ok(l)) => l,
+        Some(Err(_)) => return Err(Response::error(400, "Bad Request")),
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(Response::error(413, "Payload Too Large"));
+    }
+    request.body.resize(length, 0);
+    if reader.read_exact(&mut request.body).is_err() {
+        return Err(Response::error(400, "Bad Request"));
+    }
+    Ok(())
+}
+
+/// Write the response to the connection.  If `head` is true, the body is omitted as required for
+/// responses to HEAD requests.
+fn write_response(mut stream: &TcpStream, response: &Response, head: bool) -> Result<(), UpmError> {
+    let mut header = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason);
+    for &(name, ref value) in response.headers.iter() {
+        header.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    header.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    ));
+    stream.write_all(header.as_bytes())?;
+    if !head {
+        stream.write_all(&response.body)?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Return true if the request carries HTTP Basic credentials matching the server's.
+fn is_authorized(state: &ServerState, request: &Request) -> bool {
+    let credentials = match request
+        .header("Authorization")
+        .and_then(|a| a.strip_prefix("Basic "))
+        .and_then(|c| base64::decode(c.trim()).ok())
+    {
+        Some(c) => c,
+        None => return false,
+    };
+    let expected = format!("{}:{}", state.username, state.password);
+    // Compare digests, so the comparison takes the same time regardless of where the credentials
+    // differ (or their length).
+    crypto::constant_time_eq(
+        &crypto::sha256(&credentials),
+        &crypto::sha256(expected.as_bytes()),
+    )
+}
+
+/// Dispatch an authenticated request to the appropriate handler.
+fn handle_request(state: &ServerState, request: &Request) -> Response {
+    let name = match request.path.rfind('/') {
+        Some(i) => &request.path[i + 1..],
+        None => return Response::error(400, "Bad Request"),
+    };
+    let name = match percent_decode(name) {
+        Some(n) => n,
+        None => return Response::error(400, "Bad Request"),
+    };
+    // A thread which panicked while holding the lock may have left the repository in any state.
+    let mut repository = match state.repository.lock() {
+        Ok(r) => r,
+        Err(_) => return Response::error(500, "Internal Server Error"),
+    };
+    match (request.method.as_str(), name.as_str()) {
+        ("POST", UPLOAD_CMD) => handle_upload(&mut repository, request),
+        ("POST", DELETE_CMD) => handle_delete(&mut repository, request),
+        ("GET", _) | ("HEAD", _) => match repository.download(&name) {
+            Ok(bytes) => {
+                let mut response = Response::new(200, "OK", bytes);
+                response
+                    .headers
+                    .push(("Content-Type", String::from(DATABASE_MIME_TYPE)));
+                response
+            }
+            Err(UpmError::SyncDatabaseNotFound) | Err(UpmError::InvalidFilename) => {
+                Response::error(404, "Not Found")
+            }
+            Err(_) => Response::error(500, "Internal Server Error"),
+        },
+        _ => Response::error(405, "Method Not Allowed"),
+    }
+}
+
+/// Store the database uploaded in a multipart form.
+fn handle_upload(repository: &mut DirectoryRepository, request: &Request) -> Response {
+    let boundary = match request
+        .header("Content-Type")
+        .filter(|t| t.starts_with("multipart/form-data"))
+        .and_then(|t| header_parameter(t, "boundary"))
+    {
+        Some(b) => b,
+        None => return Response::error(400, "Bad Request"),
+    };
+    let (filename, bytes) = match multipart_file(&request.body, &boundary, UPLOAD_FIELD_NAME) {
+        Some(f) => f,
+        None => return Response::protocol(UPM_FILE_WASNT_UPLOADED),
+    };
+    match repository.exists(&filename) {
+        Ok(false) => {}
+        Ok(true) => return Response::protocol(UPM_FILE_ALREADY_EXISTS),
+        Err(_) => return Response::protocol(UPM_FILE_WASNT_UPLOADED),
+    }
+    match repository.upload(&filename, bytes) {
+        Ok(()) => Response::protocol(UPM_SUCCESS),
+        Err(_) => Response::protocol(UPM_FILE_WASNT_UPLOADED),
+    }
+}
+
+/// Delete the database named in a URL-encoded form.
+fn handle_delete(repository: &mut DirectoryRepository, request: &Request) -> Response {
+    let filename = str::from_utf8(&request.body).ok().and_then(|body| {
+        body.split('&')
+            .filter_map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(DELETE_FIELD_NAME), Some(value)) => form_decode(value),
+                    _ => None,
+                }
+            })
+            .next()
+    });
+    let filename = match filename {
+        Some(f) => f,
+        None => return Response::protocol(UPM_FILE_WASNT_DELETED),
+    };
+    match repository.delete(&filename) {
+        Ok(()) => Response::protocol(UPM_SUCCESS),
+        Err(UpmError::SyncDatabaseNotFound) => Response::protocol(UPM_FILE_DOESNT_EXIST),
+        Err(_) => Response::protocol(UPM_FILE_WASNT_DELETED),
+    }
+}
+
+/// Decode a percent-encoded URL component.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                let hex = str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Decode a value from a URL-encoded form, where `+` also represents a space.
+fn form_decode(s: &str) -> Option<String> {
+    percent_decode(&s.replace('+', " "))
+}
+
+/// Return the value of the named parameter within a header value such as
+/// `form-data; name="userfile"; filename="primary"`.
+fn header_parameter(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|parameter| {
+        let mut parameter = parameter.splitn(2, '=');
+        match (parameter.next(), parameter.next()) {
+            (Some(n), Some(v)) if n.trim().eq_ignore_ascii_case(name) => {
+                Some(v.trim().trim_matches('"').to_string())
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Return the index of the first occurrence of `needle` in `haystack` at or after `start`.
+fn find_bytes(haystack: &[u8], needle: &[u8], start: usize) -> Option<usize> {
+    if start > haystack.len() {
+        return None;
+    }
+    haystack[start..]
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| i + start)
+}
+
+/// Return the filename and contents of the named file field in a multipart form body.
+fn multipart_file(body: &[u8], boundary: &str, field_name: &str) -> Option<(String, Vec<u8>)> {
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // The first delimiter is not preceded by a line break.
+    let mut position = find_bytes(body, &delimiter[2..], 0)? + delimiter.len() - 2;
+    loop {
+        if body[position..].starts_with(b"--") {
+            // This was the closing delimiter.
+            return None;
+        }
+        let headers_start = find_bytes(body, b"\r\n", position)? + 2;
+        let headers_end = find_bytes(body, b"\r\n\r\n", headers_start - 2)?;
+        let content_start = headers_end + 4;
+        let content_end = find_bytes(body, &delimiter, content_start)?;
+
+        let headers = str::from_utf8(&body[headers_start..headers_end]).ok()?;
+        let disposition = headers.split("\r\n").find_map(|h| {
+            let mut h = h.splitn(2, ':');
+            match (h.next(), h.next()) {
+                (Some(n), Some(v)) if n.trim().eq_ignore_ascii_case("Content-Disposition") => {
+                    Some(v.trim())
+                }
+                _ => None,
+            }
+        });
+        if let Some(disposition) = disposition {
+            let name = header_parameter(disposition, "name");
+            let filename = header_parameter(disposition, "filename");
+            if let (Some(ref n), Some(f)) = (name, filename) {
+                if n == field_name {
+                    return Some((f, body[content_start..content_end].to_vec()));
+                }
+            }
+        }
+        position = content_end + delimiter.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn test_multipart_file() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"other\"\r\n\
+            \r\n\
+            ignored\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"userfile\"; filename=\"primary\"\r\n\
+            Content-Type: application/octet-stream\r\n\
+            \r\n\
+            \x01\x02\r\n\x03\r\n\
+            --XyZ--\r\n";
+        assert_eq!(
+            multipart_file(body, "XyZ", "userfile"),
+            Some((String::from("primary"), vec![1, 2, b'\r', b'\n', 3]))
+        );
+        assert_eq!(multipart_file(body, "XyZ", "missing"), None);
+        assert_eq!(multipart_file(b"garbage", "XyZ", "userfile"), None);
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c"), Some(String::from("a b+c")));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(form_decode("a%20b+c%2B"), Some(String::from("a b c+")));
+    }
+
+    #[test]
+    fn test_unauthorized_body_not_read() {
+        let directory = tempfile::tempdir().unwrap();
+        let server = Server::bind("127.0.0.1:0", directory.path(), "user", "pass").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // The server answers as soon as the headers show that the request is not authorized,
+        // without waiting for the announced body.
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+            .write_all(
+                b"POST /upload.php HTTP/1.1\r\n\
+                  Authorization: Basic dXNlcjp3cm9uZw==\r\n\
+                  Content-Length: 1000000\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        BufReader::new(&stream).read_line(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 "));
+    }
+}
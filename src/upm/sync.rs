@@ -1,36 +1,78 @@
 //! This module supports synchronizing a UPM database with a copy on a remote repository.  The
-//! remote repository should be an HTTP or HTTPS server supporting the "download", "upload", and
-//! "delete" primitives of the UPM sync protocol.
+//! remote repository is accessed through a [`SyncBackend`](trait.SyncBackend.html), which provides
+//! the "download", "upload", "delete", and "exists" primitives.  The sync logic itself -- comparing
+//! revisions and deciding which copy to replace -- is independent of the backend.
+//!
+//! The following backends are provided:
+//!
+//! * [`Repository`](struct.Repository.html): An HTTP or HTTPS server supporting the UPM sync
+//!   protocol, as used by the original UPM Java application.
+//! * [`DirectoryRepository`](struct.DirectoryRepository.html): A directory in the local
+//!   filesystem, specified with a `file://` URL.  This is useful for shared folders.
+//! * [`WebDavRepository`](struct.WebDavRepository.html): A WebDAV server, specified with a
+//!   `webdav://` or `webdavs://` URL.
+//! * [`GitRepository`](struct.GitRepository.html): A git remote, specified with a `git+` URL.
+//! * [`S3Repository`](struct.S3Repository.html): An S3-compatible object store, specified with an
+//!   `s3://` URL.
+//!
+//! The HTTP-based backends accept [`HttpOptions`](struct.HttpOptions.html) selecting the
+//! authentication scheme, a private CA bundle, a client certificate, and a proxy.  For a database,
+//! these are taken from its [`SyncSettings`](struct.SyncSettings.html).
+//!
+//! The backup copies uploaded before each remote database is replaced are recorded in the
+//! database's [`SyncState`](struct.SyncState.html), and may be deleted again with
+//! [`prune_remote_backups()`](fn.prune_remote_backups.html).  If the settings specify a
+//! [`RemoteBackupPolicy`](struct.RemoteBackupPolicy.html), this happens after each upload.
 
-use reqwest::multipart;
-use std::io::Read;
+use std::cmp::{self, Ordering};
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::str;
-use std::time::Duration;
 
 use backup;
-use database::Database;
+use database::{Database, DatabaseFormat};
 use error::UpmError;
+use merge::{AccountField, Conflict};
 
-/// The UPM sync protocol's delete command.  This is appended to the repository URL.
-const DELETE_CMD: &'static str = "deletefile.php";
-/// The UPM sync protocol's upload command.  This is appended to the repository URL.
-const UPLOAD_CMD: &'static str = "upload.php";
-/// This field name is used for the database file when uploading.
-const UPM_UPLOAD_FIELD_NAME: &'static str = "userfile";
-/// Abort the operation if the server doesn't respond for this time interval.
-const TIMEOUT_SECS: u64 = 10;
+mod client;
+mod directory;
+mod git;
+mod http;
+mod prune;
+mod retry;
+mod s3;
+mod settings;
+#[cfg(test)]
+mod stand_in;
+mod state;
+mod webdav;
 
-/// The UPM sync protocol returns an HTTP body of "OK" if the request was successful, otherwise it
-/// returns one of these error codes: FILE_DOESNT_EXIST, FILE_WASNT_DELETED, FILE_ALREADY_EXISTS,
-/// FILE_WASNT_MOVED, FILE_WASNT_UPLOADED
-const UPM_SUCCESS: &'static str = "OK";
+pub use self::client::{HttpAuth, HttpAuthScheme, HttpOptions, DEFAULT_TIMEOUT_SECS};
+pub use self::directory::DirectoryRepository;
+pub use self::git::GitRepository;
+pub use self::http::Repository;
+pub use self::prune::{prune_remote_backups, RemoteBackupPolicy, DEFAULT_REMOTE_BACKUPS_KEEP};
+pub use self::retry::{RetryingBackend, DEFAULT_RETRIES};
+pub use self::s3::{S3Location, S3Repository};
+pub use self::settings::SyncSettings;
+pub use self::state::{restore_sync_status, SyncState};
+pub use self::webdav::WebDavRepository;
 
-/// UPM sync protocol responses should never be longer than this size.
-const UPM_MAX_RESPONSE_CODE_LENGTH: usize = 64;
+/// Upload a database at most this many times if it does not arrive intact.
+const UPLOAD_ATTEMPTS: u32 = 2;
 
-/// The MIME type used when uploading a database.
-const DATABASE_MIME_TYPE: &'static str = "application/octet-stream";
+/// Number remote backups made within the same second at most this high before giving up on a
+/// unique name.
+const MAX_BACKUP_SEQUENCE: u32 = 100;
+
+/// The outcome reported when a sync fails before it changes the remote database.
+const REMOTE_UNCHANGED: &str = "The remote database was not changed.";
+
+/// The error reported when a hardened database would be uploaded to a repository which only
+/// holds version 3 databases.
+const HARDENED_REFUSED: &str = "This database is in the hardened format, which the UPM sync \
+                                server and the UPM Java client cannot read.  Convert it with \
+                                \"tupm migrate --to 3\" before syncing with this repository.";
 
 impl From<reqwest::Error> for UpmError {
     /// Convert a reqwest error into a `UpmError`.
@@ -40,6 +82,7 @@ impl From<reqwest::Error> for UpmError {
 }
 
 /// A successful sync will result in one of these three conditions.
+#[derive(Debug)]
 pub enum SyncResult {
     /// The remote repository's copy of the database was replaced with the local copy.
     RemoteSynced,
@@ -48,189 +91,848 @@ pub enum SyncResult {
     /// Neither the local database nor the remote database was changed, since they were both the
     /// same revision.
     NeitherSynced,
+    /// Both databases were changed since they were last synced, so they were merged.  The merged
+    /// database replaced both the local database and the remote repository's copy.
+    Merged,
+    /// Both databases were changed since they were last synced, and merging them produced
+    /// conflicts.  Neither database has been replaced yet: the user should review the conflicts of
+    /// the pending merge, which is then completed by [`finish_merge()`](fn.finish_merge.html).
+    Conflicted(Box<PendingMerge>),
 }
 
-/// Provide basic access to the remote repository.
-struct Repository {
-    url: String,
-    http_username: String,
-    http_password: String,
-    client: reqwest::Client,
+/// A merge which produced conflicts, and is waiting for the user to resolve them before the merged
+/// database replaces the local and remote databases.
+#[derive(Debug)]
+pub struct PendingMerge {
+    /// The merged database, which has not been saved.  Its conflicts were provisionally resolved
+    /// as described in the [`merge`](../merge/index.html) module, and any other resolutions the
+    /// user chooses should be applied to it.
+    pub merged: Database,
+    /// The conflicts of the merge.
+    pub conflicts: Vec<Conflict>,
+    /// The remote database which was merged, or `None` if there was none.
+    remote: Option<Database>,
 }
 
-impl Repository {
-    /// Create a new `Repository` struct with the provided URL and credentials.
-    fn new(url: &str, http_username: &str, http_password: &str) -> Result<Repository, UpmError> {
-        // Create a new reqwest client.
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(TIMEOUT_SECS))
-            .build()?;
+/// The steps of a sync, as reported by [`sync_with_progress()`](fn.sync_with_progress.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncProgress {
+    /// The remote database is being downloaded.
+    Downloading,
+    /// The local and remote databases are being merged.
+    Merging,
+    /// The database is being uploaded to the remote repository.
+    Uploading,
+    /// The remote database is being saved over the local database.
+    Saving,
+}
 
-        Ok(Repository {
-            url: String::from(url),
-            http_username: String::from(http_username),
-            http_password: String::from(http_password),
-            client,
-        })
+impl fmt::Display for SyncProgress {
+    /// Provide human-readable descriptions of the sync steps.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyncProgress::Downloading => write!(f, "Downloading the remote database"),
+            SyncProgress::Merging => write!(f, "Merging local and remote changes"),
+            SyncProgress::Uploading => write!(f, "Uploading the database"),
+            SyncProgress::Saving => write!(f, "Saving the remote database locally"),
+        }
     }
+}
 
-    //
-    // Provide the three operations of the UPM sync protocol:
-    // Download, delete, and upload.
-    //
+/// The way a sync would copy the databases, as reported by [`status()`](fn.status.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// The local database would replace the remote database.
+    Upload,
+    /// The remote database would replace the local database.
+    Download,
+    /// The local and remote databases would be merged, and the result would replace both.
+    Merge,
+    /// Neither database would be changed.
+    Neither,
+}
 
-    /// Download the remote database with the provided name.  The database is returned in raw form
-    /// as a byte buffer.
-    fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
-        let url = self.make_url(database_name);
-
-        // Send request
-        let mut response = self
-            .client
-            .get(&url)
-            .basic_auth(self.http_username.clone(), Some(self.http_password.clone()))
-            .send()?;
-
-        // Process response
-        if !response.status().is_success() {
-            return match response.status() {
-                reqwest::StatusCode::NOT_FOUND => Err(UpmError::SyncDatabaseNotFound),
-                _ => Err(UpmError::Sync(format!("{}", response.status()))),
-            };
+impl fmt::Display for SyncDirection {
+    /// Describe what a sync in this direction would do.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyncDirection::Upload => write!(f, "upload the local database"),
+            SyncDirection::Download => write!(f, "replace the local database with the remote one"),
+            SyncDirection::Merge => write!(f, "merge the local and remote changes"),
+            SyncDirection::Neither => write!(f, "change nothing"),
         }
-        let mut data: Vec<u8> = Vec::new();
-        response.read_to_end(&mut data)?;
-        Ok(data)
     }
+}
 
-    /// Delete the specified database from the remote repository.
-    fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
-        let url = self.make_url(DELETE_CMD);
+/// An account which differs between the local and remote databases.  Accounts are matched by
+/// name, so a renamed account appears as one account present on each side.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccountDifference {
+    /// The named account is only present in the local database.
+    LocalOnly(String),
+    /// The named account is only present in the remote database.
+    RemoteOnly(String),
+    /// The named account is present in both databases, but the listed fields differ.
+    Changed(String, Vec<AccountField>),
+}
+
+impl fmt::Display for AccountDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AccountDifference::LocalOnly(ref name) => write!(f, "+ {} (local only)", name),
+            AccountDifference::RemoteOnly(ref name) => write!(f, "- {} (remote only)", name),
+            AccountDifference::Changed(ref name, ref fields) => {
+                let fields: Vec<&str> = fields.iter().map(|f| f.name()).collect();
+                write!(f, "* {} ({} differ)", name, fields.join(", "))
+            }
+        }
+    }
+}
 
-        // Send request
-        let mut response = self
-            .client
-            .post(&url)
-            .basic_auth(self.http_username.clone(), Some(self.http_password.clone()))
-            .form(&[("fileToDelete", database_name)])
-            .send()?;
+/// The result of comparing the local database with the copy in its remote repository, as
+/// reported by [`status()`](fn.status.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncStatus {
+    /// The revision of the local database.
+    pub local_revision: u32,
+    /// The revision of the remote database, or `None` if the repository does not hold it yet.
+    pub remote_revision: Option<u32>,
+    /// What a sync would do.
+    pub direction: SyncDirection,
+    /// The accounts which differ between the two databases.
+    pub differences: Vec<AccountDifference>,
+}
 
-        // Process response
-        self.check_response(&mut response)?;
-        Ok(())
+impl fmt::Display for SyncStatus {
+    /// Summarize the status on several lines, suitable for showing to the user.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Local revision:  {}", self.local_revision)?;
+        match self.remote_revision {
+            Some(revision) => writeln!(f, "Remote revision: {}", revision)?,
+            None => writeln!(f, "Remote revision: none (not uploaded yet)")?,
+        }
+        writeln!(f, "A sync would {}.", self.direction)?;
+        if self.differences.is_empty() {
+            write!(f, "The accounts are identical.")
+        } else {
+            write!(f, "{} account(s) differ:", self.differences.len())?;
+            for difference in self.differences.iter() {
+                write!(f, "\n  {}", difference)?;
+            }
+            Ok(())
+        }
     }
+}
+
+/// A sync backend provides access to a remote repository which may hold one or more UPM databases,
+/// each identified by name.  Databases are transferred in their raw (encrypted) form, so backends
+/// never need to know the database password.
+///
+/// Implementing this trait allows [`sync_with_backend()`](fn.sync_with_backend.html) to be used
+/// with any kind of storage.
+pub trait SyncBackend {
+    /// Download the remote database with the provided name.  The database is returned in raw form
+    /// as a byte buffer.  If the database does not exist,
+    /// [`UpmError::SyncDatabaseNotFound`](../error/enum.UpmError.html#variant.SyncDatabaseNotFound)
+    /// must be returned.
+    fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError>;
 
     /// Upload the provided database to the remote repository.  The database is provided in raw
     /// form as a byte buffer.
-    fn upload(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
-        let url: String = self.make_url(UPLOAD_CMD);
+    fn upload(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError>;
 
-        // Thanks to Sean (seanmonstar) for helping to translate this code to multipart code
-        // of reqwest
-        let part = multipart::Part::bytes(database_bytes.clone())
-            .file_name(database_name.to_string())
-            .mime_str(DATABASE_MIME_TYPE)?;
+    /// Delete the specified database from the remote repository.  If the database does not exist,
+    /// [`UpmError::SyncDatabaseNotFound`](../error/enum.UpmError.html#variant.SyncDatabaseNotFound)
+    /// should be returned.
+    fn delete(&mut self, database_name: &str) -> Result<(), UpmError>;
 
-        let form = multipart::Form::new().part(UPM_UPLOAD_FIELD_NAME, part);
+    /// Return true if the specified database is present in the remote repository; otherwise return
+    /// false.
+    fn exists(&mut self, database_name: &str) -> Result<bool, UpmError>;
 
-        // Send request
-        let mut response = self.client.post(&url).multipart(form).send()?;
-
-        // Process response
-        self.check_response(&mut response)?;
-        Ok(())
+    /// Return the names of the files in the remote repository, such as databases and their backup
+    /// copies, in no particular order.  Not every kind of repository can be listed: the default
+    /// implementation returns an error.
+    fn list(&mut self) -> Result<Vec<String>, UpmError> {
+        Err(UpmError::Sync(String::from(
+            "This kind of repository cannot be listed.",
+        )))
     }
 
-    /// Construct a URL by appending the provided string to the repository URL, adding a separating
-    /// slash character if needed.
-    fn make_url(&self, path_component: &str) -> String {
-        if self.url.ends_with('/') {
-            format!("{}{}", self.url, path_component)
-        } else {
-            format!("{}/{}", self.url, path_component)
+    /// Replace the specified database in the remote repository with the provided database, which
+    /// may or may not already exist.  The default implementation deletes any existing database and
+    /// then uploads the new one, which leaves a window where the remote database is missing.
+    /// Backends which are able to replace a database atomically should override this.
+    fn replace(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        if self.exists(database_name)? {
+            self.delete(database_name).map_err(|e| {
+                UpmError::Sync(format!(
+                    "Deleting the old remote database failed: {}",
+                    error_detail(&e)
+                ))
+            })?;
         }
+        self.upload(database_name, database_bytes)
     }
 
-    /// Confirm that the HTTP response was successful and valid.
-    fn check_response(&self, response: &mut reqwest::Response) -> Result<(), UpmError> {
-        if !response.status().is_success() {
-            return Err(UpmError::Sync(format!("{}", response.status())));
-        }
-        let mut response_code = String::new();
-        response.read_to_string(&mut response_code)?;
-        if response_code.len() > UPM_MAX_RESPONSE_CODE_LENGTH {
-            return Err(UpmError::Sync(format!(
-                "Unexpected response from server ({} bytes)",
-                response_code.len()
-            )));
-        }
-        if response_code != UPM_SUCCESS {
-            return Err(UpmError::Sync(format!("Server error: {}", response_code)));
+    /// Return true if the backend preserves every previous version of a database by itself, in
+    /// which case `sync()` does not upload separate backup copies.  The default is false.
+    fn keeps_history(&self) -> bool {
+        false
+    }
+
+    /// Return true if the backend may hold databases in the hardened format, which only tupm can
+    /// read.  Backends which are shared with other UPM clients should return false.  The default
+    /// is true.
+    fn accepts_hardened_format(&self) -> bool {
+        true
+    }
+}
+
+/// Return true if the provided repository URL refers to a backend that needs a username and
+/// password; otherwise return false.
+pub fn url_requires_credentials(repo_url: &str) -> bool {
+    DirectoryRepository::url_to_path(repo_url).is_none()
+        && GitRepository::parse_url(repo_url).is_none()
+}
+
+/// Return true if the backend for the provided repository URL may hold databases in the hardened
+/// format; otherwise return false.  Only the UPM sync server refuses them, since it is shared with
+/// the UPM Java client.
+pub fn url_accepts_hardened_format(repo_url: &str) -> bool {
+    DirectoryRepository::url_to_path(repo_url).is_some()
+        || GitRepository::parse_url(repo_url).is_some()
+        || S3Repository::is_s3_url(repo_url)
+        || WebDavRepository::url_to_http(repo_url).is_some()
+}
+
+/// Return an error if the provided database is in the hardened format, and the repository only
+/// accepts version 3 databases.
+fn check_format<B: SyncBackend + ?Sized>(repo: &B, database: &Database) -> Result<(), UpmError> {
+    match database.format() {
+        DatabaseFormat::Hardened(_) if !repo.accepts_hardened_format() => {
+            Err(UpmError::Sync(String::from(HARDENED_REFUSED)))
         }
-        Ok(())
+        _ => Ok(()),
+    }
+}
+
+/// Open the sync backend for the provided repository URL.  The username and password are only
+/// used by backends which require credentials.  HTTP-based backends use the default
+/// [`HttpOptions`](struct.HttpOptions.html).
+pub fn open_url(
+    repo_url: &str,
+    repo_username: &str,
+    repo_password: &str,
+) -> Result<Box<dyn SyncBackend>, UpmError> {
+    open_url_with_options(repo_url, repo_username, repo_password, &Default::default())
+}
+
+/// Open the sync backend for the provided repository URL, using the provided options for
+/// HTTP-based backends.
+pub fn open_url_with_options(
+    repo_url: &str,
+    repo_username: &str,
+    repo_password: &str,
+    options: &HttpOptions,
+) -> Result<Box<dyn SyncBackend>, UpmError> {
+    if let Some(path) = DirectoryRepository::url_to_path(repo_url) {
+        return Ok(Box::new(DirectoryRepository::new(path)?));
     }
+    if GitRepository::parse_url(repo_url).is_some() {
+        return Ok(Box::new(GitRepository::new(repo_url, None)?));
+    }
+    if S3Repository::is_s3_url(repo_url) {
+        return Ok(Box::new(S3Repository::with_options(
+            repo_url,
+            repo_username,
+            repo_password,
+            options,
+        )?));
+    }
+    if WebDavRepository::url_to_http(repo_url).is_some() {
+        return Ok(Box::new(WebDavRepository::with_options(
+            repo_url,
+            repo_username,
+            repo_password,
+            options,
+        )?));
+    }
+    Ok(Box::new(Repository::with_options(
+        repo_url,
+        repo_username,
+        repo_password,
+        options,
+    )?))
+}
+
+/// Open the sync backend configured for the provided database.  The backend is selected based on
+/// the database's sync URL, and any needed credentials are taken from the account named by the
+/// database's sync credentials.  HTTP-based backends use the options in the database's
+/// [`SyncSettings`](struct.SyncSettings.html), and failed downloads are retried as many times as
+/// the settings allow.
+pub fn open_backend(database: &Database) -> Result<Box<dyn SyncBackend>, UpmError> {
+    if database.sync_url.is_empty() {
+        return Err(UpmError::NoSyncURL);
+    }
+    let settings = SyncSettings::load_for(database)?;
+    let backend = open_configured_backend(database, &settings)?;
+    Ok(Box::new(RetryingBackend::new(
+        backend,
+        settings.retries.unwrap_or(DEFAULT_RETRIES),
+    )))
+}
+
+/// Open the sync backend for the provided database as described in
+/// [`open_backend()`](fn.open_backend.html), without retrying failed operations.
+fn open_configured_backend(
+    database: &Database,
+    settings: &SyncSettings,
+) -> Result<Box<dyn SyncBackend>, UpmError> {
+    if GitRepository::parse_url(&database.sync_url).is_some() {
+        // Keep a persistent clone next to the database, so each sync only needs to fetch new
+        // commits.
+        let work_dir = GitRepository::work_dir_for(database);
+        return Ok(Box::new(GitRepository::new(
+            &database.sync_url,
+            work_dir.as_deref(),
+        )?));
+    }
+    if !url_requires_credentials(&database.sync_url) {
+        return open_url(&database.sync_url, "", "");
+    }
+    let options = settings.http_options(database)?;
+    if database.sync_credentials.is_empty() {
+        return Err(UpmError::NoSyncCredentials);
+    }
+    let sync_account = match database.account(&database.sync_credentials) {
+        Some(a) => a,
+        None => return Err(UpmError::NoSyncCredentials),
+    };
+    open_url_with_options(
+        &database.sync_url,
+        &sync_account.user,
+        &sync_account.password,
+        &options,
+    )
+}
+
+/// Return the name of the provided database in its remote repository.  This is the name of the
+/// local database file, unless the database's [`SyncSettings`](struct.SyncSettings.html) specify
+/// a different remote name.
+pub fn remote_name(database: &Database) -> Result<String, UpmError> {
+    let local_name = match database.name() {
+        Some(n) => n,
+        None => return Err(UpmError::NoDatabaseFilename),
+    };
+    let settings = SyncSettings::load_for(database)?;
+    Ok(settings
+        .remote_name
+        .unwrap_or_else(|| String::from(local_name)))
 }
 
 /// Download a database from the remote repository without performing any sync operation with a
 /// local database.  This is useful when downloading an existing remote database for the first
-/// time.
+/// time.  The remote database with the same name as the local database file is downloaded.
 pub fn download<P: AsRef<Path>>(
     repo_url: &str,
     repo_username: &str,
     repo_password: &str,
     database_filename: P,
 ) -> Result<Vec<u8>, UpmError> {
-    let mut repo = Repository::new(repo_url, repo_username, repo_password)?;
     let name = Database::path_to_name(&database_filename)?;
-    repo.download(&name)
+    download_named(repo_url, repo_username, repo_password, name)
+}
+
+/// Download the named database from the remote repository without performing any sync operation
+/// with a local database.  If it is saved under a different local name, the remote name should be
+/// recorded in the local database's [`SyncSettings`](struct.SyncSettings.html), so that syncs use
+/// the right remote database.
+pub fn download_named(
+    repo_url: &str,
+    repo_username: &str,
+    repo_password: &str,
+    database_name: &str,
+) -> Result<Vec<u8>, UpmError> {
+    let mut repo = open_url(repo_url, repo_username, repo_password)?;
+    repo.download(database_name)
+}
+
+/// List the databases in the remote repository, as described in
+/// [`list_databases()`](fn.list_databases.html).
+pub fn list(
+    repo_url: &str,
+    repo_username: &str,
+    repo_password: &str,
+) -> Result<Vec<String>, UpmError> {
+    let mut repo = open_url(repo_url, repo_username, repo_password)?;
+    list_databases(&mut *repo)
+}
+
+/// List the databases in the remote repository accessed through the provided sync backend, in
+/// alphabetical order.  Backup copies and hidden files are left out.  Not every kind of
+/// repository can be listed: in particular, the UPM sync protocol has no command for it.
+pub fn list_databases<B: SyncBackend + ?Sized>(repo: &mut B) -> Result<Vec<String>, UpmError> {
+    let mut names: Vec<String> = repo
+        .list()?
+        .into_iter()
+        .filter(|name| !name.starts_with('.') && backup::backup_timestamp(name).is_none())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Upload a local database to the remote repository for the first time, as described in
+/// [`upload_with_backend()`](fn.upload_with_backend.html).
+pub fn upload(
+    repo_url: &str,
+    repo_username: &str,
+    repo_password: &str,
+    database: &Database,
+    database_name: Option<&str>,
+) -> Result<(), UpmError> {
+    let mut repo = open_url(repo_url, repo_username, repo_password)?;
+    upload_with_backend(&mut *repo, database, database_name)
 }
 
-/// Synchronize the local and remote databases using the UPM sync protocol.  If an optional remote
-/// password is provided, it will be used when decrypting the remote database; otherwise, the
-/// password of the local database will be used.  Return true if the caller needs to reload the
+/// Upload a local database to the remote repository accessed through the provided sync backend
+/// for the first time, under the provided name or (if none is provided) its
+/// [remote name](fn.remote_name.html).  This refuses to replace a database which already exists
+/// in the repository -- use [`sync()`](fn.sync.html) for that.  The upload is verified, and
+/// recorded as a sync of the database.  If the name differs from the name of the local database,
+/// it is recorded in the database's [`SyncSettings`](struct.SyncSettings.html).
+///
+/// The database's sync URL is not changed.  Callers will usually want to set it to the URL of the
+/// repository beforehand, so the database can be synced with it later.  A database in the hardened
+/// format is refused if the repository only accepts version 3 databases.
+pub fn upload_with_backend<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    database_name: Option<&str>,
+) -> Result<(), UpmError> {
+    check_format(repo, database)?;
+    let (database_filename, local_name) = match (database.path(), database.name()) {
+        (Some(f), Some(n)) => (f, n),
+        _ => return Err(UpmError::NoDatabaseFilename),
+    };
+    let password = match database.password() {
+        Some(p) => p,
+        None => return Err(UpmError::NoDatabasePassword),
+    };
+    let database_name = match database_name {
+        Some(n) => String::from(n),
+        None => remote_name(database)?,
+    };
+
+    if repo.exists(&database_name)? {
+        return Err(UpmError::Sync(format!(
+            "The repository already holds a database named \"{}\".  Sync with it instead.",
+            database_name
+        )));
+    }
+    replace_verified(repo, database, &database_name, false, None, password, None)?;
+
+    let mut settings = SyncSettings::load_for(database)?;
+    let recorded_name = if database_name == local_name {
+        None
+    } else {
+        Some(database_name)
+    };
+    if settings.remote_name != recorded_name {
+        settings.remote_name = recorded_name;
+        settings.save_for(database)?;
+    }
+    record_sync(&base_path(database_filename), database, password, true);
+    Ok(())
+}
+
+/// Return the path of the file which holds a copy of the database as it was last synced.  This
+/// serves as the base revision when merging local and remote changes.
+fn base_path(database_filename: &Path) -> PathBuf {
+    let database_name = database_filename
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    database_filename.with_file_name(format!(".{}.base", database_name))
+}
+
+/// Load the database as it was last synced, if available.  Any problem loading it (e.g. the
+/// database password has since changed) simply means no base revision is known.
+fn load_base(path: &Path, password: &str) -> Option<Database> {
+    Database::load_from_file(path, password).ok()
+}
+
+/// Record the provided database as the most recently synced revision, by saving its sync state
+/// and (if `save_base` is true) a copy to serve as the base of a future merge.  This is
+/// best-effort: if the files cannot be written, the next sync will fall back to choosing the
+/// highest revision.  The recorded remote backups are kept.
+fn record_sync(base_path: &Path, database: &Database, password: &str, save_base: bool) {
+    if save_base {
+        database.save_as(base_path, password).unwrap_or_default();
+    }
+    if let (Some(state_path), Ok(mut state)) =
+        (SyncState::path_for(database), SyncState::new(database))
+    {
+        state.remote_backups = SyncState::load_remote_backups(&state_path).unwrap_or_default();
+        state.save(state_path).unwrap_or_default();
+    }
+}
+
+/// Delete old remote backups of the provided database, if its settings specify a policy for them.
+/// This is best-effort: backups which cannot be deleted now are tried again after the next upload.
+fn prune_configured<B: SyncBackend + ?Sized>(repo: &mut B, database: &Database) {
+    let policy = SyncSettings::load_for(database)
+        .ok()
+        .and_then(|s| s.remote_backup_policy());
+    if let Some(policy) = policy {
+        prune_remote_backups(repo, database, &policy).unwrap_or_default();
+    }
+}
+
+/// Upload the provided database to the remote repository, replacing the previous remote database
+/// (if any).
+fn upload_database<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    database_name: &str,
+    previous: Option<&Database>,
+    local_password: &str,
+    remote_password: &str,
+) -> Result<(), UpmError> {
+    // First, upload a backup copy in case something goes wrong while replacing the remote
+    // database.
+    let backup_name = if super::PARANOID_BACKUPS {
+        upload_backup(
+            repo,
+            database,
+            database_name,
+            database.save_to_bytes(remote_password)?,
+        )?
+    } else {
+        None
+    };
+    let backup = backup_name.map(|name| RemoteBackup {
+        name,
+        contents: Some((database, remote_password)),
+    });
+
+    // Replace the remote database with the local database.  Make sure to re-encrypt with the
+    // local password, in case it has been changed recently.
+    replace_verified(
+        repo,
+        database,
+        database_name,
+        previous.is_some(),
+        previous.map(|d| (d, remote_password)),
+        local_password,
+        backup.as_ref(),
+    )
+}
+
+/// A backup copy of a database which was uploaded to the remote repository, and can be restored
+/// if replacing the database fails.
+struct RemoteBackup<'a> {
+    /// The name of the backup copy.
+    name: String,
+    /// The database held by the backup copy, and its password.  This is `None` if the copy holds
+    /// a remote database which could not be decrypted, so only its bytes are known.
+    contents: Option<(&'a Database, &'a str)>,
+}
+
+/// Replace the named database in the remote repository with the provided database, encrypted
+/// with the provided password, and make sure that it arrived intact by downloading and decrypting
+/// it again.  If it did not, the upload is retried.  If every attempt fails, the backup copy (if
+/// any) is restored in its place, and an error reporting each step is returned, ending with what
+/// the remote repository holds afterwards.  The previous remote database (if it exists and could
+/// be decrypted) is provided along with its password, so it can be recognized.
+fn replace_verified<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    database_name: &str,
+    remote_exists: bool,
+    previous: Option<(&Database, &str)>,
+    password: &str,
+    backup: Option<&RemoteBackup>,
+) -> Result<(), UpmError> {
+    let mut report = Vec::new();
+    for attempt in 1..=UPLOAD_ATTEMPTS {
+        let database_bytes = database.save_to_bytes(password)?;
+        // After a failed attempt, the remote database may or may not exist.
+        let result = if remote_exists || attempt > 1 {
+            repo.replace(database_name, database_bytes)
+        } else {
+            repo.upload(database_name, database_bytes)
+        };
+        let problem = match result {
+            Ok(()) => match verify_upload(repo, database_name, database, password) {
+                Ok(()) => return Ok(()),
+                Err(problem) => format!(
+                    "Upload attempt {} could not be verified: {}.",
+                    attempt, problem
+                ),
+            },
+            Err(e) => format!("Upload attempt {} failed: {}.", attempt, error_detail(&e)),
+        };
+        report.push(problem);
+    }
+
+    match backup {
+        Some(backup) => {
+            let restored = repo
+                .download(&backup.name)
+                .and_then(|bytes| repo.replace(database_name, bytes.clone()).map(|()| bytes))
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| match backup.contents {
+                    Some((backup_database, backup_password)) => {
+                        verify_upload(repo, database_name, backup_database, backup_password)
+                    }
+                    None => verify_raw_upload(repo, database_name, &bytes),
+                });
+            report.push(match restored {
+                Ok(()) => format!("The remote database was restored from {}.", backup.name),
+                Err(problem) => format!(
+                    "Restoring the remote database from {} failed: {}.",
+                    backup.name, problem
+                ),
+            });
+        }
+        None => report.push(String::from("No backup copy was available to restore.")),
+    }
+
+    let mut candidates = vec![(database, password, "the uploaded revision")];
+    if let Some((previous, previous_password)) = previous {
+        candidates.push((previous, previous_password, "the previous revision"));
+    }
+    if let Some(&RemoteBackup {
+        contents: Some((backup_database, backup_password)),
+        ..
+    }) = backup
+    {
+        candidates.push((
+            backup_database,
+            backup_password,
+            "the backup copy, revision",
+        ));
+    }
+    report.push(describe_remote(repo, database_name, &candidates));
+    Err(UpmError::Sync(report.join("  ")))
+}
+
+/// Describe what the remote repository holds under the provided name, so the user is not left
+/// guessing after a failed upload.  The remote database is compared with each of the provided
+/// candidates (a database, its password, and a description), in order.
+fn describe_remote<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database_name: &str,
+    candidates: &[(&Database, &str, &str)],
+) -> String {
+    let bytes = match repo.download(database_name) {
+        Ok(bytes) => bytes,
+        Err(UpmError::SyncDatabaseNotFound) => {
+            return String::from("The remote database is now missing.")
+        }
+        Err(e) => {
+            return format!(
+                "The remote database could not be checked afterwards: {}.",
+                error_detail(&e)
+            )
+        }
+    };
+    for &(candidate, password, description) in candidates {
+        let matches = matches!(Database::load_from_bytes(&bytes, password),
+            Ok(ref d) if d.sync_revision == candidate.sync_revision
+                && d.content_hash() == candidate.content_hash());
+        if matches {
+            return format!(
+                "The remote database now holds {} {}.",
+                description, candidate.sync_revision
+            );
+        }
+    }
+    String::from("The remote database does not hold any expected revision, and may be damaged.")
+}
+
+/// Return an error reporting that the described step of a sync failed with the provided error,
+/// followed by the outcome of the sync so far.
+fn step_failed(step: &str, error: &UpmError, outcome: &str) -> UpmError {
+    UpmError::Sync(format!(
+        "{} failed: {}.  {}",
+        step,
+        error_detail(error),
+        outcome
+    ))
+}
+
+/// Describe the provided error, without the "Sync error" prefix of sync errors, for inclusion in
+/// a longer report.
+fn error_detail(error: &UpmError) -> String {
+    match *error {
+        UpmError::Sync(ref s) => s.trim_end_matches('.').to_string(),
+        ref e => e.to_string().trim_end_matches('.').to_string(),
+    }
+}
+
+/// Download the named database and check that it decrypts with the provided password to the
+/// revision and content of the expected database.  If not, return a description of the problem.
+fn verify_upload<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database_name: &str,
+    expected: &Database,
+    password: &str,
+) -> Result<(), String> {
+    let bytes = repo
+        .download(database_name)
+        .map_err(|e| format!("it could not be downloaded ({})", e))?;
+    let uploaded = Database::load_from_bytes(&bytes, password)
+        .map_err(|e| format!("it could not be decrypted ({})", e))?;
+    if uploaded.sync_revision != expected.sync_revision {
+        return Err(format!(
+            "it has revision {} instead of {}",
+            uploaded.sync_revision, expected.sync_revision
+        ));
+    }
+    if uploaded.content_hash() != expected.content_hash() {
+        return Err(String::from("its accounts differ from those uploaded"));
+    }
+    Ok(())
+}
+
+/// Download the named database and check that it holds exactly the provided bytes.  If not,
+/// return a description of the problem.
+fn verify_raw_upload<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database_name: &str,
+    expected: &[u8],
+) -> Result<(), String> {
+    let bytes = repo
+        .download(database_name)
+        .map_err(|e| format!("it could not be downloaded ({})", e))?;
+    if bytes != expected {
+        return Err(String::from("it differs from the backup copy"));
+    }
+    Ok(())
+}
+
+/// Upload a backup copy of a database to the remote repository, next to the named database, and
+/// return the name of the copy.  The name is recorded in the sync state of the provided local
+/// database, so the copy can be pruned later.  This is skipped if the backend keeps the history of
+/// the database by itself.
+fn upload_backup<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    database_name: &str,
+    database_bytes: Vec<u8>,
+) -> Result<Option<String>, UpmError> {
+    if repo.keeps_history() {
+        return Ok(None);
+    }
+    // Backup names only have a resolution of one second, and some backends refuse to overwrite
+    // an existing file, so a sequence number tells apart backups made within the same second.
+    let mut sequence = 0;
+    let backup_database_name = loop {
+        let path = backup::generate_numbered_backup_filename(database_name, sequence)?;
+        let name = match path.to_str() {
+            Some(name) => String::from(name),
+            None => return Ok(None),
+        };
+        let exists = repo
+            .exists(&name)
+            .map_err(|e| step_failed("Uploading a backup copy", &e, REMOTE_UNCHANGED))?;
+        if !exists || sequence >= MAX_BACKUP_SEQUENCE {
+            break name;
+        }
+        sequence += 1;
+    };
+    repo.upload(&backup_database_name, database_bytes)
+        .map_err(|e| step_failed("Uploading a backup copy", &e, REMOTE_UNCHANGED))?;
+    if let Some(state_path) = SyncState::path_for(database) {
+        SyncState::add_remote_backup(state_path, &backup_database_name).unwrap_or_default();
+    }
+    Ok(Some(backup_database_name))
+}
+
+/// Synchronize the local and remote databases using the provided sync backend.  If an optional
+/// remote password is provided, it will be used when decrypting the remote database; otherwise,
+/// the password of the local database will be used.  Return true if the caller needs to reload the
 /// local database.
 ///
 /// The sync logic is as follows:
 ///
-/// 1. Download the current remote database from the provided URL.
+/// 1. Download the current remote database from the backend.
 ///      - Attempt to decrypt this database with the master password.
 ///      - If decryption fails, return
-///      [`UpmError::BadPassword`](../error/enum.UpmError.html#variant.BadPassword).  (The caller
-///      may wish to prompt the user for the remote password, then try again.)
-/// 2. Take action based on the revisions of the local and remote database:
+///        [`UpmError::BadPassword`](../error/enum.UpmError.html#variant.BadPassword).  (The caller
+///        may wish to prompt the user for the remote password, then try again.)
+/// 2. Compare both databases with the [`SyncState`](struct.SyncState.html) recorded by the last
+///    sync:
+///      - If both databases have changed (and are not identical), merge them with
+///        [`Database::merge()`](../database/struct.Database.html#method.merge).  The merged
+///        database replaces both the remote database and the local database, unless the merge
+///        produced conflicts: those are returned for the user to review, and nothing is replaced
+///        until [`finish_merge()`](fn.finish_merge.html) is called.
+///      - If only the remote database has changed, replace the local database with it.
+/// 3. Otherwise, take action based on the revisions of the local and remote database:
 ///      - If the local revision is greater than the remote revision, upload the local database to
-///      the remote repository (overwriting the pre-existing remote database).
+///        the remote repository (overwriting the pre-existing remote database).
 ///      - If the local revision is less than the remote revision, replace the local database with
-///      the remote database (overwriting the pre-existing local database).
+///        the remote database (overwriting the pre-existing local database).
 ///      - If the local revision is the same as the remote revision, then do nothing.
-/// 3. The caller may wish to mimic the behavior of the UPM Java application by considering the
+/// 4. The caller may wish to mimic the behavior of the UPM Java application by considering the
 ///    local database to be dirty if it has not been synced in 5 minutes.
 ///
-/// NOTE: It is theoretically possible for two UPM clients to revision the database separately
-/// before syncing, and result in a situation where one will "win" and the other will have its
-/// changes silently lost.  The caller should exercise the appropriate level of paranoia to
-/// mitigate this risk.  For example, prompting for sync before the user begins making a
-/// modification, and marking the database as dirty after 5 minutes.
+/// Each upload is verified by downloading and decrypting the remote database again.  An upload
+/// which does not arrive intact is retried, and if it keeps failing, the backup copy uploaded
+/// beforehand is restored and an error describing each step is returned.  Any error returned as
+/// [`UpmError::Sync`](../error/enum.UpmError.html#variant.Sync) names the step which failed
+/// (download, backup upload, delete, or upload), and ends by stating whether the remote database
+/// was changed and which revision it holds.  After a successful upload, old remote backups are
+/// pruned if the database's settings specify a policy for them.
+///
+/// A database in the hardened format is refused before anything is downloaded if the repository
+/// only accepts version 3 databases.  When the remote database replaces the local database, the
+/// local database keeps its format.
+///
+/// The sync state and a copy of the database as of each sync are kept in hidden files next to the
+/// local database, the latter to serve as the base revision when merging.  If the sync state is
+/// missing (e.g. the database has never been synced by this client), the highest revision wins as
+/// described in step 3, and changes made separately by two UPM clients may be lost.  The caller
+/// should exercise the appropriate level of paranoia to mitigate this risk.
+/// [`status()`](fn.status.html) reports what a sync would do without doing it.
+///
+/// The backend is opened as described in [`open_backend()`](fn.open_backend.html).  Use
+/// [`sync_with_backend()`](fn.sync_with_backend.html) to sync with another backend.
 pub fn sync(database: &Database, remote_password: Option<&str>) -> Result<SyncResult, UpmError> {
+    let mut repo = open_backend(database)?;
+    sync_with_backend(&mut *repo, database, remote_password)
+}
+
+/// Synchronize the local and remote databases as [`sync()`](fn.sync.html) does, using the
+/// provided sync backend.
+pub fn sync_with_backend<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    remote_password: Option<&str>,
+) -> Result<SyncResult, UpmError> {
+    sync_with_progress(repo, database, remote_password, |_| {})
+}
+
+/// Synchronize the local and remote databases as [`sync()`](fn.sync.html) does, using the
+/// provided sync backend and reporting each step to the provided progress callback before it begins.  This allows callers which run
+/// the sync in the background to keep the user informed.
+pub fn sync_with_progress<B: SyncBackend + ?Sized, F: FnMut(SyncProgress)>(
+    repo: &mut B,
+    database: &Database,
+    remote_password: Option<&str>,
+    mut progress: F,
+) -> Result<SyncResult, UpmError> {
+    check_format(repo, database)?;
+
     // Collect all the facts.
-    if database.sync_url.is_empty() {
-        return Err(UpmError::NoSyncURL);
-    }
-    if database.sync_credentials.is_empty() {
-        return Err(UpmError::NoSyncCredentials);
-    }
-    let sync_account = match database.account(&database.sync_credentials) {
-        Some(a) => a,
-        None => return Err(UpmError::NoSyncCredentials),
-    };
     let database_filename = match database.path() {
         Some(f) => f,
         None => return Err(UpmError::NoDatabaseFilename),
     };
-    let database_name = match database.name() {
-        Some(n) => n,
-        None => return Err(UpmError::NoDatabaseFilename),
-    };
+    let database_name = &remote_name(database)?;
 
     let local_password = match database.password() {
         Some(p) => p,
@@ -246,60 +948,1279 @@ pub fn sync(database: &Database, remote_password: Option<&str>) -> Result<SyncRe
     // database, we will return UpmError::BadPassword and the caller can
     // prompt the user for the remote password, and call this function
     // again with Some(remote_password).
-    let mut repo = Repository::new(
-        &database.sync_url,
-        &sync_account.user,
-        &sync_account.password,
+    progress(SyncProgress::Downloading);
+    let remote_database = download_database(repo, database_name, remote_password)?;
+    // If no remote database with that name exists, this must be a fresh sync.  We'll use a stub
+    // database with revision 0.
+    let remote_exists = remote_database.is_some();
+    let remote_database = remote_database.unwrap_or_else(Database::new);
+    let previous = if remote_exists {
+        Some(&remote_database)
+    } else {
+        None
+    };
+
+    // 2. and 3. Use the state recorded by the last sync and the revisions to decide which
+    // databases to replace, then copy databases as needed.
+    let base_path = base_path(database_filename);
+    let state =
+        SyncState::path_for(database).and_then(|p| SyncState::load(p, database).unwrap_or(None));
+    match plan(database, &remote_database, remote_exists, state.as_ref()) {
+        SyncDirection::Merge => {
+            // Both databases have changed, so merge them.  If the base copy doesn't match the
+            // recorded state, merge against an empty database instead: every difference is then
+            // treated as a conflict, but nothing is lost.
+            let base = load_base(&base_path, local_password)
+                .filter(|b| matches!(state, Some(ref s) if s.content_matches(b)))
+                .unwrap_or_else(Database::new);
+            progress(SyncProgress::Merging);
+            let (merged, conflicts) = Database::merge(&base, database, &remote_database);
+            if !conflicts.is_empty() {
+                // Nothing is replaced until the user has reviewed the conflicts.
+                return Ok(SyncResult::Conflicted(Box::new(PendingMerge {
+                    merged,
+                    conflicts,
+                    remote: if remote_exists {
+                        Some(remote_database)
+                    } else {
+                        None
+                    },
+                })));
+            }
+            progress(SyncProgress::Uploading);
+            replace_merged(
+                repo,
+                &merged,
+                database_name,
+                previous,
+                local_password,
+                remote_password,
+            )?;
+            // The caller should reload the local database when it receives this result.
+            Ok(SyncResult::Merged)
+        }
+        SyncDirection::Upload => {
+            // Copy the local database to the remote.
+            progress(SyncProgress::Uploading);
+            upload_database(
+                repo,
+                database,
+                database_name,
+                previous,
+                local_password,
+                remote_password,
+            )?;
+            record_sync(&base_path, database, local_password, true);
+            prune_configured(repo, database);
+            Ok(SyncResult::RemoteSynced)
+        }
+        SyncDirection::Download => {
+            progress(SyncProgress::Saving);
+            replace_local(
+                &base_path,
+                remote_database,
+                database,
+                database_filename,
+                remote_password,
+            )
+        }
+        SyncDirection::Neither => {
+            // Revisions are the same -- do nothing, but note that the databases were found in
+            // sync.
+            let changed = match state {
+                Some(ref s) => s.differs_from(database),
+                None => true,
+            };
+            record_sync(&base_path, database, local_password, changed);
+            Ok(SyncResult::NeitherSynced)
+        }
+    }
+}
+
+/// Complete a merge which produced conflicts, once the user has reviewed them: the merged database
+/// (with the resolutions applied) replaces both the remote database and the local database, as
+/// [`sync()`](fn.sync.html) does for a merge without conflicts.  The remote password is used as it
+/// is by [`sync()`](fn.sync.html).
+///
+/// If the remote database has changed since it was merged, nothing is replaced and
+/// [`UpmError::Sync`](../error/enum.UpmError.html#variant.Sync) is returned, so the caller may
+/// sync again to merge the new changes.
+pub fn finish_merge<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    pending: &PendingMerge,
+    remote_password: Option<&str>,
+) -> Result<SyncResult, UpmError> {
+    let merged = &pending.merged;
+    check_format(repo, merged)?;
+    let database_name = &remote_name(merged)?;
+    let local_password = match merged.password() {
+        Some(p) => p,
+        None => return Err(UpmError::NoDatabasePassword),
+    };
+    let remote_password = match remote_password {
+        Some(p) => p,
+        None => local_password,
+    };
+
+    let current = download_database(repo, database_name, remote_password)?;
+    let revision = |database: Option<&Database>| database.map(|d| d.sync_revision);
+    if revision(current.as_ref()) != revision(pending.remote.as_ref()) {
+        return Err(UpmError::Sync(String::from(
+            "The remote database changed while the conflicts were being resolved, so the merge \
+             was not saved.  The remote database was not changed.  Sync again to merge the new \
+             changes.",
+        )));
+    }
+    replace_merged(
+        repo,
+        merged,
+        database_name,
+        pending.remote.as_ref(),
+        local_password,
+        remote_password,
+    )?;
+    // The caller should reload the local database when it receives this result.
+    Ok(SyncResult::Merged)
+}
+
+/// Replace the remote database and then the local database with the provided merged database, and
+/// record it as the most recently synced revision.  The remote database is replaced first, so a
+/// failed upload leaves the local database untouched and the merge can simply be repeated.
+fn replace_merged<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    merged: &Database,
+    database_name: &str,
+    previous: Option<&Database>,
+    local_password: &str,
+    remote_password: &str,
+) -> Result<(), UpmError> {
+    let database_filename = match merged.path() {
+        Some(f) => f,
+        None => return Err(UpmError::NoDatabaseFilename),
+    };
+    upload_database(
+        repo,
+        merged,
+        database_name,
+        previous,
+        local_password,
+        remote_password,
     )?;
-    let remote_exists;
-    let mut remote_database = match repo.download(database_name) {
-        Ok(bytes) => {
-            remote_exists = true;
-            Database::load_from_bytes(&bytes, remote_password)?
+    if super::PARANOID_BACKUPS {
+        backup::backup(database_filename)?;
+    }
+    merged.save()?;
+    record_sync(&base_path(database_filename), merged, local_password, true);
+    prune_configured(repo, merged);
+    Ok(())
+}
+
+/// Download the named database from the remote repository and decrypt it with the provided
+/// password.  Return `None` if the repository holds no database with that name.
+fn download_database<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database_name: &str,
+    password: &str,
+) -> Result<Option<Database>, UpmError> {
+    match repo.download(database_name) {
+        Ok(bytes) => Ok(Some(Database::load_from_bytes(&bytes, password)?)),
+        Err(UpmError::SyncDatabaseNotFound) => Ok(None),
+        Err(e) => Err(step_failed(
+            "Downloading the remote database",
+            &e,
+            REMOTE_UNCHANGED,
+        )),
+    }
+}
+
+/// Decide which way a sync should copy the databases, following steps 2 and 3 described in
+/// [`sync()`](fn.sync.html).  A missing remote database is represented by an empty stub database.
+fn plan(
+    database: &Database,
+    remote_database: &Database,
+    remote_exists: bool,
+    state: Option<&SyncState>,
+) -> SyncDirection {
+    if let Some(state) = state {
+        let local_changed = state.differs_from(database);
+        let remote_changed = remote_exists && state.differs_from(remote_database);
+        if local_changed
+            && remote_changed
+            && database.content_hash() != remote_database.content_hash()
+        {
+            return SyncDirection::Merge;
         }
-        Err(UpmError::SyncDatabaseNotFound) => {
-            // No remote database with that name exists, so this must be a fresh sync.
-            // We'll use a stub database with revision 0.
-            remote_exists = false;
-            Database::new()
+        if remote_changed && !local_changed {
+            // Only the remote database has changed, so it replaces the local database even if its
+            // revision is not higher.
+            return SyncDirection::Download;
         }
-        Err(e) => return Err(e),
+    }
+    match database.sync_revision.cmp(&remote_database.sync_revision) {
+        Ordering::Greater => SyncDirection::Upload,
+        Ordering::Less => SyncDirection::Download,
+        Ordering::Equal => SyncDirection::Neither,
+    }
+}
+
+/// Report how the local database differs from the copy in its remote repository, and what a sync
+/// would do about it, without changing either of them.  The backend is opened as described in
+/// [`open_backend()`](fn.open_backend.html), and the remote password is used as in
+/// [`sync()`](fn.sync.html).
+pub fn status(database: &Database, remote_password: Option<&str>) -> Result<SyncStatus, UpmError> {
+    let mut repo = open_backend(database)?;
+    status_with_backend(&mut *repo, database, remote_password)
+}
+
+/// Report the sync status of the local database as [`status()`](fn.status.html) does, using the
+/// provided sync backend.
+pub fn status_with_backend<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    remote_password: Option<&str>,
+) -> Result<SyncStatus, UpmError> {
+    let database_name = &remote_name(database)?;
+    let remote_password = match remote_password.or_else(|| database.password()) {
+        Some(p) => p,
+        None => return Err(UpmError::NoDatabasePassword),
+    };
+
+    let remote_database = download_database(repo, database_name, remote_password)?;
+    let state =
+        SyncState::path_for(database).and_then(|p| SyncState::load(p, database).unwrap_or(None));
+    let direction = match remote_database {
+        Some(ref r) => plan(database, r, true, state.as_ref()),
+        None => plan(database, &Database::new(), false, state.as_ref()),
     };
+    let empty = Database::new();
+    Ok(SyncStatus {
+        local_revision: database.sync_revision,
+        remote_revision: remote_database.as_ref().map(|r| r.sync_revision),
+        direction,
+        differences: account_differences(database, remote_database.as_ref().unwrap_or(&empty)),
+    })
+}
 
-    // 2. Copy databases as needed.
-    if database.sync_revision > remote_database.sync_revision {
-        // Copy the local database to the remote.
-
-        // First, upload a backup copy in case something goes wrong between delete() and upload().
-        if super::PARANOID_BACKUPS {
-            let backup_database_path =
-                backup::generate_backup_filename(&PathBuf::from(database_name))?;
-            let backup_database_name = backup_database_path.to_str();
-            if let Some(backup_database_name) = backup_database_name {
-                repo.upload(
-                    backup_database_name,
-                    database.save_to_bytes(remote_password)?,
-                )?;
+/// Replace the remote database with the local database, regardless of their revisions.  This is
+/// useful when the remote revision was bumped by a misbehaving client, or when the local database
+/// was restored from a backup with a lower revision.  The remote password is used as in
+/// [`sync()`](fn.sync.html), to learn the revision of the remote database.
+///
+/// The local database is given a revision higher than that of the remote database, so clients
+/// which simply compare revisions will pick it up, and is saved with that revision once the upload
+/// has been verified.  The caller should reload the local database afterwards.  Backups of the
+/// local database and of the replaced remote database are made first, if paranoid backups are
+/// enabled.
+///
+/// A remote database which cannot be decrypted (with a wrong password, or because it is damaged)
+/// is replaced as well, since that may be exactly what needs fixing.  Its revision is unknown, so
+/// the local database is only given the next revision after its own.  The remote database is
+/// always backed up first in this case, as it cannot be inspected beforehand.
+pub fn push_force<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    remote_password: Option<&str>,
+) -> Result<(), UpmError> {
+    check_format(repo, database)?;
+    let database_filename = match database.path() {
+        Some(f) => f,
+        None => return Err(UpmError::NoDatabaseFilename),
+    };
+    let database_name = &remote_name(database)?;
+    let local_password = match database.password() {
+        Some(p) => p,
+        None => return Err(UpmError::NoDatabasePassword),
+    };
+    let remote_password = remote_password.unwrap_or(local_password);
+
+    let remote_bytes = match repo.download(database_name) {
+        Ok(bytes) => Some(bytes),
+        Err(UpmError::SyncDatabaseNotFound) => None,
+        Err(e) => {
+            return Err(step_failed(
+                "Downloading the remote database",
+                &e,
+                REMOTE_UNCHANGED,
+            ))
+        }
+    };
+    let remote_database = match remote_bytes {
+        Some(ref bytes) => Database::load_from_bytes(bytes, remote_password).ok(),
+        None => None,
+    };
+    let undecryptable = remote_bytes.is_some() && remote_database.is_none();
+    if super::PARANOID_BACKUPS {
+        backup::backup(database_filename)?;
+    }
+    let mut backup = None;
+    if let Some(bytes) = remote_bytes.clone() {
+        if super::PARANOID_BACKUPS || undecryptable {
+            backup =
+                upload_backup(repo, database, database_name, bytes)?.map(|name| RemoteBackup {
+                    name,
+                    contents: remote_database.as_ref().map(|d| (d, remote_password)),
+                });
+        }
+    }
+
+    let mut pushed = database.clone();
+    let remote_revision = remote_database.as_ref().map_or(0, |d| d.sync_revision);
+    pushed.sync_revision = cmp::max(database.sync_revision, remote_revision) + 1;
+    replace_verified(
+        repo,
+        &pushed,
+        database_name,
+        remote_bytes.is_some(),
+        remote_database.as_ref().map(|d| (d, remote_password)),
+        local_password,
+        backup.as_ref(),
+    )?;
+    pushed.save()?;
+    record_sync(&base_path(database_filename), &pushed, local_password, true);
+    prune_configured(repo, &pushed);
+    Ok(())
+}
+
+/// Replace the local database with the remote database, regardless of their revisions.  This is
+/// the counterpart of [`push_force()`](fn.push_force.html), useful when the local database is
+/// the one which can't be trusted.  The remote password is used as in [`sync()`](fn.sync.html).
+///
+/// The caller should reload the local database afterwards.  A backup of the local database is
+/// made first, if paranoid backups are enabled.
+pub fn pull_force<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    remote_password: Option<&str>,
+) -> Result<(), UpmError> {
+    let database_filename = match database.path() {
+        Some(f) => f,
+        None => return Err(UpmError::NoDatabaseFilename),
+    };
+    let database_name = &remote_name(database)?;
+    let remote_password = match remote_password.or_else(|| database.password()) {
+        Some(p) => p,
+        None => return Err(UpmError::NoDatabasePassword),
+    };
+
+    let remote_database = match download_database(repo, database_name, remote_password)? {
+        Some(d) => d,
+        None => return Err(UpmError::SyncDatabaseNotFound),
+    };
+    if super::PARANOID_BACKUPS {
+        backup::backup(database_filename)?;
+    }
+    replace_local(
+        &base_path(database_filename),
+        remote_database,
+        database,
+        database_filename,
+        remote_password,
+    )?;
+    Ok(())
+}
+
+/// List the accounts which differ between the local and remote databases: first those present
+/// in both, then those only present in the local database, and then those only present in the
+/// remote database.  A renamed account is only present in one database under each name.
+fn account_differences(local: &Database, remote: &Database) -> Vec<AccountDifference> {
+    let diff = remote.diff(local);
+    let changed = diff.changed.into_iter().map(|change| {
+        let fields = change.fields.iter().map(|f| f.field).collect();
+        AccountDifference::Changed(change.new_name, fields)
+    });
+    let local_only = diff
+        .added
+        .into_iter()
+        .map(|a| a.name)
+        .chain(diff.renamed.iter().map(|r| r.new_name.clone()))
+        .map(AccountDifference::LocalOnly);
+    let remote_only = diff
+        .removed
+        .into_iter()
+        .map(|a| a.name)
+        .chain(diff.renamed.iter().map(|r| r.old_name.clone()))
+        .map(AccountDifference::RemoteOnly);
+    changed.chain(local_only).chain(remote_only).collect()
+}
+
+/// Replace the local database with the remote database, keeping the format of the local database.
+fn replace_local(
+    base_path: &Path,
+    mut remote_database: Database,
+    local_database: &Database,
+    database_filename: &Path,
+    remote_password: &str,
+) -> Result<SyncResult, UpmError> {
+    remote_database.set_format(local_database.format())?;
+    remote_database.set_path(&database_filename)?;
+    remote_database.save()?;
+    record_sync(base_path, &remote_database, remote_password, true);
+    // The caller should reload the local database when it receives this result.
+    Ok(SyncResult::LocalSynced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use merge::Resolution;
+    use secret::SecretString;
+    use server::Server;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    /// A trivial in-memory backend used to exercise the sync logic.
+    struct MemoryBackend {
+        files: HashMap<String, Vec<u8>>,
+        history: bool,
+    }
+
+    impl SyncBackend for MemoryBackend {
+        fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+            match self.files.get(database_name) {
+                Some(bytes) => Ok(bytes.clone()),
+                None => Err(UpmError::SyncDatabaseNotFound),
+            }
+        }
+        fn upload(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+            self.files.insert(database_name.to_string(), database_bytes);
+            Ok(())
+        }
+        fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+            match self.files.remove(database_name) {
+                Some(_) => Ok(()),
+                None => Err(UpmError::SyncDatabaseNotFound),
+            }
+        }
+        fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+            Ok(self.files.contains_key(database_name))
+        }
+        fn keeps_history(&self) -> bool {
+            self.history
+        }
+    }
+
+    /// A backend which corrupts the first few uploads of a database, but never those of its
+    /// backup copies.
+    struct FlakyBackend {
+        inner: MemoryBackend,
+        corrupt_uploads: usize,
+        failing_downloads: usize,
+        failing_deletes: bool,
+    }
+
+    impl SyncBackend for FlakyBackend {
+        fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+            if self.failing_downloads > 0 {
+                self.failing_downloads -= 1;
+                return Err(UpmError::Sync(String::from("timed out")));
+            }
+            self.inner.download(database_name)
+        }
+        fn upload(&mut self, database_name: &str, mut bytes: Vec<u8>) -> Result<(), UpmError> {
+            if self.corrupt_uploads > 0 && !database_name.ends_with(".bak") {
+                self.corrupt_uploads -= 1;
+                let len = bytes.len();
+                bytes.truncate(len / 2);
             }
+            self.inner.upload(database_name, bytes)
         }
+        fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+            if self.failing_deletes {
+                return Err(UpmError::Sync(String::from("403 Forbidden")));
+            }
+            self.inner.delete(database_name)
+        }
+        fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+            self.inner.exists(database_name)
+        }
+    }
+
+    /// A backend which, like the UPM sync server, only accepts version 3 databases.
+    struct V3OnlyBackend {
+        inner: MemoryBackend,
+    }
 
-        // Delete the existing remote database, if it exists.
-        if remote_exists {
-            repo.delete(&database_name)?;
+    impl SyncBackend for V3OnlyBackend {
+        fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+            self.inner.download(database_name)
         }
+        fn upload(&mut self, database_name: &str, bytes: Vec<u8>) -> Result<(), UpmError> {
+            self.inner.upload(database_name, bytes)
+        }
+        fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+            self.inner.delete(database_name)
+        }
+        fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+            self.inner.exists(database_name)
+        }
+        fn accepts_hardened_format(&self) -> bool {
+            false
+        }
+    }
 
-        // Upload the local database to the remote.  Make sure to re-encrypt with the local
-        // password, in case it has been changed recently.
-        repo.upload(database_name, database.save_to_bytes(local_password)?)?;
-        Ok(SyncResult::RemoteSynced)
-    } else if database.sync_revision < remote_database.sync_revision {
-        // Replace the local database with the remote database
-        remote_database.set_path(&database_filename)?;
-        remote_database.save()?;
-        // The caller should reload the local database when it receives this result.
-        Ok(SyncResult::LocalSynced)
-    } else {
-        // Revisions are the same -- do nothing.
-        Ok(SyncResult::NeitherSynced)
+    const PASSWORD: &str = "xyzzy";
+
+    #[test]
+    fn test_sync_to_remote() {
+        let mut backend = MemoryBackend {
+            files: HashMap::new(),
+            history: false,
+        };
+        let mut database = Database::new();
+        database.set_path(&"/nonexistent/primary").unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 2;
+
+        // A fresh sync uploads the local database, along with a backup copy.
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        assert!(backend.exists("primary").unwrap());
+        assert_eq!(backend.files.len(), if ::PARANOID_BACKUPS { 2 } else { 1 });
+        let remote = Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD);
+        assert_matches!(remote, Ok(ref d) if d.sync_revision == 2);
+
+        // Syncing again with the same revision does nothing.
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::NeitherSynced));
+
+        // A remote database with a different password cannot be synced.
+        let result = sync_with_backend(&mut backend, &database, Some("frobozz"));
+        assert_matches!(result, Err(_));
+    }
+
+    #[test]
+    fn test_sync_configured_backend() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote_dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new();
+        database
+            .set_path(&local_dir.path().join("primary"))
+            .unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+        database.save().unwrap();
+        assert_matches!(sync(&database, None), Err(UpmError::NoSyncURL));
+
+        // The backend is opened from the database's sync URL.
+        database.sync_url = format!("file://{}", remote_dir.path().display());
+        let result = sync(&database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        let remote = Database::load_from_file(remote_dir.path().join("primary"), PASSWORD);
+        assert_matches!(remote, Ok(ref d) if d.sync_revision == 1);
+    }
+
+    #[test]
+    fn test_sync_progress() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let mut backend = MemoryBackend {
+            files: HashMap::new(),
+            history: false,
+        };
+        let mut database = Database::new();
+        database
+            .set_path(&local_dir.path().join("primary"))
+            .unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+        database.save().unwrap();
+
+        let mut steps = vec![];
+        let result = sync_with_progress(&mut backend, &database, None, |p| steps.push(p));
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        assert_eq!(
+            steps,
+            vec![SyncProgress::Downloading, SyncProgress::Uploading]
+        );
+    }
+
+    #[test]
+    fn test_sync_status() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let local_path = local_dir.path().join("primary");
+        let mut backend = MemoryBackend {
+            files: HashMap::new(),
+            history: false,
+        };
+        let account = |name: &str, password: &str| ::database::Account {
+            name: String::from(name),
+            user: String::from("user"),
+            password: SecretString::from(password),
+            url: String::new(),
+            notes: String::new(),
+        };
+        let mut database = Database::new();
+        database.set_path(&local_path).unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+        database.add_account(&account("shared", "base")).unwrap();
+        database.save().unwrap();
+
+        // Before the first sync, every account is only present locally.
+        let status = status_with_backend(&mut backend, &database, None).unwrap();
+        assert_eq!(status.remote_revision, None);
+        assert_eq!(status.direction, SyncDirection::Upload);
+        assert_eq!(
+            status.differences,
+            vec![AccountDifference::LocalOnly(String::from("shared"))]
+        );
+        assert!(backend.files.is_empty());
+
+        // Once synced, there is nothing to do.
+        sync_with_backend(&mut backend, &database, None).unwrap();
+        let status = status_with_backend(&mut backend, &database, None).unwrap();
+        assert_eq!(status.remote_revision, Some(1));
+        assert_eq!(status.direction, SyncDirection::Neither);
+        assert!(status.differences.is_empty());
+
+        // Changes on both sides would be merged, but neither side is touched yet.
+        let mut remote = database.clone();
+        remote.sync_revision = 2;
+        remote.add_account(&account("remote", "pass")).unwrap();
+        remote.account_mut("shared").unwrap().password = SecretString::from("remote");
+        let remote_bytes = remote.save_to_bytes(PASSWORD).unwrap();
+        backend.upload("primary", remote_bytes.clone()).unwrap();
+        database.sync_revision = 2;
+        database.add_account(&account("local", "pass")).unwrap();
+        database.save().unwrap();
+        let local_bytes = fs::read(&local_path).unwrap();
+        let status = status_with_backend(&mut backend, &database, None).unwrap();
+        assert_eq!(status.local_revision, 2);
+        assert_eq!(status.remote_revision, Some(2));
+        assert_eq!(status.direction, SyncDirection::Merge);
+        assert_eq!(
+            status.differences,
+            vec![
+                AccountDifference::Changed(String::from("shared"), vec![AccountField::Password]),
+                AccountDifference::LocalOnly(String::from("local")),
+                AccountDifference::RemoteOnly(String::from("remote")),
+            ]
+        );
+        assert_eq!(backend.download("primary").unwrap(), remote_bytes);
+        assert_eq!(fs::read(&local_path).unwrap(), local_bytes);
+
+        // The remote password is needed to compare the databases.
+        let result = status_with_backend(&mut backend, &database, Some("frobozz"));
+        assert_matches!(result, Err(_));
+    }
+
+    #[test]
+    fn test_sync_force() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let local_path = local_dir.path().join("primary");
+        let mut backend = MemoryBackend {
+            files: HashMap::new(),
+            history: false,
+        };
+        let database_with = |revision: u32, name: &str| {
+            let mut database = Database::new();
+            database.sync_revision = revision;
+            database
+                .add_account(&::database::Account {
+                    name: String::from(name),
+                    user: String::from("user"),
+                    password: SecretString::from("pass"),
+                    url: String::new(),
+                    notes: String::new(),
+                })
+                .unwrap();
+            database
+        };
+
+        // A remote database bumped to a high revision is replaced by a lower local revision.
+        let remote_bytes = database_with(10, "remote").save_to_bytes(PASSWORD).unwrap();
+        backend.upload("primary", remote_bytes.clone()).unwrap();
+        let mut database = database_with(3, "local");
+        database.set_path(&local_path).unwrap();
+        database.set_password(&PASSWORD);
+        database.save().unwrap();
+        assert_matches!(push_force(&mut backend, &database, None), Ok(()));
+        let remote =
+            Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD).unwrap();
+        assert_eq!(remote.sync_revision, 11);
+        assert!(remote.contains("local") && !remote.contains("remote"));
+        if ::PARANOID_BACKUPS {
+            assert!(backend.files.values().any(|bytes| bytes == &remote_bytes));
+        }
+        let database = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(database.sync_revision, 11);
+        assert_matches!(
+            sync_with_backend(&mut backend, &database, None),
+            Ok(SyncResult::NeitherSynced)
+        );
+
+        // A local database is replaced by a lower remote revision.
+        backend
+            .upload(
+                "primary",
+                database_with(5, "older").save_to_bytes(PASSWORD).unwrap(),
+            )
+            .unwrap();
+        assert_matches!(pull_force(&mut backend, &database, None), Ok(()));
+        let database = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(database.sync_revision, 5);
+        assert!(database.contains("older") && !database.contains("local"));
+        assert_matches!(
+            sync_with_backend(&mut backend, &database, None),
+            Ok(SyncResult::NeitherSynced)
+        );
+
+        // There is nothing to pull from an empty repository.
+        backend.files.clear();
+        assert_matches!(
+            pull_force(&mut backend, &database, None),
+            Err(UpmError::SyncDatabaseNotFound)
+        );
+    }
+
+    #[test]
+    fn test_push_force_undecryptable() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let local_path = local_dir.path().join("primary");
+        let mut backend = FlakyBackend {
+            inner: MemoryBackend {
+                files: HashMap::new(),
+                history: false,
+            },
+            corrupt_uploads: 0,
+            failing_downloads: 0,
+            failing_deletes: false,
+        };
+        let mut remote = Database::new();
+        remote.sync_revision = 10;
+        let remote_bytes = remote.save_to_bytes("other password").unwrap();
+        backend.upload("primary", remote_bytes.clone()).unwrap();
+        let mut database = Database::new();
+        database.set_path(&local_path).unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 3;
+        database.save().unwrap();
+
+        // If the upload fails, the backup copy of the remote database is restored byte for byte,
+        // and the local database keeps its revision.
+        backend.corrupt_uploads = UPLOAD_ATTEMPTS as usize;
+        let result = push_force(&mut backend, &database, None);
+        assert_matches!(result, Err(UpmError::Sync(ref m)) if m.contains("was restored"));
+        assert_eq!(backend.download("primary").unwrap(), remote_bytes);
+        let local = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(local.sync_revision, 3);
+
+        // Otherwise the remote database is replaced, after its bytes are backed up.
+        backend.inner.files.clear();
+        backend.upload("primary", remote_bytes.clone()).unwrap();
+        assert_matches!(push_force(&mut backend, &database, None), Ok(()));
+        let pushed = backend.download("primary").unwrap();
+        assert_matches!(Database::load_from_bytes(&pushed, PASSWORD),
+            Ok(ref d) if d.sync_revision == 4);
+        assert!(backend
+            .inner
+            .files
+            .iter()
+            .any(|(name, bytes)| name.ends_with(".bak") && bytes == &remote_bytes));
+        let local = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(local.sync_revision, 4);
+    }
+
+    #[test]
+    fn test_sync_verify_upload() {
+        let mut backend = FlakyBackend {
+            inner: MemoryBackend {
+                files: HashMap::new(),
+                history: false,
+            },
+            corrupt_uploads: 1,
+            failing_downloads: 0,
+            failing_deletes: false,
+        };
+        let mut database = Database::new();
+        database.set_path(&"/nonexistent/primary").unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+
+        // A corrupted upload is detected and retried.
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        let remote = Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD);
+        assert_matches!(remote, Ok(ref d) if d.sync_revision == 1);
+
+        // If every attempt is corrupted, the backup copy is restored, and the steps are reported.
+        database.sync_revision = 2;
+        backend.corrupt_uploads = UPLOAD_ATTEMPTS as usize;
+        let result = sync_with_backend(&mut backend, &database, None);
+        if ::PARANOID_BACKUPS {
+            assert_matches!(result, Err(UpmError::Sync(ref m))
+                if m.contains("attempt 2 could not be verified") && m.contains("was restored")
+                    && m.ends_with("now holds the uploaded revision 2."));
+            let remote = Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD);
+            assert_matches!(remote, Ok(ref d) if d.sync_revision == 2);
+        } else {
+            assert_matches!(result, Err(UpmError::Sync(_)));
+        }
+
+        // If the restore fails too, that is reported as well.
+        database.sync_revision = 3;
+        backend.corrupt_uploads = UPLOAD_ATTEMPTS as usize + 1;
+        let result = sync_with_backend(&mut backend, &database, None);
+        if ::PARANOID_BACKUPS {
+            assert_matches!(result, Err(UpmError::Sync(ref m))
+                if m.contains("Restoring") && m.ends_with("may be damaged."));
+        }
+    }
+
+    #[test]
+    fn test_sync_step_errors() {
+        let mut backend = FlakyBackend {
+            inner: MemoryBackend {
+                files: HashMap::new(),
+                history: false,
+            },
+            corrupt_uploads: 0,
+            failing_downloads: 1,
+            failing_deletes: false,
+        };
+        let mut database = Database::new();
+        database.set_path(&"/nonexistent/primary").unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+
+        // A failed download is reported as such, and leaves the remote database alone.
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Err(UpmError::Sync(ref m))
+            if m == "Downloading the remote database failed: timed out.  \
+                     The remote database was not changed.");
+        assert!(backend.inner.files.is_empty());
+
+        // A failed delete is reported as part of the upload attempts, along with the outcome.
+        assert_matches!(sync_with_backend(&mut backend, &database, None), Ok(_));
+        backend.failing_deletes = true;
+        database.sync_revision = 2;
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Err(UpmError::Sync(ref m))
+            if m.contains("Upload attempt 1 failed: Deleting the old remote database failed: \
+                           403 Forbidden.")
+                && m.ends_with("The remote database now holds the previous revision 1."));
+
+        // A failed download succeeds when retried.
+        backend.failing_deletes = false;
+        backend.failing_downloads = 1;
+        let mut backend =
+            RetryingBackend::with_delay(Box::new(backend), 1, Duration::from_millis(1));
+        assert_matches!(
+            sync_with_backend(&mut backend, &database, None),
+            Ok(SyncResult::RemoteSynced)
+        );
+    }
+
+    #[test]
+    fn test_sync_history_backend() {
+        let mut backend = MemoryBackend {
+            files: HashMap::new(),
+            history: true,
+        };
+        let mut database = Database::new();
+        database.set_path(&"/nonexistent/primary").unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+
+        // A backend which keeps its own history never receives backup copies.
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        database.sync_revision = 2;
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        assert_eq!(backend.files.len(), 1);
+    }
+
+    #[test]
+    fn test_sync_directory() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_path = local_dir.path().join("primary");
+        let mut backend = DirectoryRepository::new(remote_dir.path()).unwrap();
+
+        // Place a newer revision in the remote repository.
+        let mut remote = Database::new();
+        remote.sync_revision = 5;
+        remote
+            .add_account(&::database::Account {
+                name: String::from("acct"),
+                user: String::from("user"),
+                password: SecretString::from("pass"),
+                url: String::new(),
+                notes: String::new(),
+            })
+            .unwrap();
+        backend
+            .upload("primary", remote.save_to_bytes(PASSWORD).unwrap())
+            .unwrap();
+
+        // The local database is replaced by the remote database.
+        let mut database = Database::new();
+        database.set_path(&local_path).unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+        database.save().unwrap();
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::LocalSynced));
+        let database = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(database.sync_revision, 5);
+        assert!(database.contains("acct"));
+
+        // A new local revision is written back to the repository.
+        let mut database = database;
+        database.sync_revision += 1;
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        let remote = Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD);
+        assert_matches!(remote, Ok(ref d) if d.sync_revision == 6 && d.contains("acct"));
+        if !::PARANOID_BACKUPS {
+            return;
+        }
+
+        // The backup copy uploaded beforehand is recorded, so it can be pruned.
+        let state_path = SyncState::path_for(&database).unwrap();
+        let backups = SyncState::load(&state_path, &database)
+            .unwrap()
+            .unwrap()
+            .remote_backups;
+        assert_eq!(backups.len(), 1);
+        assert!(remote_dir.path().join(&backups[0]).is_file());
+
+        // If the settings specify a policy, old backups are pruned after each upload.
+        let settings = SyncSettings {
+            remote_backups_keep: Some(0),
+            ..Default::default()
+        };
+        settings.save_for(&database).unwrap();
+        database.sync_revision += 1;
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        assert_eq!(
+            SyncState::load_remote_backups(&state_path).unwrap().len(),
+            0
+        );
+        assert_eq!(fs::read_dir(remote_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_sync_hardened_format() {
+        let hardened = DatabaseFormat::Hardened(::database::KdfParams {
+            memory_kib: 256,
+            iterations: 1,
+            parallelism: 1,
+        });
+        let local_dir = tempfile::tempdir().unwrap();
+        let local_path = local_dir.path().join("primary");
+        let mut database = Database::new();
+        database.set_path(&local_path).unwrap();
+        database.set_password(&PASSWORD);
+        database.set_format(hardened).unwrap();
+        database.sync_revision = 1;
+        database.save().unwrap();
+
+        // A repository which only accepts version 3 databases is never sent a hardened one, even
+        // behind a retrying backend.
+        let backend = V3OnlyBackend {
+            inner: MemoryBackend {
+                files: HashMap::new(),
+                history: false,
+            },
+        };
+        let mut backend =
+            RetryingBackend::with_delay(Box::new(backend), 1, Duration::from_millis(1));
+        let refused = |result: Result<(), UpmError>| {
+            assert_matches!(result, Err(UpmError::Sync(ref m)) if m.contains("tupm migrate --to 3"));
+        };
+        refused(sync_with_backend(&mut backend, &database, None).map(|_| ()));
+        refused(push_force(&mut backend, &database, None));
+        refused(upload_with_backend(&mut backend, &database, None));
+        assert!(!backend.exists("primary").unwrap());
+        assert!(!url_accepts_hardened_format("https://example.com/upm/"));
+        assert!(url_accepts_hardened_format("file:///var/upm"));
+        assert!(url_accepts_hardened_format("s3://bucket/upm"));
+        assert!(url_accepts_hardened_format("webdavs://example.com/upm"));
+
+        // Other repositories hold hardened databases.
+        let remote_dir = tempfile::tempdir().unwrap();
+        let mut backend = DirectoryRepository::new(remote_dir.path()).unwrap();
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        let remote = Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD);
+        assert_matches!(remote, Ok(ref d) if d.format() == hardened);
+
+        // A newer version 3 database from the repository keeps the local format.
+        let mut remote = Database::new();
+        remote.sync_revision = 5;
+        backend
+            .replace("primary", remote.save_to_bytes(PASSWORD).unwrap())
+            .unwrap();
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::LocalSynced));
+        let database = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(database.sync_revision, 5);
+        assert_eq!(database.format(), hardened);
+    }
+
+    #[test]
+    fn test_remote_databases() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote_dir = tempfile::tempdir().unwrap();
+        let url = format!("file://{}", remote_dir.path().display());
+        let mut backend = DirectoryRepository::new(remote_dir.path()).unwrap();
+        assert_eq!(list_databases(&mut backend).unwrap().len(), 0);
+
+        // A new database is uploaded under a different name, which later syncs keep using.
+        let mut database = Database::new();
+        database.set_path(&local_dir.path().join("work")).unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+        database.save().unwrap();
+        assert_matches!(
+            upload_with_backend(&mut backend, &database, Some("shared")),
+            Ok(())
+        );
+        assert_eq!(remote_name(&database).unwrap(), "shared");
+        assert!(!remote_dir.path().join("work").exists());
+        assert_matches!(
+            upload_with_backend(&mut backend, &database, None),
+            Err(UpmError::Sync(_))
+        );
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::NeitherSynced));
+        database.sync_revision = 2;
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        let remote = download_named(&url, "", "", "shared").unwrap();
+        assert_matches!(Database::load_from_bytes(&remote, PASSWORD),
+            Ok(ref d) if d.sync_revision == 2);
+
+        // Listings leave out backup copies and hidden files.
+        fs::write(remote_dir.path().join(".hidden"), b"").unwrap();
+        backend.upload("primary", remote.clone()).unwrap();
+        assert_eq!(list(&url, "", "").unwrap(), vec!["primary", "shared"]);
+    }
+
+    #[test]
+    fn test_sync_merge() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let local_path = local_dir.path().join("primary");
+        let mut backend = MemoryBackend {
+            files: HashMap::new(),
+            history: false,
+        };
+        let account = |name: &str, password: &str| ::database::Account {
+            name: String::from(name),
+            user: String::from("user"),
+            password: SecretString::from(password),
+            url: String::new(),
+            notes: String::new(),
+        };
+
+        // Establish a common base revision.
+        let mut database = Database::new();
+        database.set_path(&local_path).unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+        database.add_account(&account("shared", "base")).unwrap();
+        database.save().unwrap();
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+
+        // Another client changes the remote database, while this client changes the same account.
+        let mut remote = database.clone();
+        remote.sync_revision = 2;
+        remote.add_account(&account("remote", "pass")).unwrap();
+        remote.account_mut("shared").unwrap().password = SecretString::from("remote");
+        backend
+            .upload("primary", remote.save_to_bytes(PASSWORD).unwrap())
+            .unwrap();
+        database.sync_revision = 2;
+        database.add_account(&account("local", "pass")).unwrap();
+        database.account_mut("shared").unwrap().password = SecretString::from("local");
+        database.save().unwrap();
+
+        // The conflicting change is reported, and nothing is replaced until it has been reviewed.
+        let result = sync_with_backend(&mut backend, &database, None);
+        let mut pending = match result {
+            Ok(SyncResult::Conflicted(pending)) => pending,
+            _ => panic!("expected a conflicted merge"),
+        };
+        assert_eq!(pending.conflicts.len(), 1);
+        assert_eq!(pending.conflicts[0].name, "shared");
+        let remote =
+            Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD).unwrap();
+        assert_eq!(remote.account("shared").unwrap().password, "remote");
+        let local = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(local.account("shared").unwrap().password, "local");
+
+        // Once resolved, both sets of changes and the resolution replace both databases.
+        let conflict = pending.conflicts[0].clone();
+        conflict.resolve(&mut pending.merged, Resolution::Theirs);
+        let result = finish_merge(&mut backend, &pending, None);
+        assert_matches!(result, Ok(SyncResult::Merged));
+        let remote =
+            Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD).unwrap();
+        let local = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        for database in [&remote, &local].iter() {
+            assert_eq!(database.sync_revision, 3);
+            assert!(database.contains("local") && database.contains("remote"));
+            assert_eq!(database.account("shared").unwrap().password, "remote");
+        }
+
+        // The merged revision is the new base, so a later remote change is simply copied.
+        let mut remote = remote;
+        remote.sync_revision = 4;
+        remote.delete_account("remote");
+        backend
+            .upload("primary", remote.save_to_bytes(PASSWORD).unwrap())
+            .unwrap();
+        let result = sync_with_backend(&mut backend, &local, None);
+        assert_matches!(result, Ok(SyncResult::LocalSynced));
+
+        // The sync state shows that only the remote database changed, even if its revision did
+        // not advance.
+        let local = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert!(
+            !SyncState::load(SyncState::path_for(&local).unwrap(), &local)
+                .unwrap()
+                .unwrap()
+                .differs_from(&local)
+        );
+        remote.account_mut("shared").unwrap().password = SecretString::from("unrevised");
+        backend
+            .upload("primary", remote.save_to_bytes(PASSWORD).unwrap())
+            .unwrap();
+        let result = sync_with_backend(&mut backend, &local, None);
+        assert_matches!(result, Ok(SyncResult::LocalSynced));
+        let local = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(local.account("shared").unwrap().password, "unrevised");
+    }
+
+    #[test]
+    fn test_finish_merge_remote_changed() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let local_path = local_dir.path().join("primary");
+        let mut backend = MemoryBackend {
+            files: HashMap::new(),
+            history: false,
+        };
+        let account = |password: &str| ::database::Account {
+            name: String::from("shared"),
+            user: String::from("user"),
+            password: SecretString::from(password),
+            url: String::new(),
+            notes: String::new(),
+        };
+
+        // Both sides add the same account with different passwords.
+        let mut database = Database::new();
+        database.set_path(&local_path).unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+        database.save().unwrap();
+        assert_matches!(
+            sync_with_backend(&mut backend, &database, None),
+            Ok(SyncResult::RemoteSynced)
+        );
+        let mut remote = database.clone();
+        remote.sync_revision = 2;
+        remote.add_account(&account("remote")).unwrap();
+        backend
+            .upload("primary", remote.save_to_bytes(PASSWORD).unwrap())
+            .unwrap();
+        database.sync_revision = 2;
+        database.add_account(&account("local")).unwrap();
+        database.save().unwrap();
+        let pending = match sync_with_backend(&mut backend, &database, None) {
+            Ok(SyncResult::Conflicted(pending)) => pending,
+            _ => panic!("expected a conflicted merge"),
+        };
+
+        // Another client syncs while the conflict is being reviewed, so the merge is abandoned.
+        remote.sync_revision = 3;
+        backend
+            .replace("primary", remote.save_to_bytes(PASSWORD).unwrap())
+            .unwrap();
+        assert_matches!(
+            finish_merge(&mut backend, &pending, None),
+            Err(UpmError::Sync(_))
+        );
+        let remote =
+            Database::load_from_bytes(&backend.download("primary").unwrap(), PASSWORD).unwrap();
+        assert_eq!(remote.sync_revision, 3);
+        let local = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(local.sync_revision, 2);
+    }
+
+    /// Start a UPM sync server for the provided directory on an ephemeral port, and return its URL.
+    fn start_server(directory: &Path) -> String {
+        let server = Server::bind("127.0.0.1:0", directory, "user", "pass").unwrap();
+        let url = format!("http://{}/upm/", server.local_addr().unwrap());
+        thread::spawn(move || server.run());
+        url
+    }
+
+    #[test]
+    fn test_http_repository() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let url = start_server(remote_dir.path());
+
+        let mut repo = Repository::new(&url, "user", "pass").unwrap();
+        assert_matches!(repo.exists("primary"), Ok(false));
+        assert_matches!(
+            repo.download("primary"),
+            Err(UpmError::SyncDatabaseNotFound)
+        );
+        assert_matches!(repo.upload("primary", vec![1, 2, 3]), Ok(()));
+        assert_matches!(repo.exists("primary"), Ok(true));
+        assert_matches!(repo.download("primary"), Ok(ref b) if b == &[1, 2, 3]);
+        // The protocol never overwrites an existing database with an upload.
+        assert_matches!(repo.upload("primary", vec![4, 5]), Err(UpmError::Sync(_)));
+        assert_matches!(repo.replace("primary", vec![4, 5]), Ok(()));
+        assert_matches!(repo.download("primary"), Ok(ref b) if b == &[4, 5]);
+        assert_matches!(repo.delete("primary"), Ok(()));
+        assert_matches!(repo.delete("primary"), Err(UpmError::SyncDatabaseNotFound));
+        assert!(!remote_dir.path().join("primary").exists());
+
+        // Every operation requires the correct credentials.
+        let mut repo = Repository::new(&url, "user", "wrong").unwrap();
+        assert_matches!(repo.exists("primary"), Err(UpmError::Sync(_)));
+        assert_matches!(repo.download("primary"), Err(UpmError::Sync(_)));
+        assert_matches!(repo.upload("primary", vec![1]), Err(UpmError::Sync(_)));
+        assert!(!remote_dir.path().join("primary").exists());
+    }
+
+    #[test]
+    fn test_sync_http() {
+        let local_dir = tempfile::tempdir().unwrap();
+        let remote_dir = tempfile::tempdir().unwrap();
+        let local_path = local_dir.path().join("primary");
+        let url = start_server(remote_dir.path());
+        let mut backend = Repository::new(&url, "user", "pass").unwrap();
+
+        // The first sync uploads the local database.
+        let mut database = Database::new();
+        database.set_path(&local_path).unwrap();
+        database.set_password(&PASSWORD);
+        database.sync_revision = 1;
+        database.save().unwrap();
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+
+        // A new revision replaces the remote database, even within the same second as the
+        // previous backup, which the protocol refuses to overwrite.
+        database.sync_revision = 2;
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::RemoteSynced));
+        let remote = Database::load_from_file(remote_dir.path().join("primary"), PASSWORD);
+        assert_matches!(remote, Ok(ref d) if d.sync_revision == 2);
+
+        // An older local database is replaced by the remote database.
+        database.sync_revision = 1;
+        let result = sync_with_backend(&mut backend, &database, None);
+        assert_matches!(result, Ok(SyncResult::LocalSynced));
+        let database = Database::load_from_file(&local_path, PASSWORD).unwrap();
+        assert_eq!(database.sync_revision, 2);
     }
 }
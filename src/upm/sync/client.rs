@@ -0,0 +1,617 @@
+//! Provide the HTTP client and authentication shared by the HTTP-based backends.  The client is
+//! built from a database's [`HttpOptions`](struct.HttpOptions.html), which may add a private CA
+//! bundle, a client certificate, and a proxy.  Requests are then authenticated with the
+//! repository credentials using HTTP Basic, HTTP Digest, or bearer-token authentication.
+//!
+//! A minimal XML scanner is also provided for the listings returned by WebDAV and S3 servers.
+
+use rand;
+use reqwest::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Certificate, Identity, Proxy, StatusCode};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crypto;
+use error::UpmError;
+use secret::SecretString;
+
+/// Abort HTTP-based operations if the server doesn't respond for this time interval, unless the
+/// options specify a different timeout.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+/// The scheme prefix of a Digest challenge in a `WWW-Authenticate` header.
+const DIGEST_PREFIX: &str = "digest ";
+
+/// The way requests to an HTTP-based repository are authenticated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpAuthScheme {
+    /// Send the repository username and password with HTTP Basic authentication.
+    Basic,
+    /// Answer the server's challenge with HTTP Digest authentication (RFC 7616), so the password
+    /// itself is never sent.
+    Digest,
+    /// Send the repository password as a bearer token (RFC 6750).  The username is not used.
+    Bearer,
+}
+
+impl Default for HttpAuthScheme {
+    /// Use HTTP Basic authentication by default, as the UPM sync protocol does.
+    fn default() -> HttpAuthScheme {
+        HttpAuthScheme::Basic
+    }
+}
+
+impl fmt::Display for HttpAuthScheme {
+    /// Provide the name of the scheme, as used in the sync settings.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpAuthScheme::Basic => write!(f, "basic"),
+            HttpAuthScheme::Digest => write!(f, "digest"),
+            HttpAuthScheme::Bearer => write!(f, "bearer"),
+        }
+    }
+}
+
+impl FromStr for HttpAuthScheme {
+    type Err = UpmError;
+
+    /// Parse the name of a scheme, ignoring case.
+    fn from_str(s: &str) -> Result<HttpAuthScheme, UpmError> {
+        match s.to_ascii_lowercase().as_str() {
+            "basic" => Ok(HttpAuthScheme::Basic),
+            "digest" => Ok(HttpAuthScheme::Digest),
+            "bearer" => Ok(HttpAuthScheme::Bearer),
+            _ => Err(UpmError::Sync(format!(
+                "Unknown HTTP authentication scheme: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Connection options for the HTTP-based backends.  The default options use HTTP Basic
+/// authentication, the system's trusted CAs, no proxy, and the default timeout.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpOptions {
+    /// How requests are authenticated with the repository.  The S3 backend always signs its
+    /// requests instead.
+    pub auth_scheme: HttpAuthScheme,
+    /// A PEM file containing additional CA certificates to trust, such as a private CA.
+    pub ca_bundle: Option<PathBuf>,
+    /// A PKCS#12 file containing a certificate and private key to present to the server.  When
+    /// built with the `rust_crypto` feature, this must be an unencrypted PEM file instead, since
+    /// rustls cannot read PKCS#12 files.
+    pub client_certificate: Option<PathBuf>,
+    /// The password protecting the client certificate file.  This is not used for PEM files.
+    pub client_certificate_password: SecretString,
+    /// The URL of a proxy through which all requests are sent, such as `http://proxy:3128`.
+    pub proxy: Option<String>,
+    /// The username used to authenticate with the proxy.  If empty, no credentials are sent.
+    pub proxy_username: String,
+    /// The password used to authenticate with the proxy, sent with HTTP Basic authentication
+    /// along with the proxy username.
+    pub proxy_password: SecretString,
+    /// Abort requests if the server doesn't respond for this time interval.  If `None`,
+    /// [`DEFAULT_TIMEOUT_SECS`](constant.DEFAULT_TIMEOUT_SECS.html) is used.
+    pub timeout: Option<Duration>,
+}
+
+impl HttpOptions {
+    /// Build a reqwest client using these options.
+    pub fn client(&self) -> Result<reqwest::Client, UpmError> {
+        let timeout = self
+            .timeout
+            .unwrap_or_else(|| Duration::from_secs(DEFAULT_TIMEOUT_SECS));
+        let mut builder = reqwest::Client::builder().timeout(timeout);
+        // As with the database encryption, rust_crypto takes precedence if both are enabled.
+        #[cfg(feature = "rust_crypto")]
+        {
+            builder = builder.use_rustls_tls();
+        }
+        if let Some(ref path) = self.ca_bundle {
+            let certificates = crypto::pem_certificates_to_der(&read_file(path, "CA bundle")?)?;
+            if certificates.is_empty() {
+                return Err(UpmError::Sync(format!(
+                    "No certificates found in CA bundle: {}",
+                    path.to_string_lossy()
+                )));
+            }
+            for certificate in certificates {
+                builder = builder.add_root_certificate(Certificate::from_der(&certificate)?);
+            }
+        }
+        if let Some(ref path) = self.client_certificate {
+            let bytes = read_file(path, "client certificate")?;
+            builder = builder.identity(client_identity(&bytes, &self.client_certificate_password)?);
+        }
+        if let Some(ref url) = self.proxy {
+            let mut proxy = Proxy::all(url.as_str())?;
+            if !self.proxy_username.is_empty() {
+                proxy = proxy.basic_auth(&self.proxy_username, &self.proxy_password);
+            }
+            builder = builder.proxy(proxy);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Load a client certificate and its private key from a PKCS#12 file.
+#[cfg(not(feature = "rust_crypto"))]
+fn client_identity(der: &[u8], password: &str) -> Result<Identity, UpmError> {
+    Ok(Identity::from_pkcs12_der(der, password)?)
+}
+
+/// Load a client certificate and its private key from a PEM file.
+#[cfg(feature = "rust_crypto")]
+fn client_identity(pem: &[u8], _password: &str) -> Result<Identity, UpmError> {
+    Ok(Identity::from_pem(pem)?)
+}
+
+/// Read a file named in the HTTP options, describing it in any error.
+fn read_file(path: &PathBuf, description: &str) -> Result<Vec<u8>, UpmError> {
+    fs::read(path).map_err(|e| {
+        UpmError::Sync(format!(
+            "Cannot read {} {}: {}",
+            description,
+            path.to_string_lossy(),
+            e
+        ))
+    })
+}
+
+/// Authenticate requests to an HTTP-based repository.  Every request made by a backend is sent
+/// through [`send()`](#method.send), so all operations are authenticated the same way.
+pub struct HttpAuth {
+    scheme: HttpAuthScheme,
+    username: String,
+    password: SecretString,
+    /// The most recent Digest challenge from the server, which is reused for later requests.
+    challenge: Option<DigestChallenge>,
+}
+
+impl HttpAuth {
+    /// Create a new `HttpAuth` with the provided scheme and credentials.
+    pub fn new(scheme: HttpAuthScheme, username: &str, password: &str) -> HttpAuth {
+        HttpAuth {
+            scheme,
+            username: String::from(username),
+            password: SecretString::from(password),
+            challenge: None,
+        }
+    }
+
+    /// Authenticate and send the request made by the provided function.  With Digest
+    /// authentication, the function is called a second time if the server answers with a new
+    /// challenge, since a request (and its body) can only be sent once.
+    pub fn send<F>(
+        &mut self,
+        client: &reqwest::Client,
+        build: F,
+    ) -> Result<reqwest::Response, UpmError>
+    where
+        F: Fn() -> Result<reqwest::RequestBuilder, UpmError>,
+    {
+        match self.scheme {
+            HttpAuthScheme::Basic => Ok(build()?
+                .basic_auth(self.username.as_str(), Some(self.password.as_str()))
+                .send()?),
+            HttpAuthScheme::Bearer => Ok(build()?.bearer_auth(self.password.as_str()).send()?),
+            HttpAuthScheme::Digest => {
+                let response = self.send_digest(client, build()?)?;
+                if response.status() != StatusCode::UNAUTHORIZED {
+                    return Ok(response);
+                }
+                // Answer the new challenge.  If the credentials themselves are wrong, the second
+                // response is also "401 Unauthorized", and is returned as is.
+                match DigestChallenge::from_response(&response) {
+                    Some(challenge) => self.challenge = Some(challenge),
+                    None => return Ok(response),
+                }
+                self.send_digest(client, build()?)
+            }
+        }
+    }
+
+    /// Send the request, with a Digest authorization header if a challenge is known.
+    fn send_digest(
+        &mut self,
+        client: &reqwest::Client,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, UpmError> {
+        let mut request = request.build()?;
+        if let Some(ref mut challenge) = self.challenge {
+            let uri = match request.url().query() {
+                Some(q) => format!("{}?{}", request.url().path(), q),
+                None => String::from(request.url().path()),
+            };
+            let cnonce = format!("{:016x}", rand::random::<u64>());
+            let authorization = challenge.authorization(
+                &self.username,
+                &self.password,
+                request.method().as_str(),
+                &uri,
+                &cnonce,
+            )?;
+            let value = HeaderValue::from_str(&authorization)
+                .map_err(|e| UpmError::Sync(format!("Invalid authorization header: {}", e)))?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        Ok(client.execute(request)?)
+    }
+}
+
+/// A Digest authentication challenge, as sent by the server in a `WWW-Authenticate` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    /// The hash algorithm, such as `MD5` or `SHA-256-sess`.
+    algorithm: String,
+    /// True if the server supports the "auth" quality of protection.  Otherwise, the older RFC
+    /// 2069 form of the response is used.
+    qop_auth: bool,
+    /// The number of requests made with this nonce.
+    nonce_count: u32,
+}
+
+impl DigestChallenge {
+    /// Return the first Digest challenge offered by the provided response, if any.
+    fn from_response(response: &reqwest::Response) -> Option<DigestChallenge> {
+        response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(DigestChallenge::parse)
+            .next()
+    }
+
+    /// Parse a `WWW-Authenticate` header value, returning `None` if it is not a valid Digest
+    /// challenge.
+    fn parse(header: &str) -> Option<DigestChallenge> {
+        let header = header.trim_start();
+        if !header.to_ascii_lowercase().starts_with(DIGEST_PREFIX) {
+            return None;
+        }
+        let mut challenge = DigestChallenge {
+            realm: String::new(),
+            nonce: String::new(),
+            opaque: None,
+            algorithm: String::from("MD5"),
+            qop_auth: false,
+            nonce_count: 0,
+        };
+        let mut nonce = None;
+        for (key, value) in parse_parameters(&header[DIGEST_PREFIX.len()..]) {
+            match key.to_ascii_lowercase().as_str() {
+                "realm" => challenge.realm = value,
+                "nonce" => nonce = Some(value),
+                "opaque" => challenge.opaque = Some(value),
+                "algorithm" => challenge.algorithm = value,
+                "qop" => challenge.qop_auth = value.split(',').any(|q| q.trim() == "auth"),
+                _ => {}
+            }
+        }
+        challenge.nonce = nonce?;
+        Some(challenge)
+    }
+
+    /// Hash the provided string with the challenge's algorithm, returning lowercase hex.
+    fn hash(&self, data: &str) -> Result<String, UpmError> {
+        let algorithm = self.algorithm.to_ascii_uppercase();
+        let digest = match algorithm.trim_end_matches("-SESS") {
+            "MD5" => crypto::md5(data.as_bytes())?,
+            "SHA-256" => crypto::sha256(data.as_bytes()),
+            _ => {
+                return Err(UpmError::Sync(format!(
+                    "Unsupported Digest algorithm: {}",
+                    self.algorithm
+                )))
+            }
+        };
+        Ok(crypto::hex_encode(&digest))
+    }
+
+    /// Return the `Authorization` header value answering this challenge for a request with the
+    /// provided method and URI.
+    fn authorization(
+        &mut self,
+        username: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        cnonce: &str,
+    ) -> Result<String, UpmError> {
+        self.nonce_count += 1;
+        let nc = format!("{:08x}", self.nonce_count);
+
+        let mut ha1 = self.hash(&format!("{}:{}:{}", username, self.realm, password))?;
+        if self.algorithm.to_ascii_lowercase().ends_with("-sess") {
+            ha1 = self.hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce))?;
+        }
+        let ha2 = self.hash(&format!("{}:{}", method, uri))?;
+        let response = if self.qop_auth {
+            self.hash(&format!(
+                "{}:{}:{}:{}:auth:{}",
+                ha1, self.nonce, nc, cnonce, ha2
+            ))?
+        } else {
+            self.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2))?
+        };
+
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, \
+             response=\"{}\"",
+            quote(username),
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(uri),
+            self.algorithm,
+            response
+        );
+        if let Some(ref opaque) = self.opaque {
+            header.push_str(&format!(", opaque=\"{}\"", quote(opaque)));
+        }
+        if self.qop_auth {
+            header.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+        }
+        Ok(header)
+    }
+}
+
+/// Parse the comma-separated `key=value` parameters of an authentication challenge.  Values may
+/// be quoted strings, which can contain commas and backslash-escaped characters.
+fn parse_parameters(s: &str) -> Vec<(String, String)> {
+    let mut parameters = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        // Skip separators, then read the key.
+        while matches!(chars.peek(), Some(&c) if c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ',' {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if key.is_empty() {
+            return parameters;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+                chars.next();
+            }
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => value.extend(chars.next()),
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c == ',' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+        parameters.push((String::from(key.trim()), String::from(value.trim_end())));
+    }
+}
+
+/// Escape a string for use within a quoted header parameter.
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Percent-encode a string for use in a URL path: every byte other than the unreserved characters
+/// of RFC 3986 is encoded.  This is also the encoding required by S3's SigV4.  Slashes are left
+/// alone if `keep_slash` is true, so the string may contain path separators.
+pub fn percent_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Return the contents of each element with the provided local name in an XML document, in
+/// document order.  Namespace prefixes are ignored, and the contents are returned as they appear
+/// (see [`xml_unescape()`](fn.xml_unescape.html)).  This is not a general XML parser: it is only
+/// meant for the simple, machine-generated listings of WebDAV and S3 servers, and does not handle
+/// elements nested within elements of the same name, comments, or CDATA sections.
+pub fn xml_elements<'a>(xml: &'a str, local_name: &str) -> Vec<&'a str> {
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(e) => e,
+            None => break,
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        if name.is_empty() || name.rsplit(':').next() != Some(local_name) {
+            continue;
+        }
+        if tag.ends_with('/') {
+            elements.push("");
+            continue;
+        }
+        let close = format!("</{}>", name);
+        match rest.find(&close) {
+            Some(e) => {
+                elements.push(&rest[..e]);
+                rest = &rest[e + close.len()..];
+            }
+            None => break,
+        }
+    }
+    elements
+}
+
+/// Decode the predefined entities and character references in XML text.
+pub fn xml_unescape(s: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(e) => e,
+            None => break,
+        };
+        let decoded = match &rest[1..end] {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => match entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => entity.strip_prefix('#').and_then(|d| d.parse().ok()),
+            }
+            .and_then(::std::char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_scheme() {
+        for scheme in &[
+            HttpAuthScheme::Basic,
+            HttpAuthScheme::Digest,
+            HttpAuthScheme::Bearer,
+        ] {
+            assert_eq!(
+                scheme.to_string().parse::<HttpAuthScheme>().unwrap(),
+                *scheme
+            );
+        }
+        assert_eq!(
+            "Digest".parse::<HttpAuthScheme>().unwrap(),
+            HttpAuthScheme::Digest
+        );
+        assert_matches!("ntlm".parse::<HttpAuthScheme>(), Err(UpmError::Sync(_)));
+    }
+
+    #[test]
+    fn test_digest_challenge() {
+        let challenge = DigestChallenge::parse(
+            "Digest realm=\"a, \\\"quoted\\\" realm\", qop=\"auth,auth-int\", \
+             nonce=\"abc\", algorithm=SHA-256, stale=TRUE",
+        )
+        .unwrap();
+        assert_eq!(challenge.realm, "a, \"quoted\" realm");
+        assert_eq!(challenge.nonce, "abc");
+        assert_eq!(challenge.algorithm, "SHA-256");
+        assert_eq!(challenge.opaque, None);
+        assert!(challenge.qop_auth);
+
+        assert_eq!(DigestChallenge::parse("Basic realm=\"x\""), None);
+        assert_eq!(DigestChallenge::parse("Digest realm=\"x\""), None);
+    }
+
+    #[test]
+    fn test_digest_authorization() {
+        // The example from RFC 2617, section 3.5.
+        let mut challenge = DigestChallenge::parse(
+            "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        )
+        .unwrap();
+        let header = challenge
+            .authorization(
+                "Mufasa",
+                "Circle Of Life",
+                "GET",
+                "/dir/index.html",
+                "0a4f113b",
+            )
+            .unwrap();
+        assert!(header.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+        assert!(header.contains("nc=00000001"));
+        assert!(header.contains("opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""));
+
+        // The nonce count increases with each request.
+        let header = challenge
+            .authorization("Mufasa", "Circle Of Life", "GET", "/", "0a4f113b")
+            .unwrap();
+        assert!(header.contains("nc=00000002"));
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("upm/primary.bak", true), "upm/primary.bak");
+        assert_eq!(percent_encode("a b/c~d", false), "a%20b%2Fc~d");
+        assert_eq!(percent_encode("#1?%", false), "%231%3F%25");
+    }
+
+    #[test]
+    fn test_xml_elements() {
+        let xml = "<?xml version=\"1.0\"?>\
+                   <d:multistatus xmlns:d=\"DAV:\">\
+                   <d:response><d:href>/dav/a%20b</d:href><d:resourcetype/></d:response>\
+                   <d:response><d:href>/dav/sub/</d:href>\
+                   <d:resourcetype><d:collection/></d:resourcetype></d:response>\
+                   </d:multistatus>";
+        let responses = xml_elements(xml, "response");
+        assert_eq!(responses.len(), 2);
+        assert_eq!(xml_elements(responses[0], "href"), vec!["/dav/a%20b"]);
+        assert_eq!(xml_elements(responses[0], "collection").len(), 0);
+        assert_eq!(xml_elements(responses[1], "collection"), vec![""]);
+        assert_eq!(
+            xml_elements("<Key>x</Key><Key a=\"1\">y</Key>", "Key"),
+            vec!["x", "y"]
+        );
+        assert_eq!(xml_elements("<Key>unterminated", "Key").len(), 0);
+
+        assert_eq!(xml_unescape("a &amp; b &lt;c&gt;"), "a & b <c>");
+        assert_eq!(xml_unescape("&#65;&#x42;&quot;&apos;"), "AB\"'");
+        assert_eq!(xml_unescape("AT&T &bogus; &"), "AT&T &bogus; &");
+    }
+}
@@ -0,0 +1,170 @@
+//! Provide a sync backend which treats a directory in the local filesystem as the remote
+//! repository.  This is useful when the database is shared through a network filesystem or a file
+//! synchronization tool (e.g. NFS or Syncthing) rather than a UPM sync server.
+//!
+//! Directory repositories are specified with a `file://` URL, such as `file:///srv/upm/`.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use database::Database;
+use error::UpmError;
+use sync::SyncBackend;
+
+/// The URL scheme prefix used to specify a directory repository.
+const FILE_URL_PREFIX: &str = "file://";
+
+/// Databases are written to a temporary file with this suffix before being renamed into place.
+const TEMPORARY_SUFFIX: &str = ".tmp";
+
+/// Provide access to a repository stored in a local directory.
+#[derive(Debug)]
+pub struct DirectoryRepository {
+    path: PathBuf,
+}
+
+impl DirectoryRepository {
+    /// Create a new `DirectoryRepository` for the provided directory.  The directory must already
+    /// exist.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<DirectoryRepository, UpmError> {
+        let path = path.as_ref().to_path_buf();
+        if !path.is_dir() {
+            return Err(UpmError::Sync(format!(
+                "Repository directory does not exist: {}",
+                path.display()
+            )));
+        }
+        Ok(DirectoryRepository { path })
+    }
+
+    /// Return the directory path represented by a `file://` URL, or `None` if the URL does not
+    /// refer to a directory repository.
+    pub fn url_to_path(url: &str) -> Option<PathBuf> {
+        url.strip_prefix(FILE_URL_PREFIX).map(PathBuf::from)
+    }
+
+    /// Return the path of the named database within the repository directory.  Database names
+    /// must be a single plain path component, so that a remote name can never refer to a file
+    /// outside of the repository.
+    fn database_path(&self, database_name: &str) -> Result<PathBuf, UpmError> {
+        let mut components = Path::new(database_name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.path.join(database_name)),
+            _ => Err(UpmError::InvalidFilename),
+        }
+    }
+}
+
+impl SyncBackend for DirectoryRepository {
+    /// Read the named database from the repository directory.
+    fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+        match fs::read(self.database_path(database_name)?) {
+            Ok(bytes) => Ok(bytes),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(UpmError::SyncDatabaseNotFound)
+            }
+            Err(e) => Err(UpmError::Io(e)),
+        }
+    }
+
+    /// Write the named database into the repository directory.  The database is first written to
+    /// a temporary file which is then renamed into place, so other readers of the directory never
+    /// observe a partially written database.
+    fn upload(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        Database::save_raw_bytes(database_bytes, self.database_path(database_name)?)
+    }
+
+    /// Remove the named database from the repository directory.
+    fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+        match fs::remove_file(self.database_path(database_name)?) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(UpmError::SyncDatabaseNotFound)
+            }
+            Err(e) => Err(UpmError::Io(e)),
+        }
+    }
+
+    /// Return true if the named database is present in the repository directory.
+    fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+        Ok(self.database_path(database_name)?.is_file())
+    }
+
+    /// List the files in the repository directory.  Temporary files left by interrupted uploads
+    /// and names which are not valid Unicode are skipped.
+    fn list(&mut self) -> Result<Vec<String>, UpmError> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            match entry.file_name().into_string() {
+                Ok(name) if !name.ends_with(TEMPORARY_SUFFIX) => names.push(name),
+                _ => {}
+            }
+        }
+        Ok(names)
+    }
+
+    /// Atomically replace the named database.  Since `upload()` already renames a complete file
+    /// into place, there is no need to delete the existing database first.
+    fn replace(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        self.upload(database_name, database_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn test_url_to_path() {
+        assert_eq!(
+            DirectoryRepository::url_to_path("file:///srv/upm/"),
+            Some(PathBuf::from("/srv/upm/"))
+        );
+        assert_eq!(
+            DirectoryRepository::url_to_path("https://example.com/upm/"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_directory_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_matches!(
+            DirectoryRepository::new(dir.path().join("missing")),
+            Err(UpmError::Sync(_))
+        );
+        let mut repo = DirectoryRepository::new(dir.path()).unwrap();
+
+        assert_matches!(repo.exists("primary"), Ok(false));
+        assert_matches!(
+            repo.download("primary"),
+            Err(UpmError::SyncDatabaseNotFound)
+        );
+        assert_matches!(repo.delete("primary"), Err(UpmError::SyncDatabaseNotFound));
+
+        assert_matches!(repo.upload("primary", vec![1, 2, 3]), Ok(()));
+        assert_matches!(repo.exists("primary"), Ok(true));
+        assert_matches!(repo.download("primary"), Ok(ref b) if b == &[1, 2, 3]);
+        assert_matches!(repo.replace("primary", vec![4, 5]), Ok(()));
+        assert_matches!(repo.download("primary"), Ok(ref b) if b == &[4, 5]);
+        assert!(!dir.path().join("primary.tmp").exists());
+
+        // Directories and leftover temporary files are not listed.
+        fs::create_dir(dir.path().join("subdir")).unwrap();
+        fs::write(dir.path().join("secondary.tmp"), b"partial").unwrap();
+        assert_matches!(repo.list(), Ok(ref n) if n == &["primary"]);
+        assert_matches!(repo.delete("primary"), Ok(()));
+        assert_matches!(repo.exists("primary"), Ok(false));
+
+        // Names which would escape the repository directory are rejected.
+        assert_matches!(repo.download("../primary"), Err(UpmError::InvalidFilename));
+        assert_matches!(repo.upload("a/b", vec![]), Err(UpmError::InvalidFilename));
+        assert_matches!(repo.exists(""), Err(UpmError::InvalidFilename));
+    }
+}
@@ -0,0 +1,353 @@
+//! Provide a sync backend which stores databases in a git repository.  Every change to the remote
+//! repository is committed and pushed, so the remote keeps a full history of every revision of the
+//! database.  This makes the remote backup copies normally uploaded by `sync()` unnecessary.
+//!
+//! Git repositories are specified with a `git+` URL prefix followed by anything that `git` itself
+//! can reach, optionally followed by a `#branch` suffix.  For example, `git+/srv/upm.git`,
+//! `git+ssh://git@example.com/upm.git`, or `git+https://example.com/upm.git#passwords`.  Git's
+//! own configuration (SSH keys, credential helpers, etc.) is used for authentication.
+//!
+//! The backend works through a local clone of the remote repository.  Before every operation, the
+//! clone is fetched and fast-forwarded to the remote branch, so the local clone never holds work
+//! of its own.  The `git` command must be installed.
+
+use rand::{OsRng, Rng};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use database::Database;
+use error::UpmError;
+use sync::SyncBackend;
+
+/// The URL scheme prefix used to specify a git repository.
+const GIT_URL_PREFIX: &str = "git+";
+/// This separator introduces an optional branch name at the end of the URL.
+const BRANCH_SEPARATOR: char = '#';
+/// This branch is used if the URL does not specify one.
+const DEFAULT_BRANCH: &str = "master";
+/// The name of the git remote used within the local clone.
+const REMOTE_NAME: &str = "origin";
+/// Commits are made with this identity.  It is only configured within the local clone.
+const COMMIT_USER_NAME: &str = "UPM sync";
+const COMMIT_USER_EMAIL: &str = "upm-sync@localhost";
+
+/// Provide access to a repository stored in a git remote.
+pub struct GitRepository {
+    remote: String,
+    branch: String,
+    work_dir: PathBuf,
+    /// If true, the local clone was created in a temporary directory and is removed on drop.
+    temporary: bool,
+}
+
+impl GitRepository {
+    /// Create a new `GitRepository` for the provided `git+` URL, using a local clone in the
+    /// provided working directory.  The working directory is created if needed, and is reused by
+    /// later syncs.  If no working directory is provided, a temporary clone is used instead.
+    pub fn new(url: &str, work_dir: Option<&Path>) -> Result<GitRepository, UpmError> {
+        let (remote, branch) = match GitRepository::parse_url(url) {
+            Some(r) => r,
+            None => return Err(UpmError::Sync(format!("Not a git URL: {}", url))),
+        };
+        // Both are passed to git as arguments, where a leading dash would make them options.
+        if remote.starts_with('-') || branch.starts_with('-') {
+            return Err(UpmError::Sync(format!("Invalid git URL: {}", url)));
+        }
+        let (work_dir, temporary) = match work_dir {
+            Some(d) => {
+                if !d.is_dir() {
+                    fs::create_dir_all(d)?;
+                }
+                (d.to_path_buf(), false)
+            }
+            None => (GitRepository::create_temporary_dir()?, true),
+        };
+        let mut repo = GitRepository {
+            remote,
+            branch,
+            work_dir,
+            temporary,
+        };
+        repo.initialize()?;
+        Ok(repo)
+    }
+
+    /// Return the remote and branch represented by a `git+` URL, or `None` if the URL does not
+    /// refer to a git repository.
+    pub fn parse_url(url: &str) -> Option<(String, String)> {
+        let rest = url.strip_prefix(GIT_URL_PREFIX)?;
+        if rest.is_empty() {
+            return None;
+        }
+        match rest.rfind(BRANCH_SEPARATOR) {
+            Some(i) if i + 1 < rest.len() => {
+                Some((String::from(&rest[..i]), String::from(&rest[i + 1..])))
+            }
+            _ => Some((String::from(rest), String::from(DEFAULT_BRANCH))),
+        }
+    }
+
+    /// Return the directory used for the persistent local clone belonging to the provided
+    /// database.  The clone is kept as a hidden directory next to the database file.
+    pub fn work_dir_for(database: &Database) -> Option<PathBuf> {
+        match (database.path(), database.name()) {
+            (Some(path), Some(name)) => Some(path.with_file_name(format!(".{}.git", name))),
+            _ => None,
+        }
+    }
+
+    /// Create a new, uniquely named temporary directory.
+    fn create_temporary_dir() -> Result<PathBuf, UpmError> {
+        let mut rng = OsRng::new()?;
+        let path = ::std::env::temp_dir().join(format!("upm-git-{:016x}", rng.next_u64()));
+        fs::create_dir(&path)?;
+        Ok(path)
+    }
+
+    /// Run a git command within the local clone, and return its standard output.
+    fn git(&self, args: &[&str]) -> Result<String, UpmError> {
+        let output = match Command::new("git")
+            .args(args)
+            .current_dir(&self.work_dir)
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .output()
+        {
+            Ok(o) => o,
+            Err(e) => return Err(UpmError::Sync(format!("Cannot run git: {}", e))),
+        };
+        if !output.status.success() {
+            return Err(UpmError::Sync(format!(
+                "git {} failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Prepare the local clone, if this has not already been done.
+    fn initialize(&mut self) -> Result<(), UpmError> {
+        if self.work_dir.join(".git").is_dir() {
+            // Follow any change of remote URL.
+            self.git(&["remote", "set-url", "--", REMOTE_NAME, &self.remote])?;
+            return Ok(());
+        }
+        self.git(&["init", "--quiet"])?;
+        self.git(&["remote", "add", "--", REMOTE_NAME, &self.remote])?;
+        self.git(&["config", "user.name", COMMIT_USER_NAME])?;
+        self.git(&["config", "user.email", COMMIT_USER_EMAIL])?;
+        Ok(())
+    }
+
+    /// Fetch the remote branch and fast-forward the local clone to it.  If the remote branch does
+    /// not exist yet (e.g. a freshly created bare repository), the local clone is emptied so the
+    /// first commit will create the branch.
+    fn refresh(&mut self) -> Result<(), UpmError> {
+        let remote_ref = format!("refs/heads/{}", self.branch);
+        let heads = self.git(&["ls-remote", "--heads", REMOTE_NAME, &remote_ref])?;
+        if heads.trim().is_empty() {
+            self.git(&["symbolic-ref", "HEAD", &remote_ref])?;
+            self.git(&["update-ref", "-d", &remote_ref])?;
+            self.git(&["read-tree", "--empty"])?;
+            self.git(&["clean", "-f", "-d", "--quiet"])?;
+            return Ok(());
+        }
+        self.git(&["fetch", "--quiet", REMOTE_NAME, &remote_ref])?;
+        // The local clone never has work of its own, so it can always be moved to the fetched
+        // commit -- even if an earlier push failed and left an unpublished commit behind.
+        self.git(&[
+            "checkout",
+            "--quiet",
+            "-B",
+            &self.branch,
+            "FETCH_HEAD",
+            "--",
+        ])?;
+        self.git(&["reset", "--quiet", "--hard", "FETCH_HEAD"])?;
+        Ok(())
+    }
+
+    /// Commit any staged changes with the provided message and push them to the remote branch.
+    fn commit_and_push(&mut self, message: &str) -> Result<(), UpmError> {
+        if self.git(&["diff", "--cached", "--quiet"]).is_ok() {
+            // Nothing changed.
+            return Ok(());
+        }
+        self.git(&["commit", "--quiet", "-m", message])?;
+        let refspec = format!("HEAD:refs/heads/{}", self.branch);
+        self.git(&["push", "--quiet", REMOTE_NAME, &refspec])?;
+        Ok(())
+    }
+
+    /// Return the path of the named database within the local clone.  Database names must be a
+    /// single plain path component.
+    fn database_path(&self, database_name: &str) -> Result<PathBuf, UpmError> {
+        let mut components = Path::new(database_name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(c)), None) if c != ".git" => {
+                Ok(self.work_dir.join(database_name))
+            }
+            _ => Err(UpmError::InvalidFilename),
+        }
+    }
+}
+
+impl Drop for GitRepository {
+    fn drop(&mut self) {
+        if self.temporary {
+            fs::remove_dir_all(&self.work_dir).unwrap_or_default();
+        }
+    }
+}
+
+impl SyncBackend for GitRepository {
+    /// Fetch the remote branch and read the named database from it.
+    fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+        let path = self.database_path(database_name)?;
+        self.refresh()?;
+        match fs::read(path) {
+            Ok(bytes) => Ok(bytes),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(UpmError::SyncDatabaseNotFound)
+            }
+            Err(e) => Err(UpmError::Io(e)),
+        }
+    }
+
+    /// Commit the provided database to the remote branch.
+    fn upload(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        let path = self.database_path(database_name)?;
+        self.refresh()?;
+        fs::write(path, database_bytes)?;
+        self.git(&["add", "--", database_name])?;
+        self.commit_and_push(&format!("Update {}", database_name))
+    }
+
+    /// Commit the removal of the named database to the remote branch.
+    fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+        let path = self.database_path(database_name)?;
+        self.refresh()?;
+        if !path.is_file() {
+            return Err(UpmError::SyncDatabaseNotFound);
+        }
+        self.git(&["rm", "--quiet", "--", database_name])?;
+        self.commit_and_push(&format!("Delete {}", database_name))
+    }
+
+    /// Return true if the named database is present on the remote branch.
+    fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+        let path = self.database_path(database_name)?;
+        self.refresh()?;
+        Ok(path.is_file())
+    }
+
+    /// List the files on the remote branch.  Only files at the top of the tree are databases.
+    fn list(&mut self) -> Result<Vec<String>, UpmError> {
+        self.refresh()?;
+        let files = self.git(&["ls-files", "-z"])?;
+        Ok(files
+            .split('\0')
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .map(String::from)
+            .collect())
+    }
+
+    /// Replace the named database.  A commit replaces the file atomically, so there is no need to
+    /// delete the existing database first.
+    fn replace(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        self.upload(database_name, database_bytes)
+    }
+
+    /// Every revision is preserved in the remote history.
+    fn keeps_history(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            GitRepository::parse_url("git+/srv/upm.git"),
+            Some((String::from("/srv/upm.git"), String::from(DEFAULT_BRANCH)))
+        );
+        assert_eq!(
+            GitRepository::parse_url("git+ssh://git@example.com/upm.git#passwords"),
+            Some((
+                String::from("ssh://git@example.com/upm.git"),
+                String::from("passwords")
+            ))
+        );
+        assert_eq!(GitRepository::parse_url("git+"), None);
+        assert_eq!(GitRepository::parse_url("https://example.com/upm"), None);
+
+        // Remotes and branches which git would take as options are refused before it is run.
+        for url in &["git+-oProxyCommand=false", "git+/srv/upm.git#--orphan"] {
+            assert!(matches!(
+                GitRepository::new(url, None),
+                Err(UpmError::Sync(_))
+            ));
+        }
+    }
+
+    /// Return true if the git command is available for testing.
+    fn have_git() -> bool {
+        Command::new("git").arg("--version").output().is_ok()
+    }
+
+    #[test]
+    fn test_git_repository() {
+        if !have_git() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let remote = dir.path().join("remote.git");
+        let output = Command::new("git")
+            .args(["init", "--quiet", "--bare"])
+            .arg(&remote)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let url = format!("git+{}", remote.display());
+
+        // Operate on an empty remote with a persistent clone.
+        let work_dir = dir.path().join("clone");
+        let mut repo = GitRepository::new(&url, Some(&work_dir)).unwrap();
+        assert_matches!(repo.exists("primary"), Ok(false));
+        assert_matches!(
+            repo.download("primary"),
+            Err(UpmError::SyncDatabaseNotFound)
+        );
+        assert_matches!(repo.upload("primary", vec![1, 2, 3]), Ok(()));
+        assert_matches!(repo.download("primary"), Ok(ref b) if b == &[1, 2, 3]);
+        assert_matches!(repo.list(), Ok(ref n) if n == &["primary"]);
+
+        // A second, temporary clone sees the change and can make its own.
+        let mut other = GitRepository::new(&url, None).unwrap();
+        let other_dir = other.work_dir.clone();
+        assert_matches!(other.download("primary"), Ok(ref b) if b == &[1, 2, 3]);
+        assert_matches!(other.replace("primary", vec![4, 5]), Ok(()));
+        drop(other);
+        assert!(!other_dir.exists());
+
+        // The first clone fast-forwards to the new revision.
+        assert_matches!(repo.download("primary"), Ok(ref b) if b == &[4, 5]);
+        assert_matches!(repo.delete("primary"), Ok(()));
+        assert_matches!(repo.exists("primary"), Ok(false));
+        assert_matches!(repo.delete("primary"), Err(UpmError::SyncDatabaseNotFound));
+
+        // Every change is preserved in the history of the remote.
+        let count = repo.git(&["rev-list", "--count", "HEAD"]).unwrap();
+        assert_eq!(count.trim(), "3");
+
+        // Names which would escape the clone are rejected.
+        assert_matches!(repo.download("../primary"), Err(UpmError::InvalidFilename));
+        assert_matches!(repo.upload(".git", vec![]), Err(UpmError::InvalidFilename));
+    }
+}
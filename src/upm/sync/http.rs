@@ -0,0 +1,181 @@
+//! Provide a sync backend for remote repositories which speak the UPM sync protocol over HTTP or
+//! HTTPS.  This is the protocol implemented by the PHP scripts distributed with the original UPM
+//! Java application: databases are downloaded with a plain GET request, and modified with the
+//! `upload.php` and `deletefile.php` commands.
+
+use reqwest::multipart;
+use std::io::Read;
+
+use error::UpmError;
+use sync::{HttpAuth, HttpOptions, SyncBackend};
+
+/// The UPM sync protocol's delete command.  This is appended to the repository URL.
+const DELETE_CMD: &'static str = "deletefile.php";
+/// The UPM sync protocol's upload command.  This is appended to the repository URL.
+const UPLOAD_CMD: &'static str = "upload.php";
+/// This field name is used for the database file when uploading.
+const UPM_UPLOAD_FIELD_NAME: &'static str = "userfile";
+
+/// The UPM sync protocol returns an HTTP body of "OK" if the request was successful, otherwise it
+/// returns one of these error codes: FILE_DOESNT_EXIST, FILE_WASNT_DELETED, FILE_ALREADY_EXISTS,
+/// FILE_WASNT_MOVED, FILE_WASNT_UPLOADED
+const UPM_SUCCESS: &'static str = "OK";
+
+/// The UPM sync protocol response when the database to delete does not exist.
+const UPM_FILE_DOESNT_EXIST: &str = "FILE_DOESNT_EXIST";
+
+/// UPM sync protocol responses should never be longer than this size.
+const UPM_MAX_RESPONSE_CODE_LENGTH: usize = 64;
+
+/// The MIME type used when uploading a database.
+const DATABASE_MIME_TYPE: &'static str = "application/octet-stream";
+
+/// Provide basic access to a remote repository using the UPM sync protocol.
+pub struct Repository {
+    url: String,
+    auth: HttpAuth,
+    client: reqwest::Client,
+}
+
+impl Repository {
+    /// Create a new `Repository` struct with the provided URL and credentials, using the default
+    /// HTTP options.
+    pub fn new(
+        url: &str,
+        http_username: &str,
+        http_password: &str,
+    ) -> Result<Repository, UpmError> {
+        Repository::with_options(url, http_username, http_password, &Default::default())
+    }
+
+    /// Create a new `Repository` struct with the provided URL, credentials, and HTTP options.
+    pub fn with_options(
+        url: &str,
+        http_username: &str,
+        http_password: &str,
+        options: &HttpOptions,
+    ) -> Result<Repository, UpmError> {
+        Ok(Repository {
+            url: String::from(url),
+            auth: HttpAuth::new(options.auth_scheme, http_username, http_password),
+            client: options.client()?,
+        })
+    }
+
+    /// Construct a URL by appending the provided string to the repository URL, adding a separating
+    /// slash character if needed.
+    fn make_url(&self, path_component: &str) -> String {
+        if self.url.ends_with('/') {
+            format!("{}{}", self.url, path_component)
+        } else {
+            format!("{}/{}", self.url, path_component)
+        }
+    }
+
+    /// Confirm that the HTTP response was successful and valid.
+    fn check_response(&self, response: &mut reqwest::Response) -> Result<(), UpmError> {
+        if !response.status().is_success() {
+            return Err(UpmError::Sync(format!("{}", response.status())));
+        }
+        let mut response_code = String::new();
+        response.read_to_string(&mut response_code)?;
+        if response_code.len() > UPM_MAX_RESPONSE_CODE_LENGTH {
+            return Err(UpmError::Sync(format!(
+                "Unexpected response from server ({} bytes)",
+                response_code.len()
+            )));
+        }
+        if response_code == UPM_FILE_DOESNT_EXIST {
+            return Err(UpmError::SyncDatabaseNotFound);
+        }
+        if response_code != UPM_SUCCESS {
+            return Err(UpmError::Sync(format!("Server error: {}", response_code)));
+        }
+        Ok(())
+    }
+}
+
+impl SyncBackend for Repository {
+    /// Download the remote database with the provided name.  The database is returned in raw form
+    /// as a byte buffer.
+    fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+        let url = self.make_url(database_name);
+
+        // Send request
+        let client = &self.client;
+        let mut response = self.auth.send(client, || Ok(client.get(&url)))?;
+
+        // Process response
+        if !response.status().is_success() {
+            return match response.status() {
+                reqwest::StatusCode::NOT_FOUND => Err(UpmError::SyncDatabaseNotFound),
+                _ => Err(UpmError::Sync(format!("{}", response.status()))),
+            };
+        }
+        let mut data: Vec<u8> = Vec::new();
+        response.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Upload the provided database to the remote repository.  The database is provided in raw
+    /// form as a byte buffer.
+    fn upload(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        let url: String = self.make_url(UPLOAD_CMD);
+
+        // Send request.  The form is built for each attempt, since Digest authentication may need
+        // to send the request twice.
+        let client = &self.client;
+        let mut response = self.auth.send(client, || {
+            // Thanks to Sean (seanmonstar) for helping to translate this code to multipart code
+            // of reqwest
+            let part = multipart::Part::bytes(database_bytes.clone())
+                .file_name(database_name.to_string())
+                .mime_str(DATABASE_MIME_TYPE)?;
+
+            let form = multipart::Form::new().part(UPM_UPLOAD_FIELD_NAME, part);
+            Ok(client.post(&url).multipart(form))
+        })?;
+
+        // Process response
+        self.check_response(&mut response)?;
+        Ok(())
+    }
+
+    /// Delete the specified database from the remote repository.
+    fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+        let url = self.make_url(DELETE_CMD);
+
+        // Send request
+        let client = &self.client;
+        let mut response = self.auth.send(client, || {
+            Ok(client.post(&url).form(&[("fileToDelete", database_name)]))
+        })?;
+
+        // Process response
+        self.check_response(&mut response)?;
+        Ok(())
+    }
+
+    /// Return true if the named database is present in the remote repository.  The UPM sync
+    /// protocol has no dedicated command for this, so a HEAD request is made for the database.
+    fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+        let url = self.make_url(database_name);
+
+        // Send request
+        let client = &self.client;
+        let response = self.auth.send(client, || Ok(client.head(&url)))?;
+
+        // Process response
+        match response.status() {
+            s if s.is_success() => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            s => Err(UpmError::Sync(format!("{}", s))),
+        }
+    }
+
+    /// The UPM sync server is shared with the UPM Java client, which can only read version 3
+    /// databases.
+    fn accepts_hardened_format(&self) -> bool {
+        false
+    }
+}
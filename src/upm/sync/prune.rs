@@ -0,0 +1,253 @@
+//! Prune the backup copies which `sync()` uploads to a remote repository before replacing the
+//! remote database.  Backends provide no way to list the files in a repository, so only the
+//! backups recorded in the database's [`SyncState`](struct.SyncState.html) are considered; the
+//! timestamp in each backup's name tells its age.  Old backups are deleted with the backend's
+//! "delete" primitive.
+
+use std::time::Duration;
+
+use backup;
+use database::Database;
+use error::UpmError;
+use sync::{SyncBackend, SyncState};
+use time;
+
+/// Keep this many remote backups when pruning, unless the settings specify otherwise.
+pub const DEFAULT_REMOTE_BACKUPS_KEEP: usize = 10;
+
+/// Decide which remote backups to delete.  A backup is deleted if it is not among the newest
+/// `keep` backups, or if it is older than `max_age`.  If neither limit is set, nothing is deleted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteBackupPolicy {
+    /// Keep at most this many backups.
+    pub keep: Option<usize>,
+    /// Keep no backups older than this.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RemoteBackupPolicy {
+    fn default() -> RemoteBackupPolicy {
+        RemoteBackupPolicy {
+            keep: Some(DEFAULT_REMOTE_BACKUPS_KEEP),
+            max_age: None,
+        }
+    }
+}
+
+impl RemoteBackupPolicy {
+    /// Return the provided backup names which this policy would delete at the provided (local)
+    /// time, oldest first.  Names which do not contain a backup timestamp are never deleted.
+    pub fn expired<'a>(
+        &self,
+        backups: &'a [String],
+        now: time::Tm,
+    ) -> Result<Vec<&'a String>, UpmError> {
+        let mut dated: Vec<(&str, &String)> = backups
+            .iter()
+            .filter_map(|name| backup::backup_timestamp(name).map(|t| (t, name)))
+            .collect();
+        // Timestamps in backup names sort chronologically.
+        dated.sort_by_key(|&(timestamp, _)| timestamp);
+
+        let surplus = match self.keep {
+            Some(keep) => dated.len().saturating_sub(keep),
+            None => 0,
+        };
+        let cutoff = match self.max_age {
+            Some(age) => Some(backup::backup_timestamp_at(
+                &(now - time::Duration::seconds(age.as_secs() as i64)),
+            )?),
+            None => None,
+        };
+        Ok(dated
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, (timestamp, _))| {
+                i < surplus || matches!(cutoff, Some(ref c) if timestamp < c.as_str())
+            })
+            .map(|(_, (_, name))| name)
+            .collect())
+    }
+}
+
+/// Delete the remote backups of the provided database which have expired according to the
+/// provided policy, and return their names.  A backup which is already missing from the
+/// repository is simply forgotten.  If a deletion fails, the backups deleted so far are still
+/// forgotten before the error is returned.
+pub fn prune_remote_backups<B: SyncBackend + ?Sized>(
+    repo: &mut B,
+    database: &Database,
+    policy: &RemoteBackupPolicy,
+) -> Result<Vec<String>, UpmError> {
+    let state_path = match SyncState::path_for(database) {
+        Some(p) => p,
+        None => return Err(UpmError::NoDatabaseFilename),
+    };
+    let backups = SyncState::load_remote_backups(&state_path)?;
+    let expired: Vec<String> = policy
+        .expired(&backups, time::now())?
+        .into_iter()
+        .cloned()
+        .collect();
+
+    let mut deleted = Vec::new();
+    let mut result = Ok(());
+    for name in expired {
+        match repo.delete(&name) {
+            Ok(()) | Err(UpmError::SyncDatabaseNotFound) => deleted.push(name),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    if !deleted.is_empty() {
+        let remaining: Vec<String> = backups
+            .into_iter()
+            .filter(|name| !deleted.contains(name))
+            .collect();
+        SyncState::save_remote_backups(&state_path, &remaining)?;
+    }
+    result.map(|()| deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use tempfile;
+
+    /// A backend holding only the names of its databases, which fails to delete "locked" ones.
+    struct NameBackend {
+        names: HashSet<String>,
+    }
+
+    impl SyncBackend for NameBackend {
+        fn download(&mut self, _: &str) -> Result<Vec<u8>, UpmError> {
+            Err(UpmError::SyncDatabaseNotFound)
+        }
+        fn upload(&mut self, database_name: &str, _: Vec<u8>) -> Result<(), UpmError> {
+            self.names.insert(String::from(database_name));
+            Ok(())
+        }
+        fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+            if database_name.contains("locked") {
+                return Err(UpmError::Sync(String::from("Permission denied")));
+            }
+            if self.names.remove(database_name) {
+                Ok(())
+            } else {
+                Err(UpmError::SyncDatabaseNotFound)
+            }
+        }
+        fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+            Ok(self.names.contains(database_name))
+        }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+
+    #[test]
+    fn test_expired() {
+        let backups = names(&[
+            "primary.20190103000000.bak",
+            "primary.20190101000000.bak",
+            "primary.bak",
+            "primary.20190102000000.bak",
+        ]);
+        let now = time::strptime("20190103120000", "%Y%m%d%H%M%S").unwrap();
+        let expired = |keep, max_age_days: Option<u64>| {
+            let policy = RemoteBackupPolicy {
+                keep,
+                max_age: max_age_days.map(|d| Duration::from_secs(d * 86400)),
+            };
+            policy.expired(&backups, now).unwrap()
+        };
+
+        assert_eq!(expired(None, None).len(), 0);
+        assert_eq!(expired(Some(5), None).len(), 0);
+        assert_eq!(
+            expired(Some(1), None),
+            vec!["primary.20190101000000.bak", "primary.20190102000000.bak"]
+        );
+        assert_eq!(expired(Some(0), None).len(), 3);
+        assert_eq!(expired(None, Some(2)), vec!["primary.20190101000000.bak"]);
+        assert_eq!(expired(None, Some(30)).len(), 0);
+        // Either limit may expire a backup.
+        assert_eq!(
+            expired(Some(2), Some(2)),
+            vec!["primary.20190101000000.bak"]
+        );
+        assert_eq!(expired(Some(1), Some(30)).len(), 2);
+    }
+
+    #[test]
+    fn test_prune_remote_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new();
+        let policy = RemoteBackupPolicy {
+            keep: Some(1),
+            max_age: None,
+        };
+        let mut repo = NameBackend {
+            names: HashSet::new(),
+        };
+        assert_matches!(
+            prune_remote_backups(&mut repo, &database, &policy),
+            Err(UpmError::NoDatabaseFilename)
+        );
+        database.set_path(&dir.path().join("primary")).unwrap();
+        let state_path = SyncState::path_for(&database).unwrap();
+        assert_eq!(
+            prune_remote_backups(&mut repo, &database, &policy).unwrap(),
+            Vec::<String>::new()
+        );
+
+        // Backups which are already gone are forgotten along with the deleted ones.
+        for name in &["primary.20190101000000.bak", "primary.20190103000000.bak"] {
+            repo.names.insert(String::from(*name));
+        }
+        for name in &[
+            "primary.20190101000000.bak",
+            "primary.20190102000000.bak",
+            "primary.20190103000000.bak",
+        ] {
+            SyncState::add_remote_backup(&state_path, name).unwrap();
+        }
+        assert_eq!(
+            prune_remote_backups(&mut repo, &database, &policy).unwrap(),
+            names(&["primary.20190101000000.bak", "primary.20190102000000.bak"])
+        );
+        assert_eq!(
+            SyncState::load_remote_backups(&state_path).unwrap(),
+            names(&["primary.20190103000000.bak"])
+        );
+        assert_eq!(repo.names.len(), 1);
+
+        // A failed deletion stops pruning, but what was deleted is still forgotten.
+        SyncState::save_remote_backups(
+            &state_path,
+            &names(&[
+                "primary.20190101000000.bak",
+                "primary.locked.20190102000000.bak",
+                "primary.20190103000000.bak",
+                "primary.20190104000000.bak",
+            ]),
+        )
+        .unwrap();
+        assert_matches!(
+            prune_remote_backups(&mut repo, &database, &policy),
+            Err(UpmError::Sync(_))
+        );
+        assert_eq!(
+            SyncState::load_remote_backups(&state_path).unwrap(),
+            names(&[
+                "primary.locked.20190102000000.bak",
+                "primary.20190103000000.bak",
+                "primary.20190104000000.bak",
+            ])
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Retry the idempotent operations of a sync backend.  A download, a listing, or an existence check
+//! can be repeated without harm, so a transient failure (such as a timeout or a dropped connection)
+//! is retried a few times with exponential backoff before it is reported.  Uploads and deletions
+//! are not retried here: `sync()` verifies each upload and handles its failures itself.
+
+use std::thread;
+use std::time::Duration;
+
+use error::UpmError;
+use sync::SyncBackend;
+
+/// Retry a failed operation this many times, unless the settings specify otherwise.
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// Wait this long before the first retry.  The delay doubles before each further retry.
+const INITIAL_DELAY_MILLIS: u64 = 1000;
+
+/// Wrap a sync backend, retrying downloads, listings, and existence checks which fail.
+pub struct RetryingBackend {
+    inner: Box<dyn SyncBackend>,
+    retries: u32,
+    initial_delay: Duration,
+}
+
+impl RetryingBackend {
+    /// Create a new `RetryingBackend` which retries failed operations of the provided backend up
+    /// to the provided number of times.
+    pub fn new(inner: Box<dyn SyncBackend>, retries: u32) -> RetryingBackend {
+        RetryingBackend::with_delay(inner, retries, Duration::from_millis(INITIAL_DELAY_MILLIS))
+    }
+
+    /// Create a new `RetryingBackend` which waits the provided time before the first retry.
+    pub fn with_delay(
+        inner: Box<dyn SyncBackend>,
+        retries: u32,
+        initial_delay: Duration,
+    ) -> RetryingBackend {
+        RetryingBackend {
+            inner,
+            retries,
+            initial_delay,
+        }
+    }
+
+    /// Perform the operation, retrying it after each failure which may be transient.  If every
+    /// attempt fails, the last error is returned, noting how many attempts were made.
+    fn retry<T, F>(&mut self, mut operation: F) -> Result<T, UpmError>
+    where
+        F: FnMut(&mut dyn SyncBackend) -> Result<T, UpmError>,
+    {
+        let mut delay = self.initial_delay;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match operation(&mut *self.inner) {
+                Err(ref e) if is_transient(e) && attempt <= self.retries => {
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(UpmError::Sync(ref s)) if attempt > 1 => {
+                    return Err(UpmError::Sync(format!(
+                        "{} (after {} attempts)",
+                        s, attempt
+                    )))
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Return true if an operation which failed with the provided error might succeed if retried.
+/// Errors which are definitive answers, such as a missing database, are not retried.
+fn is_transient(error: &UpmError) -> bool {
+    matches!(*error, UpmError::Sync(_) | UpmError::Io(_))
+}
+
+impl SyncBackend for RetryingBackend {
+    fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+        self.retry(|backend| backend.download(database_name))
+    }
+
+    fn upload(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        self.inner.upload(database_name, database_bytes)
+    }
+
+    fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+        self.inner.delete(database_name)
+    }
+
+    fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+        self.retry(|backend| backend.exists(database_name))
+    }
+
+    fn list(&mut self) -> Result<Vec<String>, UpmError> {
+        self.retry(|backend| backend.list())
+    }
+
+    fn replace(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        self.inner.replace(database_name, database_bytes)
+    }
+
+    fn keeps_history(&self) -> bool {
+        self.inner.keeps_history()
+    }
+
+    fn accepts_hardened_format(&self) -> bool {
+        self.inner.accepts_hardened_format()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A backend whose downloads fail a number of times before succeeding.
+    struct UnreliableBackend {
+        failures: Rc<Cell<u32>>,
+        attempts: Rc<Cell<u32>>,
+    }
+
+    impl SyncBackend for UnreliableBackend {
+        fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+            self.attempts.set(self.attempts.get() + 1);
+            if database_name == "missing" {
+                return Err(UpmError::SyncDatabaseNotFound);
+            }
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(UpmError::Sync(String::from("timed out")));
+            }
+            Ok(vec![1, 2, 3])
+        }
+        fn upload(&mut self, _: &str, _: Vec<u8>) -> Result<(), UpmError> {
+            Ok(())
+        }
+        fn delete(&mut self, _: &str) -> Result<(), UpmError> {
+            Ok(())
+        }
+        fn exists(&mut self, _: &str) -> Result<bool, UpmError> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_retrying_backend() {
+        let failures = Rc::new(Cell::new(0));
+        let attempts = Rc::new(Cell::new(0));
+        let inner = UnreliableBackend {
+            failures: failures.clone(),
+            attempts: attempts.clone(),
+        };
+        let mut backend = RetryingBackend::with_delay(Box::new(inner), 2, Duration::from_millis(1));
+
+        // Transient failures are retried.
+        failures.set(2);
+        assert_matches!(backend.download("primary"), Ok(ref d) if d == &[1, 2, 3]);
+        assert_eq!(attempts.get(), 3);
+
+        // If they persist, the last error is reported.
+        failures.set(3);
+        attempts.set(0);
+        assert_matches!(backend.download("primary"),
+            Err(UpmError::Sync(ref s)) if s == "timed out (after 3 attempts)");
+        assert_eq!(attempts.get(), 3);
+
+        // A missing database is a definitive answer.
+        attempts.set(0);
+        assert_matches!(
+            backend.download("missing"),
+            Err(UpmError::SyncDatabaseNotFound)
+        );
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,459 @@
+//! Store per-database sync settings.  These are preferences of this client rather than properties
+//! of the database itself (which the UPM format has no room for), so they are kept in a small text
+//! file next to the database (`.<name>.conf`) and never uploaded.
+//!
+//! The file contains one `key=value` pair per line.  Unknown keys are ignored, and missing keys
+//! take their default values, so the file may be edited by hand.
+//!
+//! If the database was downloaded or uploaded under a different name than the local file,
+//! `remote_name` holds the name of the database in the remote repository.
+//!
+//! Besides the auto-sync settings, the file holds the connection options of HTTP-based
+//! repositories:
+//!
+//! * `http_auth`: `basic` (the default), `digest`, or `bearer`.  Bearer authentication sends the
+//!   password of the sync credentials account as the token.
+//! * `ca_bundle`: A PEM file of additional CA certificates to trust, such as a private CA.
+//! * `client_certificate`: A PKCS#12 file holding a client certificate and its private key.
+//! * `client_certificate_credentials`: The account whose password unlocks the client certificate.
+//! * `proxy`: The URL of a proxy for all requests, such as `http://proxy.example.com:3128`.
+//! * `proxy_credentials`: The account whose username and password authenticate with the proxy.
+//! * `timeout`: Abort a request if the server doesn't respond for this many seconds.
+//! * `retries`: Retry a failed download this many times, waiting longer before each retry.
+//!
+//! If either of these is set, old backup copies are deleted from the remote repository after each
+//! upload (see [`RemoteBackupPolicy`](struct.RemoteBackupPolicy.html)):
+//!
+//! * `remote_backups_keep`: Keep at most this many remote backups.
+//! * `remote_backups_max_age`: Delete remote backups older than this many days.
+//!
+//! Relative paths are relative to the database's directory.  Passwords are never stored in the
+//! file itself -- they are taken from the named accounts in the (encrypted) database.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use database::{Account, Database};
+use error::UpmError;
+use sync::{HttpAuthScheme, HttpOptions, RemoteBackupPolicy};
+
+const SYNC_ON_STARTUP_KEY: &str = "sync_on_startup";
+const SYNC_AFTER_SAVE_KEY: &str = "sync_after_save";
+const SYNC_INTERVAL_KEY: &str = "sync_interval";
+const REMOTE_NAME_KEY: &str = "remote_name";
+const HTTP_AUTH_KEY: &str = "http_auth";
+const CA_BUNDLE_KEY: &str = "ca_bundle";
+const CLIENT_CERTIFICATE_KEY: &str = "client_certificate";
+const CLIENT_CERTIFICATE_CREDENTIALS_KEY: &str = "client_certificate_credentials";
+const PROXY_KEY: &str = "proxy";
+const PROXY_CREDENTIALS_KEY: &str = "proxy_credentials";
+const TIMEOUT_KEY: &str = "timeout";
+const RETRIES_KEY: &str = "retries";
+const REMOTE_BACKUPS_KEEP_KEY: &str = "remote_backups_keep";
+const REMOTE_BACKUPS_MAX_AGE_KEY: &str = "remote_backups_max_age";
+
+/// The `remote_backups_max_age` setting is in days.
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The sync settings of a database.  By default, the database is only synced on request, and
+/// HTTP-based repositories are accessed with the default [`HttpOptions`](struct.HttpOptions.html).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncSettings {
+    /// Sync as soon as the database is opened.
+    pub sync_on_startup: bool,
+    /// Sync each time the database is saved.
+    pub sync_after_save: bool,
+    /// Sync at this interval while the application is idle.
+    pub sync_interval: Option<Duration>,
+    /// The name of the database in the remote repository, if it differs from the name of the
+    /// local database file.
+    pub remote_name: Option<String>,
+    /// Authenticate with HTTP-based repositories using this scheme.
+    pub http_auth: HttpAuthScheme,
+    /// Trust the CA certificates in this PEM file, in addition to the system's.
+    pub ca_bundle: Option<PathBuf>,
+    /// Present the client certificate in this PKCS#12 file.
+    pub client_certificate: Option<PathBuf>,
+    /// The name of the account whose password unlocks the client certificate.
+    pub client_certificate_credentials: Option<String>,
+    /// Send all requests through the proxy with this URL.
+    pub proxy: Option<String>,
+    /// The name of the account holding the proxy username and password.
+    pub proxy_credentials: Option<String>,
+    /// Abort requests if the server doesn't respond for this time interval.  If `None`, the
+    /// [default timeout](constant.DEFAULT_TIMEOUT_SECS.html) is used.
+    pub timeout: Option<Duration>,
+    /// Retry failed downloads this many times.  If `None`, the
+    /// [default number of retries](constant.DEFAULT_RETRIES.html) is used.
+    pub retries: Option<u32>,
+    /// Keep at most this many backup copies in the remote repository.
+    pub remote_backups_keep: Option<usize>,
+    /// Delete backup copies in the remote repository which are older than this.
+    pub remote_backups_max_age: Option<Duration>,
+}
+
+impl SyncSettings {
+    /// Return the path of the settings file belonging to the provided database, if the database
+    /// has a path.
+    pub fn path_for(database: &Database) -> Option<PathBuf> {
+        database
+            .path()
+            .and_then(|path| SyncSettings::path_for_filename(path).ok())
+    }
+
+    /// Return the path of the settings file belonging to the database at the provided path.  This
+    /// allows the settings of a database to be changed without opening it.
+    pub fn path_for_filename<P: AsRef<Path>>(database_filename: P) -> Result<PathBuf, UpmError> {
+        let name = Database::path_to_name(&database_filename)?;
+        Ok(database_filename
+            .as_ref()
+            .with_file_name(format!(".{}.conf", name)))
+    }
+
+    /// Load the settings from the provided path.  If the file does not exist, the default
+    /// settings are returned.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SyncSettings, UpmError> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(UpmError::Io(e)),
+        };
+
+        let mut settings = SyncSettings::default();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let mut pair = line.splitn(2, '=');
+            let (key, value) = match (pair.next(), pair.next()) {
+                (Some(k), Some(v)) => (k.trim(), v.trim()),
+                _ => return Err(invalid_setting(line)),
+            };
+            match key {
+                SYNC_ON_STARTUP_KEY => settings.sync_on_startup = parse_bool(line, value)?,
+                SYNC_AFTER_SAVE_KEY => settings.sync_after_save = parse_bool(line, value)?,
+                SYNC_INTERVAL_KEY => {
+                    settings.sync_interval = match value.parse::<u64>() {
+                        Ok(0) => None,
+                        Ok(secs) => Some(Duration::from_secs(secs)),
+                        Err(_) => return Err(invalid_setting(line)),
+                    }
+                }
+                REMOTE_NAME_KEY => settings.remote_name = parse_optional(value),
+                HTTP_AUTH_KEY => {
+                    settings.http_auth = value.parse().map_err(|_| invalid_setting(line))?
+                }
+                CA_BUNDLE_KEY => settings.ca_bundle = parse_optional(value).map(PathBuf::from),
+                CLIENT_CERTIFICATE_KEY => {
+                    settings.client_certificate = parse_optional(value).map(PathBuf::from)
+                }
+                CLIENT_CERTIFICATE_CREDENTIALS_KEY => {
+                    settings.client_certificate_credentials = parse_optional(value)
+                }
+                PROXY_KEY => settings.proxy = parse_optional(value),
+                PROXY_CREDENTIALS_KEY => settings.proxy_credentials = parse_optional(value),
+                TIMEOUT_KEY => {
+                    settings.timeout = match parse_optional(value).map(|v| v.parse::<u64>()) {
+                        None => None,
+                        Some(Ok(secs)) if secs > 0 => Some(Duration::from_secs(secs)),
+                        Some(_) => return Err(invalid_setting(line)),
+                    }
+                }
+                RETRIES_KEY => {
+                    settings.retries = match parse_optional(value).map(|v| v.parse::<u32>()) {
+                        None => None,
+                        Some(Ok(retries)) => Some(retries),
+                        Some(Err(_)) => return Err(invalid_setting(line)),
+                    }
+                }
+                REMOTE_BACKUPS_KEEP_KEY => {
+                    settings.remote_backups_keep =
+                        match parse_optional(value).map(|v| v.parse::<usize>()) {
+                            None => None,
+                            Some(Ok(keep)) => Some(keep),
+                            Some(Err(_)) => return Err(invalid_setting(line)),
+                        }
+                }
+                REMOTE_BACKUPS_MAX_AGE_KEY => {
+                    settings.remote_backups_max_age =
+                        match parse_optional(value).map(|v| v.parse::<u64>()) {
+                            None => None,
+                            Some(Ok(days)) => Some(Duration::from_secs(days * SECONDS_PER_DAY)),
+                            Some(Err(_)) => return Err(invalid_setting(line)),
+                        }
+                }
+                _ => {}
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Save the settings to the provided path.  Unset options are written with empty values, so
+    /// they are easy to fill in by hand.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), UpmError> {
+        let path_value = |p: &Option<PathBuf>| {
+            p.as_ref()
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let text = format!(
+            "{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n",
+            SYNC_ON_STARTUP_KEY,
+            self.sync_on_startup,
+            SYNC_AFTER_SAVE_KEY,
+            self.sync_after_save,
+            SYNC_INTERVAL_KEY,
+            self.sync_interval.map_or(0, |i| i.as_secs()),
+            REMOTE_NAME_KEY,
+            self.remote_name.as_deref().unwrap_or(""),
+            HTTP_AUTH_KEY,
+            self.http_auth,
+            CA_BUNDLE_KEY,
+            path_value(&self.ca_bundle),
+            CLIENT_CERTIFICATE_KEY,
+            path_value(&self.client_certificate),
+            CLIENT_CERTIFICATE_CREDENTIALS_KEY,
+            self.client_certificate_credentials.as_deref().unwrap_or(""),
+            PROXY_KEY,
+            self.proxy.as_deref().unwrap_or(""),
+            PROXY_CREDENTIALS_KEY,
+            self.proxy_credentials.as_deref().unwrap_or(""),
+            TIMEOUT_KEY,
+            self.timeout
+                .map(|t| t.as_secs().to_string())
+                .unwrap_or_default(),
+            RETRIES_KEY,
+            self.retries.map(|r| r.to_string()).unwrap_or_default(),
+            REMOTE_BACKUPS_KEEP_KEY,
+            self.remote_backups_keep
+                .map(|k| k.to_string())
+                .unwrap_or_default(),
+            REMOTE_BACKUPS_MAX_AGE_KEY,
+            self.remote_backups_max_age
+                .map(|a| (a.as_secs() / SECONDS_PER_DAY).to_string())
+                .unwrap_or_default()
+        );
+        Database::save_raw_bytes(text.into_bytes(), path)
+    }
+
+    /// Resolve these settings into the HTTP options for the provided database, looking up the
+    /// passwords of any named accounts and resolving relative paths against the database's
+    /// directory.
+    pub fn http_options(&self, database: &Database) -> Result<HttpOptions, UpmError> {
+        let directory = database
+            .path()
+            .and_then(|p| p.parent().map(Path::to_path_buf));
+        let resolve = |path: &Option<PathBuf>| {
+            path.as_ref().map(|p| match directory {
+                Some(ref d) if p.is_relative() => d.join(p),
+                _ => p.clone(),
+            })
+        };
+        let mut options = HttpOptions {
+            auth_scheme: self.http_auth,
+            ca_bundle: resolve(&self.ca_bundle),
+            client_certificate: resolve(&self.client_certificate),
+            proxy: self.proxy.clone(),
+            timeout: self.timeout,
+            ..Default::default()
+        };
+        if let Some(ref name) = self.client_certificate_credentials {
+            let account = credentials_account(database, name, CLIENT_CERTIFICATE_CREDENTIALS_KEY)?;
+            options.client_certificate_password = account.password.clone();
+        }
+        if let Some(ref name) = self.proxy_credentials {
+            let account = credentials_account(database, name, PROXY_CREDENTIALS_KEY)?;
+            options.proxy_username = account.user.clone();
+            options.proxy_password = account.password.clone();
+        }
+        Ok(options)
+    }
+
+    /// Return the policy for pruning remote backups after each upload, or `None` if remote backups
+    /// should be kept indefinitely.
+    pub fn remote_backup_policy(&self) -> Option<RemoteBackupPolicy> {
+        if self.remote_backups_keep.is_none() && self.remote_backups_max_age.is_none() {
+            return None;
+        }
+        Some(RemoteBackupPolicy {
+            keep: self.remote_backups_keep,
+            max_age: self.remote_backups_max_age,
+        })
+    }
+
+    /// Load the settings of the provided database.  If the database has no path yet, the default
+    /// settings are returned.
+    pub fn load_for(database: &Database) -> Result<SyncSettings, UpmError> {
+        match SyncSettings::path_for(database) {
+            Some(path) => SyncSettings::load(path),
+            None => Ok(Default::default()),
+        }
+    }
+
+    /// Save these settings as the settings of the provided database.
+    pub fn save_for(&self, database: &Database) -> Result<(), UpmError> {
+        match SyncSettings::path_for(database) {
+            Some(path) => self.save(path),
+            None => Err(UpmError::NoDatabaseFilename),
+        }
+    }
+}
+
+/// Return an error describing an invalid line in a settings file.
+fn invalid_setting(line: &str) -> UpmError {
+    UpmError::Sync(format!("Invalid sync setting: {}", line))
+}
+
+/// Return the account named by a credentials setting.
+fn credentials_account<'a>(
+    database: &'a Database,
+    name: &str,
+    key: &str,
+) -> Result<&'a Account, UpmError> {
+    database.account(name).ok_or_else(|| {
+        UpmError::Sync(format!(
+            "The account named by the {} setting does not exist: {}",
+            key, name
+        ))
+    })
+}
+
+/// Parse an optional setting value, where an empty value means the setting is not set.
+fn parse_optional(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(String::from(value))
+    }
+}
+
+/// Parse a boolean setting value.
+fn parse_bool(line: &str, value: &str) -> Result<bool, UpmError> {
+    value.parse::<bool>().map_err(|_| invalid_setting(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secret::SecretString;
+    use tempfile;
+
+    #[test]
+    fn test_sync_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new();
+        assert_eq!(
+            SyncSettings::load_for(&database).unwrap(),
+            Default::default()
+        );
+        database.set_path(&dir.path().join("primary")).unwrap();
+        let path = SyncSettings::path_for(&database).unwrap();
+        assert_eq!(path, dir.path().join(".primary.conf"));
+        assert_eq!(
+            SyncSettings::path_for_filename(dir.path().join("primary")).unwrap(),
+            path
+        );
+        assert_eq!(SyncSettings::load(&path).unwrap(), Default::default());
+
+        let settings = SyncSettings {
+            sync_on_startup: true,
+            sync_after_save: false,
+            sync_interval: Some(Duration::from_secs(300)),
+            remote_name: Some(String::from("shared")),
+            ..Default::default()
+        };
+        assert_matches!(settings.save_for(&database), Ok(()));
+        assert_eq!(SyncSettings::load_for(&database).unwrap(), settings);
+
+        let settings = SyncSettings {
+            http_auth: HttpAuthScheme::Digest,
+            ca_bundle: Some(PathBuf::from("ca.pem")),
+            client_certificate: Some(PathBuf::from("/etc/upm/client.p12")),
+            client_certificate_credentials: Some(String::from("certificate")),
+            proxy: Some(String::from("http://proxy.example.com:3128")),
+            proxy_credentials: Some(String::from("proxy")),
+            timeout: Some(Duration::from_secs(30)),
+            retries: Some(0),
+            remote_backups_keep: Some(20),
+            remote_backups_max_age: Some(Duration::from_secs(90 * 86400)),
+            ..Default::default()
+        };
+        assert_matches!(settings.save_for(&database), Ok(()));
+        assert_eq!(SyncSettings::load_for(&database).unwrap(), settings);
+        assert_eq!(
+            settings.remote_backup_policy(),
+            Some(RemoteBackupPolicy {
+                keep: Some(20),
+                max_age: Some(Duration::from_secs(90 * 86400)),
+            })
+        );
+        assert_eq!(SyncSettings::default().remote_backup_policy(), None);
+
+        // Hand-edited files may omit settings and contain unknown ones.
+        fs::write(&path, "sync_after_save = true\ncolor=blue\n\n").unwrap();
+        assert_eq!(
+            SyncSettings::load(&path).unwrap(),
+            SyncSettings {
+                sync_after_save: true,
+                ..Default::default()
+            }
+        );
+        fs::write(&path, "sync_on_startup=yes\n").unwrap();
+        assert_matches!(SyncSettings::load(&path), Err(UpmError::Sync(_)));
+        fs::write(&path, "http_auth=ntlm\n").unwrap();
+        assert_matches!(SyncSettings::load(&path), Err(UpmError::Sync(_)));
+        fs::write(&path, "timeout=0\n").unwrap();
+        assert_matches!(SyncSettings::load(&path), Err(UpmError::Sync(_)));
+    }
+
+    #[test]
+    fn test_http_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new();
+        database.set_path(&dir.path().join("primary")).unwrap();
+        assert_eq!(
+            SyncSettings::default().http_options(&database).unwrap(),
+            Default::default()
+        );
+
+        let settings = SyncSettings {
+            http_auth: HttpAuthScheme::Bearer,
+            ca_bundle: Some(PathBuf::from("ca.pem")),
+            client_certificate: Some(PathBuf::from("/etc/upm/client.p12")),
+            client_certificate_credentials: Some(String::from("certificate")),
+            proxy: Some(String::from("http://proxy.example.com:3128")),
+            proxy_credentials: Some(String::from("proxy")),
+            timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        assert_matches!(settings.http_options(&database), Err(UpmError::Sync(_)));
+
+        database
+            .add_account(&Account {
+                name: String::from("certificate"),
+                password: SecretString::from("p12pass"),
+                ..Account::new()
+            })
+            .unwrap();
+        database
+            .add_account(&Account {
+                name: String::from("proxy"),
+                user: String::from("proxyuser"),
+                password: SecretString::from("proxypass"),
+                ..Account::new()
+            })
+            .unwrap();
+        assert_eq!(
+            settings.http_options(&database).unwrap(),
+            HttpOptions {
+                auth_scheme: HttpAuthScheme::Bearer,
+                ca_bundle: Some(dir.path().join("ca.pem")),
+                client_certificate: Some(PathBuf::from("/etc/upm/client.p12")),
+                client_certificate_password: SecretString::from("p12pass"),
+                proxy: Some(String::from("http://proxy.example.com:3128")),
+                proxy_username: String::from("proxyuser"),
+                proxy_password: SecretString::from("proxypass"),
+                timeout: Some(Duration::from_secs(30)),
+            }
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Provide a stand-in HTTP server for testing the WebDAV and S3 backends.  Files are kept in
+//! memory, keyed by the request path exactly as it was sent, so tests can check how names were
+//! encoded.  Each request is recorded, and each connection serves a single request.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A request received by the stand-in server.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// The request target, including any query string, as it was sent.
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Return the value of the named header, if present.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Return the request path, without any query string.
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("")
+    }
+}
+
+#[derive(Default)]
+struct State {
+    files: HashMap<String, Vec<u8>>,
+    requests: Vec<Request>,
+}
+
+/// A stand-in HTTP server which handles GET, HEAD, PUT, DELETE, and MOVE requests.
+pub struct StandInServer {
+    address: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl StandInServer {
+    /// Start a server on a local port, serving requests in the background.
+    pub fn start() -> StandInServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                serve(stream, &server_state);
+            }
+        });
+        StandInServer { address, state }
+    }
+
+    /// Return the address of the server, as `host:port`.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Return the requests received so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Return the contents of the file at the provided request path.
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().files.get(path).cloned()
+    }
+}
+
+/// Read a single request from the stream and respond to it.
+fn serve(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap_or_default();
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(m), Some(t)) => (m.to_string(), t.to_string()),
+        _ => return,
+    };
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some(i) = line.find(':') {
+            headers.push((
+                line[..i].trim().to_string(),
+                line[i + 1..].trim().to_string(),
+            ));
+        }
+    }
+    let request = Request {
+        method,
+        target,
+        headers,
+    };
+    let length = request
+        .header("Content-Length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    if reader.read_exact(&mut body).is_err() {
+        return;
+    }
+
+    let mut state = state.lock().unwrap();
+    let path = request.path().to_string();
+    let (status, content) = match request.method.as_str() {
+        "GET" | "HEAD" => match state.files.get(&path) {
+            Some(f) => ("200 OK", f.clone()),
+            None => ("404 Not Found", Vec::new()),
+        },
+        "PUT" => {
+            state.files.insert(path, body);
+            ("201 Created", Vec::new())
+        }
+        "DELETE" => match state.files.remove(&path) {
+            Some(_) => ("204 No Content", Vec::new()),
+            None => ("404 Not Found", Vec::new()),
+        },
+        "MOVE" => {
+            // The destination is an absolute URL; only its path is significant.
+            let destination = request
+                .header("Destination")
+                .and_then(|d| d.splitn(4, '/').nth(3))
+                .map(|d| format!("/{}", d));
+            match (destination, state.files.remove(&path)) {
+                (Some(d), Some(f)) => {
+                    state.files.insert(d, f);
+                    ("201 Created", Vec::new())
+                }
+                _ => ("404 Not Found", Vec::new()),
+            }
+        }
+        _ => ("405 Method Not Allowed", Vec::new()),
+    };
+    let head_only = request.method == "HEAD";
+    state.requests.push(request);
+    drop(state);
+
+    let mut stream = reader.into_inner();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content.len()
+    );
+    stream.write_all(response.as_bytes()).unwrap_or_default();
+    if !head_only {
+        stream.write_all(&content).unwrap_or_default();
+    }
+}
@@ -0,0 +1,327 @@
+//! Persist the state of the most recent sync of a database.  This is stored in a small text file
+//! next to the database (`.<name>.sync`), so it survives restarts.  The state records:
+//!
+//! * The revision of the remote database as of the last sync.
+//! * An HMAC of the database content as of the last sync (see
+//!   [`Database::content_mac()`](../database/struct.Database.html#method.content_mac)), and the
+//!   salt from which its key is derived.  The content includes the account passwords, so a plain
+//!   hash would let anyone who can read the file check guesses of them without knowing the master
+//!   password.  The key is derived from the master password with the KDF of the database, so
+//!   guessing the master password from the file is no easier than guessing it from the database.
+//! * The time of the last sync.
+//! * The names of the backup copies uploaded to the remote repository, so they can be pruned.
+//!
+//! Comparing the local and remote databases against this state tells which of them changed since
+//! the last sync.  The file contains one `key=value` pair per line, and unknown keys are ignored.
+//! Each remote backup is recorded on its own `backup=<name>` line, which is appended as soon as
+//! the backup is uploaded -- even if the sync then fails, or no sync has completed yet.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crypto;
+use database::Database;
+use error::UpmError;
+use secret::SecretBytes;
+
+const REVISION_KEY: &str = "revision";
+const MAC_KEY: &str = "mac";
+const SALT_KEY: &str = "salt";
+const TIMESTAMP_KEY: &str = "timestamp";
+const BACKUP_KEY: &str = "backup";
+
+/// The state of a database as of its most recent sync.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncState {
+    /// The revision of the remote database after the sync.
+    pub remote_revision: u32,
+    /// The content MAC of the database after the sync.
+    pub content_mac: Vec<u8>,
+    /// The time of the sync.
+    pub timestamp: SystemTime,
+    /// The names of the backup copies uploaded to the remote repository which have not been
+    /// pruned yet, oldest first.
+    pub remote_backups: Vec<String>,
+    /// The salt from which the MAC key is derived.
+    salt: Vec<u8>,
+    /// The MAC key, derived from the password of the local database.  This is not saved.
+    key: SecretBytes,
+}
+
+impl SyncState {
+    /// Return the state of a database which was just synced, so that both the local and remote
+    /// copies now match the provided database.  No remote backups are listed.  The MAC key is
+    /// derived from the password of the provided database, and reused while it stays unlocked
+    /// with the same password.
+    pub fn new(database: &Database) -> Result<SyncState, UpmError> {
+        let (salt, key) = database.content_mac_salt_and_key()?;
+        Ok(SyncState {
+            remote_revision: database.sync_revision,
+            content_mac: database.content_mac(&key)?,
+            timestamp: SystemTime::now(),
+            remote_backups: Vec::new(),
+            salt,
+            key,
+        })
+    }
+
+    /// Return the path of the sync state file belonging to the provided database, if the database
+    /// has a path.
+    pub fn path_for(database: &Database) -> Option<PathBuf> {
+        match (database.path(), database.name()) {
+            (Some(path), Some(name)) => Some(path.with_file_name(format!(".{}.sync", name))),
+            _ => None,
+        }
+    }
+
+    /// Load the sync state of the provided local database from the provided path.  The MAC key
+    /// is derived from the password of the database.  Return `None` if no state has been
+    /// recorded.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        database: &Database,
+    ) -> Result<Option<SyncState>, UpmError> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(UpmError::Io(e)),
+        };
+
+        let (mut revision, mut mac, mut salt, mut timestamp) = (None, None, None, None);
+        for line in text.lines() {
+            let mut pair = line.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(REVISION_KEY), Some(v)) => revision = v.parse::<u32>().ok(),
+                (Some(MAC_KEY), Some(v)) => mac = crypto::hex_decode(v),
+                (Some(SALT_KEY), Some(v)) => salt = crypto::hex_decode(v),
+                (Some(TIMESTAMP_KEY), Some(v)) => timestamp = v.parse::<u64>().ok(),
+                _ => {}
+            }
+        }
+        match (revision, mac, salt, timestamp) {
+            (Some(revision), Some(mac), Some(salt), Some(timestamp)) => {
+                let key = database.content_mac_key(&salt)?;
+                Ok(Some(SyncState {
+                    remote_revision: revision,
+                    content_mac: mac,
+                    timestamp: UNIX_EPOCH + Duration::from_secs(timestamp),
+                    remote_backups: parse_remote_backups(&text),
+                    salt,
+                    key,
+                }))
+            }
+            _ => Err(UpmError::Sync(String::from("Invalid sync state file"))),
+        }
+    }
+
+    /// Load only the names of the remote backups from the sync state file at the provided path.
+    /// Unlike [`load()`](#method.load), this succeeds when the rest of the state has not been
+    /// recorded yet.
+    pub fn load_remote_backups<P: AsRef<Path>>(path: P) -> Result<Vec<String>, UpmError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(parse_remote_backups(&text)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(UpmError::Io(e)),
+        }
+    }
+
+    /// Record the name of a backup copy just uploaded to the remote repository in the sync state
+    /// file at the provided path, keeping the rest of the file as it is.
+    pub fn add_remote_backup<P: AsRef<Path>>(path: P, name: &str) -> Result<(), UpmError> {
+        let mut backups = SyncState::load_remote_backups(&path)?;
+        backups.push(String::from(name));
+        SyncState::save_remote_backups(path, &backups)
+    }
+
+    /// Replace the list of remote backups in the sync state file at the provided path, keeping
+    /// the rest of the file as it is.
+    pub fn save_remote_backups<P: AsRef<Path>>(path: P, names: &[String]) -> Result<(), UpmError> {
+        let mut text = match fs::read_to_string(&path) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(UpmError::Io(e)),
+        };
+        text = text
+            .lines()
+            .filter(|l| !l.starts_with(&format!("{}=", BACKUP_KEY)))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        text.push_str(&format_remote_backups(names));
+        Database::save_raw_bytes(text.into_bytes(), path)
+    }
+
+    /// Save the sync state to the provided path.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), UpmError> {
+        let timestamp = match self.timestamp.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs(),
+            Err(_) => 0,
+        };
+        let text = format!(
+            "{}={}\n{}={}\n{}={}\n{}={}\n{}",
+            REVISION_KEY,
+            self.remote_revision,
+            MAC_KEY,
+            crypto::hex_encode(&self.content_mac),
+            SALT_KEY,
+            crypto::hex_encode(&self.salt),
+            TIMESTAMP_KEY,
+            timestamp,
+            format_remote_backups(&self.remote_backups)
+        );
+        Database::save_raw_bytes(text.into_bytes(), path)
+    }
+
+    /// Return true if the provided database differs from the database as of this sync, either in
+    /// revision or in content.
+    pub fn differs_from(&self, database: &Database) -> bool {
+        database.sync_revision != self.remote_revision || !self.content_matches(database)
+    }
+
+    /// Return true if the provided database has the same content as the database as of this
+    /// sync, regardless of its revision.
+    pub fn content_matches(&self, database: &Database) -> bool {
+        match database.content_mac(&self.key) {
+            Ok(mac) => mac == self.content_mac,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Restore the sync status of a freshly loaded database from its sync state file.  If the database
+/// is unchanged since it was last synced, it is marked as synced as of that time (so it will
+/// still be considered unsynchronized if that was long ago).
+pub fn restore_sync_status(database: &mut Database) {
+    let state =
+        SyncState::path_for(database).and_then(|p| SyncState::load(p, database).unwrap_or(None));
+    if let Some(state) = state {
+        if !state.differs_from(database) {
+            database.set_synced_at(state.timestamp);
+        }
+    }
+}
+
+/// Return the remote backup names listed in the text of a sync state file, in order.
+fn parse_remote_backups(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| {
+            let mut pair = line.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(BACKUP_KEY), Some(v)) if !v.is_empty() => Some(String::from(v)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Format remote backup names as lines of a sync state file.
+fn format_remote_backups(names: &[String]) -> String {
+    names
+        .iter()
+        .map(|name| format!("{}={}\n", BACKUP_KEY, name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn test_sync_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new();
+        database.set_path(&dir.path().join("primary")).unwrap();
+        database.set_password(&"xyzzy");
+        database.sync_revision = 7;
+        let path = SyncState::path_for(&database).unwrap();
+        assert_eq!(path, dir.path().join(".primary.sync"));
+        assert_matches!(SyncState::load(&path, &database), Ok(None));
+
+        // The state survives a round trip, to a resolution of one second.
+        let mut state = SyncState::new(&database).unwrap();
+        state.timestamp = UNIX_EPOCH + Duration::from_secs(1500000000);
+        assert_matches!(state.save(&path), Ok(()));
+        assert_matches!(SyncState::load(&path, &database), Ok(Some(ref s)) if s == &state);
+        assert!(!state.differs_from(&database));
+        database.sync_revision += 1;
+        assert!(state.differs_from(&database));
+        database.sync_revision -= 1;
+        database.sync_url.push('x');
+        assert!(state.differs_from(&database));
+        database.sync_url.pop();
+
+        // Neither the content hash nor anything derived from it without the password is saved.
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(!text.contains(&crypto::hex_encode(&database.content_hash())));
+        let mut other = database.clone();
+        other.set_password(&"other password");
+        let loaded = SyncState::load(&path, &other).unwrap().unwrap();
+        assert!(loaded.differs_from(&database));
+
+        fs::write(&path, "revision=7\nmac=xyz\nsalt=00\ntimestamp=0\n").unwrap();
+        assert_matches!(SyncState::load(&path, &database), Err(UpmError::Sync(_)));
+    }
+
+    #[test]
+    fn test_remote_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new();
+        database.set_path(&dir.path().join("primary")).unwrap();
+        database.set_password(&"xyzzy");
+        let path = SyncState::path_for(&database).unwrap();
+        assert_eq!(SyncState::load_remote_backups(&path).unwrap().len(), 0);
+
+        // Backups may be recorded before any sync has completed, without creating a sync state.
+        SyncState::add_remote_backup(&path, "primary.20190101000000.bak").unwrap();
+        assert_matches!(SyncState::load(&path, &database), Err(_));
+        assert_eq!(
+            SyncState::load_remote_backups(&path).unwrap(),
+            vec!["primary.20190101000000.bak"]
+        );
+
+        // A completed sync keeps the list, and adding to it keeps the rest of the state.
+        let state = SyncState {
+            remote_backups: SyncState::load_remote_backups(&path).unwrap(),
+            ..SyncState::new(&database).unwrap()
+        };
+        state.save(&path).unwrap();
+        SyncState::add_remote_backup(&path, "primary.20190102000000.bak").unwrap();
+        let loaded = SyncState::load(&path, &database).unwrap().unwrap();
+        assert_eq!(loaded.content_mac, state.content_mac);
+        assert_eq!(
+            loaded.remote_backups,
+            vec!["primary.20190101000000.bak", "primary.20190102000000.bak"]
+        );
+
+        SyncState::save_remote_backups(&path, &[]).unwrap();
+        assert_eq!(
+            SyncState::load(&path, &database)
+                .unwrap()
+                .unwrap()
+                .remote_backups
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_restore_sync_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = Database::new();
+        database.set_path(&dir.path().join("primary")).unwrap();
+        database.set_password(&"xyzzy");
+        let path = SyncState::path_for(&database).unwrap();
+        SyncState::new(&database).unwrap().save(&path).unwrap();
+
+        restore_sync_status(&mut database);
+        assert!(database.is_synced());
+
+        // A database changed since the last sync is not synced.
+        database.clear_synced();
+        database.sync_revision += 1;
+        restore_sync_status(&mut database);
+        assert!(!database.is_synced());
+    }
+}
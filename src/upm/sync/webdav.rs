@@ -0,0 +1,331 @@
+//! Provide a sync backend for WebDAV servers, such as Nextcloud or Apache's mod_dav.  No UPM
+//! specific scripts are needed on the server: databases are transferred with plain GET, PUT, and
+//! DELETE requests.  The WebDAV MOVE method allows a database to be replaced atomically, by first
+//! uploading it under a temporary name and then renaming it over the existing database, and
+//! PROPFIND lists the databases in the repository.
+//!
+//! WebDAV repositories are specified with a `webdav://` or `webdavs://` URL, which are accessed
+//! using HTTP or HTTPS respectively.  For example, `webdavs://cloud.example.com/remote.php/dav/`.
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Method, StatusCode};
+use std::io::Read;
+
+use error::UpmError;
+use sync::client::{percent_encode, xml_elements, xml_unescape};
+use sync::{HttpAuth, HttpOptions, SyncBackend};
+
+/// The URL scheme prefix used to specify a WebDAV repository accessed with HTTP.
+const WEBDAV_URL_PREFIX: &str = "webdav://";
+/// The URL scheme prefix used to specify a WebDAV repository accessed with HTTPS.
+const WEBDAVS_URL_PREFIX: &str = "webdavs://";
+
+/// A database being replaced is first uploaded with this suffix appended to its name.
+const TEMPORARY_SUFFIX: &str = ".upload.tmp";
+
+/// The MIME type used when uploading a database.
+const DATABASE_MIME_TYPE: &str = "application/octet-stream";
+
+/// The MIME type of a PROPFIND request body.
+const XML_MIME_TYPE: &str = "application/xml";
+
+/// A PROPFIND request body asking only for the resource type, to tell files from collections.
+const PROPFIND_BODY: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                             <propfind xmlns=\"DAV:\"><prop><resourcetype/></prop></propfind>";
+
+/// Provide access to a repository stored on a WebDAV server.
+pub struct WebDavRepository {
+    url: String,
+    auth: HttpAuth,
+    client: reqwest::Client,
+}
+
+impl WebDavRepository {
+    /// Create a new `WebDavRepository` with the provided `webdav://` or `webdavs://` URL and
+    /// credentials, using the default HTTP options.
+    pub fn new(
+        url: &str,
+        http_username: &str,
+        http_password: &str,
+    ) -> Result<WebDavRepository, UpmError> {
+        WebDavRepository::with_options(url, http_username, http_password, &Default::default())
+    }
+
+    /// Create a new `WebDavRepository` with the provided `webdav://` or `webdavs://` URL,
+    /// credentials, and HTTP options.
+    pub fn with_options(
+        url: &str,
+        http_username: &str,
+        http_password: &str,
+        options: &HttpOptions,
+    ) -> Result<WebDavRepository, UpmError> {
+        let url = match WebDavRepository::url_to_http(url) {
+            Some(u) => u,
+            None => return Err(UpmError::Sync(format!("Not a WebDAV URL: {}", url))),
+        };
+        Ok(WebDavRepository {
+            url,
+            auth: HttpAuth::new(options.auth_scheme, http_username, http_password),
+            client: options.client()?,
+        })
+    }
+
+    /// Return the HTTP or HTTPS URL represented by a `webdav://` or `webdavs://` URL, or `None` if
+    /// the URL does not refer to a WebDAV repository.
+    pub fn url_to_http(url: &str) -> Option<String> {
+        url.strip_prefix(WEBDAV_URL_PREFIX)
+            .map(|rest| format!("http://{}", rest))
+            .or_else(|| {
+                url.strip_prefix(WEBDAVS_URL_PREFIX)
+                    .map(|rest| format!("https://{}", rest))
+            })
+    }
+
+    /// Construct a URL by appending the provided string to the repository URL as a single
+    /// percent-encoded path segment, adding a separating slash character if needed.
+    fn make_url(&self, path_component: &str) -> String {
+        let path_component = percent_encode(path_component, false);
+        if self.url.ends_with('/') {
+            format!("{}{}", self.url, path_component)
+        } else {
+            format!("{}/{}", self.url, path_component)
+        }
+    }
+
+    /// Send an authenticated request with the provided method for the named database, after
+    /// letting the provided function add any headers or body.  Return the response if its status
+    /// indicates success.  A "404 Not Found" status is reported as
+    /// [`UpmError::SyncDatabaseNotFound`](../error/enum.UpmError.html#variant.SyncDatabaseNotFound).
+    fn send<F>(
+        &mut self,
+        method: Method,
+        database_name: &str,
+        finish: F,
+    ) -> Result<reqwest::Response, UpmError>
+    where
+        F: Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
+    {
+        let url = self.make_url(database_name);
+        let client = &self.client;
+        let response = self
+            .auth
+            .send(client, || Ok(finish(client.request(method.clone(), &url))))?;
+        match response.status() {
+            s if s.is_success() => Ok(response),
+            StatusCode::NOT_FOUND => Err(UpmError::SyncDatabaseNotFound),
+            s => Err(UpmError::Sync(format!("{}", s))),
+        }
+    }
+}
+
+impl SyncBackend for WebDavRepository {
+    /// Download the named database with a GET request.
+    fn download(&mut self, database_name: &str) -> Result<Vec<u8>, UpmError> {
+        let mut response = self.send(Method::GET, database_name, |r| r)?;
+        let mut data: Vec<u8> = Vec::new();
+        response.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Upload the named database with a PUT request.  Any existing database with this name is
+    /// overwritten.
+    fn upload(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        self.send(Method::PUT, database_name, |r| {
+            r.header(CONTENT_TYPE, DATABASE_MIME_TYPE)
+                .body(database_bytes.clone())
+        })?;
+        Ok(())
+    }
+
+    /// Delete the named database with a DELETE request.
+    fn delete(&mut self, database_name: &str) -> Result<(), UpmError> {
+        self.send(Method::DELETE, database_name, |r| r)?;
+        Ok(())
+    }
+
+    /// Return true if a HEAD request for the named database succeeds.
+    fn exists(&mut self, database_name: &str) -> Result<bool, UpmError> {
+        match self.send(Method::HEAD, database_name, |r| r) {
+            Ok(_) => Ok(true),
+            Err(UpmError::SyncDatabaseNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// List the files in the repository collection with a PROPFIND request of depth 1.
+    /// Subcollections and the temporary copies made while replacing a database are skipped.
+    fn list(&mut self) -> Result<Vec<String>, UpmError> {
+        let method = Method::from_bytes(b"PROPFIND").unwrap();
+        let mut response = self.send(method, "", |r| {
+            r.header("Depth", "1")
+                .header(CONTENT_TYPE, XML_MIME_TYPE)
+                .body(PROPFIND_BODY)
+        })?;
+        let mut body = String::new();
+        response.read_to_string(&mut body)?;
+        Ok(parse_listing(&body))
+    }
+
+    /// Atomically replace the named database by uploading it under a temporary name, then using
+    /// MOVE to rename it over the existing database.
+    fn replace(&mut self, database_name: &str, database_bytes: Vec<u8>) -> Result<(), UpmError> {
+        let temporary_name = format!("{}{}", database_name, TEMPORARY_SUFFIX);
+        self.upload(&temporary_name, database_bytes)?;
+
+        let destination = self.make_url(database_name);
+        let method = Method::from_bytes(b"MOVE").unwrap();
+        let result = self.send(method, &temporary_name, |r| {
+            r.header("Destination", destination.as_str())
+                .header("Overwrite", "T")
+        });
+        if let Err(e) = result {
+            // Don't leave the temporary copy behind.  (Ignore errors -- the original error is the
+            // one worth reporting.)
+            self.delete(&temporary_name).unwrap_or_default();
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Return the names of the files in a PROPFIND response: the last path segment of the `href` of
+/// each response which is not a collection.
+fn parse_listing(xml: &str) -> Vec<String> {
+    xml_elements(xml, "response")
+        .into_iter()
+        .filter(|response| xml_elements(response, "collection").is_empty())
+        .filter_map(|response| {
+            xml_elements(response, "href")
+                .first()
+                .map(|h| xml_unescape(h))
+        })
+        .filter_map(|href| {
+            href.trim()
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .and_then(percent_decode)
+        })
+        .filter(|name| !name.is_empty() && !name.ends_with(TEMPORARY_SUFFIX))
+        .collect()
+}
+
+/// Decode the percent-encoded characters in a URL path segment.  Return `None` if the result is
+/// not valid UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = s
+            .get(i + 1..i + 3)
+            .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sync::stand_in::StandInServer;
+
+    #[test]
+    fn test_parse_listing() {
+        // A response in the style of Apache's mod_dav, which uses absolute URLs for some hrefs.
+        let xml = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+            <D:multistatus xmlns:D=\"DAV:\">\
+            <D:response><D:href>/dav/upm/</D:href><D:propstat><D:prop>\
+            <D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>\
+            <D:response><D:href>/dav/upm/primary</D:href><D:propstat><D:prop>\
+            <D:resourcetype/></D:prop></D:propstat></D:response>\
+            <D:response><D:href>https://example.com/dav/upm/my%20passwords</D:href>\
+            <D:propstat><D:prop><D:resourcetype/></D:prop></D:propstat></D:response>\
+            <D:response><D:href>/dav/upm/primary.upload.tmp</D:href><D:propstat><D:prop>\
+            <D:resourcetype/></D:prop></D:propstat></D:response>\
+            <D:response><D:href>/dav/upm/old/</D:href><D:propstat><D:prop>\
+            <D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>\
+            </D:multistatus>";
+        assert_eq!(parse_listing(xml), vec!["primary", "my passwords"]);
+        assert_eq!(percent_decode("a%2Fb%zz%"), Some(String::from("a/b%zz%")));
+        assert_eq!(percent_decode("%FF"), None);
+        assert_eq!(percent_decode("%+f"), Some(String::from("%+f")));
+    }
+
+    #[test]
+    fn test_webdav_requests() {
+        let server = StandInServer::start();
+        let url = format!("webdav://{}/dav", server.address());
+        let mut repo = WebDavRepository::new(&url, "user", "pass").unwrap();
+
+        // Names are sent as a single percent-encoded path segment, so characters which are
+        // significant in URLs can't change which resource is requested.
+        const NAME: &str = "my #1?%.db";
+        const PATH: &str = "/dav/my%20%231%3F%25.db";
+        assert_matches!(repo.exists(NAME), Ok(false));
+        assert_matches!(repo.upload(NAME, vec![1, 2, 3]), Ok(()));
+        assert_eq!(server.file(PATH), Some(vec![1, 2, 3]));
+        assert_matches!(repo.download(NAME), Ok(ref b) if b == &[1, 2, 3]);
+        assert_matches!(repo.replace(NAME, vec![4, 5]), Ok(()));
+        assert_eq!(server.file(PATH), Some(vec![4, 5]));
+        assert_matches!(repo.delete(NAME), Ok(()));
+        assert_eq!(server.file(PATH), None);
+        assert_matches!(repo.download(NAME), Err(UpmError::SyncDatabaseNotFound));
+
+        let requests: Vec<(String, String)> = server
+            .requests()
+            .into_iter()
+            .map(|r| (r.method, r.target))
+            .collect();
+        let temporary = "/dav/my%20%231%3F%25.db.upload.tmp";
+        let expected = [
+            ("HEAD", PATH),
+            ("PUT", PATH),
+            ("GET", PATH),
+            ("PUT", temporary),
+            ("MOVE", temporary),
+            ("DELETE", PATH),
+            ("GET", PATH),
+        ];
+        assert_eq!(
+            requests,
+            expected
+                .iter()
+                .map(|&(m, t)| (String::from(m), String::from(t)))
+                .collect::<Vec<_>>()
+        );
+        let destination = format!("http://{}{}", server.address(), PATH);
+        let requests = server.requests();
+        let move_request = requests.iter().find(|r| r.method == "MOVE").unwrap();
+        assert_eq!(
+            move_request.header("Destination"),
+            Some(destination.as_str())
+        );
+    }
+
+    #[test]
+    fn test_url_to_http() {
+        assert_eq!(
+            WebDavRepository::url_to_http("webdav://example.com/dav/"),
+            Some(String::from("http://example.com/dav/"))
+        );
+        assert_eq!(
+            WebDavRepository::url_to_http("webdavs://example.com:8443/dav"),
+            Some(String::from("https://example.com:8443/dav"))
+        );
+        assert_eq!(
+            WebDavRepository::url_to_http("https://example.com/dav/"),
+            None
+        );
+    }
+}